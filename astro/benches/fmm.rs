@@ -0,0 +1,118 @@
+#![feature(test)]
+
+extern crate test;
+use std::collections::HashMap;
+
+use astro::transformers::{AstroOctreeElement, FmmElement, SimpleAstroElement};
+use physim_core::{Acceleration, Entity, plugin::transform::TransformElement};
+use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
+use serde_json::json;
+use test::Bencher;
+
+fn random_state(num_entities: usize) -> Vec<Entity> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    (0..num_entities)
+        .map(|_| Entity::random(&mut rng))
+        .collect()
+}
+
+fn accelerations(element: &impl TransformElement, state: &[Entity]) -> Vec<Acceleration> {
+    let mut accelerations = vec![Acceleration::zero(); state.len()];
+    element.transform(state, &mut accelerations);
+    accelerations
+}
+
+/// Median relative error of `element` compared to `simple_astro`. The
+/// benchmark only reports times, so the error is printed.
+fn report_error(name: &str, element: &impl TransformElement, state: &[Entity]) {
+    let exact = SimpleAstroElement::new(HashMap::from([("e".to_string(), json!(0.0001))]));
+    let expected = accelerations(&exact, state);
+    let mut errors: Vec<f64> = expected
+        .iter()
+        .zip(accelerations(element, state))
+        .map(|(a, b)| (a.x - b.x).hypot(a.y - b.y).hypot(a.z - b.z) / a.x.hypot(a.y).hypot(a.z))
+        .collect();
+    errors.sort_by(f64::total_cmp);
+    eprintln!(
+        "{name} with {} entities: median relative error {:.2e}",
+        state.len(),
+        errors[errors.len() / 2]
+    );
+}
+
+fn benchmark(element: impl TransformElement, name: &str, num_entities: usize, b: &mut Bencher) {
+    let state = random_state(num_entities);
+    // the exact accelerations take too long for large states
+    if num_entities <= 10_000 {
+        report_error(name, &element, &state);
+    }
+    b.iter(|| accelerations(&element, &state));
+}
+
+fn fmm(order: usize) -> FmmElement {
+    FmmElement::new(HashMap::from([
+        ("e".to_string(), json!(0.0001)),
+        ("order".to_string(), json!(order)),
+    ]))
+}
+
+fn astro2(theta: f64) -> AstroOctreeElement {
+    AstroOctreeElement::new(HashMap::from([
+        ("e".to_string(), json!(0.0001)),
+        ("theta".to_string(), json!(theta)),
+    ]))
+}
+
+fn simple_astro() -> SimpleAstroElement {
+    SimpleAstroElement::new(HashMap::from([("e".to_string(), json!(0.0001))]))
+}
+
+#[bench]
+fn fmm_1000(b: &mut Bencher) {
+    benchmark(fmm(4), "fmm order=4", 1_000, b);
+}
+
+#[bench]
+fn fmm_10_000(b: &mut Bencher) {
+    benchmark(fmm(4), "fmm order=4", 10_000, b);
+}
+
+#[bench]
+fn fmm_100_000(b: &mut Bencher) {
+    benchmark(fmm(4), "fmm order=4", 100_000, b);
+}
+
+#[bench]
+fn fmm_order_2_10_000(b: &mut Bencher) {
+    benchmark(fmm(2), "fmm order=2", 10_000, b);
+}
+
+#[bench]
+fn fmm_order_6_10_000(b: &mut Bencher) {
+    benchmark(fmm(6), "fmm order=6", 10_000, b);
+}
+
+#[bench]
+fn astro2_1000(b: &mut Bencher) {
+    benchmark(astro2(0.3), "astro2 theta=0.3", 1_000, b);
+}
+
+#[bench]
+fn astro2_10_000(b: &mut Bencher) {
+    benchmark(astro2(0.3), "astro2 theta=0.3", 10_000, b);
+}
+
+#[bench]
+fn astro2_100_000(b: &mut Bencher) {
+    benchmark(astro2(0.3), "astro2 theta=0.3", 100_000, b);
+}
+
+#[bench]
+fn simple_astro_1000(b: &mut Bencher) {
+    benchmark(simple_astro(), "simple_astro", 1_000, b);
+}
+
+#[bench]
+fn simple_astro_10_000(b: &mut Bencher) {
+    benchmark(simple_astro(), "simple_astro", 10_000, b);
+}
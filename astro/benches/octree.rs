@@ -1,7 +1,7 @@
 #![feature(test)]
 
 extern crate test;
-use astro::octree::Octree;
+use astro::{linear_octree::LinearOctree, octree::Octree};
 use bumpalo::Bump;
 use physim_core::Entity;
 use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
@@ -51,3 +51,51 @@ fn push_100_000(b: &mut Bencher) {
 fn push_1_000_000(b: &mut Bencher) {
     push_benchmarks(1_000_000, b);
 }
+
+fn random_state(num_entities: usize) -> Vec<Entity> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    (0..num_entities)
+        .map(|_| Entity::random(&mut rng))
+        .collect()
+}
+
+fn build_benchmarks(num_entities: usize, b: &mut Bencher) {
+    let state = random_state(num_entities);
+    b.iter(|| LinearOctree::new([0.0; 3], 1.0, &state));
+}
+
+fn refit_benchmarks(num_entities: usize, b: &mut Bencher) {
+    let state = random_state(num_entities);
+    let mut tree = LinearOctree::new([0.0; 3], 1.0, &state);
+    b.iter(|| tree.refit(&state));
+}
+
+#[bench]
+fn build_10_000(b: &mut Bencher) {
+    build_benchmarks(10_000, b);
+}
+
+#[bench]
+fn build_100_000(b: &mut Bencher) {
+    build_benchmarks(100_000, b);
+}
+
+#[bench]
+fn build_1_000_000(b: &mut Bencher) {
+    build_benchmarks(1_000_000, b);
+}
+
+#[bench]
+fn refit_10_000(b: &mut Bencher) {
+    refit_benchmarks(10_000, b);
+}
+
+#[bench]
+fn refit_100_000(b: &mut Bencher) {
+    refit_benchmarks(100_000, b);
+}
+
+#[bench]
+fn refit_1_000_000(b: &mut Bencher) {
+    refit_benchmarks(1_000_000, b);
+}
@@ -0,0 +1,630 @@
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    Star,
+    linear_octree::{KEY_LEVELS, LinearOctree},
+    softening::{Kernel, Softening},
+};
+
+/// Nodes with more entities than this find their interactions in parallel
+const PARALLEL_THRESHOLD: usize = 4096;
+
+/// Cartesian Taylor expansions up to an order `p`. Terms are indexed by a
+/// multi-index `[a, b, c]`, the powers of x, y and z, and are sorted by
+/// `a + b + c`, so the terms up to order `n` come first.
+#[derive(Debug)]
+struct Expansions {
+    order: usize,
+    terms: Vec<[usize; 3]>,
+    // index of each multi-index in `terms`, in a (p+1)^3 cube
+    index: Vec<usize>,
+    // number of terms up to each order
+    counts: Vec<usize>,
+    // for each term a, the index of a+b for every term b where |a|+|b| <= p.
+    // As terms are sorted by order, these b are the first terms. Shifting
+    // and converting expansions are sums over these.
+    sums: Vec<Vec<usize>>,
+    // (a, a+e_i) for each axis i, for the gradient of a local expansion
+    gradients: [Vec<(usize, usize)>; 3],
+    // how each term is built up from lower ones. Monomials and derivatives
+    // are built up from these.
+    lower: Vec<Lower>,
+}
+
+/// For a term t, the first axis i with t_i > 0, and the indices of t-e_i and
+/// t-2e_i. `weight` is t_i - 1, or 0 when t_i < 2 and `twice` is unused.
+#[derive(Debug, Default)]
+struct Lower {
+    axis: usize,
+    down: usize,
+    twice: usize,
+    weight: f64,
+}
+
+impl Expansions {
+    fn new(order: usize) -> Self {
+        let mut terms = vec![];
+        let mut counts = vec![];
+        for n in 0..=order {
+            for a in (0..=n).rev() {
+                for b in (0..=n - a).rev() {
+                    terms.push([a, b, n - a - b]);
+                }
+            }
+            counts.push(terms.len());
+        }
+        let side = order + 1;
+        let mut index = vec![usize::MAX; side * side * side];
+        for (i, t) in terms.iter().enumerate() {
+            index[(t[0] * side + t[1]) * side + t[2]] = i;
+        }
+        let mut expansions = Self {
+            order,
+            terms,
+            index,
+            counts,
+            sums: vec![],
+            gradients: [vec![], vec![], vec![]],
+            lower: vec![],
+        };
+
+        let terms = &expansions.terms;
+        let degree = |t: &[usize; 3]| t[0] + t[1] + t[2];
+        let sums = terms
+            .iter()
+            .map(|a| {
+                terms[..expansions.counts[order - degree(a)]]
+                    .iter()
+                    .map(|b| expansions.idx([a[0] + b[0], a[1] + b[1], a[2] + b[2]]))
+                    .collect()
+            })
+            .collect();
+        let mut gradients = [vec![], vec![], vec![]];
+        for (i, t) in terms.iter().enumerate().filter(|(_, t)| degree(t) < order) {
+            for (axis, gradient) in gradients.iter_mut().enumerate() {
+                let mut up = *t;
+                up[axis] += 1;
+                gradient.push((i, expansions.idx(up)));
+            }
+        }
+        let lower = terms
+            .iter()
+            .map(|&t| {
+                let Some(axis) = t.iter().position(|&n| n > 0) else {
+                    return Lower::default();
+                };
+                let mut down = t;
+                down[axis] -= 1;
+                let mut lower = Lower {
+                    axis,
+                    down: expansions.idx(down),
+                    ..Default::default()
+                };
+                if t[axis] >= 2 {
+                    down[axis] -= 1;
+                    lower.twice = expansions.idx(down);
+                    lower.weight = (t[axis] - 1) as f64;
+                }
+                lower
+            })
+            .collect();
+        expansions.sums = sums;
+        expansions.gradients = gradients;
+        expansions.lower = lower;
+        expansions
+    }
+
+    fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    fn idx(&self, t: [usize; 3]) -> usize {
+        let side = self.order + 1;
+        self.index[(t[0] * side + t[1]) * side + t[2]]
+    }
+
+    /// d^t / t! for every term t
+    fn monomials(&self, d: [f64; 3]) -> Vec<f64> {
+        let mut out = vec![0.0; self.len()];
+        out[0] = 1.0;
+        for k in 1..self.len() {
+            let Lower { axis, down, .. } = self.lower[k];
+            out[k] = out[down] * d[axis] / self.terms[k][axis] as f64;
+        }
+        out
+    }
+
+    /// Derivatives of the potential at a separation `r`, for every term. The
+    /// potential is the one whose gradient is the softened force, e.g.
+    /// r/|r| / (|r|^2 + e) for the easing kernel. Only its gradient matters,
+    /// so the potential itself is left at 0. The spline kernel is Newtonian
+    /// this far apart.
+    fn derivatives(&self, r: [f64; 3], softening: &Softening) -> Vec<f64> {
+        let p = self.order;
+        let r2 = r[0] * r[0] + r[1] * r[1] + r[2] * r[2];
+
+        // the potential is a function of u = r^2/2, and f[m] is its m-th
+        // derivative with respect to u. The first derivative is the force
+        // factor of the kernel.
+        let mut f = vec![0.0; p + 1];
+        match softening.kernel {
+            Kernel::Easing => {
+                // 1/r * 1/(r^2 + e), and the rest follow from the product rule
+                let eased = r2 + softening.e;
+                let mut inverse_r = vec![1.0 / r2.sqrt(); p];
+                let mut inverse_eased = vec![1.0 / eased; p];
+                for j in 1..p {
+                    inverse_r[j] = -inverse_r[j - 1] * (2 * j - 1) as f64 / r2;
+                    inverse_eased[j] = -inverse_eased[j - 1] * (2 * j) as f64 / eased;
+                }
+                for (m, f) in f.iter_mut().enumerate().skip(1) {
+                    let mut binomial = 1.0;
+                    for j in 0..m {
+                        *f += binomial * inverse_r[j] * inverse_eased[m - 1 - j];
+                        binomial *= (m - 1 - j) as f64 / (j + 1) as f64;
+                    }
+                }
+            }
+            Kernel::Plummer | Kernel::None | Kernel::Spline => {
+                // (r^2 + e)^(-3/2), where e is 0 without softening
+                let s = match softening.kernel {
+                    Kernel::Plummer => r2 + softening.e,
+                    _ => r2,
+                };
+                f[1] = 1.0 / (s * s.sqrt());
+                for m in 1..p {
+                    f[m + 1] = -f[m] * (2 * m + 1) as f64 / s;
+                }
+            }
+        }
+
+        // d/dx_i g(u) = x_i g'(u), so the derivatives of each f[m] follow
+        // from those of f[m+1]
+        let mut next = vec![0.0; self.len()];
+        let mut current = vec![0.0; self.len()];
+        next[0] = f[p];
+        for m in (0..p).rev() {
+            current[0] = f[m];
+            for (c, lower) in current[..self.counts[p - m]]
+                .iter_mut()
+                .zip(&self.lower)
+                .skip(1)
+            {
+                *c = r[lower.axis] * next[lower.down] + lower.weight * next[lower.twice];
+            }
+            std::mem::swap(&mut next, &mut current);
+        }
+        next
+    }
+}
+
+/// Approximate gravity with the fast multipole method. The entities are put
+/// in an octree whose leaves hold a few entities each. Each node has a
+/// multipole expansion of the mass inside it, which is converted into a local
+/// expansion of the field around every well separated node. Entities in
+/// nearby leaves interact directly.
+#[derive(Debug)]
+pub struct Fmm {
+    expansions: Expansions,
+    theta: f64,
+    leaf_size: usize,
+    softening: Softening,
+}
+
+/// The far field of a set of entities, and which entities are near to each
+/// one.
+///
+/// The tree is a [`LinearOctree`] rather than a [`crate::octree::Octree`].
+/// The leaves of an `Octree` hold one entity each and its nodes can only be
+/// reached by walking down from the root, but the expansions need leaves of
+/// `leaf_size` entities, nodes which can be indexed to store an expansion
+/// each, and children which come after their parents so the upward and
+/// downward passes are loops over the nodes. The linear octree has all of
+/// these, and is built in parallel.
+pub struct FmmField<'s, T>
+where
+    T: Star,
+{
+    entities: &'s [T],
+    // the entities in the order of the tree, so those in a leaf are together
+    sorted: Vec<T>,
+    tree: LinearOctree<T>,
+    expansions: &'s Expansions,
+    // local expansion of each node, one after another
+    locals: Vec<f64>,
+    // leaf of each entity
+    leaf_of: Vec<usize>,
+    // leaves which interact directly with each leaf
+    near: Vec<Vec<usize>>,
+}
+
+impl Fmm {
+    /// `order` is the order of the expansions, and nodes interact through
+    /// their expansions when the sum of their half widths is less than
+    /// `theta` times the distance between them. The far field ignores the
+    /// radii of entities, as they are far apart.
+    pub fn new(order: usize, theta: f64, leaf_size: usize, softening: Softening) -> Self {
+        Self {
+            expansions: Expansions::new(order.max(1)),
+            theta,
+            leaf_size,
+            softening,
+        }
+    }
+
+    pub fn field<'s, T>(&'s self, entities: &'s [T]) -> FmmField<'s, T>
+    where
+        T: Star + Default + Copy + Send + Sync,
+    {
+        let extent = entities
+            .iter()
+            .flat_map(|x| x.get_centre())
+            .map(|x| x.abs())
+            .reduce(f64::max)
+            .unwrap_or(1.0);
+        let tree = LinearOctree::with_leaf_size([0.0; 3], extent, entities, self.leaf_size);
+        let nodes = tree.nodes();
+        let order = tree.order();
+        let terms = self.expansions.len();
+
+        // multipoles of the leaves from their entities, then of the other
+        // nodes from their children. Children come after their parent.
+        let mut multipoles = vec![0.0; nodes.len() * terms];
+        multipoles
+            .par_chunks_mut(terms)
+            .zip(nodes)
+            .filter(|(_, node)| node.is_leaf())
+            .for_each(|(multipole, node)| {
+                for &i in &order[node.start..node.end] {
+                    let c = entities[i].get_centre();
+                    let d = [
+                        node.centre[0] - c[0],
+                        node.centre[1] - c[1],
+                        node.centre[2] - c[2],
+                    ];
+                    let m = entities[i].get_mass();
+                    for (a, x) in multipole.iter_mut().zip(self.expansions.monomials(d)) {
+                        *a += m * x;
+                    }
+                }
+            });
+        for idx in (0..nodes.len()).rev() {
+            let node = &nodes[idx];
+            for child in tree.children(idx) {
+                let c = nodes[child].centre;
+                let d = [
+                    node.centre[0] - c[0],
+                    node.centre[1] - c[1],
+                    node.centre[2] - c[2],
+                ];
+                let shift = self.expansions.monomials(d);
+                let (parent, rest) = multipoles.split_at_mut(child * terms);
+                let parent = &mut parent[idx * terms..(idx + 1) * terms];
+                let child = &rest[..terms];
+                for (s, sums) in shift.iter().zip(&self.expansions.sums) {
+                    for (&sum, c) in sums.iter().zip(child) {
+                        parent[sum] += s * c;
+                    }
+                }
+            }
+        }
+
+        // which nodes interact with each node, by walking pairs of nodes
+        let mut interactions = vec![];
+        if !nodes.is_empty() {
+            self.interactions(&tree, 0, 0, &mut interactions);
+        }
+        let mut far = vec![vec![]; nodes.len()];
+        let mut near = vec![vec![]; nodes.len()];
+        for i in interactions {
+            if i.far {
+                far[i.target].push(i.source);
+            } else {
+                near[i.target].push(i.source);
+            }
+        }
+
+        // node centres are on a grid as fine as the smallest possible node, so
+        // many pairs of nodes are the same distance apart and can share the
+        // derivatives of the potential
+        let unit = nodes
+            .first()
+            .map_or(1.0, |root| root.extent / (1u64 << KEY_LEVELS) as f64);
+        let offset = |target: usize, source: usize| {
+            let (a, b) = (nodes[target].centre, nodes[source].centre);
+            [0, 1, 2].map(|i| ((a[i] - b[i]) / unit).round() as i64)
+        };
+        let mut offsets: Vec<[i64; 3]> = far
+            .iter()
+            .enumerate()
+            .flat_map(|(target, sources)| sources.iter().map(move |&s| offset(target, s)))
+            .collect();
+        offsets.par_sort_unstable();
+        offsets.dedup();
+        let mut derivatives = vec![0.0; offsets.len() * terms];
+        derivatives
+            .par_chunks_mut(terms)
+            .zip(&offsets)
+            .for_each(|(d, offset)| {
+                let r = offset.map(|x| x as f64 * unit);
+                d.copy_from_slice(&self.expansions.derivatives(r, &self.softening));
+            });
+
+        // local expansions from the multipoles of the well separated nodes,
+        // then passed down from each node to its children
+        let mut locals = vec![0.0; nodes.len() * terms];
+        locals.par_chunks_mut(terms).zip(&far).enumerate().for_each(
+            |(target, (local, sources))| {
+                for &source in sources {
+                    let k = offsets
+                        .binary_search(&offset(target, source))
+                        .expect("every offset was found");
+                    let derivatives = &derivatives[k * terms..(k + 1) * terms];
+                    let multipole = &multipoles[source * terms..(source + 1) * terms];
+                    for (m, sums) in multipole.iter().zip(&self.expansions.sums) {
+                        for (l, &sum) in local.iter_mut().zip(sums) {
+                            *l += derivatives[sum] * m;
+                        }
+                    }
+                }
+            },
+        );
+        for idx in 0..nodes.len() {
+            let node = &nodes[idx];
+            for child in tree.children(idx) {
+                let c = nodes[child].centre;
+                let d = [
+                    c[0] - node.centre[0],
+                    c[1] - node.centre[1],
+                    c[2] - node.centre[2],
+                ];
+                let shift = self.expansions.monomials(d);
+                let (parent, rest) = locals.split_at_mut(child * terms);
+                let parent = &parent[idx * terms..(idx + 1) * terms];
+                let child = &mut rest[..terms];
+                for (s, sums) in shift.iter().zip(&self.expansions.sums) {
+                    for (c, &sum) in child.iter_mut().zip(sums) {
+                        *c += parent[sum] * s;
+                    }
+                }
+            }
+        }
+
+        let mut leaf_of = vec![0; entities.len()];
+        for (idx, node) in nodes.iter().enumerate().filter(|(_, n)| n.is_leaf()) {
+            for &i in &order[node.start..node.end] {
+                leaf_of[i] = idx;
+            }
+        }
+
+        FmmField {
+            entities,
+            sorted: order.iter().map(|&i| entities[i]).collect(),
+            tree,
+            expansions: &self.expansions,
+            locals,
+            leaf_of,
+            near,
+        }
+    }
+
+    /// Whether nodes `a` and `b` are far enough apart to interact through
+    /// their expansions
+    fn separated<T>(&self, tree: &LinearOctree<T>, a: usize, b: usize) -> bool
+    where
+        T: Star + Default + Copy + Send + Sync,
+    {
+        let (a, b) = (&tree.nodes()[a], &tree.nodes()[b]);
+        let d2 = (0..3)
+            .map(|i| (a.centre[i] - b.centre[i]).powi(2))
+            .sum::<f64>();
+        (a.extent + b.extent).powi(2) < self.theta * self.theta * d2
+    }
+
+    /// Find the interactions of the nodes below `target` with the nodes
+    /// below `source`
+    fn interactions<T>(
+        &self,
+        tree: &LinearOctree<T>,
+        target: usize,
+        source: usize,
+        out: &mut Vec<Interaction>,
+    ) where
+        T: Star + Default + Copy + Send + Sync,
+    {
+        let nodes = tree.nodes();
+        let (a, b) = (&nodes[target], &nodes[source]);
+        if target != source && self.separated(tree, target, source) {
+            out.push(Interaction {
+                target,
+                source,
+                far: true,
+            });
+            return;
+        }
+        if a.is_leaf() && b.is_leaf() {
+            out.push(Interaction {
+                target,
+                source,
+                far: false,
+            });
+            return;
+        }
+        let split_target = !a.is_leaf() && (b.is_leaf() || a.extent >= b.extent);
+        let pairs: Vec<(usize, usize)> = if target == source {
+            tree.children(target)
+                .flat_map(|t| tree.children(source).map(move |s| (t, s)))
+                .collect()
+        } else if split_target {
+            tree.children(target).map(|t| (t, source)).collect()
+        } else {
+            tree.children(source).map(|s| (target, s)).collect()
+        };
+        // different targets can be found in parallel, and the order of the
+        // results doesn't depend on it
+        if (split_target || target == source) && a.end - a.start > PARALLEL_THRESHOLD {
+            let found: Vec<Vec<Interaction>> = pairs
+                .into_par_iter()
+                .map(|(t, s)| {
+                    let mut found = vec![];
+                    self.interactions(tree, t, s, &mut found);
+                    found
+                })
+                .collect();
+            out.extend(found.into_iter().flatten());
+        } else {
+            for (t, s) in pairs {
+                self.interactions(tree, t, s, out);
+            }
+        }
+    }
+}
+
+/// Node `source` acting on node `target`, through its expansion if `far` or
+/// directly if not
+struct Interaction {
+    target: usize,
+    source: usize,
+    far: bool,
+}
+
+impl<T> FmmField<'_, T>
+where
+    T: Star + Default + Copy + Send + Sync,
+{
+    /// Acceleration of entity `i` due to the entities which are far from it,
+    /// without G
+    pub fn far_acceleration(&self, i: usize) -> [f64; 3] {
+        let leaf = self.leaf_of[i];
+        let centre = self.tree.nodes()[leaf].centre;
+        let c = self.entities[i].get_centre();
+        let d = [c[0] - centre[0], c[1] - centre[1], c[2] - centre[2]];
+        let monomials = self.expansions.monomials(d);
+        let terms = self.expansions.len();
+        let local = &self.locals[leaf * terms..(leaf + 1) * terms];
+        let mut a = [0.0; 3];
+        for (axis, gradient) in self.expansions.gradients.iter().enumerate() {
+            for &(t, up) in gradient {
+                a[axis] -= local[up] * monomials[t];
+            }
+        }
+        a
+    }
+
+    /// The entities near to entity `i`, including itself, whose gravity has
+    /// to be calculated directly
+    pub fn near_entities(&self, i: usize) -> impl Iterator<Item = &T> {
+        let nodes = self.tree.nodes();
+        self.near[self.leaf_of[i]]
+            .iter()
+            .flat_map(move |&leaf| &self.sorted[nodes[leaf].start..nodes[leaf].end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use physim_core::Entity;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn test_expansion_terms() {
+        let expansions = Expansions::new(4);
+        assert_eq!(expansions.len(), 35);
+        assert_eq!(expansions.counts, [1, 4, 10, 20, 35]);
+        assert_eq!(expansions.idx([0, 0, 0]), 0);
+        assert_eq!(expansions.terms[expansions.idx([1, 2, 1])], [1, 2, 1]);
+        let monomials = expansions.monomials([2.0, 3.0, 5.0]);
+        // x^1 y^2 z^1 / (1! 2! 1!)
+        assert_eq!(monomials[expansions.idx([1, 2, 1])], 2.0 * 9.0 * 5.0 / 2.0);
+    }
+
+    #[test]
+    fn test_derivatives() {
+        // finite differences of the first derivatives give the second
+        let expansions = Expansions::new(3);
+        let r = [0.7, -0.4, 1.1];
+        for kernel in [Kernel::Easing, Kernel::Plummer, Kernel::None] {
+            let softening = Softening::new(kernel, 0.3, false);
+            let d = expansions.derivatives(r, &softening);
+            // the gradient is the softened force
+            let r2: f64 = r.iter().map(|x| x * x).sum();
+            let force = softening.factor(r2, softening.e);
+            assert!((d[expansions.idx([1, 0, 0])] - r[0] * force).abs() < 1e-12);
+            let h = 1e-6;
+            for (up, axis) in [([2, 0, 0], 0), ([1, 1, 0], 1), ([0, 1, 1], 2)] {
+                let mut shifted = r;
+                shifted[axis] += h;
+                let below = {
+                    let mut b = up;
+                    b[axis] -= 1;
+                    expansions.idx(b)
+                };
+                let numeric = (expansions.derivatives(shifted, &softening)[below] - d[below]) / h;
+                assert!((numeric - d[expansions.idx(up)]).abs() < 1e-5, "{kernel:?}");
+            }
+        }
+    }
+
+    /// Median relative error of the FMM compared to summing every pair
+    fn error(order: usize, softening: Softening) -> f64 {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let entities: Vec<Entity> = (0..1500).map(|_| Entity::random(&mut rng)).collect();
+        let fmm = Fmm::new(order, 0.5, 16, softening);
+        let field = fmm.field(&entities);
+        let mut errors: Vec<f64> = (0..entities.len())
+            .map(|i| {
+                let a = &entities[i];
+                let mut exact = [0.0; 3];
+                let mut approximate = field.far_acceleration(i);
+                for (all, sum) in [
+                    (entities.iter().collect::<Vec<_>>(), &mut exact),
+                    (field.near_entities(i).collect(), &mut approximate),
+                ] {
+                    for b in all.into_iter().filter(|b| b.get_centre() != a.get_centre()) {
+                        let f = a.newtons_law_of_universal_gravitation(b, 1.0, &softening);
+                        for k in 0..3 {
+                            sum[k] += f[k] / a.mass;
+                        }
+                    }
+                }
+                let difference = (exact[0] - approximate[0])
+                    .hypot(exact[1] - approximate[1])
+                    .hypot(exact[2] - approximate[2]);
+                difference / exact[0].hypot(exact[1]).hypot(exact[2])
+            })
+            .collect();
+        errors.sort_by(f64::total_cmp);
+        errors[errors.len() / 2]
+    }
+
+    #[test]
+    fn test_accuracy_improves_with_order() {
+        for softening in [
+            Softening::easing(0.0001),
+            Softening::easing(0.1),
+            Softening::new(Kernel::Plummer, 0.0001, false),
+        ] {
+            let errors: Vec<f64> = [1, 2, 4, 6].map(|p| error(p, softening)).to_vec();
+            assert!(errors.windows(2).all(|w| w[1] < w[0]), "{errors:?}");
+            assert!(errors[3] < 1e-3, "{errors:?}");
+        }
+    }
+
+    #[test]
+    fn test_every_entity_is_near_itself() {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let entities: Vec<Entity> = (0..500).map(|_| Entity::random(&mut rng)).collect();
+        let fmm = Fmm::new(2, 0.5, 8, Softening::easing(0.01));
+        let field = fmm.field(&entities);
+        for (i, e) in entities.iter().enumerate() {
+            assert!(field.near_entities(i).any(|n| n == e));
+        }
+        let empty: Vec<Entity> = vec![];
+        assert!(fmm.field(&empty).near.is_empty());
+    }
+}
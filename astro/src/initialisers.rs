@@ -3,18 +3,45 @@ use rand::Rng;
 use rand_distr::Distribution;
 use std::{collections::HashMap, f64::consts::PI, sync::Mutex};
 
-use physim_attribute::initialise_state_element;
+use physim_attribute::{Properties, initialise_state_element};
 use physim_core::{
     Entity,
     messages::MessageClient,
-    plugin::{Element, ElementCreator, generator::GeneratorElement},
+    plugin::{Element, ElementCreator, generator::GeneratorElement, properties::Properties},
+    units::{AU, SOLAR_MASS, UnitSystem, unit_system},
 };
 use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
 use serde_json::Value;
 
+#[derive(Properties)]
+struct CubeProperties {
+    /// Number of stars
+    #[property(default = 100_000)]
+    n: u64,
+    /// Random seed
+    #[property(default = 0)]
+    seed: u64,
+    /// Spin factor v = (r*s)
+    #[property(default = 0.0)]
+    spin: f64,
+    /// side length of cube
+    #[property(default = 1.0, range = 0.0..)]
+    size: f64,
+    /// Total mass of cube
+    #[property(default = 1.0, range = 0.0..)]
+    mass: f64,
+    /// Centre (specify in CLI with \[x,y,z\])
+    #[property(default = [0.0, 0.0, 0.0])]
+    centre: [f64; 3],
+    /// Id assigned to stars in galaxy
+    #[property(default = 0)]
+    id: usize,
+}
+
 #[initialise_state_element(
     name = "cube",
-    blurb = "Generate a galaxy where stars are randomly placed in a cubic volume."
+    blurb = "Generate a galaxy where stars are randomly placed in a cubic volume.",
+    properties = CubeProperties
 )]
 #[derive(Debug)]
 pub struct RandomCube {
@@ -34,45 +61,16 @@ struct InnerRandomCube {
 
 impl ElementCreator for RandomCube {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let n = properties
-            .get("n")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(100_000);
-        let seed = properties.get("seed").and_then(|v| v.as_u64()).unwrap_or(0);
-        let spin = properties
-            .get("spin")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(0.0);
-        let size = properties
-            .get("size")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let centre = properties
-            .get("centre")
-            .and_then(|v| {
-                let coords = v.as_array()?;
-                if coords.len() != 3 {
-                    None
-                } else {
-                    let coords: Vec<f64> = coords.iter().flat_map(|x| x.as_f64()).collect();
-                    Some([coords[0], coords[1], coords[2]])
-                }
-            })
-            .unwrap_or([0.0_f64; 3]);
-        let mass = properties
-            .get("mass")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let id = properties.get("id").and_then(|id| id.as_u64()).unwrap_or(0);
+        let properties = CubeProperties::parse("cube", &properties);
         Box::new(Self {
             inner: Mutex::new(InnerRandomCube {
-                n,
-                seed,
-                spin,
-                centre,
-                size,
-                mass,
-                id: id as usize,
+                n: properties.n,
+                seed: properties.seed,
+                spin: properties.spin,
+                centre: properties.centre,
+                size: properties.size,
+                mass: properties.mass,
+                id: properties.id,
             }),
         })
     }
@@ -109,30 +107,51 @@ impl Element for RandomCube {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            ("n".to_string(), "Number of stars".to_string()),
-            ("seed".to_string(), "Random seed".to_string()),
-            ("spin".to_string(), "Spin factor v = (r*s)".to_string()),
-            ("size".to_string(), "side length of cube".to_string()),
-            (
-                "mass".to_string(),
-                "Total mass of cube. Default is 1.0".to_string(),
-            ),
-            (
-                "centre".to_string(),
-                "Centre (specify in CLI with \\[x,y,z\\])".to_string(),
-            ),
-            (
-                "id".to_string(),
-                "Id assigned to stars in galaxy".to_string(),
-            ),
-        ]))
+        Ok(CubeProperties::descriptions())
     }
 }
 
 impl MessageClient for RandomCube {}
 
-#[initialise_state_element(name = "star", blurb = "create a configurable star")]
+#[derive(Properties)]
+struct StarProperties {
+    /// x position
+    #[property(default = 0.0)]
+    x: f64,
+    /// y position
+    #[property(default = 0.0)]
+    y: f64,
+    /// z position
+    #[property(default = 0.0)]
+    z: f64,
+    /// velocity in x direction
+    #[property(default = 0.0)]
+    vx: f64,
+    /// velocity in y direction
+    #[property(default = 0.0)]
+    vy: f64,
+    /// velocity in z direction
+    #[property(default = 0.0)]
+    vz: f64,
+    /// mass
+    #[property(default = 0.0, range = 0.0..)]
+    mass: f64,
+    /// Radius (screen units)
+    #[property(default = 0.1, range = 0.0..)]
+    radius: f64,
+    /// ID of entity
+    #[property(default = 0)]
+    id: usize,
+    /// Fix location
+    #[property(default = false)]
+    fixed: bool,
+}
+
+#[initialise_state_element(
+    name = "star",
+    blurb = "create a configurable star",
+    properties = StarProperties
+)]
 pub struct SingleStar {
     inner: Mutex<SingleStarInner>,
 }
@@ -144,33 +163,18 @@ struct SingleStarInner {
 
 impl ElementCreator for SingleStar {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        fn get_f64(properties: &HashMap<String, Value>, key: &str) -> f64 {
-            properties
-                .get(key)
-                .and_then(|v| v.as_f64())
-                .unwrap_or_default()
-        }
-
+        let properties = StarProperties::parse("star", &properties);
         let entity = Entity {
-            x: get_f64(&properties, "x"),
-            y: get_f64(&properties, "y"),
-            z: get_f64(&properties, "z"),
-            vx: get_f64(&properties, "vx"),
-            vy: get_f64(&properties, "vy"),
-            vz: get_f64(&properties, "vz"),
-            radius: properties
-                .get("radius")
-                .and_then(|v| v.as_f64())
-                .unwrap_or(0.1),
-            mass: get_f64(&properties, "mass"),
-            id: properties
-                .get("id")
-                .and_then(|v| v.as_u64().map(|v| v as usize))
-                .unwrap_or(0),
-            fixed: properties
-                .get("fixed")
-                .and_then(|v| v.as_bool())
-                .unwrap_or(false),
+            x: properties.x,
+            y: properties.y,
+            z: properties.z,
+            vx: properties.vx,
+            vy: properties.vy,
+            vz: properties.vz,
+            radius: properties.radius,
+            mass: properties.mass,
+            id: properties.id,
+            fixed: properties.fixed,
         };
 
         let inner = SingleStarInner { entity };
@@ -194,24 +198,42 @@ impl Element for SingleStar {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            ("x".to_string(), "x position".to_string()),
-            ("y".to_string(), "y position".to_string()),
-            ("z".to_string(), "z position".to_string()),
-            ("vx".to_string(), "velocity in x direction".to_string()),
-            ("vy".to_string(), "velocity in y direction".to_string()),
-            ("vz".to_string(), "velocity in z direction".to_string()),
-            ("m".to_string(), "mass".to_string()),
-            ("r".to_string(), "Radius (screen units)".to_string()),
-            ("id".to_string(), "ID of entity".to_string()),
-            ("fixed".to_string(), "Fix location".to_string()),
-        ]))
+        Ok(StarProperties::descriptions())
     }
 }
 
+#[derive(Properties)]
+struct PlummerProperties {
+    /// Number of stars
+    #[property(default = 100_000)]
+    n: u64,
+    /// Random seed
+    #[property(default = 0)]
+    seed: u64,
+    /// Mass of Galaxy
+    #[property(default = 1.0, range = 0.0..)]
+    mass: f64,
+    /// Spin factor
+    #[property(default = 0.0)]
+    spin: f64,
+    /// Plummer radius
+    #[property(default = 1.0, range = 0.0..)]
+    a: f64,
+    /// Centre (specify in CLI with \[x,y,z\])
+    #[property(default = [0.0, 0.0, 0.0])]
+    centre: [f64; 3],
+    /// velocity (specify in CLI with \[vx,vy,vz\])
+    #[property(default = [0.0, 0.0, 0.0])]
+    v: [f64; 3],
+    /// Id assigned to stars in galaxy
+    #[property(default = 0)]
+    id: usize,
+}
+
 #[initialise_state_element(
     name = "plummer",
-    blurb = "Generate a galaxy where stars are distributed using a Plummer model."
+    blurb = "Generate a galaxy where stars are distributed using a Plummer model.",
+    properties = PlummerProperties
 )]
 #[derive(Debug)]
 pub struct Plummer {
@@ -228,63 +250,23 @@ struct InnerPlummer {
     plummer_r: f64,
     spin: f64,
     id: usize,
+    g: f64,
 }
 
 impl ElementCreator for Plummer {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let n = properties
-            .get("n")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(100_000);
-        let seed = properties.get("seed").and_then(|v| v.as_u64()).unwrap_or(0);
-        let mass = properties
-            .get("mass")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let plummer_r = properties.get("a").and_then(|v| v.as_f64()).unwrap_or(1.0);
-        let centre = properties
-            .get("centre")
-            .and_then(|v| {
-                let coords = v.as_array()?;
-                if coords.len() != 3 {
-                    None
-                } else {
-                    let coords: Vec<f64> = coords.iter().flat_map(|x| x.as_f64()).collect();
-                    Some([coords[0], coords[1], coords[2]])
-                }
-            })
-            .unwrap_or([0.0_f64; 3]);
-
-        let initial_v = properties
-            .get("v")
-            .and_then(|v| {
-                let coords = v.as_array()?;
-                if coords.len() != 3 {
-                    None
-                } else {
-                    let coords: Vec<f64> = coords.iter().flat_map(|x| x.as_f64()).collect();
-                    Some([coords[0], coords[1], coords[2]])
-                }
-            })
-            .unwrap_or([0.0_f64; 3]);
-
-        let spin = properties
-            .get("spin")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(0.0);
-
-        let id = properties.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
-
+        let properties = PlummerProperties::parse("plummer", &properties);
         Box::new(Self {
             inner: Mutex::new(InnerPlummer {
-                n,
-                seed,
-                mass,
-                centre,
-                initial_v,
-                plummer_r,
-                spin,
-                id: id as usize,
+                n: properties.n,
+                seed: properties.seed,
+                mass: properties.mass,
+                centre: properties.centre,
+                initial_v: properties.v,
+                plummer_r: properties.a,
+                spin: properties.spin,
+                id: properties.id,
+                g: unit_system().g(),
             }),
         })
     }
@@ -311,7 +293,8 @@ impl GeneratorElement for Plummer {
             let r2 = r.powi(2);
             let a2 = element.plummer_r.powi(2);
 
-            let v_phi = element.spin * ((element.mass) * r2 / (r2 + a2).powf(1.5)).sqrt();
+            let v_phi =
+                element.spin * (element.g * element.mass * r2 / (r2 + a2).powf(1.5)).sqrt();
 
             let vx = -v_phi * phi.sin();
             let vy = v_phi * phi.cos();
@@ -333,53 +316,45 @@ impl Element for Plummer {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            ("n".to_string(), "Number of stars".to_string()),
-            ("seed".to_string(), "Random seed".to_string()),
-            ("mass".to_string(), "Mass of Galaxy".to_string()),
-            ("spin".to_string(), "Spin factor".to_string()),
-            ("a".to_string(), "Plummer radius".to_string()),
-            (
-                "centre".to_string(),
-                "Centre (specify in CLI with \\[x,y,z\\])".to_string(),
-            ),
-            (
-                "v".to_string(),
-                "velocity (specify in CLI with \\[vx,vy,vz\\])".to_string(),
-            ),
-            (
-                "id".to_string(),
-                "Id assigned to stars in galaxy".to_string(),
-            ),
-        ]))
+        Ok(PlummerProperties::descriptions())
     }
 }
 
 impl MessageClient for Plummer {}
 
-#[initialise_state_element(name = "solar", blurb = "Generate a toy solar system")]
+#[derive(Properties)]
+struct SolarProperties {
+    /// Random seed
+    #[property(default = 0)]
+    seed: u64,
+    /// Number of planets. Each planet has a moon
+    #[property(default = 0)]
+    planets: u64,
+    /// Number of asteroids
+    #[property(default = 0)]
+    asteroids: u64,
+}
+
+#[initialise_state_element(
+    name = "solar",
+    blurb = "Generate a toy solar system",
+    properties = SolarProperties
+)]
 pub struct SolarSystem {
     seed: u64,
     planets: u64,
     asteroids: u64,
+    units: UnitSystem,
 }
 
 impl ElementCreator for SolarSystem {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let seed = properties.get("seed").and_then(|v| v.as_u64()).unwrap_or(0);
-        let planets = properties
-            .get("planets")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0);
-        let asteroids = properties
-            .get("asteroids")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0);
-
+        let properties = SolarProperties::parse("solar", &properties);
         Box::new(Self {
-            seed,
-            planets,
-            asteroids,
+            seed: properties.seed,
+            planets: properties.planets,
+            asteroids: properties.asteroids,
+            units: unit_system(),
         })
     }
 }
@@ -389,13 +364,20 @@ impl GeneratorElement for SolarSystem {
     // https://arxiv.org/abs/1502.05011
     fn create_entities(&self) -> Vec<Entity> {
         let rng = ChaCha8Rng::seed_from_u64(self.seed);
+        // distances are in AU and masses in solar masses, which are also the
+        // N-body units of length and mass
+        let (length, mass) = match self.units.scales() {
+            Some([length, mass, _]) => (AU / length, SOLAR_MASS / mass),
+            None => (1.0, 1.0),
+        };
+        let g = self.units.g();
 
         let sun = Entity {
             x: 0.0,
             y: 0.0,
-            z: 0.5,
+            z: 0.5 * length,
             radius: 0.1,
-            mass: 1.0,
+            mass,
             fixed: true,
             ..Default::default()
         };
@@ -418,18 +400,20 @@ impl GeneratorElement for SolarSystem {
             .zip(m_planets)
             .zip(angle)
             .map(|((r, m), theta)| {
+                let r = r * length;
                 let x = r * theta.sin();
                 let y = r * theta.cos();
-                let vx = -theta.cos() * 1.0 / r.powf(0.5);
-                let vy = theta.sin() * 1.0 / r.powf(0.5);
+                let v = (g * sun.mass / r).sqrt();
+                let vx = -theta.cos() * v;
+                let vy = theta.sin() * v;
                 Entity {
                     x,
                     y,
                     vx,
                     vy,
-                    z: 0.5,
+                    z: 0.5 * length,
                     radius: 0.05,
-                    mass: m * 1e-5,
+                    mass: m * 1e-5 * mass,
                     ..Default::default()
                 }
             })
@@ -440,8 +424,8 @@ impl GeneratorElement for SolarSystem {
             .cloned()
             .map(|mut e| {
                 e.mass /= 10.0;
-                e.x += 0.01;
-                e.vy += (e.mass / 0.01).powf(0.5);
+                e.x += 0.01 * length;
+                e.vy += (g * e.mass / (0.01 * length)).sqrt();
                 e.radius = 0.005;
                 e
             })
@@ -471,6 +455,7 @@ impl GeneratorElement for SolarSystem {
             .zip(orientations)
             .map(|(((a, theta), e), phi)| {
                 // Ellipse parameters
+                let a = a * length;
                 let b = a * (1.0_f64 - e * e).sqrt();
 
                 // Position in unrotated ellipse coords
@@ -479,7 +464,7 @@ impl GeneratorElement for SolarSystem {
 
                 // Current distance from focus
                 let r_current = (x0 * x0 + y0 * y0).sqrt();
-                let mu = 1.0; // gravitational parameter
+                let mu = g * sun.mass; // gravitational parameter
                 let v_mag = (mu * (2.0 / r_current - 1.0 / a)).sqrt();
 
                 // Tangent direction in unrotated coords
@@ -502,9 +487,9 @@ impl GeneratorElement for SolarSystem {
                     y,
                     vx,
                     vy,
-                    z: 0.5,
+                    z: 0.5 * length,
                     radius: 0.01,
-                    mass: 1e-7,
+                    mass: 1e-7 * mass,
                     ..Default::default()
                 }
             })
@@ -521,15 +506,56 @@ impl Element for SolarSystem {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::new())
+        Ok(SolarProperties::descriptions())
     }
 }
 
 impl MessageClient for SolarSystem {}
 
+#[derive(Properties)]
+struct BarProperties {
+    /// Number of stars
+    #[property(default = 100_000)]
+    n: u64,
+    /// Random seed
+    #[property(default = 0)]
+    seed: u64,
+    /// linear scaling factor for rotational velocity
+    #[property(default = 0.0)]
+    spin: f64,
+    /// power law for rotational velocity
+    #[property(default = 1.0)]
+    spin_power: f64,
+    /// semi_major axis
+    #[property(default = 1.0, range = 0.0..)]
+    semi_major: f64,
+    /// semi_minor axis
+    #[property(default = 1.0, range = 0.0..)]
+    semi_minor: f64,
+    /// extent of ellipsoid in z direction
+    #[property(default = 1.0, range = 0.0..)]
+    thickness: f64,
+    /// Higher concentrates more mass at the centre of the ellipsoid. Good values are between 0.0 and 2.0
+    #[property(default = 1.0, range = 0.0..)]
+    ferrers_parameter: f64,
+    /// Total mass of the bar
+    #[property(default = 1.0, range = 0.0..)]
+    mass: f64,
+    /// Centre (specify in CLI with \[x,y,z\])
+    #[property(default = [0.0, 0.0, 0.0])]
+    centre: [f64; 3],
+    /// Id assigned to stars in galaxy
+    #[property(default = 0)]
+    id: usize,
+    /// Angle of the semi-major axis in degrees
+    #[property(default = 0.0)]
+    angle: f64,
+}
+
 #[initialise_state_element(
     name = "bar",
-    blurb = "Generate a galaxy where stars are randomly placed in a Ferrers bar."
+    blurb = "Generate a galaxy where stars are randomly placed in a Ferrers bar.",
+    properties = BarProperties
 )]
 #[derive(Debug)]
 /// Ferrers bar https://academic.oup.com/mnras/article/493/2/2676/5739934?login=false
@@ -555,71 +581,21 @@ struct InnerBar {
 
 impl ElementCreator for Bar {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let n = properties
-            .get("n")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(100_000);
-        let seed = properties.get("seed").and_then(|v| v.as_u64()).unwrap_or(0);
-        let spin = properties
-            .get("spin")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(0.0);
-        let spin_power = properties
-            .get("spin_power")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let centre = properties
-            .get("centre")
-            .and_then(|v| {
-                let coords = v.as_array()?;
-                if coords.len() != 3 {
-                    None
-                } else {
-                    let coords: Vec<f64> = coords.iter().flat_map(|x| x.as_f64()).collect();
-                    Some([coords[0], coords[1], coords[2]])
-                }
-            })
-            .unwrap_or([0.0_f64; 3]);
-        let semi_major = properties
-            .get("semi_major")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let semi_minor = properties
-            .get("semi_minor")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let thickness = properties
-            .get("thickness")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let ferrers_parameter = properties
-            .get("ferrers_parameter")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let mass = properties
-            .get("mass")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
-        let angle = properties
-            .get("angle")
-            .and_then(|v| v.as_f64())
-            .and_then(|x| Some(x.to_radians()))
-            .unwrap_or(0.0);
-        let id = properties.get("id").and_then(|id| id.as_u64()).unwrap_or(0);
+        let properties = BarProperties::parse("bar", &properties);
         Box::new(Self {
             inner: Mutex::new(InnerBar {
-                n,
-                seed,
-                spin,
-                spin_power,
-                centre,
-                semi_minor,
-                semi_major,
-                thickness,
-                ferrers_parameter,
-                mass,
-                angle,
-                id: id as usize,
+                n: properties.n,
+                seed: properties.seed,
+                spin: properties.spin,
+                spin_power: properties.spin_power,
+                centre: properties.centre,
+                semi_minor: properties.semi_minor,
+                semi_major: properties.semi_major,
+                thickness: properties.thickness,
+                ferrers_parameter: properties.ferrers_parameter,
+                mass: properties.mass,
+                angle: properties.angle.to_radians(),
+                id: properties.id,
             }),
         })
     }
@@ -678,30 +654,7 @@ impl Element for Bar {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            ("n".to_string(), "Number of stars".to_string()),
-            ("seed".to_string(), "Random seed".to_string()),
-            ("spin".to_string(), "linear scaling factor for rotational velocity".to_string()),
-            ("spin_power".to_string(), "power law for rotational velocity".to_string()),
-            ("semi_major".to_string(), "semi_major axis".to_string()),
-            ("semi_minor".to_string(), "semi_minor axis".to_string()),
-            ("thickness".to_string(), "extent of ellipsoid in z direction".to_string()),
-            ("ferrers_parameter".to_string(), "Higher concentrates more mass at the centre of the ellipsoid. Good values are between 0.0 and 2.0".to_string()),
-
-            (
-                "mass".to_string(),
-                "Total mass of cube. Default is 1.0".to_string(),
-            ),
-            (
-                "centre".to_string(),
-                "Centre (specify in CLI with \\[x,y,z\\])".to_string(),
-            ),
-            (
-                "id".to_string(),
-                "Id assigned to stars in galaxy".to_string(),
-            ),
-            ("angle".to_string(), "Angle of the semi-major axis in degrees".to_string()),
-        ]))
+        Ok(BarProperties::descriptions())
     }
 }
 
@@ -1,20 +1,26 @@
-#![feature(test)]
 #![feature(str_from_raw_parts)]
-#![feature(vec_into_raw_parts)]
-#![feature(trait_alias)]
 
+pub mod fmm;
 mod initialisers;
+pub mod linear_octree;
 pub mod octree;
+pub mod pm;
+pub mod pn;
 pub mod quadtree;
-mod transformers;
+pub mod softening;
+pub mod transformers;
 
 use physim_core::{Entity, register_plugin};
+use softening::Softening;
 
 // static ELEMENTS: &str = "astro,simple_astro,debug";
 register_plugin!(
     "astro",
     "astro2",
     "simple_astro",
+    "fmm",
+    "pm",
+    "pn",
     "cube",
     "star",
     "plummer",
@@ -24,15 +30,20 @@ register_plugin!(
 
 // make a function that when is called, sets a global bus variable in dynamic library
 
-const G: f64 = 1.0;
-
 pub trait Star {
     fn get_mass(&self) -> f64;
     fn get_centre(&self) -> [f64; 3];
     fn centre_of_mass(&self, other: &Self) -> [f64; 3];
     fn fake(centre: [f64; 3], mass: f64) -> Self;
     fn inside(a: &Self, b: &Self) -> bool;
-    fn newtons_law_of_universal_gravitation(&self, other: &Self, easing_factor: f64) -> [f64; 3];
+    /// The softened gravitational force on `self` from `other`, where `g` is
+    /// the gravitational constant
+    fn newtons_law_of_universal_gravitation(
+        &self,
+        other: &Self,
+        g: f64,
+        softening: &Softening,
+    ) -> [f64; 3];
 }
 
 // could implement this so
@@ -81,34 +92,18 @@ impl Star for Entity {
             && ((a.z - b.z).abs() < a.radius / 2.0 || (a.z - b.z).abs() < b.radius / 2.0)
     }
 
-    fn newtons_law_of_universal_gravitation(&self, other: &Self, easing_factor: f64) -> [f64; 3] {
-        // if Self::inside(&self, other) {
-        //     info!("within");
-        //     return [0.0,0.0,0.0]
-        // }
+    fn newtons_law_of_universal_gravitation(
+        &self,
+        other: &Self,
+        g: f64,
+        softening: &Softening,
+    ) -> [f64; 3] {
         let ac = self.get_centre();
         let bc = other.get_centre();
-
-        let r_norm =
-            ((ac[0] - bc[0]).powi(2) + (ac[1] - bc[1]).powi(2) + (ac[2] - bc[2]).powi(2)).powf(0.5);
-
-        let r_easing = (ac[0] - bc[0]).powi(2)
-            + (ac[1] - bc[1]).powi(2)
-            + (ac[2] - bc[2]).powi(2)
-            + easing_factor;
-
-        let r = [
-            (bc[0] - ac[0]) / r_norm,
-            (bc[1] - ac[1]) / r_norm,
-            (bc[2] - ac[2]) / r_norm,
-        ];
-
-        let am = self.mass;
-        let bm = other.mass;
-        [
-            r[0] * G * am * bm / r_easing,
-            r[1] * G * am * bm / r_easing,
-            r[2] * G * am * bm / r_easing,
-        ]
+        let r = [bc[0] - ac[0], bc[1] - ac[1], bc[2] - ac[2]];
+        let r2 = r[0] * r[0] + r[1] * r[1] + r[2] * r[2];
+        let e = softening.pair(self.radius, other.radius);
+        let f = g * self.mass * other.mass * softening.factor(r2, e);
+        [r[0] * f, r[1] * f, r[2] * f]
     }
 }
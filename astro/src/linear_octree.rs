@@ -0,0 +1,605 @@
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefMutIterator},
+    prelude::ParallelIterator,
+    slice::ParallelSliceMut,
+};
+
+use physim_core::domain::Domain;
+
+use crate::Star;
+
+/// Bits of each coordinate in a Morton key, so a key fits in a u64
+pub(crate) const KEY_LEVELS: u32 = 21;
+
+/// Subtrees with more entities than this are built in parallel
+const PARALLEL_THRESHOLD: usize = 4096;
+
+/// An octree built in one go from a set of entities, rather than by pushing
+/// them one at a time like [`crate::octree::Octree`]. The entities are sorted
+/// by their Morton key, so every node holds a contiguous range of them and
+/// subtrees can be built in parallel. The tree doesn't borrow the entities,
+/// so it can be kept and refitted to their new positions.
+#[derive(Debug)]
+pub struct LinearOctree<T>
+where
+    T: Star,
+{
+    // nodes in depth-first order, so children come after their parent
+    nodes: Vec<LinearOctreeNode<T>>,
+    // indices of the entities, sorted by Morton key
+    order: Vec<usize>,
+    quadrupoles: bool,
+    // distances are measured to the nearest images in a periodic domain
+    domain: Option<Domain>,
+}
+
+/// Traceless quadrupole moment of the entities in a node about their centre
+/// of mass, sum m(3 d d - |d|^2 I), stored as xx, yy, zz, xy, xz, yz
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Quadrupole([f64; 6]);
+
+impl Quadrupole {
+    fn add(&mut self, mass: f64, d: [f64; 3]) {
+        let d2 = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+        self.0[0] += mass * (3.0 * d[0] * d[0] - d2);
+        self.0[1] += mass * (3.0 * d[1] * d[1] - d2);
+        self.0[2] += mass * (3.0 * d[2] * d[2] - d2);
+        self.0[3] += mass * 3.0 * d[0] * d[1];
+        self.0[4] += mass * 3.0 * d[0] * d[2];
+        self.0[5] += mass * 3.0 * d[1] * d[2];
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&q| q == 0.0)
+    }
+
+    /// Acceleration due to the quadrupole, without G, at a separation `r`
+    /// from the centre of mass. This is added to the acceleration due to the
+    /// mass at the centre of mass.
+    pub fn acceleration(&self, r: [f64; 3]) -> [f64; 3] {
+        let [xx, yy, zz, xy, xz, yz] = self.0;
+        let qr = [
+            xx * r[0] + xy * r[1] + xz * r[2],
+            xy * r[0] + yy * r[1] + yz * r[2],
+            xz * r[0] + yz * r[1] + zz * r[2],
+        ];
+        let rqr = r[0] * qr[0] + r[1] * qr[1] + r[2] * qr[2];
+        let r2 = r[0] * r[0] + r[1] * r[1] + r[2] * r[2];
+        let r5 = r2 * r2 * r2.sqrt();
+        let radial = 2.5 * rqr / r2;
+        [
+            (qr[0] - radial * r[0]) / r5,
+            (qr[1] - radial * r[1]) / r5,
+            (qr[2] - radial * r[2]) / r5,
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LinearOctreeNode<T>
+where
+    T: Star,
+{
+    pub(crate) centre: [f64; 3],
+    pub(crate) extent: f64,
+    entity: T,
+    quadrupole: Quadrupole,
+    // range of `order` inside this node
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    // offset of each child from this node. 0 is an empty octant
+    children: [u32; 8],
+}
+
+impl<T> LinearOctreeNode<T>
+where
+    T: Star,
+{
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.children.iter().all(|&c| c == 0)
+    }
+}
+
+/// How a tree is built
+#[derive(Clone, Copy)]
+struct BuildOptions {
+    quadrupoles: bool,
+    // most entities in a leaf, unless they are too close to tell apart
+    leaf_size: usize,
+}
+
+impl<T> LinearOctree<T>
+where
+    T: Star + Default + Copy + Send + Sync,
+{
+    /// Build a tree of `entities` in the cube centred on `centre` which
+    /// extends `extent` in each direction. Entities outside of the cube are
+    /// put in the nearest cell.
+    pub fn new(centre: [f64; 3], extent: f64, entities: &[T]) -> Self {
+        let options = BuildOptions {
+            quadrupoles: false,
+            leaf_size: 1,
+        };
+        Self::build(centre, extent, entities, options)
+    }
+
+    /// Build a tree like [`LinearOctree::new`] whose nodes also have the
+    /// quadrupole moments of their entities
+    pub fn with_quadrupoles(centre: [f64; 3], extent: f64, entities: &[T]) -> Self {
+        let options = BuildOptions {
+            quadrupoles: true,
+            leaf_size: 1,
+        };
+        Self::build(centre, extent, entities, options)
+    }
+
+    /// Build a tree like [`LinearOctree::new`] whose leaves hold up to
+    /// `leaf_size` entities. Such leaves stand for the centre of mass of their
+    /// entities, so they are only useful with [`LinearOctree::nodes`].
+    pub(crate) fn with_leaf_size(
+        centre: [f64; 3],
+        extent: f64,
+        entities: &[T],
+        leaf_size: usize,
+    ) -> Self {
+        let options = BuildOptions {
+            quadrupoles: false,
+            leaf_size: leaf_size.max(1),
+        };
+        Self::build(centre, extent, entities, options)
+    }
+
+    fn build(centre: [f64; 3], extent: f64, entities: &[T], options: BuildOptions) -> Self {
+        let mut keys: Vec<(u64, usize)> = entities
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, e)| (morton_key(e.get_centre(), centre, extent), i))
+            .collect();
+        // the index breaks ties, so the order doesn't depend on the sort
+        keys.par_sort_unstable();
+
+        let order: Vec<usize> = keys.iter().map(|(_, i)| *i).collect();
+        let keys: Vec<u64> = keys.into_iter().map(|(k, _)| k).collect();
+        let mut nodes = vec![];
+        if !entities.is_empty() {
+            let cell = Cell {
+                centre,
+                extent,
+                depth: 0,
+                start: 0,
+            };
+            build(&mut nodes, &keys, &order, entities, cell, options);
+        }
+        Self {
+            nodes,
+            order,
+            quadrupoles: options.quadrupoles,
+            domain: None,
+        }
+    }
+
+    /// Measure the distances of walks through the tree to the nearest images
+    /// of the nodes in `domain`. The nodes are still where their entities
+    /// are, rather than at their images.
+    pub fn set_domain(&mut self, domain: Option<Domain>) {
+        self.domain = domain;
+    }
+
+    /// Update the centres of mass of the nodes to new positions of the
+    /// entities, without changing which node each entity is in. This is much
+    /// quicker than building a new tree, but the tree becomes less accurate as
+    /// the entities move away from their cells.
+    pub fn refit(&mut self, entities: &[T]) {
+        assert_eq!(
+            entities.len(),
+            self.order.len(),
+            "A tree can only be refitted to the same number of entities"
+        );
+        let (order, quadrupoles) = (&self.order, self.quadrupoles);
+        self.nodes.par_iter_mut().for_each(|node| {
+            (node.entity, node.quadrupole) =
+                summarise(&order[node.start..node.end], entities, quadrupoles);
+        });
+    }
+
+    /// The nodes in depth-first order, so the descendants of a node come
+    /// straight after it
+    pub(crate) fn nodes(&self) -> &[LinearOctreeNode<T>] {
+        &self.nodes
+    }
+
+    /// Indices of the nodes which are children of node `idx`
+    pub(crate) fn children(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes[idx]
+            .children
+            .iter()
+            .filter(|&&c| c != 0)
+            .map(move |&c| idx + c as usize)
+    }
+
+    /// Indices of the entities, sorted so that the entities in a node are
+    /// `order()[node.start..node.end]`
+    pub(crate) fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// Number of entities in the tree
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn get_leaves_with_resolution(&self, location: [f64; 3], bh_factor: f64) -> Vec<T> {
+        let mut result = Vec::with_capacity(100);
+        self.walk(location, bh_factor, |node| result.push(node.entity));
+        result
+    }
+
+    /// Like [`LinearOctree::get_leaves_with_resolution`], with the quadrupole
+    /// moment of each node. Leaves and trees built without quadrupoles have
+    /// a moment of zero.
+    pub fn get_multipoles_with_resolution(
+        &self,
+        location: [f64; 3],
+        bh_factor: f64,
+    ) -> Vec<(T, Quadrupole)> {
+        let mut result = Vec::with_capacity(100);
+        self.walk(location, bh_factor, |node| {
+            result.push((node.entity, node.quadrupole))
+        });
+        result
+    }
+
+    /// Visit the nodes which are far enough from `location`, or are leaves
+    fn walk(
+        &self,
+        location: [f64; 3],
+        bh_factor: f64,
+        mut visit: impl FnMut(&LinearOctreeNode<T>),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = Vec::with_capacity(100);
+        stack.push(0);
+
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            // the distance is to the centre of mass, which can be anywhere in
+            // the cell, and a node is never far enough from a location inside it
+            let separation = |x: [f64; 3]| match &self.domain {
+                Some(domain) => domain.separation(location, x),
+                None => [location[0] - x[0], location[1] - x[1], location[2] - x[2]],
+            };
+            let d = separation(node.entity.get_centre());
+            let r = (d[0].powi(2) + d[1].powi(2) + d[2].powi(2)).sqrt();
+            let offset = separation(node.centre);
+            let inside = (0..3).all(|i| offset[i].abs() <= node.extent);
+            // the nearest images of the entities of a node which crosses the
+            // far side of the domain around the location are on both sides
+            let straddles = self.domain.is_some_and(|domain| {
+                let size = domain.size();
+                (0..3).any(|i| domain.periodic[i] && offset[i].abs() + node.extent > 0.5 * size[i])
+            });
+            if (node.extent / r < bh_factor && !inside && !straddles) || node.is_leaf() {
+                visit(node);
+                continue;
+            }
+            for &child in node.children.iter().filter(|&&c| c != 0) {
+                stack.push(idx + child as usize);
+            }
+        }
+    }
+}
+
+/// The cell of a node, and where its entities start in the sorted order
+#[derive(Clone, Copy)]
+struct Cell {
+    centre: [f64; 3],
+    extent: f64,
+    depth: u32,
+    start: usize,
+}
+
+impl Cell {
+    fn child(&self, octant: usize, start: usize) -> Self {
+        let offset = |bit: usize| {
+            if octant & bit == 0 {
+                -self.extent / 2.0
+            } else {
+                self.extent / 2.0
+            }
+        };
+        Self {
+            centre: [
+                self.centre[0] + offset(1),
+                self.centre[1] + offset(2),
+                self.centre[2] + offset(4),
+            ],
+            extent: self.extent / 2.0,
+            depth: self.depth + 1,
+            start,
+        }
+    }
+}
+
+/// Append the nodes of the subtree holding `order`, whose Morton keys are
+/// `keys`, to `nodes`
+fn build<T>(
+    nodes: &mut Vec<LinearOctreeNode<T>>,
+    keys: &[u64],
+    order: &[usize],
+    entities: &[T],
+    cell: Cell,
+    options: BuildOptions,
+) where
+    T: Star + Default + Copy + Send + Sync,
+{
+    let idx = nodes.len();
+    let (entity, quadrupole) = summarise(order, entities, options.quadrupoles);
+    nodes.push(LinearOctreeNode {
+        centre: cell.centre,
+        extent: cell.extent,
+        entity,
+        quadrupole,
+        start: cell.start,
+        end: cell.start + order.len(),
+        children: [0; 8],
+    });
+    // few enough entities, or entities too close together for the keys to
+    // tell apart, are a leaf
+    if keys.len() <= options.leaf_size
+        || keys[0] == keys[keys.len() - 1]
+        || cell.depth == KEY_LEVELS
+    {
+        return;
+    }
+
+    let shift = 3 * (KEY_LEVELS - 1 - cell.depth);
+    let mut bounds = [0; 9];
+    for (octant, bound) in bounds.iter_mut().enumerate().skip(1) {
+        *bound = keys.partition_point(|k| ((k >> shift) & 7) < octant as u64);
+    }
+    let children: Vec<(usize, Cell, std::ops::Range<usize>)> = (0..8)
+        .filter(|&o| bounds[o] < bounds[o + 1])
+        .map(|o| {
+            let range = bounds[o]..bounds[o + 1];
+            (o, cell.child(o, cell.start + range.start), range)
+        })
+        .collect();
+
+    if order.len() > PARALLEL_THRESHOLD {
+        let subtrees: Vec<(usize, Vec<LinearOctreeNode<T>>)> = children
+            .into_par_iter()
+            .map(|(o, child, range)| {
+                let mut subtree = vec![];
+                build(
+                    &mut subtree,
+                    &keys[range.clone()],
+                    &order[range],
+                    entities,
+                    child,
+                    options,
+                );
+                (o, subtree)
+            })
+            .collect();
+        for (o, subtree) in subtrees {
+            nodes[idx].children[o] = (nodes.len() - idx) as u32;
+            nodes.extend(subtree);
+        }
+    } else {
+        for (o, child, range) in children {
+            nodes[idx].children[o] = (nodes.len() - idx) as u32;
+            build(
+                nodes,
+                &keys[range.clone()],
+                &order[range],
+                entities,
+                child,
+                options,
+            );
+        }
+    }
+}
+
+/// The entity a node stands for and, if `quadrupoles`, its quadrupole
+/// moment. A single entity is itself, and several are a fake entity at their
+/// centre of mass
+fn summarise<T>(order: &[usize], entities: &[T], quadrupoles: bool) -> (T, Quadrupole)
+where
+    T: Star + Copy,
+{
+    if let [i] = order {
+        return (entities[*i], Quadrupole::default());
+    }
+    let mut mass = 0.0;
+    let mut moment = [0.0; 3];
+    for &i in order {
+        let m = entities[i].get_mass();
+        let c = entities[i].get_centre();
+        mass += m;
+        moment[0] += m * c[0];
+        moment[1] += m * c[1];
+        moment[2] += m * c[2];
+    }
+    let centre = if mass != 0.0 {
+        moment.map(|x| x / mass)
+    } else {
+        entities[order[0]].get_centre()
+    };
+    let mut quadrupole = Quadrupole::default();
+    if quadrupoles {
+        for &i in order {
+            let c = entities[i].get_centre();
+            let d = [c[0] - centre[0], c[1] - centre[1], c[2] - centre[2]];
+            quadrupole.add(entities[i].get_mass(), d);
+        }
+    }
+    (T::fake(centre, mass), quadrupole)
+}
+
+/// Interleave the bits of the position of `location` in the cube, so that
+/// the top three bits are the octant of the root, the next three are the
+/// octant within that, and so on
+fn morton_key(location: [f64; 3], centre: [f64; 3], extent: f64) -> u64 {
+    let cells = (1_u64 << KEY_LEVELS) as f64;
+    let quantise = |axis: usize| {
+        let fraction = (location[axis] - centre[axis] + extent) / (2.0 * extent);
+        // `as` saturates, and NaN becomes 0
+        ((fraction * cells) as u64).min((1 << KEY_LEVELS) - 1)
+    };
+    let (x, y, z) = (quantise(0), quantise(1), quantise(2));
+    let mut key = 0;
+    for bit in (0..KEY_LEVELS).rev() {
+        let octant = ((x >> bit) & 1) | (((y >> bit) & 1) << 1) | (((z >> bit) & 1) << 2);
+        key = (key << 3) | octant;
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use physim_core::Entity;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use rayon::ThreadPoolBuilder;
+
+    fn random_entities(n: usize) -> Vec<Entity> {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        (0..n).map(|_| Entity::random(&mut rng)).collect()
+    }
+
+    #[test]
+    fn test_morton_key() {
+        let key = |location| morton_key(location, [0.0; 3], 1.0);
+        // the top three bits are the octant of the root
+        assert_eq!(key([-0.5, -0.5, -0.5]) >> 60, 0);
+        assert_eq!(key([0.5, -0.5, -0.5]) >> 60, 1);
+        assert_eq!(key([-0.5, 0.5, -0.5]) >> 60, 2);
+        assert_eq!(key([0.5, 0.5, 0.5]) >> 60, 7);
+        // outside of the cube is clamped
+        assert_eq!(key([-5.0; 3]), 0);
+        assert_eq!(key([5.0; 3]), (1 << 63) - 1);
+    }
+
+    #[test]
+    fn test_all_entities_are_leaves() {
+        let entities = random_entities(20_000);
+        let tree = LinearOctree::new([0.0; 3], 1.0, &entities);
+        assert_eq!(tree.len(), entities.len());
+        let mut leaves = tree.get_leaves_with_resolution([0.0; 3], -0.1);
+        assert_eq!(leaves.len(), entities.len());
+        let mut entities = entities;
+        leaves.sort_by(|a, b| a.x.total_cmp(&b.x));
+        entities.sort_by(|a, b| a.x.total_cmp(&b.x));
+        assert_eq!(leaves, entities);
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let tree: LinearOctree<Entity> = LinearOctree::new([0.0; 3], 1.0, &[]);
+        assert!(tree.is_empty());
+        assert!(tree.get_leaves_with_resolution([0.0; 3], 0.5).is_empty());
+    }
+
+    #[test]
+    fn test_entities_in_the_same_place() {
+        let entities = vec![Entity::new(0.1, 0.2, 0.3, 1.0); 10];
+        let tree = LinearOctree::new([0.0; 3], 1.0, &entities);
+        let leaves = tree.get_leaves_with_resolution([0.0; 3], -0.1);
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].mass, 10.0);
+    }
+
+    #[test]
+    fn test_root_is_centre_of_mass() {
+        let entities = random_entities(1000);
+        let tree = LinearOctree::new([0.0; 3], 1.0, &entities);
+        // far enough away for the whole tree to be one entity
+        let far = tree.get_leaves_with_resolution([1000.0; 3], 0.5);
+        assert_eq!(far.len(), 1);
+        let mass: f64 = entities.iter().map(|e| e.mass).sum();
+        let x = entities.iter().map(|e| e.mass * e.x).sum::<f64>() / mass;
+        assert!((far[0].mass - mass).abs() < 1e-9);
+        assert!((far[0].x - x).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_quadrupole() {
+        // two masses on the x axis
+        let entities = [
+            Entity::new(-0.5, 0.0, 0.0, 1.0),
+            Entity::new(0.5, 0.0, 0.0, 1.0),
+        ];
+        let tree = LinearOctree::with_quadrupoles([0.0; 3], 1.0, &entities);
+        let far = tree.get_multipoles_with_resolution([10.0, 0.0, 0.0], 0.5);
+        assert_eq!(far.len(), 1);
+        let (com, quadrupole) = far[0];
+        assert_eq!(com.mass, 2.0);
+        assert_eq!(quadrupole, Quadrupole([1.0, -0.5, -0.5, 0.0, 0.0, 0.0]));
+
+        // the expansion is closer to the exact acceleration than the mass
+        // at the centre of mass
+        let r = [10.0, 0.0, 0.0];
+        let exact = -1.0 / 9.5_f64.powi(2) - 1.0 / 10.5_f64.powi(2);
+        let monopole = -2.0 / 100.0;
+        let expanded = monopole + quadrupole.acceleration(r)[0];
+        assert!((expanded - exact).abs() < 0.01 * (monopole - exact).abs());
+        assert_eq!(quadrupole.acceleration([0.0, 10.0, 0.0])[0], 0.0);
+
+        // without quadrupoles the moments are zero
+        let tree = LinearOctree::new([0.0; 3], 1.0, &entities);
+        let far = tree.get_multipoles_with_resolution([10.0, 0.0, 0.0], 0.5);
+        assert!(far[0].1.is_zero());
+    }
+
+    #[test]
+    fn test_parallel_build_is_deterministic() {
+        let entities = random_entities(50_000);
+        let build = |threads| {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| LinearOctree::new([0.0; 3], 1.0, &entities))
+        };
+        let serial = build(1);
+        let parallel = build(4);
+        assert_eq!(serial.order, parallel.order);
+        let location = [0.3, -0.2, 0.1];
+        assert_eq!(
+            serial.get_leaves_with_resolution(location, 0.5),
+            parallel.get_leaves_with_resolution(location, 0.5)
+        );
+    }
+
+    #[test]
+    fn test_refit() {
+        let mut entities = random_entities(5000);
+        let mut tree = LinearOctree::new([0.0; 3], 1.0, &entities);
+        let location = [0.3, -0.2, 0.1];
+
+        // refitting to the same positions changes nothing
+        let before = tree.get_leaves_with_resolution(location, 0.5);
+        tree.refit(&entities);
+        assert_eq!(before, tree.get_leaves_with_resolution(location, 0.5));
+
+        // after a move, the nodes are at the new centres of mass
+        for e in entities.iter_mut() {
+            e.x += 1e-3;
+        }
+        tree.refit(&entities);
+        let all = tree.get_leaves_with_resolution(location, -0.1);
+        assert_eq!(all.len(), entities.len());
+        assert!(all.iter().all(|leaf| entities.contains(leaf)));
+        let root = tree.get_leaves_with_resolution([1000.0; 3], 0.5)[0];
+        let rebuilt = LinearOctree::new([0.0; 3], 1.0, &entities);
+        let expected = rebuilt.get_leaves_with_resolution([1000.0; 3], 0.5)[0];
+        assert!((root.x - expected.x).abs() < 1e-12);
+    }
+}
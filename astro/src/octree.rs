@@ -40,6 +40,28 @@ where
     pub fn get_leaves_with_resolution(&self, location: [f64; 3], bh_factor: f64) -> Vec<T> {
         self.root.get_leaves_with_resolution(location, bh_factor)
     }
+
+    /// A read-only view of the tree. Unlike the tree, it can be shared
+    /// between threads.
+    pub fn query(&self) -> OctreeQuery<'_, 'a, T> {
+        OctreeQuery { root: &self.root }
+    }
+}
+
+pub struct OctreeQuery<'t, 'a, T>
+where
+    T: Star,
+{
+    root: &'t OctreeNode<'a, T>,
+}
+
+impl<T> OctreeQuery<'_, '_, T>
+where
+    T: Star + Default + Copy,
+{
+    pub fn get_leaves_with_resolution(&self, location: [f64; 3], bh_factor: f64) -> Vec<T> {
+        self.root.get_leaves_with_resolution(location, bh_factor)
+    }
 }
 
 impl<'a, T> OctreeNode<'a, T>
@@ -143,11 +165,12 @@ where
                     continue;
                 }
             }
-            if node.entity.is_none() {
+            let Some(entity) = node.entity else {
                 // this only happens with empty trees
                 continue;
-            } else if node.children.iter().all(|x| x.is_none()) {
-                result.push(node.entity.expect("This can is already handled above"));
+            };
+            if node.children.iter().all(|x| x.is_none()) {
+                result.push(entity);
             } else {
                 for child in node.children.iter().flatten() {
                     stack.push(child)
@@ -313,7 +336,7 @@ mod tests {
         // With positive bh_factor, distant entities might be aggregated
         let filtered_leaves = tree.get_leaves_with_resolution([0.0; 3], 1.0);
         assert!(
-            filtered_leaves.len() > 0,
+            !filtered_leaves.is_empty(),
             "Should return at least some entities"
         );
     }
@@ -354,8 +377,8 @@ mod tests {
         let from_far = tree.get_leaves_with_resolution([100.0, 0.0, 0.0], 0.5);
 
         // Results should differ based on observer location
-        assert!(from_origin.len() > 0, "Should find entities from origin");
-        assert!(from_far.len() > 0, "Should find entities from far location");
+        assert!(!from_origin.is_empty(), "Should find entities from origin");
+        assert!(!from_far.is_empty(), "Should find entities from far location");
     }
 
     #[test]
@@ -487,7 +510,7 @@ mod tests {
         // Query from location far outside the tree bounds
         let leaves = tree.get_leaves_with_resolution([1000.0, 1000.0, 1000.0], 0.5);
         assert!(
-            leaves.len() > 0,
+            !leaves.is_empty(),
             "Should still find entities when querying from outside"
         );
     }
@@ -0,0 +1,441 @@
+use std::f64::consts::PI;
+
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator,
+        ParallelIterator,
+    },
+    slice::ParallelSliceMut,
+};
+
+use crate::Star;
+
+/// How mass is spread over the mesh, and the field read back from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Assignment {
+    /// Cloud in cell, which shares each entity between the 8 nearest points
+    Cic,
+    /// Triangular shaped cloud, which shares each entity between the 27
+    /// nearest points. It is smoother than cloud in cell.
+    Tsc,
+}
+
+impl Assignment {
+    /// The first mesh point an entity at `u`, in units of cells, is shared
+    /// with, and the weights of it and the points after it
+    fn weights(self, u: f64) -> (i64, [f64; 3]) {
+        match self {
+            Assignment::Cic => {
+                let first = u.floor();
+                let f = u - first;
+                (first as i64, [1.0 - f, f, 0.0])
+            }
+            Assignment::Tsc => {
+                let nearest = u.round();
+                let d = u - nearest;
+                (
+                    nearest as i64 - 1,
+                    [
+                        0.5 * (0.5 - d).powi(2),
+                        0.75 - d * d,
+                        0.5 * (0.5 + d).powi(2),
+                    ],
+                )
+            }
+        }
+    }
+
+    fn points(self) -> usize {
+        match self {
+            Assignment::Cic => 2,
+            Assignment::Tsc => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn add(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re - other.re,
+            im: self.im - other.im,
+        }
+    }
+}
+
+/// Radix 2 fast Fourier transforms of length `n`, which must be a power of
+/// two
+#[derive(Debug)]
+struct Fft {
+    n: usize,
+    // e^(-2 pi i k / n) for k < n/2
+    twiddles: Vec<Complex>,
+}
+
+impl Fft {
+    fn new(n: usize) -> Self {
+        assert!(n.is_power_of_two(), "FFT length must be a power of two");
+        let twiddles = (0..n / 2)
+            .map(|k| {
+                let angle = -2.0 * PI * k as f64 / n as f64;
+                Complex {
+                    re: angle.cos(),
+                    im: angle.sin(),
+                }
+            })
+            .collect();
+        Self { n, twiddles }
+    }
+
+    /// Transform `data` in place. The inverse is not scaled by 1/n.
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        let n = self.n;
+        let bits = n.trailing_zeros();
+        if bits == 0 {
+            return;
+        }
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let stride = n / len;
+            for chunk in data.chunks_exact_mut(len) {
+                let (low, high) = chunk.split_at_mut(len / 2);
+                for (k, (a, b)) in low.iter_mut().zip(high.iter_mut()).enumerate() {
+                    let mut w = self.twiddles[k * stride];
+                    if inverse {
+                        w.im = -w.im;
+                    }
+                    let t = w.mul(*b);
+                    *b = a.sub(t);
+                    *a = a.add(t);
+                }
+            }
+            len *= 2;
+        }
+    }
+
+    /// Transform a cube of side `n` along all three axes. Each pass
+    /// transforms the last axis and then rotates the axes, so after three
+    /// the cube is back in its original layout.
+    fn transform_3d(&self, data: &mut Vec<Complex>, inverse: bool) {
+        let n = self.n;
+        let mut rotated = vec![Complex::default(); data.len()];
+        for _ in 0..3 {
+            data.par_chunks_mut(n)
+                .for_each(|line| self.transform(line, inverse));
+            // (i, j, k) -> (k, i, j)
+            rotated
+                .par_chunks_mut(n * n)
+                .enumerate()
+                .for_each(|(k, plane)| {
+                    for (ij, x) in plane.iter_mut().enumerate() {
+                        *x = data[ij * n + k];
+                    }
+                });
+            std::mem::swap(data, &mut rotated);
+        }
+    }
+}
+
+/// Periodic gravity on a mesh. Mass is spread over the points of a mesh
+/// which covers a box, e.g. `[-lim, lim)` on each axis, Poisson's equation is
+/// solved for the field with FFTs, and the field is read back at each
+/// entity. The mean density is removed, so a uniform box feels no force.
+#[derive(Debug)]
+pub struct ParticleMesh {
+    cells: usize,
+    min: [f64; 3],
+    size: [f64; 3],
+    assignment: Assignment,
+    fft: Fft,
+}
+
+/// The gravitational field on the mesh, without G
+pub struct PmField<'m> {
+    mesh: &'m ParticleMesh,
+    field: Vec<[f64; 3]>,
+}
+
+impl ParticleMesh {
+    /// A mesh of `cells` points on each axis, which must be a power of two
+    pub fn new(cells: usize, lim: [f64; 3], assignment: Assignment) -> Self {
+        Self::with_box(cells, lim.map(|l| -l), lim, assignment)
+    }
+
+    /// A mesh covering the box from `min` to `max`
+    pub fn with_box(cells: usize, min: [f64; 3], max: [f64; 3], assignment: Assignment) -> Self {
+        Self {
+            cells,
+            min,
+            size: [0, 1, 2].map(|a| max[a] - min[a]),
+            assignment,
+            fft: Fft::new(cells),
+        }
+    }
+
+    /// Size of a cell on each axis
+    pub fn cell_size(&self) -> [f64; 3] {
+        self.size.map(|l| l / self.cells as f64)
+    }
+
+    fn index(&self, i: [usize; 3]) -> usize {
+        (i[0] * self.cells + i[1]) * self.cells + i[2]
+    }
+
+    /// The mesh points near `centre` and how much of it each one gets
+    fn spread(&self, centre: [f64; 3], mut f: impl FnMut(usize, f64)) {
+        let n = self.cells as i64;
+        let h = self.cell_size();
+        // mesh points are at the middle of the cells
+        let weights: [(i64, [f64; 3]); 3] = std::array::from_fn(|a| {
+            self.assignment
+                .weights((centre[a] - self.min[a]) / h[a] - 0.5)
+        });
+        let points = self.assignment.points();
+        for (di, wi) in weights[0].1[..points].iter().enumerate() {
+            for (dj, wj) in weights[1].1[..points].iter().enumerate() {
+                for (dk, wk) in weights[2].1[..points].iter().enumerate() {
+                    let i = [(0, di), (1, dj), (2, dk)]
+                        .map(|(a, d)| (weights[a].0 + d as i64).rem_euclid(n) as usize);
+                    f(self.index(i), wi * wj * wk);
+                }
+            }
+        }
+    }
+
+    pub fn field<T>(&self, entities: &[T]) -> PmField<'_>
+    where
+        T: Star,
+    {
+        let n = self.cells;
+        let h = self.cell_size();
+        let volume = h[0] * h[1] * h[2];
+        let mut density = vec![Complex::default(); n * n * n];
+        for e in entities {
+            let m = e.get_mass() / volume;
+            self.spread(e.get_centre(), |i, w| density[i].re += m * w);
+        }
+        self.fft.transform_3d(&mut density, false);
+
+        // the Fourier transform of the field on axis a is
+        // 4 pi i k_a rho_k / k^2. The terms at the Nyquist frequency would
+        // give an imaginary field, so they are left out of the gradient.
+        let wavenumber = |a: usize, m: usize| {
+            let m = if m > n / 2 {
+                m as f64 - n as f64
+            } else {
+                m as f64
+            };
+            2.0 * PI * m / self.size[a]
+        };
+        let mut field = vec![vec![Complex::default(); n * n * n]; 3];
+        let [fx, fy, fz] = &mut field[..] else {
+            unreachable!()
+        };
+        fx.par_iter_mut()
+            .zip(fy.par_iter_mut())
+            .zip(fz.par_iter_mut())
+            .zip(density.par_iter())
+            .enumerate()
+            .for_each(|(idx, (((fx, fy), fz), rho))| {
+                let m = [idx / (n * n), idx / n % n, idx % n];
+                let k = [0, 1, 2].map(|a| wavenumber(a, m[a]));
+                let k2 = k[0] * k[0] + k[1] * k[1] + k[2] * k[2];
+                if k2 == 0.0 {
+                    return;
+                }
+                let scale = 4.0 * PI / k2;
+                for (a, f) in [fx, fy, fz].into_iter().enumerate() {
+                    if n > 1 && m[a] == n / 2 {
+                        continue;
+                    }
+                    // i k rho
+                    *f = Complex {
+                        re: -k[a] * rho.im * scale,
+                        im: k[a] * rho.re * scale,
+                    };
+                }
+            });
+        for f in field.iter_mut() {
+            self.fft.transform_3d(f, true);
+        }
+
+        let points = (n * n * n) as f64;
+        let field = (0..n * n * n)
+            .map(|i| [0, 1, 2].map(|a| field[a][i].re / points))
+            .collect();
+        PmField { mesh: self, field }
+    }
+}
+
+impl PmField<'_> {
+    /// Acceleration at `centre` without G, read from the mesh with the same
+    /// weights that spread the mass, so that momentum is conserved
+    pub fn acceleration(&self, centre: [f64; 3]) -> [f64; 3] {
+        let mut a = [0.0; 3];
+        self.mesh.spread(centre, |i, w| {
+            for (a, f) in a.iter_mut().zip(self.field[i]) {
+                *a += w * f;
+            }
+        });
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use physim_core::Entity;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn test_fft_matches_dft() {
+        let n = 16;
+        let input: Vec<Complex> = (0..n)
+            .map(|i| Complex {
+                re: (i as f64 * 0.7).sin(),
+                im: (i as f64 * 0.3).cos(),
+            })
+            .collect();
+        let mut output = input.clone();
+        let fft = Fft::new(n);
+        fft.transform(&mut output, false);
+        for (k, x) in output.iter().enumerate() {
+            let expected = input
+                .iter()
+                .enumerate()
+                .fold(Complex::default(), |sum, (j, y)| {
+                    let angle = -2.0 * PI * (j * k) as f64 / n as f64;
+                    sum.add(y.mul(Complex {
+                        re: angle.cos(),
+                        im: angle.sin(),
+                    }))
+                });
+            assert!((x.re - expected.re).abs() < 1e-12);
+            assert!((x.im - expected.im).abs() < 1e-12);
+        }
+        fft.transform(&mut output, true);
+        for (x, y) in output.iter().zip(&input) {
+            assert!((x.re / n as f64 - y.re).abs() < 1e-12);
+            assert!((x.im / n as f64 - y.im).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_force_of_point_mass() {
+        for assignment in [Assignment::Cic, Assignment::Tsc] {
+            let mesh = ParticleMesh::new(64, [1.0; 3], assignment);
+            let mass = Entity {
+                mass: 1.0,
+                ..Default::default()
+            };
+            let field = mesh.field(&[mass]);
+            // several cells away, but close compared to the size of the box
+            for r in [[0.25, 0.0, 0.0], [0.0, -0.2, 0.1], [0.15, 0.15, 0.15]] {
+                let a = field.acceleration(r);
+                let d2: f64 = r.iter().map(|x| x * x).sum();
+                let expected = r.map(|x| -x / d2.powf(1.5));
+                let error = (0..3).map(|i| (a[i] - expected[i]).powi(2)).sum::<f64>();
+                assert!(
+                    error.sqrt() < 0.05 / d2,
+                    "{assignment:?} {r:?} {a:?} {expected:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_momentum_is_conserved() {
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let entities: Vec<Entity> = (0..300)
+            .map(|_| Entity {
+                x: rng.random_range(-1.0..1.0),
+                y: rng.random_range(-1.0..1.0),
+                z: rng.random_range(-0.5..0.5),
+                mass: rng.random_range(0.1..1.0),
+                ..Default::default()
+            })
+            .collect();
+        for assignment in [Assignment::Cic, Assignment::Tsc] {
+            let mesh = ParticleMesh::new(32, [1.0, 1.0, 0.5], assignment);
+            let field = mesh.field(&entities);
+            let (mut total, mut size) = ([0.0; 3], 0.0);
+            for e in &entities {
+                let a = field.acceleration(e.get_centre());
+                for i in 0..3 {
+                    total[i] += e.mass * a[i];
+                }
+                size += e.mass * a[0].hypot(a[1]).hypot(a[2]);
+            }
+            assert!(total.iter().all(|t| t.abs() < 1e-10 * size), "{total:?}");
+        }
+    }
+
+    #[test]
+    fn test_gravity_crosses_the_boundary() {
+        let mesh = ParticleMesh::new(32, [1.0; 3], Assignment::Tsc);
+        let entities = [-0.9, 0.9].map(|x| Entity {
+            x,
+            mass: 1.0,
+            ..Default::default()
+        });
+        let field = mesh.field(&entities);
+        // the nearest image of each is 0.2 away across the edge of the box
+        assert!(field.acceleration(entities[0].get_centre())[0] < 0.0);
+        assert!(field.acceleration(entities[1].get_centre())[0] > 0.0);
+    }
+
+    #[test]
+    fn test_offset_box() {
+        let centred = ParticleMesh::new(16, [1.0; 3], Assignment::Cic);
+        let offset =
+            ParticleMesh::with_box(16, [0.0, 1.0, -3.0], [2.0, 3.0, -1.0], Assignment::Cic);
+        let shift = [1.0, 2.0, -2.0];
+        let entities = [[0.3, -0.2, 0.5], [-0.6, 0.1, -0.4]].map(|[x, y, z]| Entity {
+            x,
+            y,
+            z,
+            mass: 1.0,
+            ..Default::default()
+        });
+        let shifted = entities.map(|e| Entity {
+            x: e.x + shift[0],
+            y: e.y + shift[1],
+            z: e.z + shift[2],
+            ..e
+        });
+        let (a, b) = (centred.field(&entities), offset.field(&shifted));
+        for (e, s) in entities.iter().zip(&shifted) {
+            let (a, b) = (
+                a.acceleration(e.get_centre()),
+                b.acceleration(s.get_centre()),
+            );
+            assert!((0..3).all(|i| (a[i] - b[i]).abs() < 1e-9), "{a:?} {b:?}");
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use physim_core::Entity;
+
+/// A body whose gravity has post-Newtonian corrections, with the Newtonian
+/// potential and acceleration it feels from the other sources
+#[derive(Debug, Clone, Copy)]
+pub struct Source {
+    index: usize,
+    centre: [f64; 3],
+    velocity: [f64; 3],
+    // G times the mass
+    gm: f64,
+    potential: f64,
+    acceleration: [f64; 3],
+}
+
+/// Post-Newtonian corrections to gravity. The 1PN terms are those of the
+/// Einstein-Infeld-Hoffmann equations, with the sources of gravity limited to
+/// a few massive bodies. Every entity feels the corrections of the sources,
+/// and the Newtonian gravity is left to the other gravity elements.
+#[derive(Debug, Clone, Copy)]
+pub struct PostNewtonian {
+    g: f64,
+    c: f64,
+    radiation: bool,
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn velocity(entity: &Entity) -> [f64; 3] {
+    [entity.vx, entity.vy, entity.vz]
+}
+
+fn centre(entity: &Entity) -> [f64; 3] {
+    [entity.x, entity.y, entity.z]
+}
+
+impl PostNewtonian {
+    /// `g` and `c` are the gravitational constant and the speed of light.
+    /// `radiation` adds the 2.5PN radiation reaction between the sources.
+    pub fn new(g: f64, c: f64, radiation: bool) -> Self {
+        Self { g, c, radiation }
+    }
+
+    /// The entities at `selected` as sources
+    pub fn sources(&self, state: &[Entity], selected: &[usize]) -> Vec<Source> {
+        let mut sources: Vec<Source> = selected
+            .iter()
+            .map(|&index| Source {
+                index,
+                centre: centre(&state[index]),
+                velocity: velocity(&state[index]),
+                gm: self.g * state[index].mass,
+                potential: 0.0,
+                acceleration: [0.0; 3],
+            })
+            .collect();
+        for j in 0..sources.len() {
+            let (potential, acceleration) =
+                newtonian(sources[j].index, sources[j].centre, &sources);
+            sources[j].potential = potential;
+            sources[j].acceleration = acceleration;
+        }
+        sources
+    }
+
+    /// The post-Newtonian acceleration of `entity`, which is at `index` in
+    /// the state
+    pub fn acceleration(&self, index: usize, entity: &Entity, sources: &[Source]) -> [f64; 3] {
+        let c2 = self.c * self.c;
+        let x = centre(entity);
+        let v = velocity(entity);
+        let (potential, _) = newtonian(index, x, sources);
+        let is_source = sources.iter().any(|s| s.index == index);
+        let mut a = [0.0; 3];
+        for source in sources.iter().filter(|s| s.index != index) {
+            let r = sub(x, source.centre);
+            let r2 = dot(r, r);
+            if r2 == 0.0 {
+                continue;
+            }
+            let distance = r2.sqrt();
+            let n = r.map(|r| r / distance);
+            let w = source.velocity;
+            // the potential at the source only includes the entity if it is a
+            // source itself
+            let radial = -source.gm / r2
+                * (-4.0 * potential - source.potential + dot(v, v) + 2.0 * dot(w, w)
+                    - 4.0 * dot(v, w)
+                    - 1.5 * dot(n, w).powi(2)
+                    - 0.5 * dot(r, source.acceleration));
+            let along = source.gm / (r2 * distance)
+                * dot(
+                    r,
+                    [
+                        4.0 * v[0] - 3.0 * w[0],
+                        4.0 * v[1] - 3.0 * w[1],
+                        4.0 * v[2] - 3.0 * w[2],
+                    ],
+                );
+            for k in 0..3 {
+                a[k] += (radial * n[k]
+                    + along * (v[k] - w[k])
+                    + 3.5 * source.gm * source.acceleration[k] / distance)
+                    / c2;
+            }
+            if self.radiation && is_source {
+                let gm = self.g * entity.mass + source.gm;
+                let eta = self.g * entity.mass * source.gm / (gm * gm);
+                let rr = radiation_reaction(r, sub(v, w), gm, eta, self.c);
+                for k in 0..3 {
+                    a[k] += source.gm / gm * rr[k];
+                }
+            }
+        }
+        a
+    }
+}
+
+/// The Newtonian potential and acceleration at `x` due to the sources other
+/// than the one at `index`
+fn newtonian(index: usize, x: [f64; 3], sources: &[Source]) -> (f64, [f64; 3]) {
+    let mut potential = 0.0;
+    let mut acceleration = [0.0; 3];
+    for source in sources.iter().filter(|s| s.index != index) {
+        let r = sub(source.centre, x);
+        let r2 = dot(r, r);
+        if r2 == 0.0 {
+            continue;
+        }
+        let distance = r2.sqrt();
+        potential += source.gm / distance;
+        for k in 0..3 {
+            acceleration[k] += source.gm * r[k] / (r2 * distance);
+        }
+    }
+    (potential, acceleration)
+}
+
+/// The 2.5PN radiation reaction on the separation `r` and relative velocity
+/// `v` of a binary, where `gm` is G times its total mass and `eta` its
+/// symmetric mass ratio (Kidder 1995)
+fn radiation_reaction(r: [f64; 3], v: [f64; 3], gm: f64, eta: f64, c: f64) -> [f64; 3] {
+    let r2 = dot(r, r);
+    let distance = r2.sqrt();
+    let n = r.map(|r| r / distance);
+    let rdot = dot(n, v);
+    let v2 = dot(v, v);
+    let scale = 1.6 * eta * gm * gm / (r2 * distance * c.powi(5));
+    let radial = rdot * (18.0 * v2 + 2.0 / 3.0 * gm / distance - 25.0 * rdot * rdot);
+    let along = 6.0 * v2 - 2.0 * gm / distance - 15.0 * rdot * rdot;
+    [0, 1, 2].map(|k| scale * (radial * n[k] - along * v[k]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Integrate `state` with RK4 for `steps` steps of `dt`, calling `visit`
+    /// after each one
+    fn integrate(
+        state: &mut [Entity],
+        dt: f64,
+        steps: usize,
+        acceleration: impl Fn(&[Entity]) -> Vec<[f64; 3]>,
+        mut visit: impl FnMut(&[Entity]),
+    ) {
+        let derivative = |state: &[Entity]| -> Vec<[f64; 6]> {
+            acceleration(state)
+                .iter()
+                .zip(state)
+                .map(|(a, e)| [e.vx, e.vy, e.vz, a[0], a[1], a[2]])
+                .collect()
+        };
+        let shifted = |state: &[Entity], k: &[[f64; 6]], h: f64| -> Vec<Entity> {
+            state
+                .iter()
+                .zip(k)
+                .map(|(e, k)| Entity {
+                    x: e.x + h * k[0],
+                    y: e.y + h * k[1],
+                    z: e.z + h * k[2],
+                    vx: e.vx + h * k[3],
+                    vy: e.vy + h * k[4],
+                    vz: e.vz + h * k[5],
+                    ..*e
+                })
+                .collect()
+        };
+        for _ in 0..steps {
+            let k1 = derivative(state);
+            let k2 = derivative(&shifted(state, &k1, dt / 2.0));
+            let k3 = derivative(&shifted(state, &k2, dt / 2.0));
+            let k4 = derivative(&shifted(state, &k3, dt));
+            let k: Vec<[f64; 6]> = (0..state.len())
+                .map(|i| {
+                    [0, 1, 2, 3, 4, 5]
+                        .map(|j| (k1[i][j] + 2.0 * k2[i][j] + 2.0 * k3[i][j] + k4[i][j]) / 6.0)
+                })
+                .collect();
+            let next = shifted(state, &k, dt);
+            state.copy_from_slice(&next);
+            visit(state);
+        }
+    }
+
+    fn newtonian_accelerations(state: &[Entity]) -> Vec<[f64; 3]> {
+        state
+            .iter()
+            .enumerate()
+            .map(|(i, a)| {
+                let mut acceleration = [0.0; 3];
+                for (_, b) in state.iter().enumerate().filter(|(j, _)| *j != i) {
+                    let r = sub(centre(b), centre(a));
+                    let r2 = dot(r, r);
+                    for k in 0..3 {
+                        acceleration[k] += b.mass * r[k] / (r2 * r2.sqrt());
+                    }
+                }
+                acceleration
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_perihelion_precession() {
+        let (a, e, c) = (1.0, 0.5, 30.0);
+        let perihelion = a * (1.0 - e);
+        let mut state = [
+            Entity {
+                mass: 1.0,
+                ..Default::default()
+            },
+            Entity {
+                x: perihelion,
+                vy: ((1.0 + e) / perihelion).sqrt(),
+                mass: 1e-12,
+                id: 1,
+                ..Default::default()
+            },
+        ];
+        let pn = PostNewtonian::new(1.0, c, false);
+        let acceleration = |state: &[Entity]| {
+            let sources = pn.sources(state, &[0]);
+            let mut accelerations = newtonian_accelerations(state);
+            for (i, a) in accelerations.iter_mut().enumerate() {
+                let correction = pn.acceleration(i, &state[i], &sources);
+                for k in 0..3 {
+                    a[k] += correction[k];
+                }
+            }
+            accelerations
+        };
+        // find the next perihelion, about one period later
+        let dt = 1e-3;
+        let mut closest = (f64::INFINITY, state[1]);
+        let mut step = 0;
+        integrate(&mut state, dt, 8000, acceleration, |state| {
+            step += 1;
+            let planet = state[1];
+            let r = dot(centre(&planet), centre(&planet)).sqrt();
+            if step as f64 * dt > 4.0 && r < closest.0 {
+                closest = (r, planet);
+            }
+        });
+        // the direction of the Runge-Lenz vector is the direction of the
+        // perihelion
+        let planet = closest.1;
+        let (x, v) = (centre(&planet), velocity(&planet));
+        let l = x[0] * v[1] - x[1] * v[0];
+        let r = dot(x, x).sqrt();
+        let runge_lenz = [v[1] * l - x[0] / r, -v[0] * l - x[1] / r];
+        let precession = runge_lenz[1].atan2(runge_lenz[0]);
+        let expected = 6.0 * std::f64::consts::PI / (c * c * a * (1.0 - e * e));
+        assert!(
+            (precession - expected).abs() < 0.03 * expected,
+            "{precession} vs {expected}"
+        );
+    }
+
+    #[test]
+    fn test_radiation_reaction() {
+        // an equal mass circular binary loses energy at the rate given by
+        // Peters & Mathews
+        let c = 10.0;
+        let speed = 0.5 * 2.0_f64.sqrt();
+        let mut state = [
+            Entity {
+                x: 0.5,
+                vy: speed,
+                mass: 1.0,
+                ..Default::default()
+            },
+            Entity {
+                x: -0.5,
+                vy: -speed,
+                mass: 1.0,
+                id: 1,
+                ..Default::default()
+            },
+        ];
+        let energy = |state: &[Entity]| {
+            let kinetic: f64 = state
+                .iter()
+                .map(|e| 0.5 * e.mass * dot(velocity(e), velocity(e)))
+                .sum();
+            let r = sub(centre(&state[0]), centre(&state[1]));
+            kinetic - state[0].mass * state[1].mass / dot(r, r).sqrt()
+        };
+        let initial = energy(&state);
+        let acceleration = |state: &[Entity]| {
+            let mut accelerations = newtonian_accelerations(state);
+            for (i, a) in accelerations.iter_mut().enumerate() {
+                let (b, d) = (&state[i], &state[1 - i]);
+                let rr = radiation_reaction(
+                    sub(centre(b), centre(d)),
+                    sub(velocity(b), velocity(d)),
+                    2.0,
+                    0.25,
+                    c,
+                );
+                for k in 0..3 {
+                    a[k] += 0.5 * rr[k];
+                }
+            }
+            accelerations
+        };
+        let time = 20.0;
+        integrate(&mut state, 1e-3, 20_000, acceleration, |_| {});
+        let rate = (energy(&state) - initial) / time;
+        let expected = -32.0 / 5.0 * 2.0 / c.powi(5);
+        assert!(
+            (rate - expected).abs() < 0.02 * expected.abs(),
+            "{rate} vs {expected}"
+        );
+    }
+}
@@ -40,6 +40,28 @@ where
     pub fn get_leaves_with_resolution(&self, location: [f64; 3], bh_factor: f64) -> Vec<T> {
         self.root.get_leaves_with_resolution(location, bh_factor)
     }
+
+    /// A read-only view of the tree. Unlike the tree, it can be shared
+    /// between threads.
+    pub fn query(&self) -> QuadTreeQuery<'_, 'a, T> {
+        QuadTreeQuery { root: &self.root }
+    }
+}
+
+pub struct QuadTreeQuery<'t, 'a, T>
+where
+    T: Star,
+{
+    root: &'t QuadTreeNode<'a, T>,
+}
+
+impl<T> QuadTreeQuery<'_, '_, T>
+where
+    T: Star + Default + Copy,
+{
+    pub fn get_leaves_with_resolution(&self, location: [f64; 3], bh_factor: f64) -> Vec<T> {
+        self.root.get_leaves_with_resolution(location, bh_factor)
+    }
 }
 
 impl<'a, T> QuadTreeNode<'a, T>
@@ -288,7 +310,7 @@ mod tests {
         // With positive bh_factor, distant entities might be aggregated
         let filtered_leaves = tree.get_leaves_with_resolution([0.0; 3], 1.0);
         assert!(
-            filtered_leaves.len() > 0,
+            !filtered_leaves.is_empty(),
             "Should return at least some entities"
         );
     }
@@ -327,8 +349,8 @@ mod tests {
         let from_far = tree.get_leaves_with_resolution([100.0, 0.0, 0.0], 0.5);
 
         // Results should differ based on observer location
-        assert!(from_origin.len() > 0, "Should find entities from origin");
-        assert!(from_far.len() > 0, "Should find entities from far location");
+        assert!(!from_origin.is_empty(), "Should find entities from origin");
+        assert!(!from_far.is_empty(), "Should find entities from far location");
     }
 
     #[test]
@@ -460,7 +482,7 @@ mod tests {
         // Query from location far outside the tree bounds
         let leaves = tree.get_leaves_with_resolution([1000.0, 1000.0, 1000.0], 0.5);
         assert!(
-            leaves.len() > 0,
+            !leaves.is_empty(),
             "Should still find entities when querying from outside"
         );
     }
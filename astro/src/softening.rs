@@ -0,0 +1,191 @@
+use std::str::FromStr;
+
+/// How gravity between two entities is weakened when they are close, so that
+/// close encounters don't need tiny timesteps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    /// The original easing, |F| = G Ma Mb / (r^2 + e)
+    Easing,
+    /// Newtonian gravity, which is infinite at r = 0
+    None,
+    /// Gravity of a Plummer sphere, F = G Ma Mb r / (r^2 + e)^(3/2)
+    Plummer,
+    /// The cubic spline of Monaghan & Lattanzio, as used by GADGET. Gravity
+    /// is exactly Newtonian beyond 2.8 times the softening length
+    Spline,
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easing" => Ok(Kernel::Easing),
+            "none" => Ok(Kernel::None),
+            "plummer" => Ok(Kernel::Plummer),
+            "spline" => Ok(Kernel::Spline),
+            _ => Err(format!(
+                "unknown softening {s}, expected one of easing, none, plummer or spline"
+            )),
+        }
+    }
+}
+
+/// The softening of a gravity calculation. `e` is the easing factor of
+/// `Kernel::Easing`, and the square of the softening length of the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Softening {
+    pub kernel: Kernel,
+    pub e: f64,
+    /// Soften each pair by at least the larger of their radii
+    pub radii: bool,
+}
+
+impl Softening {
+    pub fn new(kernel: Kernel, e: f64, radii: bool) -> Self {
+        Self { kernel, e, radii }
+    }
+
+    /// The easing of the original force law
+    pub fn easing(e: f64) -> Self {
+        Self::new(Kernel::Easing, e, false)
+    }
+
+    /// The `e` of a pair of entities with radii `a` and `b`. It is the same
+    /// either way round, so the forces of a pair are equal and opposite.
+    pub fn pair(&self, a: f64, b: f64) -> f64 {
+        if self.radii {
+            let length = a.max(b);
+            self.e.max(length * length)
+        } else {
+            self.e
+        }
+    }
+
+    /// The factor `k` where the force on an entity from another is
+    /// `G Ma Mb k r`, `r` is the separation between them and `r2` its square.
+    /// It is finite for coincident entities, except without softening, which
+    /// has no direction to push them in, so it is 0.
+    pub fn factor(&self, r2: f64, e: f64) -> f64 {
+        if r2 == 0.0 && matches!(self.kernel, Kernel::Easing | Kernel::None) {
+            return 0.0;
+        }
+        match self.kernel {
+            Kernel::Easing => 1.0 / (r2.sqrt() * (r2 + e)),
+            Kernel::None => 1.0 / (r2 * r2.sqrt()),
+            Kernel::Plummer => {
+                let s = r2 + e;
+                1.0 / (s * s.sqrt())
+            }
+            Kernel::Spline => spline(r2, 2.8 * e.sqrt()),
+        }
+    }
+}
+
+/// The force factor of the cubic spline kernel with a support of `h`, from
+/// Springel, Yoshida & White (2001)
+fn spline(r2: f64, h: f64) -> f64 {
+    let r = r2.sqrt();
+    if r >= h {
+        return 1.0 / (r2 * r);
+    }
+    let u = r / h;
+    let u2 = u * u;
+    let k = if u < 0.5 {
+        32.0 / 3.0 + u2 * (32.0 * u - 38.4)
+    } else {
+        64.0 / 3.0 - 48.0 * u + 38.4 * u2 - 32.0 / 3.0 * u2 * u - 1.0 / (15.0 * u2 * u)
+    };
+    k / (h * h * h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNELS: [Kernel; 4] = [
+        Kernel::Easing,
+        Kernel::None,
+        Kernel::Plummer,
+        Kernel::Spline,
+    ];
+
+    #[test]
+    fn test_kernels_are_newtonian_far_away() {
+        for kernel in KERNELS {
+            let softening = Softening::new(kernel, 0.01, false);
+            let r2: f64 = 100.0;
+            let newton = 1.0 / (r2 * r2.sqrt());
+            let k = softening.factor(r2, softening.e);
+            assert!((k - newton).abs() < 1e-3 * newton, "{kernel:?}");
+        }
+        // the spline is exact outside its support
+        let spline = Softening::new(Kernel::Spline, 0.01, false);
+        assert_eq!(spline.factor(0.09, 0.01), 1.0 / (0.09 * 0.09_f64.sqrt()));
+    }
+
+    #[test]
+    fn test_spline_is_continuous() {
+        let h = 1.0;
+        for u in [0.5, 1.0] {
+            let below = spline((u - 1e-9) * (u - 1e-9), h);
+            let above = spline((u + 1e-9) * (u + 1e-9), h);
+            assert!((below - above).abs() < 1e-6 * above, "{u}: {below} {above}");
+        }
+        // the force is finite at the centre, like a Plummer sphere
+        assert_eq!(spline(0.0, h), 32.0 / 3.0);
+    }
+
+    #[test]
+    fn test_pair_softening_is_symmetric() {
+        let softening = Softening::new(Kernel::Plummer, 0.01, true);
+        assert_eq!(softening.pair(0.5, 0.01), softening.pair(0.01, 0.5));
+        assert_eq!(softening.pair(0.5, 0.01), 0.25);
+        assert_eq!(softening.pair(0.0, 0.0), 0.01);
+        assert_eq!(
+            Softening::new(Kernel::Plummer, 0.01, false).pair(0.5, 0.5),
+            0.01
+        );
+    }
+
+    #[test]
+    fn test_coincident_entities() {
+        use crate::Star;
+        use physim_core::Entity;
+
+        let a = Entity {
+            mass: 2.0,
+            radius: 0.05,
+            ..Default::default()
+        };
+        for kernel in KERNELS {
+            for radii in [false, true] {
+                let softening = Softening::new(kernel, 0.01, radii);
+                // only softened forces stay finite as entities come together
+                let separations: &[f64] = match kernel {
+                    Kernel::None => &[0.0, 1e-170],
+                    _ => &[0.0, 1e-170, 1e-12, 1e-3],
+                };
+                for &dx in separations {
+                    let b = Entity {
+                        x: dx,
+                        y: -dx,
+                        mass: 3.0,
+                        radius: 0.2,
+                        ..Default::default()
+                    };
+                    let ab = a.newtons_law_of_universal_gravitation(&b, 1.0, &softening);
+                    let ba = b.newtons_law_of_universal_gravitation(&a, 1.0, &softening);
+                    for k in 0..3 {
+                        assert!(ab[k].is_finite(), "{kernel:?} {dx}: {ab:?}");
+                        assert!(
+                            (ab[k] + ba[k]).abs() <= 1e-12 * ab[k].abs(),
+                            "{kernel:?} {dx}"
+                        );
+                    }
+                    assert!(dx < 1e-12 || ab[0] > 0.0, "{kernel:?} {dx}");
+                }
+            }
+        }
+    }
+}
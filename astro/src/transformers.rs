@@ -1,36 +1,225 @@
 use std::{collections::HashMap, sync::Mutex};
 
 use bumpalo::Bump;
-use physim_attribute::transform_element;
+use physim_attribute::{Properties, transform_element};
 use physim_core::{
     Acceleration, Entity,
+    domain::{Domain, periodic_domain},
+    log::warn,
     messages::{MessageClient, MessagePriority},
     msg,
-    plugin::transform::TransformElement,
+    plugin::{properties::Properties, transform::TransformElement},
     post_bus_msg,
+    units::unit_system,
+};
+use rayon::{
+    ThreadPool, ThreadPoolBuilder,
+    iter::{IntoParallelRefIterator, ParallelIterator},
 };
 use serde_json::Value;
 
-use crate::{Star, octree::Octree, quadtree::QuadTree};
+use crate::{
+    Star,
+    fmm::Fmm,
+    linear_octree::{LinearOctree, Quadrupole},
+    pm::{Assignment, ParticleMesh},
+    pn::PostNewtonian,
+    quadtree::QuadTree,
+    softening::{Kernel, Softening},
+};
+
+#[derive(Properties)]
+struct BarnesHutProperties {
+    /// Barnes-Hut parameter. Increase for speed, decrease for accuracy
+    #[property(default = 1.0, range = 0.0..)]
+    theta: f64,
+    #[property(flatten)]
+    gravity: GravityProperties,
+}
+
+#[derive(Properties)]
+struct OctreeProperties {
+    /// Barnes-Hut parameter. Increase for speed, decrease for accuracy
+    #[property(default = 1.0, range = 0.0..)]
+    theta: f64,
+    #[property(flatten)]
+    gravity: GravityProperties,
+    /// Number of times the tree is refitted to new positions before it is rebuilt. 0 rebuilds it every time, 3 rebuilds it once per rk4 step
+    #[property(default = 0)]
+    refit: u64,
+    /// Order of the multipole expansion of the tree's nodes. 0 is their centre of mass, 2 adds their quadrupole moments. 1 is the same as 0
+    #[property(default = 0, range = 0..=2)]
+    order: u64,
+}
+
+#[derive(Properties)]
+struct FmmProperties {
+    /// Opening parameter. Cells interact through their expansions when the sum of their half widths is less than theta times their distance. Increase for speed, decrease for accuracy
+    #[property(default = 0.5, range = 0.0..)]
+    theta: f64,
+    /// Order of the expansions. Increase for accuracy, decrease for speed
+    #[property(default = 4, range = 1..=12)]
+    order: usize,
+    /// Most entities in a leaf of the tree. Entities in neighbouring leaves interact directly
+    #[property(default = 64, range = 1..)]
+    leaf_size: usize,
+    #[property(flatten)]
+    gravity: GravityProperties,
+}
+
+/// Properties shared by the elements which calculate gravity between pairs of entities
+#[derive(Properties)]
+struct GravityProperties {
+    /// Softening. The easing factor of the easing kernel, and the square of the softening length of the others
+    #[property(default = 1.0, range = 0.0..)]
+    e: f64,
+    /// Softening kernel. easing is the original force law, none is Newtonian gravity, plummer is the gravity of a Plummer sphere and spline is a cubic spline which is Newtonian beyond 2.8*sqrt(e)
+    #[property(default = "easing", choices = ["easing", "none", "plummer", "spline"])]
+    softening: String,
+    /// Soften each pair of entities by at least the larger of their radii
+    #[property(default = false)]
+    radius_softening: bool,
+    /// Accuracy of the suggested timestep eta*sqrt(e/a) for adaptive timesteps. 0 disables it
+    #[property(default = 0.1, range = 0.0..)]
+    eta: f64,
+    /// Number of threads used to calculate accelerations, and to build the tree of the elements which have one. 0 uses one per core
+    #[property(default = 0)]
+    threads: usize,
+}
+
+impl GravityProperties {
+    /// The softening chosen by the properties
+    fn softening(&self) -> Softening {
+        // the properties only allow the known kernels
+        Softening::new(
+            self.softening.parse().unwrap_or(Kernel::Easing),
+            self.e,
+            self.radius_softening,
+        )
+    }
+}
 
 #[transform_element(
     name = "astro",
-    blurb = "Compute approximate gravitational accelerations with the Barnes-Hut algorithm (quadtree)"
+    blurb = "Compute approximate gravitational accelerations with the Barnes-Hut algorithm (quadtree)",
+    properties = BarnesHutProperties
 )]
 #[repr(C)]
 pub struct AstroElement {
     inner: Mutex<InnerBhElement>,
+    pool: ThreadPool,
 }
 
 #[repr(C)]
 struct InnerBhElement {
     theta: f64,
-    easing_factor: f64,
+    softening: Softening,
+    // the gravitational constant in the units of the simulation
+    g: f64,
+    eta: f64,
+    suggested_dt: Option<f64>,
 }
 
-impl TransformElement for AstroElement {
-    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
-        // let mut new_state = Vec::with_capacity(state.len());
+/// The timestep at which an entity with the largest acceleration moves a
+/// fraction `eta` of the easing length, or `None` if this is disabled.
+fn suggest_dt(eta: f64, easing_factor: f64, max_acceleration: f64) -> Option<f64> {
+    (eta > 0.0 && easing_factor > 0.0 && max_acceleration > 0.0)
+        .then(|| eta * (easing_factor / max_acceleration).sqrt())
+}
+
+/// Worker threads for the calculations of an element
+fn thread_pool(element: &str, threads: usize) -> ThreadPool {
+    let name = element.to_string();
+    match ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{name}-{i}"))
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("{element}: failed to start threads: {e}");
+            std::process::exit(1)
+        }
+    }
+}
+
+/// Move `star_b` to its image nearest to `star_a` in a periodic domain
+fn nearest_image(domain: &Domain, star_a: &Entity, star_b: &mut Entity) {
+    [star_b.x, star_b.y, star_b.z] = domain.nearest_image(star_a.get_centre(), star_b.get_centre());
+}
+
+/// The gravitational acceleration of `star_a` due to `star_bs`, where `g` is
+/// the gravitational constant. In a periodic `domain`, it is due to the
+/// nearest image of each of them.
+fn gravity<'b>(
+    star_a: &Entity,
+    star_bs: impl IntoIterator<Item = &'b Entity>,
+    g: f64,
+    softening: &Softening,
+    domain: Option<&Domain>,
+) -> Acceleration {
+    let mut f = [0.0; 3];
+    for star_b in star_bs {
+        let mut image;
+        let star_b = match domain {
+            Some(domain) => {
+                image = *star_b;
+                nearest_image(domain, star_a, &mut image);
+                &image
+            }
+            None => star_b,
+        };
+        if star_a.get_centre() == star_b.get_centre() {
+            continue;
+        }
+        let fij = star_a.newtons_law_of_universal_gravitation(star_b, g, softening);
+        f[0] += fij[0];
+        f[1] += fij[1];
+        f[2] += fij[2];
+    }
+    Acceleration {
+        x: f[0] / star_a.mass,
+        y: f[1] / star_a.mass,
+        z: f[2] / star_a.mass,
+    }
+}
+
+/// Add the accelerations of the targets which aren't fixed, calculating them
+/// in parallel. Each acceleration is calculated the same way whichever thread
+/// it is on, so the result doesn't depend on the number of threads. Returns
+/// the largest acceleration.
+fn add_accelerations(
+    pool: &ThreadPool,
+    state: &[Entity],
+    targets: &[usize],
+    accelerations: &mut [Acceleration],
+    acceleration: impl Fn(usize, &Entity) -> Acceleration + Sync,
+) -> f64 {
+    let results: Vec<Option<Acceleration>> = pool.install(|| {
+        targets
+            .par_iter()
+            .map(|&i| (!state[i].fixed).then(|| acceleration(i, &state[i])))
+            .collect()
+    });
+    let mut max_acceleration: f64 = 0.0;
+    for (&i, a) in targets.iter().zip(results) {
+        if let Some(a) = a {
+            max_acceleration = max_acceleration.max(a.x.hypot(a.y).hypot(a.z));
+            accelerations[i] += a;
+        }
+    }
+    max_acceleration
+}
+
+/// Keep the smallest suggestion until it is read by the pipeline
+fn record_suggested_dt(suggested_dt: &mut Option<f64>, dt: Option<f64>) {
+    if let Some(dt) = dt {
+        *suggested_dt = Some(suggested_dt.map_or(dt, |s| s.min(dt)));
+    }
+}
+
+impl AstroElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
         let arena = Bump::new();
         let extent = state
             .iter()
@@ -42,65 +231,60 @@ impl TransformElement for AstroElement {
         for star in state.iter() {
             tree.push(*star);
         }
-        let element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
-        for (i, star_a) in state.iter().enumerate() {
-            if star_a.fixed {
-                continue;
-            }
-            let mut f = [0.0; 3];
+        let tree = tree.query();
 
-            let star_bs = tree.get_leaves_with_resolution(star_a.get_centre(), element.theta);
-            for star_b in star_bs.iter() {
-                if star_a.get_centre() == star_b.get_centre() {
-                    continue;
-                }
-                let fij =
-                    star_a.newtons_law_of_universal_gravitation(star_b, element.easing_factor);
-                f[0] += fij[0];
-                f[1] += fij[1];
-                f[2] += fij[2];
-            }
-            accelerations[i] += Acceleration {
-                x: f[0] / star_a.mass,
-                y: f[1] / star_a.mass,
-                z: f[2] / star_a.mass,
-            }
-        }
+        let mut element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (theta, g, softening) = (element.theta, element.g, &element.softening);
+        let max_acceleration =
+            add_accelerations(&self.pool, state, targets, accelerations, |_, star_a| {
+                let star_bs = tree.get_leaves_with_resolution(star_a.get_centre(), theta);
+                gravity(star_a, &star_bs, g, softening, None)
+            });
+        let dt = suggest_dt(element.eta, element.softening.e, max_acceleration);
+        record_suggested_dt(&mut element.suggested_dt, dt);
     }
+}
 
-    fn new(properties: HashMap<String, Value>) -> Self {
-        let theta = properties
-            .get("theta")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
+impl TransformElement for AstroElement {
+    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
 
-        let easing_factor = properties
-            .get("e")
-            .and_then(|v| v.as_f64())
-            .map(|x| x.abs())
-            .unwrap_or(1.0);
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
 
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = BarnesHutProperties::parse("astro", &properties);
+        warn_open_space("astro");
         AstroElement {
             inner: Mutex::new(InnerBhElement {
-                theta,
-                easing_factor,
+                theta: properties.theta,
+                softening: properties.gravity.softening(),
+                g: unit_system().g(),
+                eta: properties.gravity.eta,
+                suggested_dt: None,
             }),
+            pool: thread_pool("astro", properties.gravity.threads),
         }
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([
-            (
-                String::from("theta"),
-                String::from(
-                    "Barnes-Hut parameter. Increase for speed, decrease for accuracy. Default=1.0",
-                ),
-            ),
-            (
-                String::from("e"),
-                String::from("Easing factor. Modify G*Ma*Mb*(r-e)^-2. Default=1.0"),
-            ),
-        ])
+        BarnesHutProperties::descriptions()
+    }
+
+    fn suggested_dt(&self) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .suggested_dt
+            .take()
     }
 }
 
@@ -113,85 +297,163 @@ impl MessageClient for AstroElement {
 
 #[transform_element(
     name = "astro2",
-    blurb = "Compute approximate gravitational accelerations with the Barnes-Hut algorithm (octree)"
+    blurb = "Compute approximate gravitational accelerations with the Barnes-Hut algorithm (octree)",
+    properties = OctreeProperties
 )]
 pub struct AstroOctreeElement {
-    inner: Mutex<InnerBhElement>,
+    inner: Mutex<InnerOctreeElement>,
+    pool: ThreadPool,
 }
 
-impl TransformElement for AstroOctreeElement {
-    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
-        // let mut new_state = Vec::with_capacity(state.len());
-        let arena = Bump::new();
+struct InnerOctreeElement {
+    theta: f64,
+    softening: Softening,
+    // the gravitational constant in the units of the simulation
+    g: f64,
+    eta: f64,
+    suggested_dt: Option<f64>,
+    quadrupoles: bool,
+    refit: u64,
+    // the tree from the last calculation, and how many times it was refitted
+    tree: Option<LinearOctree<Entity>>,
+    refits: u64,
+    domain: Option<Domain>,
+}
+
+impl InnerOctreeElement {
+    /// Refit the last tree to `state` if it is allowed, otherwise build a new
+    /// one
+    fn update_tree(&mut self, state: &[Entity]) {
+        // entities jump across a periodic domain, out of their cells
+        if let Some(tree) = self.tree.as_mut()
+            && self.refits < self.refit
+            && tree.len() == state.len()
+            && self.domain.is_none()
+        {
+            tree.refit(state);
+            self.refits += 1;
+            return;
+        }
         let extent = state
             .iter()
             .flat_map(|x| x.get_centre())
             .map(|x| x.abs())
             .reduce(f64::max)
             .unwrap_or(1.0);
-        let mut tree: Octree<'_, Entity> = Octree::new([0.0; 3], 1.0 * extent, &arena);
-        for star in state.iter() {
-            tree.push(*star);
-        }
+        let mut tree = if self.quadrupoles {
+            LinearOctree::with_quadrupoles([0.0; 3], extent, state)
+        } else {
+            LinearOctree::new([0.0; 3], extent, state)
+        };
+        tree.set_domain(self.domain);
+        self.tree = Some(tree);
+        self.refits = 0;
+    }
+}
 
-        let element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
-        for (i, star_a) in state.iter().enumerate() {
-            if star_a.fixed {
-                continue;
-            }
-            let mut f = [0.0; 3];
-            let star_bs = tree.get_leaves_with_resolution(star_a.get_centre(), element.theta);
-            for star_b in star_bs.iter() {
-                if star_a.get_centre() == star_b.get_centre() {
-                    continue;
+impl AstroOctreeElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
+        let mut element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *element;
+        self.pool.install(|| inner.update_tree(state));
+        let Some(tree) = element.tree.as_ref() else {
+            return;
+        };
+        let (theta, g, softening) = (element.theta, element.g, &element.softening);
+        let domain = element.domain.as_ref();
+        let max_acceleration = if element.quadrupoles {
+            add_accelerations(&self.pool, state, targets, accelerations, |_, star_a| {
+                let mut nodes = tree.get_multipoles_with_resolution(star_a.get_centre(), theta);
+                if let Some(domain) = domain {
+                    for (star_b, _) in nodes.iter_mut() {
+                        nearest_image(domain, star_a, star_b);
+                    }
                 }
-                let fij =
-                    star_a.newtons_law_of_universal_gravitation(star_b, element.easing_factor);
-                f[0] += fij[0];
-                f[1] += fij[1];
-                f[2] += fij[2];
-            }
-            accelerations[i] += Acceleration {
-                x: f[0] / star_a.mass,
-                y: f[1] / star_a.mass,
-                z: f[2] / star_a.mass,
-            }
-        }
+                quadrupole_gravity(star_a, &nodes, g, softening)
+            })
+        } else {
+            add_accelerations(&self.pool, state, targets, accelerations, |_, star_a| {
+                let star_bs = tree.get_leaves_with_resolution(star_a.get_centre(), theta);
+                gravity(star_a, &star_bs, g, softening, domain)
+            })
+        };
+        let dt = suggest_dt(element.eta, element.softening.e, max_acceleration);
+        record_suggested_dt(&mut element.suggested_dt, dt);
     }
+}
 
-    fn new(properties: HashMap<String, Value>) -> Self {
-        let theta = properties
-            .get("theta")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0);
+/// The gravitational acceleration of `star_a` due to `nodes` and their
+/// quadrupole moments. The softening only applies to the mass at the centre
+/// of mass, since the quadrupole is only used far from a node.
+fn quadrupole_gravity(
+    star_a: &Entity,
+    nodes: &[(Entity, Quadrupole)],
+    g: f64,
+    softening: &Softening,
+) -> Acceleration {
+    let mut a = gravity(
+        star_a,
+        nodes.iter().map(|(star_b, _)| star_b),
+        g,
+        softening,
+        None,
+    );
+    let centre = star_a.get_centre();
+    for (star_b, quadrupole) in nodes.iter().filter(|(_, q)| !q.is_zero()) {
+        let b = star_b.get_centre();
+        let r = [centre[0] - b[0], centre[1] - b[1], centre[2] - b[2]];
+        let aq = quadrupole.acceleration(r);
+        a.x += g * aq[0];
+        a.y += g * aq[1];
+        a.z += g * aq[2];
+    }
+    a
+}
 
-        let easing_factor = properties
-            .get("e")
-            .and_then(|v| v.as_f64())
-            .map(|x| x.abs())
-            .unwrap_or(1.0);
+impl TransformElement for AstroOctreeElement {
+    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
 
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
+
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = OctreeProperties::parse("astro2", &properties);
         Self {
-            inner: Mutex::new(InnerBhElement {
-                theta,
-                easing_factor,
+            inner: Mutex::new(InnerOctreeElement {
+                theta: properties.theta,
+                softening: properties.gravity.softening(),
+                g: unit_system().g(),
+                eta: properties.gravity.eta,
+                suggested_dt: None,
+                quadrupoles: properties.order == 2,
+                refit: properties.refit,
+                tree: None,
+                refits: 0,
+                domain: periodic_domain(),
             }),
+            pool: thread_pool("astro2", properties.gravity.threads),
         }
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([
-            (
-                String::from("theta"),
-                String::from(
-                    "Barnes-Hut parameter. Increase for speed, decrease for accuracy. Default=1.0",
-                ),
-            ),
-            (
-                String::from("e"),
-                String::from("Easing factor. Modify G*Ma*Mb*(r-e)^-2. Default=1.0"),
-            ),
-        ])
+        OctreeProperties::descriptions()
+    }
+
+    fn suggested_dt(&self) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .suggested_dt
+            .take()
     }
 }
 
@@ -206,66 +468,751 @@ impl MessageClient for AstroOctreeElement {
 
 #[transform_element(
     name = "simple_astro",
-    blurb = "Compute exact gravitational accelerations"
+    blurb = "Compute exact gravitational accelerations",
+    properties = GravityProperties
 )]
 pub struct SimpleAstroElement {
     inner: Mutex<InnerSimpleAstroElement>,
+    pool: ThreadPool,
 }
 
 struct InnerSimpleAstroElement {
-    easing_factor: f64,
+    softening: Softening,
+    // the gravitational constant in the units of the simulation
+    g: f64,
+    eta: f64,
+    suggested_dt: Option<f64>,
+    domain: Option<Domain>,
+}
+
+impl SimpleAstroElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (g, softening, domain) = (inner.g, &inner.softening, inner.domain.as_ref());
+        let max_acceleration =
+            add_accelerations(&self.pool, state, targets, accelerations, |_, star_a| {
+                gravity(star_a, state, g, softening, domain)
+            });
+        let dt = suggest_dt(inner.eta, inner.softening.e, max_acceleration);
+        record_suggested_dt(&mut inner.suggested_dt, dt);
+    }
 }
 
 impl TransformElement for SimpleAstroElement {
     fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
-        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
 
-        for (i, star_a) in state.iter().enumerate() {
-            if star_a.fixed {
-                continue;
-            }
-            let mut f = [0.0; 3];
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
 
-            for star_b in state.iter() {
-                if star_a.get_centre() == star_b.get_centre() {
-                    continue;
-                }
-                let fij = star_a.newtons_law_of_universal_gravitation(star_b, inner.easing_factor);
-                f[0] += fij[0];
-                f[1] += fij[1];
-                f[2] += fij[2];
-            }
-            accelerations[i] += Acceleration {
-                x: f[0] / star_a.mass,
-                y: f[1] / star_a.mass,
-                z: f[2] / star_a.mass,
-            }
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = GravityProperties::parse("simple_astro", &properties);
+        Self {
+            inner: Mutex::new(InnerSimpleAstroElement {
+                softening: properties.softening(),
+                g: unit_system().g(),
+                eta: properties.eta,
+                suggested_dt: None,
+                domain: periodic_domain(),
+            }),
+            pool: thread_pool("simple_astro", properties.threads),
         }
     }
 
-    fn new(properties: HashMap<String, Value>) -> Self {
-        let easing_factor = properties
-            .get("e")
-            .and_then(|v| v.as_f64())
-            .map(|x| x.abs())
-            .unwrap_or(1.0);
+    fn get_property_descriptions(&self) -> HashMap<String, String> {
+        GravityProperties::descriptions()
+    }
+
+    fn suggested_dt(&self) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .suggested_dt
+            .take()
+    }
+}
+
+impl MessageClient for SimpleAstroElement {
+    fn post_configuration_messages(&self) {
+        let msg = msg!(self, "energysink", "gravity", MessagePriority::Low);
+        post_bus_msg!(msg)
+    }
+}
+
+#[derive(Properties)]
+#[properties(validate = PmProperties::validate)]
+struct PmProperties {
+    /// Maximum distance from origin in x. Without any limits, the box is the periodic domain of the simulation, or has limits of 1
+    #[property(range = 0.0..)]
+    xlim: Option<f64>,
+    /// Maximum distance from origin in y
+    #[property(range = 0.0..)]
+    ylim: Option<f64>,
+    /// Maximum distance from origin in z
+    #[property(range = 0.0..)]
+    zlim: Option<f64>,
+    /// Number of cells along each side of the mesh. Must be a power of two
+    #[property(default = 64, range = 1..)]
+    grid: usize,
+    /// How mass is spread over the mesh and the field read back. cic uses the 8 nearest mesh points, tsc uses 27 and is smoother
+    #[property(default = "cic", choices = ["cic", "tsc"])]
+    assignment: String,
+    /// Accuracy of the suggested timestep eta*sqrt(h/a), where h is the size of a cell, for adaptive timesteps. 0 disables it
+    #[property(default = 0.1, range = 0.0..)]
+    eta: f64,
+    /// Number of threads used to calculate accelerations. 0 uses one per core
+    #[property(default = 0)]
+    threads: usize,
+}
+
+impl PmProperties {
+    fn validate(&self) -> Result<(), String> {
+        if !self.grid.is_power_of_two() {
+            return Err(format!("grid must be a power of two, not {}", self.grid));
+        }
+        Ok(())
+    }
+}
+
+#[transform_element(
+    name = "fmm",
+    blurb = "Compute approximate gravitational accelerations with the fast multipole method",
+    properties = FmmProperties
+)]
+pub struct FmmElement {
+    inner: Mutex<InnerFmmElement>,
+    pool: ThreadPool,
+}
+
+struct InnerFmmElement {
+    fmm: Fmm,
+    softening: Softening,
+    // the gravitational constant in the units of the simulation
+    g: f64,
+    eta: f64,
+    suggested_dt: Option<f64>,
+}
+
+impl FmmElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (g, softening) = (inner.g, &inner.softening);
+        let fmm = &inner.fmm;
+        let field = self.pool.install(|| fmm.field(state));
+        let max_acceleration =
+            add_accelerations(&self.pool, state, targets, accelerations, |i, star_a| {
+                let mut a = gravity(star_a, field.near_entities(i), g, softening, None);
+                let far = field.far_acceleration(i);
+                a.x += g * far[0];
+                a.y += g * far[1];
+                a.z += g * far[2];
+                a
+            });
+        drop(field);
+        let dt = suggest_dt(inner.eta, inner.softening.e, max_acceleration);
+        record_suggested_dt(&mut inner.suggested_dt, dt);
+    }
+}
 
+impl TransformElement for FmmElement {
+    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
+
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
+
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = FmmProperties::parse("fmm", &properties);
+        warn_open_space("fmm");
+        let softening = properties.gravity.softening();
         Self {
-            inner: Mutex::new(InnerSimpleAstroElement { easing_factor }),
+            inner: Mutex::new(InnerFmmElement {
+                fmm: Fmm::new(
+                    properties.order,
+                    properties.theta,
+                    properties.leaf_size,
+                    softening,
+                ),
+                softening,
+                g: unit_system().g(),
+                eta: properties.gravity.eta,
+                suggested_dt: None,
+            }),
+            pool: thread_pool("fmm", properties.gravity.threads),
         }
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([(
-            String::from("e"),
-            String::from("Easing factor. Modify G*Ma*Mb*(r-e)^-2. Default=1.0"),
-        )])
+        FmmProperties::descriptions()
+    }
+
+    fn suggested_dt(&self) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .suggested_dt
+            .take()
     }
 }
 
-impl MessageClient for SimpleAstroElement {
+impl MessageClient for FmmElement {
     fn post_configuration_messages(&self) {
         let msg = msg!(self, "energysink", "gravity", MessagePriority::Low);
         post_bus_msg!(msg)
     }
 }
+
+#[transform_element(
+    name = "pm",
+    blurb = "Compute periodic gravitational accelerations on a mesh",
+    properties = PmProperties
+)]
+pub struct PmElement {
+    inner: Mutex<InnerPmElement>,
+    pool: ThreadPool,
+}
+
+struct InnerPmElement {
+    mesh: ParticleMesh,
+    // the gravitational constant in the units of the simulation
+    g: f64,
+    eta: f64,
+    suggested_dt: Option<f64>,
+}
+
+impl PmElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (g, mesh) = (inner.g, &inner.mesh);
+        let field = self.pool.install(|| mesh.field(state));
+        let max_acceleration =
+            add_accelerations(&self.pool, state, targets, accelerations, |_, star| {
+                let a = field.acceleration(star.get_centre());
+                Acceleration {
+                    x: g * a[0],
+                    y: g * a[1],
+                    z: g * a[2],
+                }
+            });
+        drop(field);
+        let cell = inner.mesh.cell_size().into_iter().reduce(f64::min);
+        let dt = suggest_dt(inner.eta, cell.unwrap_or_default(), max_acceleration);
+        record_suggested_dt(&mut inner.suggested_dt, dt);
+    }
+}
+
+impl TransformElement for PmElement {
+    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
+
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
+
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = PmProperties::parse("pm", &properties);
+        let assignment = match properties.assignment.as_str() {
+            "tsc" => Assignment::Tsc,
+            _ => Assignment::Cic,
+        };
+        let limits = [properties.xlim, properties.ylim, properties.zlim];
+        let (min, max) = match pm_box(limits, periodic_domain()) {
+            Ok(bounds) => bounds,
+            Err(e) => {
+                eprintln!("pm: {e}");
+                std::process::exit(1)
+            }
+        };
+        Self {
+            inner: Mutex::new(InnerPmElement {
+                mesh: ParticleMesh::with_box(properties.grid, min, max, assignment),
+                g: unit_system().g(),
+                eta: properties.eta,
+                suggested_dt: None,
+            }),
+            pool: thread_pool("pm", properties.threads),
+        }
+    }
+
+    fn get_property_descriptions(&self) -> HashMap<String, String> {
+        PmProperties::descriptions()
+    }
+
+    fn suggested_dt(&self) -> Option<f64> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .suggested_dt
+            .take()
+    }
+}
+
+/// The corners of the box of `pm`. It is the periodic domain when there is
+/// one, and limits which are given must describe the same box.
+fn pm_box(
+    limits: [Option<f64>; 3],
+    domain: Option<Domain>,
+) -> Result<([f64; 3], [f64; 3]), String> {
+    let Some(domain) = domain else {
+        let limits = limits.map(|l| l.unwrap_or(1.0));
+        return Ok((limits.map(|l| -l), limits));
+    };
+    if domain.periodic != [true; 3] {
+        return Err("the periodic domain must be periodic along every axis".to_string());
+    }
+    for (k, (axis, limit)) in ["x", "y", "z"].iter().zip(limits).enumerate() {
+        if let Some(l) = limit
+            && (-l != domain.min[k] || l != domain.max[k])
+        {
+            return Err(format!(
+                "{axis}lim={l} doesn't match the periodic domain, which runs from {} to {} in {axis}",
+                domain.min[k], domain.max[k]
+            ));
+        }
+    }
+    Ok((domain.min, domain.max))
+}
+
+/// Warn that `element` calculates gravity as if there was no periodic domain
+fn warn_open_space(element: &str) {
+    if periodic_domain().is_some() {
+        warn!("{element} ignores the periodic domain. Use simple_astro, astro2 or pm instead");
+    }
+}
+
+// the energy sink's potential energy is for open space, not a periodic box
+impl MessageClient for PmElement {}
+
+#[derive(Properties)]
+struct PnProperties {
+    /// Speed of light in the units of the simulation. 0 uses the speed of light in the global units, which must be si or au
+    #[property(default = 0.0, range = 0.0..)]
+    c: f64,
+    /// Ids of the bodies whose gravity is corrected, e.g. \[0,3\]
+    #[property(default = [])]
+    ids: Vec<u64>,
+    /// Bodies at least this massive also have their gravity corrected. 0 selects none by mass
+    #[property(default = 0.0, range = 0.0..)]
+    mass: f64,
+    /// Add the 2.5PN radiation reaction between the selected bodies
+    #[property(default = false)]
+    radiation: bool,
+    /// Number of threads used to calculate accelerations. 0 uses one per core
+    #[property(default = 0)]
+    threads: usize,
+}
+
+#[transform_element(
+    name = "pn",
+    blurb = "Add post-Newtonian corrections to the gravity of a few massive bodies",
+    properties = PnProperties
+)]
+pub struct PnElement {
+    inner: Mutex<InnerPnElement>,
+    pool: ThreadPool,
+}
+
+struct InnerPnElement {
+    pn: PostNewtonian,
+    ids: Vec<u64>,
+    mass: f64,
+}
+
+impl InnerPnElement {
+    /// Indices of the bodies whose gravity is corrected
+    fn selected(&self, state: &[Entity]) -> Vec<usize> {
+        state
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| {
+                self.ids.contains(&(e.id as u64)) || (self.mass > 0.0 && e.mass >= self.mass)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+impl PnElement {
+    fn accelerate(&self, state: &[Entity], targets: &[usize], accelerations: &mut [Acceleration]) {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let selected = inner.selected(state);
+        if selected.is_empty() {
+            return;
+        }
+        let pn = &inner.pn;
+        let sources = pn.sources(state, &selected);
+        add_accelerations(&self.pool, state, targets, accelerations, |i, entity| {
+            let a = pn.acceleration(i, entity, &sources);
+            Acceleration {
+                x: a[0],
+                y: a[1],
+                z: a[2],
+            }
+        });
+    }
+}
+
+impl TransformElement for PnElement {
+    fn transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
+        let targets: Vec<usize> = (0..state.len()).collect();
+        self.accelerate(state, &targets, accelerations);
+    }
+
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        accelerations: &mut [Acceleration],
+    ) {
+        self.accelerate(state, targets, accelerations);
+    }
+
+    fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = PnProperties::parse("pn", &properties);
+        let units = unit_system();
+        let selects = !properties.ids.is_empty() || properties.mass > 0.0;
+        // elements are also created without properties to describe them, so
+        // c is only needed when bodies are selected
+        let c = match (properties.c, units.speed_of_light()) {
+            (c, _) if c > 0.0 => c,
+            (_, Some(c)) => c,
+            _ if !selects => 0.0,
+            _ => {
+                eprintln!("pn: c must be set in {units} units");
+                std::process::exit(1)
+            }
+        };
+        Self {
+            inner: Mutex::new(InnerPnElement {
+                pn: PostNewtonian::new(units.g(), c, properties.radiation),
+                ids: properties.ids,
+                mass: properties.mass,
+            }),
+            pool: thread_pool("pn", properties.threads),
+        }
+    }
+
+    fn get_property_descriptions(&self) -> HashMap<String, String> {
+        PnProperties::descriptions()
+    }
+}
+
+// the energy sink's potential energy is Newtonian
+impl MessageClient for PnElement {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster() -> Vec<Entity> {
+        // a deterministic scatter of stars, with one fixed
+        let mut seed: u64 = 12345;
+        let mut random = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 11) as f64 / (1_u64 << 53) as f64 - 0.5
+        };
+        let mut state: Vec<Entity> = (0..500)
+            .map(|_| Entity {
+                x: random(),
+                y: random(),
+                z: random(),
+                mass: 1.0 + random(),
+                ..Default::default()
+            })
+            .collect();
+        state[7].fixed = true;
+        state
+    }
+
+    fn accelerations<T: TransformElement>(
+        threads: usize,
+        targets: Option<&[usize]>,
+    ) -> Vec<Acceleration> {
+        let element = T::new(HashMap::from([(
+            "threads".to_string(),
+            serde_json::json!(threads),
+        )]));
+        let state = cluster();
+        let mut accelerations = vec![Acceleration::zero(); state.len()];
+        match targets {
+            Some(targets) => element.transform_targets(&state, targets, &mut accelerations),
+            None => element.transform(&state, &mut accelerations),
+        }
+        accelerations
+    }
+
+    fn assert_bitwise_eq(a: &[Acceleration], b: &[Acceleration]) {
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!(a.x.to_bits(), b.x.to_bits());
+            assert_eq!(a.y.to_bits(), b.y.to_bits());
+            assert_eq!(a.z.to_bits(), b.z.to_bits());
+        }
+    }
+
+    fn check_deterministic<T: TransformElement>() {
+        let serial = accelerations::<T>(1, None);
+        assert_bitwise_eq(&serial[7..8], &[Acceleration::zero()]);
+        assert!(serial[0].x != 0.0);
+        for threads in [2, 4, 7] {
+            assert_bitwise_eq(&serial, &accelerations::<T>(threads, None));
+        }
+
+        let targets = [3, 7, 100, 499];
+        let targeted = accelerations::<T>(4, Some(&targets));
+        for (i, a) in targeted.iter().enumerate() {
+            if targets.contains(&i) {
+                assert_bitwise_eq(&[*a], &[serial[i]]);
+            } else {
+                assert_bitwise_eq(&[*a], &[Acceleration::zero()]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_thread_count_does_not_change_accelerations() {
+        check_deterministic::<AstroElement>();
+        check_deterministic::<AstroOctreeElement>();
+        check_deterministic::<SimpleAstroElement>();
+        check_deterministic::<FmmElement>();
+        check_deterministic::<PmElement>();
+    }
+
+    #[test]
+    fn test_post_newtonian_selection() {
+        let mut state = cluster();
+        for (i, e) in state.iter_mut().enumerate() {
+            e.id = i;
+        }
+        let corrections = |selection: (&str, serde_json::Value)| {
+            let element = PnElement::new(HashMap::from([
+                ("c".to_string(), serde_json::json!(10.0)),
+                (selection.0.to_string(), selection.1),
+            ]));
+            let mut accelerations = vec![Acceleration::zero(); state.len()];
+            element.transform(&state, &mut accelerations);
+            accelerations
+        };
+        // without sources there are no corrections
+        let none = corrections(("ids", serde_json::json!([])));
+        assert_bitwise_eq(&none, &vec![Acceleration::zero(); state.len()]);
+
+        // a single source doesn't correct its own gravity
+        let one = corrections(("ids", serde_json::json!(3)));
+        assert_bitwise_eq(&one[3..4], &[Acceleration::zero()]);
+        assert!(one[0].x != 0.0);
+
+        // every star of the cluster is at least this massive
+        let all = corrections(("mass", serde_json::json!(0.4)));
+        assert!(all.iter().enumerate().all(|(i, a)| i == 7 || a.x != 0.0));
+    }
+
+    #[test]
+    fn test_refitted_tree() {
+        let element = |refit: u64| {
+            AstroOctreeElement::new(HashMap::from([(
+                "refit".to_string(),
+                serde_json::json!(refit),
+            )]))
+        };
+        let rebuilt = element(0);
+        let refitted = element(3);
+        let mut state = cluster();
+        for _ in 0..4 {
+            let mut expected = vec![Acceleration::zero(); state.len()];
+            let mut accelerations = vec![Acceleration::zero(); state.len()];
+            rebuilt.transform(&state, &mut expected);
+            refitted.transform(&state, &mut accelerations);
+            // small moves between the stages of a step barely change the tree
+            for (a, b) in expected.iter().zip(&accelerations) {
+                let difference = (a.x - b.x).hypot(a.y - b.y).hypot(a.z - b.z);
+                assert!(difference <= 1e-3 * a.x.hypot(a.y).hypot(a.z) + 1e-12);
+            }
+            for e in state.iter_mut() {
+                e.x += 1e-4;
+            }
+        }
+        assert_eq!(refitted.inner.lock().unwrap().refits, 3);
+
+        // a new tree is needed for a different number of entities
+        state.pop();
+        let mut accelerations = vec![Acceleration::zero(); state.len()];
+        refitted.transform(&state, &mut accelerations);
+        assert_eq!(refitted.inner.lock().unwrap().refits, 0);
+    }
+
+    #[test]
+    fn test_periodic_domain() {
+        let domain = Domain::new([-0.5; 3], [0.5; 3], [true; 3]);
+        let easing = ("e".to_string(), serde_json::json!(0.05));
+        let simple = SimpleAstroElement::new(HashMap::from([easing.clone()]));
+        simple.inner.lock().unwrap().domain = Some(domain);
+
+        // the nearest image of the other is through the wall behind it
+        let pair = [
+            Entity {
+                x: -0.45,
+                mass: 1.0,
+                ..Default::default()
+            },
+            Entity {
+                x: 0.45,
+                mass: 1.0,
+                ..Default::default()
+            },
+        ];
+        let mut accelerations = vec![Acceleration::zero(); 2];
+        simple.transform(&pair, &mut accelerations);
+        assert!(accelerations[0].x < 0.0 && accelerations[1].x > 0.0);
+        assert!((accelerations[0].x + 1.0 / (0.1 * 0.1 + 0.05)).abs() < 1e-9);
+
+        // the tree finds the nearest images too
+        let tree = AstroOctreeElement::new(HashMap::from([
+            easing,
+            ("theta".to_string(), serde_json::json!(0.1)),
+        ]));
+        tree.inner.lock().unwrap().domain = Some(domain);
+        let state = cluster();
+        let mut expected = vec![Acceleration::zero(); state.len()];
+        let mut accelerations = vec![Acceleration::zero(); state.len()];
+        simple.transform(&state, &mut expected);
+        tree.transform(&state, &mut accelerations);
+        // the forces of a periodic cluster mostly cancel, so the errors are
+        // compared to the typical acceleration
+        let typical: f64 = expected
+            .iter()
+            .map(|a| a.x.hypot(a.y).hypot(a.z))
+            .sum::<f64>()
+            / state.len() as f64;
+        let mut errors: Vec<f64> = expected
+            .iter()
+            .zip(&accelerations)
+            .map(|(a, b)| (a.x - b.x).hypot(a.y - b.y).hypot(a.z - b.z) / typical)
+            .collect();
+        errors.sort_by(f64::total_cmp);
+        assert!(
+            errors[errors.len() / 2] < 0.01,
+            "{}",
+            errors[errors.len() / 2]
+        );
+    }
+
+    #[test]
+    fn test_shared_properties() {
+        let names: Vec<String> = OctreeProperties::schema()
+            .into_iter()
+            .map(|spec| spec.name)
+            .collect();
+        for name in ["theta", "e", "softening", "eta", "threads", "refit"] {
+            assert!(names.contains(&name.to_string()), "{name}");
+        }
+
+        let properties = HashMap::from([
+            ("theta".to_string(), serde_json::json!(0.5)),
+            ("e".to_string(), serde_json::json!(0.01)),
+            ("softening".to_string(), serde_json::json!("plummer")),
+        ]);
+        let properties = OctreeProperties::from_properties(&properties).unwrap();
+        assert_eq!(properties.theta, 0.5);
+        assert_eq!(properties.gravity.eta, 0.1);
+        assert_eq!(
+            properties.gravity.softening(),
+            Softening::new(Kernel::Plummer, 0.01, false)
+        );
+
+        let properties = HashMap::from([("thetta".to_string(), serde_json::json!(0.5))]);
+        assert!(FmmProperties::from_properties(&properties).is_err());
+    }
+
+    #[test]
+    fn test_pm_grid() {
+        let grid = |n: usize| HashMap::from([("grid".to_string(), serde_json::json!(n))]);
+        assert!(pm_check_properties(&grid(32)).is_ok());
+        assert_eq!(
+            pm_check_properties(&grid(48)),
+            Err("grid must be a power of two, not 48".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pm_box() {
+        assert_eq!(
+            pm_box([Some(2.0), None, None], None),
+            Ok(([-2.0, -1.0, -1.0], [2.0, 1.0, 1.0]))
+        );
+        let domain = Domain::new([0.0, -1.0, -1.0], [2.0, 1.0, 1.0], [true; 3]);
+        assert_eq!(
+            pm_box([None; 3], Some(domain)),
+            Ok(([0.0, -1.0, -1.0], [2.0, 1.0, 1.0]))
+        );
+        assert!(pm_box([None, Some(1.0), None], Some(domain)).is_ok());
+        assert!(pm_box([Some(1.0), None, None], Some(domain)).is_err());
+        let slab = Domain::new([-1.0; 3], [1.0; 3], [true, true, false]);
+        assert!(pm_box([None; 3], Some(slab)).is_err());
+    }
+
+    /// Median relative error of the accelerations of `astro2` on a Plummer
+    /// sphere compared to `simple_astro`
+    fn plummer_error(theta: f64, order: u64) -> f64 {
+        use crate::initialisers::Plummer;
+        use physim_core::plugin::{ElementCreator, generator::GeneratorElement};
+
+        let plummer =
+            Plummer::create_element(HashMap::from([("n".to_string(), serde_json::json!(2000))]));
+        let state = plummer.create_entities();
+        let easing = ("e".to_string(), serde_json::json!(1e-6));
+        let exact = SimpleAstroElement::new(HashMap::from([easing.clone()]));
+        let approximate = AstroOctreeElement::new(HashMap::from([
+            easing,
+            ("theta".to_string(), serde_json::json!(theta)),
+            ("order".to_string(), serde_json::json!(order)),
+        ]));
+        let mut expected = vec![Acceleration::zero(); state.len()];
+        let mut accelerations = vec![Acceleration::zero(); state.len()];
+        exact.transform(&state, &mut expected);
+        approximate.transform(&state, &mut accelerations);
+        let mut errors: Vec<f64> = expected
+            .iter()
+            .zip(&accelerations)
+            .map(|(a, b)| (a.x - b.x).hypot(a.y - b.y).hypot(a.z - b.z) / a.x.hypot(a.y).hypot(a.z))
+            .collect();
+        errors.sort_by(f64::total_cmp);
+        errors[errors.len() / 2]
+    }
+
+    #[test]
+    fn test_quadrupole_accuracy() {
+        for theta in [0.3, 0.5, 0.8] {
+            assert!(plummer_error(theta, 2) < plummer_error(theta, 0));
+        }
+        // quadrupoles give about the same accuracy at a larger theta
+        let monopole = plummer_error(0.4, 0);
+        let quadrupole = plummer_error(0.5, 2);
+        assert!(monopole < 0.02);
+        assert!(quadrupole < 1.1 * monopole, "{quadrupole} vs {monopole}");
+    }
+}
@@ -3,41 +3,57 @@ use std::{
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-use physim_attribute::render_element;
+use physim_attribute::{Properties, render_element};
 use physim_core::{
     Entity,
     messages::MessageClient,
-    plugin::{Element, ElementCreator, render::RenderElement},
+    plugin::{
+        Element, ElementCreator,
+        properties::Properties,
+        render::{Backpressure, Frame, RenderElement},
+    },
+    units::unit_system,
 };
 use serde_json::Value;
 
-#[render_element(name = "energysink", blurb = "Do nothing with data")]
+#[derive(Properties)]
+struct EnergySinkProperties {
+    /// print every n iterations
+    #[property(default = 1, range = 1..)]
+    print_n: u64,
+}
+
+#[render_element(
+    name = "energysink",
+    blurb = "Do nothing with data",
+    properties = EnergySinkProperties
+)]
 struct EnergySink {
     iteration: AtomicUsize,
     print_n: usize,
     calc_gpe: AtomicBool,
+    // the gravitational constant in the units of the simulation
+    g: f64,
 }
 
 impl ElementCreator for EnergySink {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let print_n = properties
-            .get("print_n")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(1) as usize;
+        let properties = EnergySinkProperties::parse("energysink", &properties);
         Box::new(EnergySink {
             iteration: AtomicUsize::new(0),
-            print_n,
+            print_n: properties.print_n as usize,
             calc_gpe: AtomicBool::new(false),
+            g: unit_system().g(),
         })
     }
 }
 
 impl RenderElement for EnergySink {
-    fn render(&self, state_recv: std::sync::mpsc::Receiver<Vec<Entity>>) {
+    fn render(&self, state_recv: std::sync::mpsc::Receiver<Frame>) {
         let mut initial_energy = 0.0;
         if let Ok(state) = state_recv.recv() {
             let iteration = self.iteration.fetch_add(1, Ordering::Relaxed);
-            let (potential, kinetic) = self.calculate_energy(&state);
+            let (potential, kinetic) = self.calculate_energy(&state.entities);
             initial_energy = potential + kinetic;
             let delta = 0.0;
             println!(
@@ -46,7 +62,7 @@ impl RenderElement for EnergySink {
         }
 
         while let Ok(state) = state_recv.recv() {
-            let (potential, kinetic) = self.calculate_energy(&state);
+            let (potential, kinetic) = self.calculate_energy(&state.entities);
             let energy = kinetic + potential;
             let energy_delta = initial_energy - energy;
 
@@ -60,12 +76,17 @@ impl RenderElement for EnergySink {
             // calculate the energy represented by state
         }
     }
+
+    fn default_backpressure(&self) -> Backpressure {
+        // the energy is reported every print_n iterations
+        Backpressure::Block
+    }
 }
 
 impl EnergySink {
     fn calculate_energy(&self, state: &[Entity]) -> (f64, f64) {
         let potential = match self.calc_gpe.load(Ordering::Relaxed) {
-            true => calculate_gravitational_potential(state),
+            true => self.g * calculate_gravitational_potential(state),
             false => 0.0,
         };
 
@@ -75,6 +96,7 @@ impl EnergySink {
     }
 }
 
+/// The gravitational potential energy of `state` without G
 fn calculate_gravitational_potential(state: &[Entity]) -> f64 {
     let mut potential = 0.0;
     for i in 0..state.len() {
@@ -83,7 +105,7 @@ fn calculate_gravitational_potential(state: &[Entity]) -> f64 {
                 + (state[i].y - state[j].y).powi(2)
                 + (state[i].z - state[j].z).powi(2))
             .sqrt();
-            potential = -state[i].mass * state[j].mass / r;
+            potential -= state[i].mass * state[j].mass / r;
         }
     }
     potential
@@ -102,10 +124,7 @@ impl Element for EnergySink {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([(
-            String::from("print_n"),
-            String::from("print every n iterations"),
-        )]))
+        Ok(EnergySinkProperties::descriptions())
     }
 }
 
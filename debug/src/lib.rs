@@ -13,8 +13,8 @@ use std::{
 };
 
 use physim_attribute::{
-    initialise_state_element, integrator_element, render_element, synth_element, transform_element,
-    transmute_element,
+    Properties, initialise_state_element, integrator_element, render_element, synth_element,
+    transform_element, transmute_element,
 };
 use physim_core::{
     Acceleration, Entity,
@@ -22,8 +22,13 @@ use physim_core::{
     messages::{MessageClient, MessagePriority},
     msg,
     plugin::{
-        Element, ElementCreator, generator::GeneratorElement, integrator::IntegratorElement,
-        render::RenderElement, transform::TransformElement, transmute::TransmuteElement,
+        Element, ElementCreator,
+        generator::GeneratorElement,
+        integrator::IntegratorElement,
+        properties::Properties,
+        render::{Frame, RenderElement},
+        transform::TransformElement,
+        transmute::TransmuteElement,
     },
     post_bus_msg, register_plugin,
 };
@@ -138,7 +143,7 @@ impl ElementCreator for FakeSink {
 }
 
 impl RenderElement for FakeSink {
-    fn render(&self, state_recv: std::sync::mpsc::Receiver<Vec<Entity>>) {
+    fn render(&self, state_recv: std::sync::mpsc::Receiver<Frame>) {
         while state_recv.recv().is_ok() {
             info!("Fake Rendering!");
             let large_message = "x".repeat(10_000_000);
@@ -163,7 +168,18 @@ enum MessageDebugMode {
     Brief,
 }
 
-#[initialise_state_element(name = "msgdebug", blurb = "Print messages")]
+#[derive(Properties)]
+struct MessageDebugProperties {
+    /// verbose prints the whole message, brief only prints the topic
+    #[property(default = "verbose", choices = ["verbose", "brief"])]
+    mode: String,
+}
+
+#[initialise_state_element(
+    name = "msgdebug",
+    blurb = "Print messages",
+    properties = MessageDebugProperties
+)]
 struct MessageDebug {
     mode: MessageDebugMode,
 }
@@ -176,15 +192,11 @@ impl GeneratorElement for MessageDebug {
 
 impl ElementCreator for MessageDebug {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let mode: MessageDebugMode = properties
-            .get("mode")
-            .and_then(|x| x.as_str())
-            .map(|mode_str| match mode_str {
-                "verbose" => MessageDebugMode::Verbose,
-                "brief" => MessageDebugMode::Brief,
-                _ => MessageDebugMode::Verbose,
-            })
-            .unwrap_or(MessageDebugMode::Verbose);
+        let properties = MessageDebugProperties::parse("msgdebug", &properties);
+        let mode = match properties.mode.as_str() {
+            "brief" => MessageDebugMode::Brief,
+            _ => MessageDebugMode::Verbose,
+        };
 
         Box::new(Self { mode })
     }
@@ -194,7 +206,7 @@ impl Element for MessageDebug {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([]))
+        Ok(MessageDebugProperties::descriptions())
     }
 }
 
@@ -217,7 +229,14 @@ impl MessageClient for MessageDebug {
     }
 }
 
-#[transmute_element(name = "void", blurb = "Destroy Entities")]
+#[derive(Properties)]
+struct VoidProperties {
+    /// Maximum distance from origin in x,y, or z an entity can be s
+    #[property(default = 1.0, range = 0.0..)]
+    lim: f64,
+}
+
+#[transmute_element(name = "void", blurb = "Destroy Entities", properties = VoidProperties)]
 struct Void {
     inner: Mutex<VoidInner>,
 }
@@ -237,11 +256,10 @@ impl MessageClient for Void {}
 
 impl ElementCreator for Void {
     fn create_element(props: HashMap<String, Value>) -> Box<Self> {
-        let lim = props
-            .get("lim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        let inner = VoidInner { lim };
+        let properties = VoidProperties::parse("void", &props);
+        let inner = VoidInner {
+            lim: properties.lim,
+        };
         Box::new(Self {
             inner: Mutex::new(inner),
         })
@@ -252,10 +270,7 @@ impl Element for Void {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([(
-            "lim".to_string(),
-            "Maximum distance from origin in x,y, or z an entity can be s".to_string(),
-        )]))
+        Ok(VoidProperties::descriptions())
     }
 }
 
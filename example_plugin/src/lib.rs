@@ -3,8 +3,9 @@ use std::collections::HashMap;
 
 use serde_json::Value;
 
-use physim_attribute::transform_element;
+use physim_attribute::{Properties, transform_element};
 use physim_core::messages::MessageClient;
+use physim_core::plugin::properties::Properties;
 use physim_core::plugin::transform::TransformElement;
 use physim_core::register_plugin;
 use physim_core::{Acceleration, Entity};
@@ -12,9 +13,17 @@ use physim_core::{Acceleration, Entity};
 // ANCHOR: element_declaration
 register_plugin!("ex_drag");
 
+#[derive(Properties)]
+struct DragProperties {
+    /// Coefficient of drag
+    #[property(default = 0.0, range = 0.0..)]
+    alpha: f64,
+}
+
 #[transform_element(
     name = "ex_drag",
-    blurb = "Applies a drag force which scales with the square of velocity"
+    blurb = "Applies a drag force which scales with the square of velocity",
+    properties = DragProperties
 )]
 pub struct Drag {
     alpha: f64,
@@ -35,16 +44,14 @@ impl TransformElement for Drag {
     // ANCHOR_END: element_transform
     // ANCHOR: element_props
     fn new(properties: HashMap<String, Value>) -> Self {
+        let properties = DragProperties::parse("ex_drag", &properties);
         Drag {
-            alpha: properties
-                .get("alpha")
-                .and_then(|x| x.as_f64())
-                .unwrap_or(0.0),
+            alpha: properties.alpha,
         }
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([(String::from("alpha"), String::from("Coefficient of drag"))])
+        DragProperties::descriptions()
     }
     // ANCHOR_END: element_props
 }
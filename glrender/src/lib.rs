@@ -1,4 +1,3 @@
-#![feature(str_from_raw_parts)]
 use std::io::Write;
 use std::str::FromStr;
 use std::sync::Mutex;
@@ -16,18 +15,41 @@ use glium::{
         event_loop::EventLoop,
     },
 };
-use physim_attribute::render_element;
+use physim_attribute::{Properties, render_element};
 use physim_core::log::{debug, error, warn};
 use physim_core::messages::{Message, MessageClient, MessagePriority};
-use physim_core::plugin::render::RenderElement;
+use physim_core::plugin::properties::Properties;
+use physim_core::plugin::render::{Backpressure, Frame, RenderElement};
 use physim_core::plugin::{Element, ElementCreator};
 use physim_core::{Entity, msg, post_bus_msg, register_plugin};
 use serde_json::Value;
 
 register_plugin!("glrender,stdout");
 
-const SHADER_DESC: &str =
-    "yellowblue, velocity, rgb-velocity, smoke, twinkle, id, orange-blue, hot, psychedelic";
+const SHADERS: [&str; 12] = [
+    "yellowblue",
+    "yellow-blue",
+    "velocity",
+    "rgb-velocity",
+    "rgbvelocity",
+    "smoke",
+    "twinkle",
+    "id",
+    "orange-blue",
+    "hot",
+    "psychedelic",
+    "psyc",
+];
+
+const RESOLUTIONS: [&str; 3] = ["4k", "1080p", "720p"];
+
+fn resolution(name: &str) -> (u64, u64) {
+    match name {
+        "720p" => (1280, 720),
+        "4k" => (3840, 2160),
+        _ => (1920, 1080),
+    }
+}
 
 const MAX_BUFFER_SIZE: usize = 10_000_000;
 
@@ -174,7 +196,24 @@ impl RenderPipelineShader {
 
 implement_vertex!(Vertex, position, velocity, id);
 
-#[render_element(name = "glrender", blurb = "Render simulation to a window")]
+#[derive(Properties)]
+struct GLRenderProperties {
+    /// Size of the window
+    #[property(default = "1080p", choices = RESOLUTIONS)]
+    resolution: String,
+    /// Camera zoom
+    #[property(default = 1.0, range = 0.0..)]
+    zoom: f64,
+    /// Colour scheme
+    #[property(default = "yellowblue", choices = SHADERS)]
+    shader: String,
+}
+
+#[render_element(
+    name = "glrender",
+    blurb = "Render simulation to a window",
+    properties = GLRenderProperties
+)]
 pub struct GLRenderElement {
     inner: Mutex<InnerRenderElement>,
 }
@@ -200,33 +239,13 @@ impl GLRenderElement {
 
 impl ElementCreator for GLRenderElement {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let resolution = properties
-            .get("resolution")
-            .and_then(|theta| theta.as_str())
-            .map(|s| match s {
-                "1080p" => (1920, 1080),
-                "720p" => (1280, 720),
-                "4k" => (3840, 2160),
-                _ => (1920, 1080),
-            })
-            .unwrap_or((1920, 1080));
-
-        let zoom = properties
-            .get("zoom")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0) as f32;
-
-        let shader = properties
-            .get("shader")
-            .and_then(|s| s.as_str())
-            .unwrap_or_default();
-
-        let shader = RenderPipelineShader::from_str(shader).unwrap_or_default();
+        let properties = GLRenderProperties::parse("glrender", &properties);
+        let shader = RenderPipelineShader::from_str(&properties.shader).unwrap_or_default();
 
         Box::new(GLRenderElement {
             inner: Mutex::new(InnerRenderElement {
-                resolution,
-                zoom,
+                resolution: resolution(&properties.resolution),
+                zoom: properties.zoom as f32,
                 shader,
                 running: true,
             }),
@@ -235,7 +254,7 @@ impl ElementCreator for GLRenderElement {
 }
 
 impl RenderElement for GLRenderElement {
-    fn render(&self, state_recv: Receiver<Vec<Entity>>) {
+    fn render(&self, state_recv: Receiver<Frame>) {
         let element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
         let event_loop = EventLoop::builder().build().expect("event loop building");
         let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new()
@@ -244,7 +263,7 @@ impl RenderElement for GLRenderElement {
             .build(&event_loop);
 
         let state: Vec<Entity> = match state_recv.recv() {
-            Ok(s) => s,
+            Ok(s) => s.entities,
             Err(_) => return,
         };
 
@@ -354,32 +373,32 @@ impl RenderElement for GLRenderElement {
                     if let Some(message) = self.keycode_msg(&kin) {
                         post_bus_msg!(message);
                     }
-                    if let glium::winit::event::ElementState::Released = kin.state {
-                        if let PhysicalKey::Code(key_code) = kin.physical_key {
-                            match key_code {
-                                KeyCode::BracketLeft => zoom = 0.01_f32.max(zoom/2.0),
-                                KeyCode::BracketRight => zoom = 4_f32.min(zoom*2.0),
-                                _ => {},
-                            }
+                    if let glium::winit::event::ElementState::Released = kin.state
+                        && let PhysicalKey::Code(key_code) = kin.physical_key
+                    {
+                        match key_code {
+                            KeyCode::BracketLeft => zoom = 0.01_f32.max(zoom/2.0),
+                            KeyCode::BracketRight => zoom = 4_f32.min(zoom*2.0),
+                            _ => {},
                         }
                     }
-                    if let glium::winit::event::ElementState::Pressed  = kin.state {
-                        if let PhysicalKey::Code(key_code) = kin.physical_key {
-                            match key_code {
-                                KeyCode::KeyS => pos_y = 1.0_f32.min(pos_y + 0.1*zoom),
-                                KeyCode::KeyW => pos_y = (-1.0_f32).max(pos_y - 0.1*zoom),
-                                KeyCode::KeyA => pos_x = 1.0_f32.min(pos_x + 0.1*zoom),
-                                KeyCode::KeyD => pos_x = (-1.0_f32).max(pos_x - 0.1*zoom),
-                                KeyCode::KeyP | KeyCode::Space  => {
-                                    let pause = msg!(self,"pipeline","pause_toggle",MessagePriority::High);
-                                    post_bus_msg!(pause);
-                                },
-                                KeyCode::KeyQ => {
-                                    let pause = msg!(self,"pipeline","quit",MessagePriority::High);
-                                    post_bus_msg!(pause);
-                                },
+                    if let glium::winit::event::ElementState::Pressed  = kin.state
+                        && let PhysicalKey::Code(key_code) = kin.physical_key
+                    {
+                        match key_code {
+                            KeyCode::KeyS => pos_y = 1.0_f32.min(pos_y + 0.1*zoom),
+                            KeyCode::KeyW => pos_y = (-1.0_f32).max(pos_y - 0.1*zoom),
+                            KeyCode::KeyA => pos_x = 1.0_f32.min(pos_x + 0.1*zoom),
+                            KeyCode::KeyD => pos_x = (-1.0_f32).max(pos_x - 0.1*zoom),
+                            KeyCode::KeyP | KeyCode::Space  => {
+                                let pause = msg!(self,"pipeline","pause_toggle",MessagePriority::High);
+                                post_bus_msg!(pause);
+                            },
+                            KeyCode::KeyQ => {
+                                let pause = msg!(self,"pipeline","quit",MessagePriority::High);
+                                post_bus_msg!(pause);
+                            },
                             _ => {},
-                            }
                         }
                     }
                 },
@@ -392,7 +411,7 @@ impl RenderElement for GLRenderElement {
                     debug!("Waiting for next state update");
                     vertices.clear();
                     if let Ok(state) = state_recv.recv() {
-                        vertices.extend(state.iter().flat_map(|s| s.vertices()));
+                        vertices.extend(state.entities.iter().flat_map(|s| s.vertices()));
                         let max_rendered = std::cmp::min(vertex_buffer.len() ,vertices.len());
                         vertex_buffer.invalidate();
                         if max_rendered > 0 {
@@ -410,23 +429,17 @@ impl RenderElement for GLRenderElement {
         };
     });
     }
+
+    fn needs_main_thread(&self) -> bool {
+        true
+    }
 }
 
 impl Element for GLRenderElement {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            (
-                "resolution".to_string(),
-                "Choices of 4k, 1080p and 720p".to_string(),
-            ),
-            (
-                "zoom".to_string(),
-                "Camera zoom (1.0 is default)".to_string(),
-            ),
-            ("shader".to_string(), SHADER_DESC.to_string()),
-        ]))
+        Ok(GLRenderProperties::descriptions())
     }
 }
 
@@ -438,9 +451,28 @@ impl MessageClient for GLRenderElement {
     }
 }
 
+#[derive(Properties)]
+struct StdOutProperties {
+    /// Size of the frames
+    #[property(default = "1080p", choices = RESOLUTIONS)]
+    resolution: String,
+    /// Camera zoom
+    #[property(default = 1.0, range = 0.0..)]
+    zoom: f64,
+    /// Colour scheme
+    #[property(default = "yellowblue", choices = SHADERS)]
+    shader: String,
+    /// Number of frames to buffer before writing
+    #[property(default = 30, range = 1..)]
+    buffer: usize,
+    /// If specified, only one frame will be generated at the timestep specified by frame
+    frame: Option<usize>,
+}
+
 #[render_element(
     name = "stdout",
-    blurb = "Render simulation to stdout as 8bit RGBA pixels for further processing by video software"
+    blurb = "Render simulation to stdout as 8bit RGBA pixels for further processing by video software",
+    properties = StdOutProperties
 )]
 pub struct StdOutRender {
     inner: Mutex<InnerRenderElement>,
@@ -487,60 +519,31 @@ impl FrameBuffer {
 
 impl ElementCreator for StdOutRender {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let resolution = properties
-            .get("resolution")
-            .and_then(|theta| theta.as_str())
-            .map(|s| match s {
-                "1080p" => (1920, 1080),
-                "720p" => (1280, 720),
-                "4k" => (3840, 2160),
-                _ => (1920, 1080),
-            })
-            .unwrap_or((1920, 1080));
-
-        let zoom = properties
-            .get("zoom")
-            .and_then(|v| v.as_f64())
-            .unwrap_or(1.0) as f32;
-
-        let shader = properties
-            .get("shader")
-            .and_then(|s| s.as_str())
-            .unwrap_or_default();
-
-        let buffer_size = properties
-            .get("buffer")
-            .and_then(|v| v.as_u64().map(|x| x as usize))
-            .unwrap_or(30);
-
-        let capture_frame = properties
-            .get("frame")
-            .and_then(|v| v.as_u64().map(|x| x as usize));
-
-        let shader = RenderPipelineShader::from_str(shader).unwrap_or_default();
+        let properties = StdOutProperties::parse("stdout", &properties);
+        let shader = RenderPipelineShader::from_str(&properties.shader).unwrap_or_default();
 
         Box::new(StdOutRender {
             inner: Mutex::new(InnerRenderElement {
-                resolution,
-                zoom,
+                resolution: resolution(&properties.resolution),
+                zoom: properties.zoom as f32,
                 shader,
                 running: true,
             }),
-            buffer_size,
-            capture_frame,
+            buffer_size: properties.buffer,
+            capture_frame: properties.frame,
             counter: AtomicUsize::new(0),
         })
     }
 }
 
 impl RenderElement for StdOutRender {
-    fn render(&self, state_recv: Receiver<Vec<Entity>>) {
+    fn render(&self, state_recv: Receiver<Frame>) {
         match std::panic::catch_unwind(|| {
             let mut pixel_buffer = FrameBuffer::new(self.buffer_size);
 
             let element = self.inner.lock().unwrap_or_else(|e| e.into_inner());
             let mut vertices: Vec<Vertex> = match state_recv.recv() {
-                Ok(state) => state.iter().flat_map(|s| s.vertices()).collect(),
+                Ok(state) => state.entities.iter().flat_map(|s| s.vertices()).collect(),
                 Err(e) => {
                     error!("Failed to receive state {}", e);
                     return;
@@ -638,7 +641,7 @@ impl RenderElement for StdOutRender {
                 vertices.clear();
                 match state_recv.recv() {
                     Ok(state) => {
-                        vertices.extend(state.iter().flat_map(|s| s.vertices()));
+                        vertices.extend(state.entities.iter().flat_map(|s| s.vertices()));
                     }
                     Err(_) => {
                         pixel_buffer.flush();
@@ -701,32 +704,22 @@ impl RenderElement for StdOutRender {
             Err(e) => eprintln!("{:?}", e),
         }
     }
+
+    fn default_backpressure(&self) -> Backpressure {
+        // every state is a frame of the video
+        Backpressure::Block
+    }
+
+    fn needs_main_thread(&self) -> bool {
+        true
+    }
 }
 
 impl Element for StdOutRender {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            (
-                "resolution".to_string(),
-                "Choices of 4k, 1080p and 720p".to_string(),
-            ),
-            (
-                "zoom".to_string(),
-                "Camera zoom (1.0 is default)".to_string(),
-            ),
-            ("shader".to_string(), SHADER_DESC.to_string()),
-            (
-                "buffer_size".to_string(),
-                "Number of frames to buffer before writing".to_string(),
-            ),
-            (
-                "frame".to_string(),
-                "If specified, only one frame will be generated at the timestep specified by frame"
-                    .to_string(),
-            ),
-        ]))
+        Ok(StdOutProperties::descriptions())
     }
 }
 
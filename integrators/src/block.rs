@@ -0,0 +1,330 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use physim_attribute::{Properties, integrator_element};
+use physim_core::{
+    Acceleration, Entity,
+    messages::MessageClient,
+    plugin::{
+        Element, ElementCreator,
+        integrator::{IntegratorElement, TargetedAccelerationFn},
+        properties::Properties,
+    },
+};
+use serde_json::Value;
+
+#[derive(Properties)]
+struct BlockProperties {
+    /// Accuracy of each entity's timestep eta*|a|/|jerk|. Decrease for accuracy
+    #[property(default = 0.02, range = 0.0..)]
+    eta: f64,
+    /// Number of timestep bins. The smallest timestep is dt/2^levels
+    #[property(default = 10, range = 0..=30)]
+    levels: u64,
+}
+
+#[integrator_element(
+    name = "block",
+    blurb = "Evaluate evolution with time using kick-drift-kick leapfrog with individual power-of-two timesteps",
+    properties = BlockProperties
+)]
+struct Block {
+    eta: f64,
+    levels: u32,
+    inner: Mutex<BlockInner>,
+}
+
+/// What is known about the entities at the end of the last iteration
+#[derive(Default)]
+struct BlockInner {
+    state: Vec<Entity>,
+    accelerations: Vec<Acceleration>,
+    // |a|/|jerk| of each entity, or None before it has taken a step
+    timescales: Vec<Option<f64>>,
+}
+
+fn magnitude(a: &Acceleration) -> f64 {
+    a.x.hypot(a.y).hypot(a.z)
+}
+
+impl Block {
+    /// The level of the bin for an entity with a `timescale`. Level `k` has a
+    /// timestep of `dt/2^k`, and entities without a timescale get the
+    /// smallest timestep until they have one.
+    fn level(&self, timescale: Option<f64>, dt: f64) -> u32 {
+        let Some(timescale) = timescale else {
+            return self.levels;
+        };
+        let step = self.eta * timescale;
+        if step >= dt {
+            return 0;
+        }
+        ((dt / step).log2().ceil() as u32).min(self.levels)
+    }
+}
+
+impl IntegratorElement for Block {
+    fn integrate(
+        &self,
+        entities: &[Entity],
+        new_state: &mut [Entity],
+        acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+        dt: f64,
+    ) {
+        // without a way to calculate a subset of accelerations, every
+        // acceleration is calculated and the targets are kept
+        let targeted =
+            |state: &[Entity], targets: Option<&[usize]>, accelerations: &mut [Acceleration]| {
+                let Some(targets) = targets else {
+                    acc_fn(state, accelerations);
+                    return;
+                };
+                let mut all = vec![Acceleration::zero(); state.len()];
+                acc_fn(state, &mut all);
+                for &i in targets {
+                    accelerations[i] += all[i];
+                }
+            };
+        self.integrate_targets(entities, new_state, &targeted, dt);
+    }
+
+    fn integrate_targets(
+        &self,
+        entities: &[Entity],
+        new_state: &mut [Entity],
+        acc_fn: &TargetedAccelerationFn<'_>,
+        dt: f64,
+    ) {
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => {
+                eprintln!("Block mutex poisoned");
+                std::process::exit(1)
+            }
+        };
+        let n = entities.len();
+
+        // the accelerations from the end of the last iteration can be reused
+        // unless another element has changed the entities since
+        let mut accelerations = if inner.state == entities {
+            std::mem::take(&mut inner.accelerations)
+        } else {
+            let mut accelerations = vec![Acceleration::zero(); n];
+            acc_fn(entities, None, &mut accelerations);
+            accelerations
+        };
+        // entities added since the last iteration are appended, so the
+        // timescales of the others still apply
+        let mut timescales = std::mem::take(&mut inner.timescales);
+        if timescales.len() > n {
+            timescales.clear();
+        }
+        timescales.resize(n, None);
+
+        new_state.copy_from_slice(entities);
+        let ticks: u64 = 1 << self.levels;
+        let tick_dt = dt / ticks as f64;
+        let mut levels = vec![0; n];
+        let mut ends = vec![ticks; n];
+        for (i, e) in new_state.iter_mut().enumerate() {
+            if e.fixed {
+                e.vx = 0.0;
+                e.vy = 0.0;
+                e.vz = 0.0;
+                continue;
+            }
+            levels[i] = self.level(timescales[i], dt);
+            ends[i] = 1 << (self.levels - levels[i]);
+            let h = dt / (1_u64 << levels[i]) as f64;
+            let a = accelerations[i];
+            e.vx += 0.5 * h * a.x;
+            e.vy += 0.5 * h * a.y;
+            e.vz += 0.5 * h * a.z;
+        }
+
+        let mut tick = 0;
+        let mut active = vec![];
+        while tick < ticks {
+            let next = ends.iter().copied().min().unwrap_or(ticks);
+            let drift = (next - tick) as f64 * tick_dt;
+            for e in new_state.iter_mut() {
+                e.x += drift * e.vx;
+                e.y += drift * e.vy;
+                e.z += drift * e.vz;
+            }
+            tick = next;
+
+            active.clear();
+            active.extend((0..n).filter(|&i| ends[i] == tick && !new_state[i].fixed));
+            if active.is_empty() {
+                // only fixed entities are left
+                break;
+            }
+            let mut new_accelerations = vec![Acceleration::zero(); n];
+            acc_fn(new_state, Some(&active), &mut new_accelerations);
+
+            for &i in &active {
+                let h = dt / (1_u64 << levels[i]) as f64;
+                let a = new_accelerations[i];
+                let jerk = Acceleration {
+                    x: (a.x - accelerations[i].x) / h,
+                    y: (a.y - accelerations[i].y) / h,
+                    z: (a.z - accelerations[i].z) / h,
+                };
+                timescales[i] = Some(magnitude(&a) / magnitude(&jerk));
+                accelerations[i] = a;
+                let e = &mut new_state[i];
+                e.vx += 0.5 * h * a.x;
+                e.vy += 0.5 * h * a.y;
+                e.vz += 0.5 * h * a.z;
+                if tick == ticks {
+                    continue;
+                }
+
+                // a bin can only be joined when its steps line up with now
+                let aligned = self.levels - tick.trailing_zeros().min(self.levels);
+                levels[i] = self.level(timescales[i], dt).max(aligned);
+                let h = dt / (1_u64 << levels[i]) as f64;
+                ends[i] = tick + (1 << (self.levels - levels[i]));
+                e.vx += 0.5 * h * a.x;
+                e.vy += 0.5 * h * a.y;
+                e.vz += 0.5 * h * a.z;
+            }
+        }
+
+        inner.state = new_state.to_vec();
+        inner.accelerations = accelerations;
+        inner.timescales = timescales;
+    }
+}
+
+impl MessageClient for Block {}
+
+impl ElementCreator for Block {
+    fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
+        let properties = BlockProperties::parse("block", &properties);
+        Box::new(Self {
+            eta: properties.eta,
+            levels: properties.levels as u32,
+            inner: Mutex::new(BlockInner::default()),
+        })
+    }
+}
+
+impl Element for Block {
+    fn get_property_descriptions(
+        &self,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        Ok(BlockProperties::descriptions())
+    }
+
+    // every entity is synchronised at the end of an iteration, so the
+    // timescales, which set the levels of the next one, are the schedule.
+    // Infinite and NaN timescales both give level 0, and are kept as
+    // f64::MAX, which JSON can hold.
+    fn checkpoint_state(&self) -> Option<Value> {
+        let inner = self.inner.lock().ok()?;
+        let timescales: Vec<Option<f64>> = inner
+            .timescales
+            .iter()
+            .map(|t| t.map(|t| if t.is_finite() { t } else { f64::MAX }))
+            .collect();
+        serde_json::to_value(timescales).ok()
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let timescales = serde_json::from_value(state)?;
+        let mut inner = self.inner.lock().map_err(|_| "Block mutex poisoned")?;
+        inner.timescales = timescales;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    // a unit mass at the origin
+    fn kepler(state: &[Entity], targets: Option<&[usize]>, a: &mut [Acceleration]) {
+        let all: Vec<usize> = (0..state.len()).collect();
+        for &i in targets.unwrap_or(&all) {
+            let e = state[i];
+            let r3 = (e.x * e.x + e.y * e.y + e.z * e.z).powf(1.5);
+            a[i].x -= e.x / r3;
+            a[i].y -= e.y / r3;
+            a[i].z -= e.z / r3;
+        }
+    }
+
+    #[test]
+    fn test_individual_timesteps() {
+        // a fast orbit close to a unit mass at the origin and a slow one far
+        // from it. The fast one needs smaller timesteps.
+        let evaluations = RefCell::new(vec![0; 2]);
+        let counted = |state: &[Entity], targets: Option<&[usize]>, a: &mut [Acceleration]| {
+            let all: Vec<usize> = (0..state.len()).collect();
+            for &i in targets.unwrap_or(&all) {
+                evaluations.borrow_mut()[i] += 1;
+            }
+            kepler(state, targets, a);
+        };
+        let circular = |r: f64| Entity {
+            x: r,
+            vy: r.powf(-0.5),
+            ..Default::default()
+        };
+        let block = Block::create_element(HashMap::from([(
+            "levels".to_string(),
+            serde_json::json!(8),
+        )]));
+        let mut state = vec![circular(0.1), circular(10.0)];
+        let mut new_state = state.clone();
+        for _ in 0..200 {
+            block.integrate_targets(&state, &mut new_state, &counted, 0.1);
+            std::mem::swap(&mut state, &mut new_state);
+        }
+        let evaluations = evaluations.into_inner();
+        assert!(evaluations[0] > 10 * evaluations[1]);
+
+        // both stay on their orbits
+        for (e, r) in state.iter().zip([0.1, 10.0]) {
+            let radius = e.x.hypot(e.y);
+            assert!((radius - r).abs() / r < 1e-2, "{radius} should be {r}");
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        let properties = HashMap::from([("levels".to_string(), serde_json::json!(8))]);
+        let block = Block::create_element(properties.clone());
+        let mut state = vec![
+            Entity {
+                x: 0.1,
+                vy: 0.1_f64.powf(-0.5),
+                ..Default::default()
+            },
+            // it starts at rest, so its timescale is infinite
+            Entity {
+                x: 1e9,
+                ..Default::default()
+            },
+        ];
+        let mut new_state = state.clone();
+        for _ in 0..20 {
+            block.integrate_targets(&state, &mut new_state, &kepler, 0.1);
+            std::mem::swap(&mut state, &mut new_state);
+        }
+
+        let restarted = Block::create_element(properties);
+        let checkpoint = block.checkpoint_state().unwrap();
+        restarted
+            .restore_state(serde_json::from_str(&checkpoint.to_string()).unwrap())
+            .unwrap();
+        let mut expected = state.clone();
+        block.integrate_targets(&state, &mut expected, &kepler, 0.1);
+        let mut resumed = state.clone();
+        restarted.integrate_targets(&state, &mut resumed, &kepler, 0.1);
+        assert_eq!(resumed, expected);
+    }
+}
@@ -1,7 +1,20 @@
 use physim_core::register_plugin;
 
-register_plugin!("euler", "verlet", "rk4");
+register_plugin!(
+    "euler",
+    "verlet",
+    "rk4",
+    "rk45",
+    "leapfrog",
+    "yoshida4",
+    "yoshida6",
+    "forest_ruth",
+    "block"
+);
 
+mod block;
 mod euler;
 mod rk4;
+mod rk45;
+mod symplectic;
 mod verlet;
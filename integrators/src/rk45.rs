@@ -0,0 +1,368 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use physim_attribute::{Properties, integrator_element};
+use physim_core::{
+    Acceleration, Entity,
+    log::warn,
+    messages::MessageClient,
+    plugin::{
+        Element, ElementCreator,
+        integrator::{ErrorEstimate, IntegratorElement},
+        properties::Properties,
+    },
+};
+use serde_json::Value;
+
+// Dormand-Prince 5(4) tableau. The accelerations don't depend on time, so
+// the nodes aren't needed.
+const A: [&[f64]; 7] = [
+    &[],
+    &[1.0 / 5.0],
+    &[3.0 / 40.0, 9.0 / 40.0],
+    &[44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0],
+    &[
+        19372.0 / 6561.0,
+        -25360.0 / 2187.0,
+        64448.0 / 6561.0,
+        -212.0 / 729.0,
+    ],
+    &[
+        9017.0 / 3168.0,
+        -355.0 / 33.0,
+        46732.0 / 5247.0,
+        49.0 / 176.0,
+        -5103.0 / 18656.0,
+    ],
+    // the 5th order solution, so the last stage is the derivative at the end
+    // of the step and can be reused by the next step
+    &[
+        35.0 / 384.0,
+        0.0,
+        500.0 / 1113.0,
+        125.0 / 192.0,
+        -2187.0 / 6784.0,
+        11.0 / 84.0,
+    ],
+];
+// difference between the 5th and 4th order solutions
+const E: [f64; 7] = [
+    71.0 / 57600.0,
+    0.0,
+    -71.0 / 16695.0,
+    71.0 / 1920.0,
+    -17253.0 / 339200.0,
+    22.0 / 525.0,
+    -1.0 / 40.0,
+];
+
+// how far the error of one substep can change the size of the next
+const SAFETY: f64 = 0.9;
+const MIN_SCALE: f64 = 0.2;
+const MAX_SCALE: f64 = 5.0;
+
+/// Position and velocity of an entity
+type State = [f64; 6];
+
+#[derive(Properties)]
+#[properties(validate = Rk45Properties::validate)]
+struct Rk45Properties {
+    /// Absolute tolerance of the position and velocity of each entity
+    #[property(default = 1e-6, range = 0.0..)]
+    atol: f64,
+    /// Relative tolerance of the position and velocity of each entity
+    #[property(default = 1e-6, range = 0.0..)]
+    rtol: f64,
+    /// Most substeps taken in one iteration. Substeps after this are accepted even if they are not accurate enough
+    #[property(default = 1000, range = 1..)]
+    max_substeps: u64,
+}
+
+impl Rk45Properties {
+    fn validate(&self) -> Result<(), String> {
+        if self.atol == 0.0 && self.rtol == 0.0 {
+            return Err("atol and rtol can't both be 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[integrator_element(
+    name = "rk45",
+    blurb = "Evaluate evolution with time using adaptive Dormand-Prince 5(4) integration",
+    properties = Rk45Properties
+)]
+struct Rk45 {
+    atol: f64,
+    rtol: f64,
+    max_substeps: u64,
+    inner: Mutex<Rk45Inner>,
+}
+
+struct Rk45Inner {
+    // size of the next substep, carried between iterations
+    step: Option<f64>,
+    estimate: Option<ErrorEstimate>,
+}
+
+impl Rk45 {
+    /// Take a step of `h` from `y`, whose derivative is `k1`. Returns the new
+    /// state, its derivative and the error of the step relative to the
+    /// tolerance.
+    fn trial(
+        &self,
+        entities: &[Entity],
+        acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+        y: &[State],
+        k1: Vec<State>,
+        h: f64,
+    ) -> (Vec<State>, Vec<State>, f64) {
+        let mut k = vec![k1];
+        let mut stage = vec![];
+        for a in &A[1..] {
+            stage = y
+                .iter()
+                .enumerate()
+                .map(|(idx, y)| {
+                    std::array::from_fn(|c| {
+                        y[c] + h * a.iter().zip(&k).map(|(a, k)| a * k[idx][c]).sum::<f64>()
+                    })
+                })
+                .collect();
+            k.push(derivatives(entities, acc_fn, &stage));
+        }
+        let k = &k;
+        let error = y
+            .iter()
+            .zip(&stage)
+            .enumerate()
+            .flat_map(|(idx, (y, new))| {
+                (0..6).map(move |c| {
+                    let error = h * E.iter().zip(k).map(|(e, k)| e * k[idx][c]).sum::<f64>();
+                    let scale = self.atol + self.rtol * y[c].abs().max(new[c].abs());
+                    (error / scale).abs()
+                })
+            })
+            .fold(0.0, f64::max);
+        let derivative = k.last().expect("there are 7 stages").clone();
+        (stage, derivative, error)
+    }
+}
+
+/// The derivative of each entity's state. Fixed entities don't move.
+fn derivatives(
+    entities: &[Entity],
+    acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+    y: &[State],
+) -> Vec<State> {
+    let state: Vec<Entity> = entities.iter().zip(y).map(|(e, y)| entity(e, y)).collect();
+    let mut accelerations = vec![Acceleration::zero(); state.len()];
+    acc_fn(&state, &mut accelerations);
+    state
+        .iter()
+        .zip(accelerations)
+        .map(|(e, a)| {
+            if e.fixed {
+                [0.0; 6]
+            } else {
+                [e.vx, e.vy, e.vz, a.x, a.y, a.z]
+            }
+        })
+        .collect()
+}
+
+fn entity(e: &Entity, y: &State) -> Entity {
+    Entity {
+        x: y[0],
+        y: y[1],
+        z: y[2],
+        vx: y[3],
+        vy: y[4],
+        vz: y[5],
+        ..*e
+    }
+}
+
+fn step_scale(error: f64) -> f64 {
+    if error > 0.0 {
+        (SAFETY * error.powf(-1.0 / 5.0)).clamp(MIN_SCALE, MAX_SCALE)
+    } else {
+        MAX_SCALE
+    }
+}
+
+impl IntegratorElement for Rk45 {
+    fn integrate(
+        &self,
+        entities: &[Entity],
+        new_state: &mut [Entity],
+        acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+        dt: f64,
+    ) {
+        let mut inner = match self.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => {
+                eprintln!("Rk45 mutex poisoned");
+                std::process::exit(1)
+            }
+        };
+        let mut y: Vec<State> = entities
+            .iter()
+            .map(|e| {
+                if e.fixed {
+                    [e.x, e.y, e.z, 0.0, 0.0, 0.0]
+                } else {
+                    [e.x, e.y, e.z, e.vx, e.vy, e.vz]
+                }
+            })
+            .collect();
+        let mut k1 = None;
+        let mut h = inner.step.unwrap_or(dt).min(dt);
+        let mut t = 0.0;
+        let mut substeps = 0;
+        let mut forced_error = None;
+        while t < dt {
+            let last = t + h >= dt;
+            let h_try = if last { dt - t } else { h };
+            let derivative = k1
+                .take()
+                .unwrap_or_else(|| derivatives(entities, acc_fn, &y));
+            let (new_y, new_derivative, error) =
+                self.trial(entities, acc_fn, &y, derivative.clone(), h_try);
+            substeps += 1;
+            let next_h = h_try * step_scale(error);
+            if error <= 1.0 || substeps >= self.max_substeps {
+                if error > 1.0 {
+                    warn!("rk45 reached {substeps} substeps with an error of {error}");
+                    forced_error = Some(error);
+                }
+                y = new_y;
+                k1 = Some(new_derivative);
+                t = if last { dt } else { t + h_try };
+                // a shortened final substep says little about the next one
+                h = if last && h_try < h { h } else { next_h };
+            } else {
+                k1 = Some(derivative);
+                h = next_h;
+            }
+        }
+        inner.step = Some(h);
+        // the error of a single step of dt, assuming the substep size is
+        // the one which meets the tolerance
+        inner.estimate = Some(match forced_error {
+            Some(error) => ErrorEstimate {
+                error,
+                order: 4,
+                resolved: false,
+            },
+            None => ErrorEstimate {
+                error: (SAFETY * dt / h).powi(5),
+                order: 4,
+                resolved: true,
+            },
+        });
+
+        for ((e, y), ns) in entities.iter().zip(&y).zip(new_state.iter_mut()) {
+            *ns = entity(e, y);
+        }
+    }
+
+    fn error_estimate(&self) -> Option<ErrorEstimate> {
+        self.inner.lock().ok()?.estimate
+    }
+}
+
+impl MessageClient for Rk45 {}
+
+impl ElementCreator for Rk45 {
+    fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
+        let properties = Rk45Properties::parse("rk45", &properties);
+        Box::new(Self {
+            atol: properties.atol,
+            rtol: properties.rtol,
+            max_substeps: properties.max_substeps,
+            inner: Mutex::new(Rk45Inner {
+                step: None,
+                estimate: None,
+            }),
+        })
+    }
+}
+
+impl Element for Rk45 {
+    fn get_property_descriptions(
+        &self,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        Ok(Rk45Properties::descriptions())
+    }
+
+    fn checkpoint_state(&self) -> Option<Value> {
+        let inner = self.inner.lock().ok()?;
+        serde_json::to_value(inner.step).ok()
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let step = serde_json::from_value(state)?;
+        let mut inner = self.inner.lock().map_err(|_| "Rk45 mutex poisoned")?;
+        inner.step = step;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oscillator(atol: f64, rtol: f64) -> Box<Rk45> {
+        Rk45::create_element(HashMap::from([
+            ("atol".to_string(), serde_json::json!(atol)),
+            ("rtol".to_string(), serde_json::json!(rtol)),
+        ]))
+    }
+
+    #[test]
+    fn test_harmonic_oscillator() {
+        // a = -x, so x = cos(t)
+        let spring = |state: &[Entity], accelerations: &mut [Acceleration]| {
+            for (e, a) in state.iter().zip(accelerations.iter_mut()) {
+                a.x += -e.x;
+            }
+        };
+        let rk45 = oscillator(1e-10, 1e-10);
+        let mut state = vec![Entity {
+            x: 1.0,
+            ..Default::default()
+        }];
+        let mut new_state = state.clone();
+        // one iteration covers several periods, so it has to substep
+        let dt = 20.0;
+        rk45.integrate(&state, &mut new_state, &spring, dt);
+        assert!((new_state[0].x - dt.cos()).abs() < 1e-7);
+        assert!((new_state[0].vx + dt.sin()).abs() < 1e-7);
+        let estimate = rk45.error_estimate().unwrap();
+        assert_eq!(estimate.order, 4);
+        assert!(estimate.resolved);
+        assert!(estimate.error > 1.0);
+
+        // a loose tolerance takes fewer, less accurate substeps
+        state = new_state.clone();
+        let loose = oscillator(1e-3, 1e-3);
+        loose.integrate(&state, &mut new_state, &spring, dt);
+        assert!((new_state[0].x - (2.0 * dt).cos()).abs() > 1e-7);
+        assert!((new_state[0].x - (2.0 * dt).cos()).abs() < 1e-1);
+    }
+
+    #[test]
+    fn test_tolerances() {
+        let tolerances = |atol: f64, rtol: f64| {
+            HashMap::from([
+                ("atol".to_string(), serde_json::json!(atol)),
+                ("rtol".to_string(), serde_json::json!(rtol)),
+            ])
+        };
+        assert!(rk45_check_properties(&tolerances(0.0, 1e-6)).is_ok());
+        assert_eq!(
+            rk45_check_properties(&tolerances(0.0, 0.0)),
+            Err("atol and rtol can't both be 0".to_string())
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use physim_attribute::integrator_element;
+use physim_core::{
+    Acceleration, Entity,
+    messages::MessageClient,
+    plugin::{Element, ElementCreator, integrator::IntegratorElement},
+};
+use serde_json::Value;
+
+/// One stage of a splitting method, as a fraction of the timestep
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    /// Update velocities with the accelerations at the current positions
+    Kick(f64),
+    /// Update positions with the current velocities
+    Drift(f64),
+}
+
+/// The stages of a composition of kick-drift-kick leapfrog steps, each of a
+/// fraction `weights[i]` of the timestep. The kicks between steps are merged.
+fn leapfrog_composition(weights: &[f64]) -> Vec<Stage> {
+    let mut stages = vec![];
+    let mut kick = 0.0;
+    for w in weights {
+        stages.push(Stage::Kick(kick + 0.5 * w));
+        stages.push(Stage::Drift(*w));
+        kick = 0.5 * w;
+    }
+    stages.push(Stage::Kick(kick));
+    stages
+}
+
+/// Advance `new_state` by `dt` with a splitting method. Accelerations are
+/// recalculated after every drift, and velocities are updated directly, so
+/// the state doesn't depend on previous iterations.
+fn split(
+    stages: &[Stage],
+    entities: &[Entity],
+    new_state: &mut [Entity],
+    acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+    dt: f64,
+) {
+    new_state.copy_from_slice(entities);
+    for e in new_state.iter_mut().filter(|e| e.fixed) {
+        e.vx = 0.0;
+        e.vy = 0.0;
+        e.vz = 0.0;
+    }
+    let mut accelerations = vec![Acceleration::zero(); entities.len()];
+    let mut stale = true;
+    for stage in stages {
+        match *stage {
+            Stage::Kick(c) => {
+                if stale {
+                    accelerations.fill(Acceleration::zero());
+                    acc_fn(new_state, &mut accelerations);
+                    stale = false;
+                }
+                for (e, a) in new_state.iter_mut().zip(&accelerations) {
+                    if !e.fixed {
+                        e.vx += c * dt * a.x;
+                        e.vy += c * dt * a.y;
+                        e.vz += c * dt * a.z;
+                    }
+                }
+            }
+            Stage::Drift(c) => {
+                for e in new_state.iter_mut() {
+                    e.x += c * dt * e.vx;
+                    e.y += c * dt * e.vy;
+                    e.z += c * dt * e.vz;
+                }
+                stale = true;
+            }
+        }
+    }
+}
+
+// Yoshida's triple jump, which makes a 4th order method from three 2nd order
+// steps. Forest and Ruth found the same coefficients.
+const CBRT_2: f64 = 1.259_921_049_894_873_2;
+const TRIPLE_JUMP: [f64; 3] = [
+    1.0 / (2.0 - CBRT_2),
+    -CBRT_2 / (2.0 - CBRT_2),
+    1.0 / (2.0 - CBRT_2),
+];
+
+// Yoshida's 6th order solution A
+const YOSHIDA6_W1: f64 = -1.177_679_984_178_87;
+const YOSHIDA6_W2: f64 = 0.235_573_213_359_357;
+const YOSHIDA6_W3: f64 = 0.784_513_610_477_560;
+const YOSHIDA6_W0: f64 = 1.0 - 2.0 * (YOSHIDA6_W1 + YOSHIDA6_W2 + YOSHIDA6_W3);
+
+#[integrator_element(
+    name = "leapfrog",
+    blurb = "Evaluate evolution with time using symplectic kick-drift-kick leapfrog integration"
+)]
+struct Leapfrog {
+    stages: Vec<Stage>,
+}
+
+#[integrator_element(
+    name = "yoshida4",
+    blurb = "Evaluate evolution with time using a symplectic 4th order Yoshida composition of leapfrog steps"
+)]
+struct Yoshida4 {
+    stages: Vec<Stage>,
+}
+
+#[integrator_element(
+    name = "yoshida6",
+    blurb = "Evaluate evolution with time using a symplectic 6th order Yoshida composition of leapfrog steps"
+)]
+struct Yoshida6 {
+    stages: Vec<Stage>,
+}
+
+#[integrator_element(
+    name = "forest_ruth",
+    blurb = "Evaluate evolution with time using symplectic 4th order Forest-Ruth integration"
+)]
+struct ForestRuth {
+    stages: Vec<Stage>,
+}
+
+impl ElementCreator for Leapfrog {
+    fn create_element(_: HashMap<String, Value>) -> Box<Self> {
+        Box::new(Self {
+            stages: leapfrog_composition(&[1.0]),
+        })
+    }
+}
+
+impl ElementCreator for Yoshida4 {
+    fn create_element(_: HashMap<String, Value>) -> Box<Self> {
+        Box::new(Self {
+            stages: leapfrog_composition(&TRIPLE_JUMP),
+        })
+    }
+}
+
+impl ElementCreator for Yoshida6 {
+    fn create_element(_: HashMap<String, Value>) -> Box<Self> {
+        Box::new(Self {
+            stages: leapfrog_composition(&[
+                YOSHIDA6_W3,
+                YOSHIDA6_W2,
+                YOSHIDA6_W1,
+                YOSHIDA6_W0,
+                YOSHIDA6_W1,
+                YOSHIDA6_W2,
+                YOSHIDA6_W3,
+            ]),
+        })
+    }
+}
+
+impl ElementCreator for ForestRuth {
+    fn create_element(_: HashMap<String, Value>) -> Box<Self> {
+        // drift-kick-drift form of the triple jump, which needs one less
+        // evaluation of the accelerations than yoshida4
+        let [theta, middle, _] = TRIPLE_JUMP;
+        Box::new(Self {
+            stages: vec![
+                Stage::Drift(0.5 * theta),
+                Stage::Kick(theta),
+                Stage::Drift(0.5 * (theta + middle)),
+                Stage::Kick(middle),
+                Stage::Drift(0.5 * (theta + middle)),
+                Stage::Kick(theta),
+                Stage::Drift(0.5 * theta),
+            ],
+        })
+    }
+}
+
+macro_rules! splitting_integrator {
+    ($name:ident) => {
+        impl IntegratorElement for $name {
+            fn integrate(
+                &self,
+                entities: &[Entity],
+                new_state: &mut [Entity],
+                acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+                dt: f64,
+            ) {
+                split(&self.stages, entities, new_state, acc_fn, dt);
+            }
+        }
+
+        impl MessageClient for $name {}
+
+        impl Element for $name {
+            fn get_property_descriptions(
+                &self,
+            ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+                Ok(HashMap::from([]))
+            }
+        }
+    };
+}
+
+splitting_integrator!(Leapfrog);
+splitting_integrator!(Yoshida4);
+splitting_integrator!(Yoshida6);
+splitting_integrator!(ForestRuth);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a unit mass at the origin
+    fn kepler(state: &[Entity], accelerations: &mut [Acceleration]) {
+        for (e, a) in state.iter().zip(accelerations.iter_mut()) {
+            let r = (e.x * e.x + e.y * e.y + e.z * e.z).sqrt();
+            a.x -= e.x / r.powi(3);
+            a.y -= e.y / r.powi(3);
+            a.z -= e.z / r.powi(3);
+        }
+    }
+
+    fn energy(e: &Entity) -> f64 {
+        0.5 * (e.vx * e.vx + e.vy * e.vy + e.vz * e.vz)
+            - 1.0 / (e.x * e.x + e.y * e.y + e.z * e.z).sqrt()
+    }
+
+    // an eccentric orbit with a period of 2 pi
+    fn orbit() -> Entity {
+        Entity {
+            x: 0.5,
+            vy: 3.0_f64.sqrt(),
+            ..Default::default()
+        }
+    }
+
+    /// Largest change of energy and the final state after `steps` steps
+    fn run(integrator: &dyn IntegratorElement, dt: f64, steps: usize) -> (f64, Entity) {
+        let mut state = vec![orbit()];
+        let mut new_state = state.clone();
+        let initial = energy(&state[0]);
+        let mut drift: f64 = 0.0;
+        for _ in 0..steps {
+            integrator.integrate(&state, &mut new_state, &kepler, dt);
+            std::mem::swap(&mut state, &mut new_state);
+            drift = drift.max((energy(&state[0]) - initial).abs());
+        }
+        (drift, state[0])
+    }
+
+    #[test]
+    fn test_energy_is_bounded() {
+        let leapfrog = Leapfrog::create_element(HashMap::new());
+        let dt = 2.0 * std::f64::consts::PI / 200.0;
+        let (short, _) = run(leapfrog.as_ref(), dt, 2_000);
+        let (long, _) = run(leapfrog.as_ref(), dt, 200_000);
+        // the error oscillates rather than growing over 1000 orbits
+        assert!(short < 1e-2);
+        assert!(long < 1.5 * short);
+    }
+
+    #[test]
+    fn test_order() {
+        // error after one orbit, which is back at the start
+        let error = |integrator: &dyn IntegratorElement, steps: usize| {
+            let dt = 2.0 * std::f64::consts::PI / steps as f64;
+            let (_, end) = run(integrator, dt, steps);
+            let start = orbit();
+            (end.x - start.x).hypot(end.y - start.y)
+        };
+        let integrators: [(Box<dyn IntegratorElement>, i32); 4] = [
+            (Leapfrog::create_element(HashMap::new()), 2),
+            (Yoshida4::create_element(HashMap::new()), 4),
+            (ForestRuth::create_element(HashMap::new()), 4),
+            (Yoshida6::create_element(HashMap::new()), 6),
+        ];
+        for (integrator, order) in integrators {
+            let ratio = error(integrator.as_ref(), 500) / error(integrator.as_ref(), 1000);
+            let expected = 2.0_f64.powi(order);
+            assert!(
+                ratio > 0.7 * expected && ratio < 1.4 * expected,
+                "order {order} gave a ratio of {ratio}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_changing_entity_count() {
+        let integrator = Yoshida4::create_element(HashMap::new());
+        let mut state = vec![orbit()];
+        let mut new_state = state.clone();
+        integrator.integrate(&state, &mut new_state, &kepler, 0.01);
+        let mut added = orbit();
+        added.x = 1.0;
+        added.vy = 1.0;
+        added.fixed = true;
+        state = new_state.clone();
+        state.push(added);
+        new_state.push(added);
+        integrator.integrate(&state, &mut new_state, &kepler, 0.01);
+        assert_ne!(new_state[0], state[0]);
+        assert_eq!(new_state[1].x, 1.0);
+        assert_eq!(new_state[1].vy, 0.0);
+    }
+}
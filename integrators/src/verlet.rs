@@ -126,4 +126,16 @@ impl Element for Verlet {
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
         Ok(HashMap::from([]))
     }
+
+    fn checkpoint_state(&self) -> Option<Value> {
+        let inner = self.inner.lock().ok()?;
+        serde_json::to_value(&inner.previous_state).ok()
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let previous_state = serde_json::from_value(state)?;
+        let mut inner = self.inner.lock().map_err(|_| "Verlet mutex poisoned")?;
+        inner.previous_state = previous_state;
+        Ok(())
+    }
 }
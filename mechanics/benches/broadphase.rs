@@ -0,0 +1,49 @@
+#![feature(test)]
+
+extern crate test;
+use mechanics::broadphase::HashGrid;
+use physim_core::Entity;
+use rand::Rng;
+use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
+use test::Bencher;
+
+/// Entities in a unit cube. Their radii are up to half the mean spacing
+/// between them, and spread over `decades` orders of magnitude.
+fn random_state(num_entities: usize, decades: f64) -> Vec<Entity> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    let spacing = (num_entities as f64).powf(-1.0 / 3.0);
+    (0..num_entities)
+        .map(|_| Entity {
+            x: rng.random_range(0.0..1.0),
+            y: rng.random_range(0.0..1.0),
+            z: rng.random_range(0.0..1.0),
+            radius: 0.5 * spacing * 10.0_f64.powf(-rng.random_range(0.0..=decades)),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn pairs_benchmark(num_entities: usize, decades: f64, b: &mut Bencher) {
+    let state = random_state(num_entities, decades);
+    b.iter(|| HashGrid::new(&state).pairs(&state));
+}
+
+#[bench]
+fn equal_100_000(b: &mut Bencher) {
+    pairs_benchmark(100_000, 0.0, b);
+}
+
+#[bench]
+fn equal_1_000_000(b: &mut Bencher) {
+    pairs_benchmark(1_000_000, 0.0, b);
+}
+
+#[bench]
+fn mixed_100_000(b: &mut Bencher) {
+    pairs_benchmark(100_000, 3.0, b);
+}
+
+#[bench]
+fn mixed_1_000_000(b: &mut Bencher) {
+    pairs_benchmark(1_000_000, 3.0, b);
+}
@@ -0,0 +1,269 @@
+//! Finding the pairs of entities which might touch, without checking every
+//! pair.
+use ahash::RandomState;
+use std::collections::HashMap;
+
+use physim_core::{Entity, domain::Domain};
+
+type Cell = (u32, i64, i64, i64);
+
+/// A hierarchy of hash grids. The cells of each level are twice the size of
+/// the level below, and each entity is in the cell of the smallest level
+/// whose cells are at least as wide as it. Entities which touch are then in
+/// neighbouring cells of the level of the larger one, so the pairs are found
+/// for any mix of sizes.
+#[derive(Debug)]
+pub struct HashGrid {
+    // width of the cells of the lowest level
+    size: f64,
+    // levels which have entities in them, in order
+    levels: Vec<u32>,
+    cells: HashMap<Cell, Vec<usize>, RandomState>,
+    // cell of each entity
+    cell_of: Vec<Cell>,
+}
+
+impl HashGrid {
+    pub fn new(entities: &[Entity]) -> Self {
+        let size = entities
+            .iter()
+            .map(|e| 2.0 * e.radius)
+            .filter(|d| *d > 0.0)
+            .reduce(f64::min)
+            .unwrap_or(1.0);
+        let cell_of: Vec<Cell> = entities
+            .iter()
+            .map(|e| {
+                let mut level = (2.0 * e.radius / size).log2().ceil().max(0.0) as u32;
+                // in case of rounding
+                if size * 2.0_f64.powi(level as i32) < 2.0 * e.radius {
+                    level += 1;
+                }
+                let width = size * 2.0_f64.powi(level as i32);
+                let [x, y, z] = [e.x, e.y, e.z].map(|x| (x / width).floor() as i64);
+                (level, x, y, z)
+            })
+            .collect();
+        let mut cells: HashMap<Cell, Vec<usize>, RandomState> = HashMap::default();
+        for (i, cell) in cell_of.iter().enumerate() {
+            cells.entry(*cell).or_default().push(i);
+        }
+        let mut levels: Vec<u32> = cells.keys().map(|cell| cell.0).collect();
+        levels.sort_unstable();
+        levels.dedup();
+        Self {
+            size,
+            levels,
+            cells,
+            cell_of,
+        }
+    }
+
+    /// Every pair of entities which might touch, as `(i, j)` with `i < j`.
+    /// Each pair appears once, and they are sorted.
+    pub fn pairs(&self, entities: &[Entity]) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for (i, e) in entities.iter().enumerate() {
+            let (level, ..) = self.cell_of[i];
+            for &upper in self.levels.iter().filter(|l| **l >= level) {
+                let width = self.size * 2.0_f64.powi(upper as i32);
+                let [x, y, z] = [e.x, e.y, e.z].map(|x| (x / width).floor() as i64);
+                for dx in -1..=1 {
+                    for dy in -1..=1 {
+                        for dz in -1..=1 {
+                            let Some(others) = self.cells.get(&(upper, x + dx, y + dy, z + dz))
+                            else {
+                                continue;
+                            };
+                            // pairs in the same level are found from both
+                            // sides, so only the first is kept
+                            pairs.extend(
+                                others
+                                    .iter()
+                                    .filter(|&&j| upper > level || j > i)
+                                    .map(|&j| (i.min(j), i.max(j))),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs
+    }
+}
+
+/// Every pair of entities which might touch, like [`HashGrid::pairs`], in
+/// a periodic domain if there is one. Entities near the faces of the domain
+/// are copied beyond the opposite faces, so the pairs through them are found
+/// too.
+pub fn candidate_pairs(entities: &[Entity], domain: Option<&Domain>) -> Vec<(usize, usize)> {
+    let Some(domain) = domain else {
+        return HashGrid::new(entities).pairs(entities);
+    };
+    let size = domain.size();
+    let margin = 2.0 * entities.iter().map(|e| e.radius).fold(0.0, f64::max);
+    let mut copies = vec![];
+    // the entity each copy is of
+    let mut original = vec![];
+    for (i, e) in entities.iter().enumerate() {
+        let x = domain.wrap([e.x, e.y, e.z]);
+        let shifts: [Vec<f64>; 3] = [0, 1, 2].map(|k| {
+            let mut shifts = vec![0.0];
+            if domain.periodic[k] {
+                if x[k] - domain.min[k] < margin {
+                    shifts.push(size[k]);
+                }
+                if domain.max[k] - x[k] < margin {
+                    shifts.push(-size[k]);
+                }
+            }
+            shifts
+        });
+        for sx in &shifts[0] {
+            for sy in &shifts[1] {
+                for sz in &shifts[2] {
+                    copies.push(Entity {
+                        x: x[0] + sx,
+                        y: x[1] + sy,
+                        z: x[2] + sz,
+                        ..*e
+                    });
+                    original.push(i);
+                }
+            }
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> = HashGrid::new(&copies)
+        .pairs(&copies)
+        .into_iter()
+        .map(|(a, b)| (original[a], original[b]))
+        .filter(|(a, b)| a != b)
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+}
+
+/// The separation `a - b` of two entities, to the nearest image of `b` in a
+/// periodic domain
+pub fn separation(a: &Entity, b: &Entity, domain: Option<&Domain>) -> [f64; 3] {
+    match domain {
+        Some(domain) => domain.separation([a.x, a.y, a.z], [b.x, b.y, b.z]),
+        None => [a.x - b.x, a.y - b.y, a.z - b.z],
+    }
+}
+
+/// Whether two entities touch
+pub fn touching(a: &Entity, b: &Entity, domain: Option<&Domain>) -> bool {
+    let [dx, dy, dz] = separation(a, b, domain);
+    let reach = a.radius + b.radius;
+    dx * dx + dy * dy + dz * dz <= reach * reach
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    fn brute_force(entities: &[Entity]) -> Vec<(usize, usize)> {
+        let mut pairs = vec![];
+        for i in 0..entities.len() {
+            for j in i + 1..entities.len() {
+                if touching(&entities[i], &entities[j], None) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn touching_pairs(entities: &[Entity]) -> Vec<(usize, usize)> {
+        let pairs = HashGrid::new(entities).pairs(entities);
+        assert!(pairs.windows(2).all(|w| w[0] < w[1]), "pairs are repeated");
+        pairs
+            .into_iter()
+            .filter(|&(i, j)| touching(&entities[i], &entities[j], None))
+            .collect()
+    }
+
+    #[test]
+    fn test_mixed_sizes() {
+        // radii spread over four orders of magnitude
+        let mut rng = StdRng::seed_from_u64(4);
+        let entities: Vec<Entity> = (0..3000)
+            .map(|_| Entity {
+                x: rng.random_range(-1.0..1.0),
+                y: rng.random_range(-1.0..1.0),
+                z: rng.random_range(-1.0..1.0),
+                radius: 10.0_f64.powf(rng.random_range(-4.0..0.0)) * 0.3,
+                ..Default::default()
+            })
+            .collect();
+        let expected = brute_force(&entities);
+        assert!(expected.len() > 1000);
+        assert_eq!(touching_pairs(&entities), expected);
+    }
+
+    #[test]
+    fn test_pairs_across_cells() {
+        // pairs which straddle the edges of cells, including a tiny entity
+        // touching a huge one and entities without a radius
+        let entity = |x: f64, y: f64, radius: f64| Entity {
+            x,
+            y,
+            radius,
+            ..Default::default()
+        };
+        let entities = vec![
+            entity(0.9995, 0.0, 0.001),
+            entity(1.0005, 0.0, 0.001),
+            entity(-0.0005, 0.0, 0.001),
+            entity(0.001, 0.0, 0.001),
+            entity(5.0, 5.0, 100.0),
+            entity(104.99, 5.0, 0.001),
+            entity(-3.0, -3.0, 0.0),
+            entity(-3.0, -3.0, 0.0),
+        ];
+        let expected = brute_force(&entities);
+        // the huge entity touches all the others
+        assert_eq!(expected.len(), 1 + 1 + 7 + 1);
+        assert_eq!(touching_pairs(&entities), expected);
+    }
+
+    #[test]
+    fn test_no_entities() {
+        assert!(HashGrid::new(&[]).pairs(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_periodic_pairs() {
+        let domain = Domain::new([-1.0; 3], [1.0; 3], [true, true, false]);
+        let mut rng = StdRng::seed_from_u64(5);
+        let entities: Vec<Entity> = (0..2000)
+            .map(|_| Entity {
+                x: rng.random_range(-1.0..1.0),
+                y: rng.random_range(-1.0..1.0),
+                z: rng.random_range(-1.0..1.0),
+                radius: 10.0_f64.powf(rng.random_range(-3.0..-1.0)),
+                ..Default::default()
+            })
+            .collect();
+        let mut expected = vec![];
+        for i in 0..entities.len() {
+            for j in i + 1..entities.len() {
+                if touching(&entities[i], &entities[j], Some(&domain)) {
+                    expected.push((i, j));
+                }
+            }
+        }
+        let found: Vec<(usize, usize)> = candidate_pairs(&entities, Some(&domain))
+            .into_iter()
+            .filter(|&(i, j)| touching(&entities[i], &entities[j], Some(&domain)))
+            .collect();
+        assert_eq!(found, expected);
+        // some of them only touch through the faces
+        assert!(expected.len() > brute_force(&entities).len());
+    }
+}
@@ -1,186 +1,357 @@
-use ahash::RandomState;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Mutex};
 
-use physim_attribute::transmute_element;
+use physim_attribute::{Properties, transmute_element};
 use physim_core::{
     Entity,
-    messages::MessageClient,
-    plugin::{Element, ElementCreator, transmute::TransmuteElement},
+    domain::{Domain, periodic_domain},
+    messages::{MessageClient, MessagePriority},
+    msg,
+    plugin::{Element, ElementCreator, properties::Properties, transmute::TransmuteElement},
+    post_bus_msg,
 };
-use serde_json::Value;
+use serde_json::{Value, json};
 
-#[transmute_element(name = "collisions", blurb = "Add collisions to particles")]
-struct Collisions {}
-
-impl TransmuteElement for Collisions {
-    fn transmute(&self, data: &mut Vec<Entity>) {
-        let cell_size = data
-            .iter()
-            .map(|e| e.radius)
-            .max_by(|a, b| a.total_cmp(b))
-            .unwrap_or(0.1);
-        let grid = Grid::new(data, cell_size);
-
-        for neighbourhood in grid.iter() {
-            for i in 0..neighbourhood.len() {
-                for j in (i + 1)..neighbourhood.len() {
-                    let (ai, bi) = (neighbourhood[i], neighbourhood[j]);
-
-                    let (a, b) = (&data[ai], &data[bi]);
-
-                    let r0 = a.radius;
-                    let r1 = b.radius;
-
-                    let dx = a.x - b.x;
-                    let dy = a.y - b.y;
-                    let dz = a.z - b.z;
+use crate::{
+    broadphase::{candidate_pairs, separation, touching},
+    ccd::{displacement, impacts},
+};
 
-                    let dist2 = dx * dx + dy * dy + dz * dz;
+#[derive(Properties)]
+struct CollisionsProperties {
+    /// bounce makes entities which touch bounce off each other, merge fuses them into one entity
+    #[property(default = "bounce", choices = ["bounce", "merge"])]
+    mode: String,
+    /// Coefficient of restitution of a bounce. 1 is elastic and 0 is perfectly inelastic
+    #[property(default = 1.0, range = 0.0..=1.0)]
+    restitution: f64,
+    /// Find collisions between the positions of consecutive steps, so fast entities don't pass through each other
+    #[property(default = false)]
+    ccd: bool,
+}
 
-                    let min_dist = r0 + r1;
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CollisionMode {
+    Bounce { restitution: f64 },
+    Merge,
+}
 
-                    if dist2 <= min_dist * min_dist {
-                        // Elastic collision response
-                        let dvx = a.vx - b.vx;
-                        let dvy = a.vy - b.vy;
-                        let dvz = a.vz - b.vz;
+#[transmute_element(
+    name = "collisions",
+    blurb = "Add collisions to particles",
+    properties = CollisionsProperties
+)]
+struct Collisions {
+    inner: Mutex<CollisionsInner>,
+}
 
-                        let dot = dvx * dx + dvy * dy + dvz * dz;
-                        if dot > 0.0 {
-                            continue;
-                        } // already separating
+struct CollisionsInner {
+    mode: CollisionMode,
+    ccd: bool,
+    // the entities at the end of the last step
+    previous: Vec<Entity>,
+    domain: Option<Domain>,
+}
 
-                        let ma = a.mass;
-                        let mb = b.mass;
+/// A merger of two entities, with the id of the merged entity and the id of
+/// the one it absorbed
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Merger {
+    id: usize,
+    absorbed: usize,
+}
 
-                        let scale = 2.0 * dot / ((ma + mb) * dist2);
+impl TransmuteElement for Collisions {
+    fn transmute(&self, data: &mut Vec<Entity>) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (mode, domain) = (inner.mode, inner.domain);
+        // the previous step can only be used if it has the same entities
+        let continuous = inner.ccd
+            && inner.previous.len() == data.len()
+            && inner
+                .previous
+                .iter()
+                .zip(data.iter())
+                .all(|(p, e)| p.id == e.id);
+        let mergers = match continuous {
+            true => sweep(&inner.previous, data, mode, domain.as_ref()),
+            false => collide(data, mode, domain.as_ref()),
+        };
+        if inner.ccd {
+            inner.previous.clone_from(data);
+        }
+        for merger in mergers {
+            let message = json!({"id": merger.id, "absorbed": merger.absorbed});
+            let msg = msg!(self, "collisions", message, MessagePriority::Low);
+            post_bus_msg!(msg);
+        }
+    }
+}
 
-                        let impulse_a = scale * mb;
-                        let impulse_b = scale * ma;
+/// Resolve the collisions between the entities which touch, through the
+/// faces of a periodic domain if there is one. Returns the mergers, in the
+/// order they happened. A merged entity is bigger and has moved, so the
+/// pairs are found again after any mergers, until there are none.
+fn collide(data: &mut Vec<Entity>, mode: CollisionMode, domain: Option<&Domain>) -> Vec<Merger> {
+    let mut mergers = vec![];
+    loop {
+        let merged = collide_pairs(data, mode, domain);
+        if merged.is_empty() {
+            return mergers;
+        }
+        mergers.extend(merged);
+    }
+}
 
-                        data[ai].vx -= impulse_a * dx;
-                        data[ai].vy -= impulse_a * dy;
-                        data[ai].vz -= impulse_a * dz;
+/// Resolve the collisions between the pairs of entities which touch, each
+/// pair at most once. Returns the mergers.
+fn collide_pairs(
+    data: &mut Vec<Entity>,
+    mode: CollisionMode,
+    domain: Option<&Domain>,
+) -> Vec<Merger> {
+    let pairs = candidate_pairs(data, domain);
+
+    let mut mergers = vec![];
+    let mut removed = vec![false; data.len()];
+    for (ai, bi) in pairs {
+        if removed[ai] || removed[bi] {
+            continue;
+        }
+        let (a, b) = (data[ai], data[bi]);
+        if !touching(&a, &b, domain) {
+            continue;
+        }
 
-                        data[bi].vx += impulse_b * dx;
-                        data[bi].vy += impulse_b * dy;
-                        data[bi].vz += impulse_b * dz;
-                    }
-                }
+        match mode {
+            CollisionMode::Bounce { restitution } => {
+                let Some((dva, dvb)) = bounce(
+                    separation(&a, &b, domain),
+                    velocity(&a),
+                    velocity(&b),
+                    a.mass,
+                    b.mass,
+                    restitution,
+                ) else {
+                    continue;
+                };
+                data[ai] = with_velocity(&a, add(velocity(&a), dva));
+                data[bi] = with_velocity(&b, add(velocity(&b), dvb));
+            }
+            CollisionMode::Merge => {
+                let merged = merge_images(&a, &b, domain);
+                mergers.push(Merger {
+                    id: merged.id,
+                    absorbed: if merged.id == a.id { b.id } else { a.id },
+                });
+                data[ai] = merged;
+                removed[bi] = true;
             }
         }
     }
+
+    if !mergers.is_empty() {
+        let mut removed = removed.into_iter();
+        data.retain(|_| !removed.next().unwrap_or(false));
+    }
+    mergers
 }
 
-impl MessageClient for Collisions {}
+/// Resolve the collisions of the step from `previous` to `data`. Entities
+/// are taken to move in straight lines during the step, and a pair which
+/// touched part way through it is resolved at the time of impact. The rest of
+/// the step is then taken in a straight line with the new velocities. Each
+/// entity has at most one such collision per step. Entities which touch at
+/// the end of the step are then resolved as in `collide`.
+fn sweep(
+    previous: &[Entity],
+    data: &mut Vec<Entity>,
+    mode: CollisionMode,
+    domain: Option<&Domain>,
+) -> Vec<Merger> {
+    let steps: Vec<[f64; 3]> = previous
+        .iter()
+        .zip(data.iter())
+        .map(|(p, e)| displacement(p, e, domain))
+        .collect();
+    // an entity part way through the step
+    let at = |e: &Entity, start: &Entity, step: [f64; 3], t: f64| Entity {
+        x: start.x + step[0] * t,
+        y: start.y + step[1] * t,
+        z: start.z + step[2] * t,
+        ..*e
+    };
+    // and moved on to the end of it
+    let moved = |e: Entity, step: [f64; 3], t: f64| {
+        if e.fixed {
+            return e;
+        }
+        let x = [0, 1, 2].map(|k| [e.x, e.y, e.z][k] + step[k] * (1.0 - t));
+        let [x, y, z] = domain.map_or(x, |domain| domain.wrap(x));
+        Entity { x, y, z, ..e }
+    };
+
+    let mut mergers = vec![];
+    let mut hit = vec![false; data.len()];
+    let mut removed = vec![false; data.len()];
+    for (t, ai, bi) in impacts(previous, data, domain) {
+        if hit[ai] || hit[bi] {
+            continue;
+        }
+        let a = at(&data[ai], &previous[ai], steps[ai], t);
+        let b = at(&data[bi], &previous[bi], steps[bi], t);
+        let (sa, sb) = (steps[ai], steps[bi]);
+        match mode {
+            CollisionMode::Bounce { restitution } => {
+                let separation = separation(&a, &b, domain);
+                let Some((dva, dvb)) = bounce(
+                    separation,
+                    velocity(&a),
+                    velocity(&b),
+                    a.mass,
+                    b.mass,
+                    restitution,
+                ) else {
+                    continue;
+                };
+                // the displacements bounce just like the velocities
+                let (dsa, dsb) =
+                    bounce(separation, sa, sb, a.mass, b.mass, restitution).unwrap_or_default();
+                data[ai] = moved(with_velocity(&a, add(velocity(&a), dva)), add(sa, dsa), t);
+                data[bi] = moved(with_velocity(&b, add(velocity(&b), dvb)), add(sb, dsb), t);
+            }
+            CollisionMode::Merge => {
+                let merged = merge_images(&a, &b, domain);
+                let step = velocity(&merge(&with_velocity(&a, sa), &with_velocity(&b, sb)));
+                mergers.push(Merger {
+                    id: merged.id,
+                    absorbed: if merged.id == a.id { b.id } else { a.id },
+                });
+                data[ai] = moved(merged, step, t);
+                removed[bi] = true;
+            }
+        }
+        hit[ai] = true;
+        hit[bi] = true;
+    }
 
-impl ElementCreator for Collisions {
-    fn create_element(_: HashMap<String, Value>) -> Box<Self> {
-        Box::new(Self {})
+    if !mergers.is_empty() {
+        let mut removed = removed.into_iter();
+        data.retain(|_| !removed.next().unwrap_or(false));
     }
+    mergers.extend(collide(data, mode, domain));
+    mergers
 }
 
-impl Element for Collisions {
-    fn get_property_descriptions(
-        &self,
-    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([]))
+/// The changes in the velocities `va` and `vb` of two entities which bounce
+/// off each other, where `separation` is from the centre of `b` to `a`. The
+/// relative velocity along the line between the centres is reversed and
+/// scaled by the restitution. None if they are already separating.
+fn bounce(
+    separation: [f64; 3],
+    va: [f64; 3],
+    vb: [f64; 3],
+    ma: f64,
+    mb: f64,
+    restitution: f64,
+) -> Option<([f64; 3], [f64; 3])> {
+    let [dx, dy, dz] = separation;
+    let dist2 = dx * dx + dy * dy + dz * dz;
+    let dot = (va[0] - vb[0]) * dx + (va[1] - vb[1]) * dy + (va[2] - vb[2]) * dz;
+    if dot > 0.0 || dist2 == 0.0 {
+        return None;
     }
+    let scale = (1.0 + restitution) * dot / ((ma + mb) * dist2);
+    Some((
+        separation.map(|d| -scale * mb * d),
+        separation.map(|d| scale * ma * d),
+    ))
 }
 
-type Coordinate = (i32, i32, i32);
-
-#[derive(Debug)]
-struct Grid {
-    cells: HashMap<Coordinate, Vec<usize>, RandomState>,
+fn velocity(e: &Entity) -> [f64; 3] {
+    [e.vx, e.vy, e.vz]
 }
 
-impl Grid {
-    fn new(entities: &[Entity], cell_size: f64) -> Self {
-        let mut cells: HashMap<(i32, i32, i32), Vec<usize>, RandomState> = HashMap::default();
-        for (i, e) in entities.iter().enumerate() {
-            let key = Self::cell_coords(e.x, e.y, e.z, cell_size);
-            cells.entry(key).or_default().push(i);
-        }
-        Self { cells }
-    }
-
-    fn cell_coords(x: f64, y: f64, z: f64, cell_size: f64) -> Coordinate {
-        let cx = (x / cell_size).floor() as i32;
-        let cy = (y / cell_size).floor() as i32;
-        let cz = (z / cell_size).floor() as i32;
-        (cx, cy, cz)
-    }
-
-    fn get_neighbours(coordinate: Coordinate) -> [Coordinate; 27] {
-        [
-            (coordinate.0 - 1, coordinate.1 - 1, coordinate.2),
-            (coordinate.0, coordinate.1 - 1, coordinate.2),
-            (coordinate.0 + 1, coordinate.1 - 1, coordinate.2),
-            (coordinate.0 - 1, coordinate.1, coordinate.2),
-            (coordinate.0, coordinate.1, coordinate.2),
-            (coordinate.0 + 1, coordinate.1, coordinate.2),
-            (coordinate.0 - 1, coordinate.1 + 1, coordinate.2),
-            (coordinate.0, coordinate.1 + 1, coordinate.2),
-            (coordinate.0 + 1, coordinate.1 + 1, coordinate.2),
-            (coordinate.0 - 1, coordinate.1 - 1, coordinate.2 - 1),
-            (coordinate.0, coordinate.1 - 1, coordinate.2 - 1),
-            (coordinate.0 + 1, coordinate.1 - 1, coordinate.2 - 1),
-            (coordinate.0 - 1, coordinate.1, coordinate.2 - 1),
-            (coordinate.0, coordinate.1, coordinate.2 - 1),
-            (coordinate.0 + 1, coordinate.1, coordinate.2 - 1),
-            (coordinate.0 - 1, coordinate.1 + 1, coordinate.2 - 1),
-            (coordinate.0, coordinate.1 + 1, coordinate.2 - 1),
-            (coordinate.0 + 1, coordinate.1 + 1, coordinate.2 - 1),
-            (coordinate.0 - 1, coordinate.1 - 1, coordinate.2 + 1),
-            (coordinate.0, coordinate.1 - 1, coordinate.2 + 1),
-            (coordinate.0 + 1, coordinate.1 - 1, coordinate.2 + 1),
-            (coordinate.0 - 1, coordinate.1, coordinate.2 + 1),
-            (coordinate.0, coordinate.1, coordinate.2 + 1),
-            (coordinate.0 + 1, coordinate.1, coordinate.2 + 1),
-            (coordinate.0 - 1, coordinate.1 + 1, coordinate.2 + 1),
-            (coordinate.0, coordinate.1 + 1, coordinate.2 + 1),
-            (coordinate.0 + 1, coordinate.1 + 1, coordinate.2 + 1),
-        ]
-    }
-
-    fn iter(&self) -> GridIter<'_> {
-        GridIter {
-            grid: self,
-            keys: self.cells.keys().cloned().collect(),
-            idx: 0,
-        }
-    }
+fn with_velocity(e: &Entity, [vx, vy, vz]: [f64; 3]) -> Entity {
+    Entity { vx, vy, vz, ..*e }
 }
 
-struct GridIter<'a> {
-    grid: &'a Grid,
-    keys: Vec<Coordinate>,
-    idx: usize,
+fn add(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
 }
 
-impl Iterator for GridIter<'_> {
-    type Item = Vec<usize>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.idx >= self.keys.len() {
-            return None;
-        }
+/// Fuse two entities like `merge`, with `b` at its image nearest to `a` in a
+/// periodic domain. The merged entity is moved inside the domain.
+fn merge_images(a: &Entity, b: &Entity, domain: Option<&Domain>) -> Entity {
+    let Some(domain) = domain else {
+        return merge(a, b);
+    };
+    let [x, y, z] = domain.nearest_image([a.x, a.y, a.z], [b.x, b.y, b.z]);
+    let merged = merge(a, &Entity { x, y, z, ..*b });
+    let [x, y, z] = domain.wrap([merged.x, merged.y, merged.z]);
+    Entity { x, y, z, ..merged }
+}
 
-        let key = self.keys[self.idx];
-        self.idx += 1;
+/// Fuse two entities, conserving their mass and momentum. The merged entity
+/// has the volume of both and the id of the more massive one. It is fixed if
+/// either of them is, and then stays where the fixed one is.
+fn merge(a: &Entity, b: &Entity) -> Entity {
+    let mass = a.mass + b.mass;
+    let (wa, wb) = match mass > 0.0 {
+        true => (a.mass / mass, b.mass / mass),
+        false => (0.5, 0.5),
+    };
+    let id = if b.mass > a.mass { b.id } else { a.id };
+    let radius = (a.radius.powi(3) + b.radius.powi(3)).cbrt();
+    if a.fixed || b.fixed {
+        let fixed = if a.fixed { a } else { b };
+        return Entity {
+            mass,
+            radius,
+            id,
+            ..*fixed
+        };
+    }
+    Entity {
+        x: wa * a.x + wb * b.x,
+        y: wa * a.y + wb * b.y,
+        z: wa * a.z + wb * b.z,
+        vx: wa * a.vx + wb * b.vx,
+        vy: wa * a.vy + wb * b.vy,
+        vz: wa * a.vz + wb * b.vz,
+        radius,
+        mass,
+        id,
+        fixed: false,
+    }
+}
 
-        let mut result = Vec::new();
+impl MessageClient for Collisions {}
 
-        for neighbor in Grid::get_neighbours(key) {
-            if let Some(indices) = self.grid.cells.get(&neighbor) {
-                for &i in indices {
-                    result.push(i);
-                }
-            }
-        }
+impl ElementCreator for Collisions {
+    fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
+        let properties = CollisionsProperties::parse("collisions", &properties);
+        let mode = match properties.mode.as_str() {
+            "merge" => CollisionMode::Merge,
+            _ => CollisionMode::Bounce {
+                restitution: properties.restitution,
+            },
+        };
+        Box::new(Self {
+            inner: Mutex::new(CollisionsInner {
+                mode,
+                ccd: properties.ccd,
+                previous: vec![],
+                domain: periodic_domain(),
+            }),
+        })
+    }
+}
 
-        Some(result)
+impl Element for Collisions {
+    fn get_property_descriptions(
+        &self,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        Ok(CollisionsProperties::descriptions())
     }
 }
 
@@ -189,41 +360,165 @@ mod tests {
 
     use super::*;
 
+    fn entity(x: f64, vx: f64, mass: f64, id: usize) -> Entity {
+        Entity {
+            x,
+            vx,
+            mass,
+            radius: 0.1,
+            id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_restitution() {
+        for restitution in [1.0, 0.5, 0.0] {
+            let mut data = vec![entity(0.0, 1.0, 1.0, 1), entity(0.15, -0.5, 3.0, 2)];
+            let momentum = 1.0 - 1.5;
+            collide(&mut data, CollisionMode::Bounce { restitution }, None);
+            let relative = data[0].vx - data[1].vx;
+            assert!((relative + restitution * 1.5).abs() < 1e-12, "{relative}");
+            let after = data[0].mass * data[0].vx + data[1].mass * data[1].vx;
+            assert!((after - momentum).abs() < 1e-12);
+        }
+    }
+
     #[test]
-    fn test_grid_iter_neighbourhoods() {
-        let entities = vec![
-            Entity {
-                x: 1.0,
-                y: 1.0,
-                ..Default::default()
-            }, // should land in (0,0)
-            Entity {
-                x: 2.0,
-                y: 1.0,
-                ..Default::default()
-            }, // also (0,0)
-            Entity {
-                x: 10.0,
-                y: 10.0,
-                ..Default::default()
-            }, // (2,2) if cell_size = 5.0
+    fn test_merge() {
+        let mut data = vec![
+            entity(0.0, 1.0, 1.0, 1),
+            entity(0.15, -0.5, 3.0, 2),
+            entity(5.0, 0.0, 1.0, 3),
         ];
+        let mergers = collide(&mut data, CollisionMode::Merge, None);
+        assert_eq!(mergers, [Merger { id: 2, absorbed: 1 }]);
+        assert_eq!(data.len(), 2);
+        let merged = data.iter().find(|e| e.id == 2).unwrap();
+        assert_eq!(merged.mass, 4.0);
+        // momentum and the centre of mass are kept
+        assert!((merged.vx * merged.mass - (1.0 - 1.5)).abs() < 1e-12);
+        assert!((merged.x - 0.1125).abs() < 1e-12);
+        // and so is the volume
+        assert!((merged.radius.powi(3) - 2.0 * 0.1_f64.powi(3)).abs() < 1e-15);
+        assert!(data.iter().any(|e| e.id == 3));
+    }
 
-        let grid = Grid::new(&entities, 5.0);
+    #[test]
+    fn test_merge_chain() {
+        // the first and last don't touch, but the merger of the first two
+        // touches the last
+        let mut data: Vec<Entity> = (0..3)
+            .map(|i| entity(0.14 * i as f64, 0.0, 1.0, i + 1))
+            .collect();
+        let mergers = collide(&mut data, CollisionMode::Merge, None);
+        assert_eq!(data.len(), 1);
+        assert_eq!(mergers.len(), 2);
+        assert_eq!(data[0].mass, 3.0);
+        assert!((data[0].x - 0.14).abs() < 1e-12);
+
+        // the merger of the first two grows towards the last, which starts
+        // too far away from either of them to be a candidate
+        let mut data = vec![
+            entity(0.0, 0.0, 1.0, 1),
+            entity(0.19, 0.0, 100.0, 2),
+            entity(0.41, 0.0, 1.0, 3),
+        ];
+        let mergers = collide(&mut data, CollisionMode::Merge, None);
+        assert_eq!(data.len(), 1);
+        assert_eq!(
+            mergers,
+            vec![Merger { id: 2, absorbed: 1 }, Merger { id: 2, absorbed: 3 }]
+        );
 
-        // Collect all neighbourhoods
-        let neighbourhoods: Vec<Vec<usize>> = grid.iter().collect();
+        // a fixed entity stays where it is
+        let mut fixed = entity(0.0, 0.0, 1.0, 1);
+        fixed.fixed = true;
+        let mut data = vec![fixed, entity(0.15, -1.0, 1.0, 2)];
+        collide(&mut data, CollisionMode::Merge, None);
+        assert_eq!((data[0].x, data[0].vx, data[0].mass), (0.0, 0.0, 2.0));
+        assert!(data[0].fixed);
+    }
 
-        // There should be 2 keys -> 2 neighbourhoods
-        assert_eq!(neighbourhoods.len(), 2);
-        // One of them should contain the first two entities together
-        assert!(
-            neighbourhoods
-                .iter()
-                .any(|nh| { nh.contains(&0) && nh.contains(&1) })
+    #[test]
+    fn test_tunnelling() {
+        // they pass through each other during the step without touching at
+        // either end of it
+        let previous = vec![entity(0.0, 10.0, 1.0, 1), entity(1.0, -10.0, 1.0, 2)];
+        let passed = |data: &mut Vec<Entity>| {
+            data[0].x += 1.2;
+            data[1].x -= 1.2;
+        };
+        let mut data = previous.clone();
+        passed(&mut data);
+        collide(&mut data, CollisionMode::Bounce { restitution: 1.0 }, None);
+        assert_eq!((data[0].vx, data[1].vx), (10.0, -10.0));
+
+        // they touch a third of the way through the step, at 0.4 and 0.6, and
+        // bounce back elastically for the rest of it
+        let mut data = previous.clone();
+        passed(&mut data);
+        sweep(
+            &previous,
+            &mut data,
+            CollisionMode::Bounce { restitution: 1.0 },
+            None,
+        );
+        assert!((data[0].vx + 10.0).abs() < 1e-12 && (data[1].vx - 10.0).abs() < 1e-12);
+        assert!((data[0].x + 0.4).abs() < 1e-12 && (data[1].x - 1.4).abs() < 1e-12);
+
+        // or stop at the point of impact
+        let mut data = previous.clone();
+        passed(&mut data);
+        sweep(
+            &previous,
+            &mut data,
+            CollisionMode::Bounce { restitution: 0.0 },
+            None,
         );
+        assert!(data[0].vx.abs() < 1e-12 && data[1].vx.abs() < 1e-12);
+        assert!((data[0].x - 0.4).abs() < 1e-12 && (data[1].x - 0.6).abs() < 1e-12);
+
+        // or merge there and carry on together
+        let mut data = previous.clone();
+        data[1].vx = 0.0;
+        data[0].x += 2.4;
+        let mergers = sweep(&previous, &mut data, CollisionMode::Merge, None);
+        assert_eq!(mergers.len(), 1);
+        assert_eq!(data.len(), 1);
+        // they met at 0.8 and 1.0 a third of the way through the step
+        assert!((data[0].x - (0.9 + 1.2 * 2.0 / 3.0)).abs() < 1e-12);
+        assert_eq!(data[0].vx, 5.0);
+    }
 
-        // And the other should contain the third entity
-        assert!(neighbourhoods.iter().any(|nh| nh.contains(&2)));
+    #[test]
+    fn test_periodic_domain() {
+        // they touch through the faces at x = -1 and 1
+        let domain = Domain::new([-1.0; 3], [1.0; 3], [true; 3]);
+        let mut data = vec![entity(-0.9, -1.0, 1.0, 1), entity(0.96, 1.0, 1.0, 2)];
+        collide(
+            &mut data,
+            CollisionMode::Bounce { restitution: 1.0 },
+            Some(&domain),
+        );
+        assert_eq!((data[0].vx, data[1].vx), (1.0, -1.0));
+
+        let mut data = vec![entity(-0.9, -1.0, 1.0, 1), entity(0.96, 1.0, 1.0, 2)];
+        collide(&mut data, CollisionMode::Merge, Some(&domain));
+        assert_eq!(data.len(), 1);
+        assert!((data[0].x + 0.97).abs() < 1e-12, "{}", data[0].x);
+
+        // they pass through each other across the faces during the step, touch
+        // at -0.9 and 0.9 and bounce back
+        let previous = vec![entity(-0.8, -10.0, 1.0, 1), entity(0.8, 10.0, 1.0, 2)];
+        let mut data = vec![entity(0.6, -10.0, 1.0, 1), entity(-0.6, 10.0, 1.0, 2)];
+        sweep(
+            &previous,
+            &mut data,
+            CollisionMode::Bounce { restitution: 1.0 },
+            Some(&domain),
+        );
+        assert_eq!((data[0].vx, data[1].vx), (10.0, -10.0));
+        assert!((data[0].x + 0.4).abs() < 1e-12 && (data[1].x - 0.4).abs() < 1e-12);
     }
 }
@@ -3,15 +3,31 @@ use std::{
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use physim_attribute::transform_element;
+use physim_attribute::{Properties, transform_element};
 use physim_core::{
-    Acceleration, Entity, messages::MessageClient, plugin::transform::TransformElement,
+    Acceleration, Entity,
+    messages::MessageClient,
+    plugin::{properties::Properties, transform::TransformElement},
 };
 use serde_json::Value;
 
+#[derive(Properties)]
+struct ImpulseProperties {
+    /// Acceleration in x direction
+    #[property(default = 0.0)]
+    x: f64,
+    /// Acceleration in y direction
+    #[property(default = 0.0)]
+    y: f64,
+    /// Acceleration in z direction
+    #[property(default = 0.0)]
+    z: f64,
+}
+
 #[transform_element(
     name = "impulse",
-    blurb = "Apply an impulse acceleration to all particles on the initial iteration of the simulation."
+    blurb = "Apply an impulse acceleration to all particles on the initial iteration of the simulation.",
+    properties = ImpulseProperties
 )]
 pub struct Impluse {
     should_pulse: AtomicBool,
@@ -28,10 +44,12 @@ impl TransformElement for Impluse {
     }
 
     fn new(properties: HashMap<String, Value>) -> Self {
-        let x = properties.get("x").and_then(|x| x.as_f64()).unwrap_or(0.0);
-        let y = properties.get("y").and_then(|x| x.as_f64()).unwrap_or(0.0);
-        let z = properties.get("z").and_then(|x| x.as_f64()).unwrap_or(0.0);
-        let acceleration = Acceleration { x, y, z };
+        let properties = ImpulseProperties::parse("impulse", &properties);
+        let acceleration = Acceleration {
+            x: properties.x,
+            y: properties.y,
+            z: properties.z,
+        };
         Impluse {
             acceleration,
             should_pulse: AtomicBool::new(true),
@@ -39,21 +57,68 @@ impl TransformElement for Impluse {
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([
-            (
-                String::from("x"),
-                String::from("Acceleration in x direction. Default=0.0"),
-            ),
-            (
-                String::from("y"),
-                String::from("Acceleration in y direction. Default=0.0"),
-            ),
-            (
-                String::from("z"),
-                String::from("Acceleration in z direction. Default=0.0"),
-            ),
-        ])
+        ImpulseProperties::descriptions()
+    }
+
+    // a restarted simulation is past its initial iteration
+    fn checkpoint_state(&self) -> Option<Value> {
+        Some(Value::Bool(self.should_pulse.load(Ordering::Relaxed)))
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let should_pulse = state.as_bool().ok_or("impulse state must be a bool")?;
+        self.should_pulse.store(should_pulse, Ordering::Relaxed);
+        Ok(())
     }
 }
 
 impl MessageClient for Impluse {}
+
+#[cfg(test)]
+mod tests {
+    use physim_core::plugin::host_alloc_string;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_restart() {
+        let properties = json!({"x": 1.0}).to_string();
+        let pulsed = unsafe { impulse_init(properties.as_ptr(), properties.len()) };
+        let mut acceleration = [Acceleration::zero()];
+        unsafe {
+            impulse_transform(
+                pulsed,
+                [Entity::default()].as_ptr(),
+                1,
+                acceleration.as_mut_ptr(),
+                1,
+            )
+        };
+        assert_eq!(acceleration[0].x, 1.0);
+
+        // a restart from a checkpoint taken after the pulse doesn't pulse again
+        let state = unsafe { impulse_checkpoint_state(pulsed, host_alloc_string) };
+        let state = unsafe { std::ffi::CString::from_raw(state) }
+            .into_string()
+            .unwrap();
+        let restarted = unsafe { impulse_init(properties.as_ptr(), properties.len()) };
+        assert!(unsafe { impulse_restore_state(restarted, state.as_ptr(), state.len()) });
+        let mut acceleration = [Acceleration::zero()];
+        unsafe {
+            impulse_transform(
+                restarted,
+                [Entity::default()].as_ptr(),
+                1,
+                acceleration.as_mut_ptr(),
+                1,
+            )
+        };
+        assert_eq!(acceleration[0].x, 0.0);
+        assert!(!unsafe { impulse_restore_state(restarted, "1".as_ptr(), 1) });
+        unsafe {
+            impulse_destroy(pulsed);
+            impulse_destroy(restarted);
+        }
+    }
+}
@@ -2,6 +2,8 @@
 
 use physim_core::register_plugin;
 
+pub mod broadphase;
+mod ccd;
 mod collisions;
 mod impulse;
 mod shm;
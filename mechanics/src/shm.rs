@@ -1,8 +1,11 @@
 use std::{collections::HashMap, sync::Mutex};
 
-use physim_attribute::transform_element;
+use physim_attribute::{Properties, transform_element};
 use physim_core::{
-    Acceleration, Entity, messages::MessageClient, plugin::transform::TransformElement,
+    Acceleration, Entity,
+    domain::{Domain, periodic_domain},
+    messages::MessageClient,
+    plugin::{properties::Properties, transform::TransformElement},
 };
 use serde_json::Value;
 
@@ -11,9 +14,23 @@ enum ShmTransformMode {
     ParticleCentre,
 }
 
+#[derive(Properties)]
+struct ShmProperties {
+    /// Spring constant
+    #[property(default = 1.0)]
+    k: f64,
+    /// Damping coefficient
+    #[property(default = 0.0)]
+    c: f64,
+    /// Oscillate about the origin or about the initial position of each particle
+    #[property(default = "centre", choices = ["centre", "particle"])]
+    mode: String,
+}
+
 #[transform_element(
     name = "shm",
-    blurb = "Make all entities into simple harmonic oscillators"
+    blurb = "Make all entities into simple harmonic oscillators",
+    properties = ShmProperties
 )]
 pub struct ShmTransform {
     inner: Mutex<ShmTransformInner>,
@@ -24,6 +41,7 @@ struct ShmTransformInner {
     k: f64,
     c: f64,
     mode: ShmTransformMode,
+    domain: Option<Domain>,
 }
 
 impl TransformElement for ShmTransform {
@@ -38,56 +56,48 @@ impl TransformElement for ShmTransform {
     }
 
     fn new(properties: HashMap<String, Value>) -> Self {
-        let k = properties.get("k").and_then(|x| x.as_f64()).unwrap_or(1.0);
-        let c = properties.get("c").and_then(|x| x.as_f64()).unwrap_or(0.0);
-
-        let mode: ShmTransformMode = properties
-            .get("mode")
-            .and_then(|x| x.as_str())
-            .map(|mode_str| match mode_str {
-                "centre" => ShmTransformMode::GlobalCentre,
-                "particle" => ShmTransformMode::ParticleCentre,
-                _ => ShmTransformMode::GlobalCentre,
-            })
-            .unwrap_or(ShmTransformMode::GlobalCentre);
+        let properties = ShmProperties::parse("shm", &properties);
+        let mode = match properties.mode.as_str() {
+            "particle" => ShmTransformMode::ParticleCentre,
+            _ => ShmTransformMode::GlobalCentre,
+        };
 
         ShmTransform {
             inner: Mutex::new(ShmTransformInner {
                 origins: vec![],
-                k,
-                c,
+                k: properties.k,
+                c: properties.c,
                 mode,
+                domain: periodic_domain(),
             }),
         }
     }
 
     fn get_property_descriptions(&self) -> HashMap<String, String> {
-        HashMap::from([
-            (
-                String::from("k"),
-                String::from("Spring constant. Default=1.0"),
-            ),
-            (
-                String::from("c"),
-                String::from("Damping coefficient. Default=0.0"),
-            ),
-            (
-                String::from("mode"),
-                String::from("Either 'centre' or 'particle'. Defaults to centre"),
-            ),
-        ])
+        ShmProperties::descriptions()
     }
 }
 
 impl MessageClient for ShmTransform {}
 
 impl ShmTransformInner {
+    /// The displacement of an entity from `origin`, to its nearest image in a
+    /// periodic domain
+    fn displacement(&self, entity: &Entity, origin: [f64; 3]) -> [f64; 3] {
+        let x = [entity.x, entity.y, entity.z];
+        match &self.domain {
+            Some(domain) => domain.separation(x, origin),
+            None => [x[0] - origin[0], x[1] - origin[1], x[2] - origin[2]],
+        }
+    }
+
     fn global_centre_transform(&self, state: &[Entity], accelerations: &mut [Acceleration]) {
         for (f, entity) in accelerations.iter_mut().zip(state) {
+            let delta = self.displacement(entity, [0.0; 3]);
             *f += Acceleration {
-                x: (-self.k * entity.x - self.c * entity.vx) / entity.mass,
-                y: (-self.k * entity.y - self.c * entity.vy) / entity.mass,
-                z: (-self.k * entity.z - self.c * entity.vz) / entity.mass,
+                x: (-self.k * delta[0] - self.c * entity.vx) / entity.mass,
+                y: (-self.k * delta[1] - self.c * entity.vy) / entity.mass,
+                z: (-self.k * delta[2] - self.c * entity.vz) / entity.mass,
             };
         }
     }
@@ -101,7 +111,7 @@ impl ShmTransformInner {
             .origins
             .iter()
             .zip(state)
-            .map(|(a, b)| [b.x - a[0], b.y - a[1], b.z - a[2]])
+            .map(|(a, b)| self.displacement(b, *a))
             .collect();
 
         for (f, (delta, entity)) in accelerations.iter_mut().zip(deltas.iter().zip(state)) {
@@ -1,4 +1,3 @@
-#![feature(vec_into_raw_parts)]
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 use syn::{DeriveInput, LitStr, Token, parse::Parse, parse_macro_input};
@@ -6,21 +5,23 @@ use syn::{DeriveInput, LitStr, Token, parse::Parse, parse_macro_input};
 struct ElementArgs {
     name: LitStr,
     blurb: LitStr,
+    properties: Option<syn::Path>,
 }
 
 impl Parse for ElementArgs {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let mut name = None;
         let mut blurb = None;
+        let mut properties = None;
 
         while !input.is_empty() {
             let key: syn::Ident = input.parse()?;
             input.parse::<Token![=]>()?;
-            let value: LitStr = input.parse()?;
 
             match &*key.to_string() {
-                "name" => name = Some(value),
-                "blurb" => blurb = Some(value),
+                "name" => name = Some(input.parse()?),
+                "blurb" => blurb = Some(input.parse()?),
+                "properties" => properties = Some(input.parse()?),
                 _ => return Err(syn::Error::new_spanned(key, "unsupported property")),
             }
 
@@ -32,10 +33,37 @@ impl Parse for ElementArgs {
         Ok(Self {
             name: name.ok_or_else(|| input.error("missing `name`"))?,
             blurb: blurb.ok_or_else(|| input.error("missing `blurb`"))?,
+            properties,
         })
     }
 }
 
+impl ElementArgs {
+    /// Exposes the element's property schema, and a check of the properties
+    /// as a whole, so that the pipeline can check properties before the
+    /// element is created.
+    fn property_schema_fn(&self) -> proc_macro2::TokenStream {
+        let Some(properties) = &self.properties else {
+            return quote! {};
+        };
+        let schema_fn = format_ident!("{}_property_schema", self.name.value());
+        let check_fn = format_ident!("{}_check_properties", self.name.value());
+        quote! {
+            #[unsafe(no_mangle)]
+            fn #schema_fn() -> Vec<::physim_core::plugin::properties::PropertySpec> {
+                <#properties as ::physim_core::plugin::properties::Properties>::schema()
+            }
+
+            #[unsafe(no_mangle)]
+            fn #check_fn(
+                properties: &::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>,
+            ) -> Result<(), String> {
+                <#properties as ::physim_core::plugin::properties::Properties>::from_properties(properties).map(|_| ())
+            }
+        }
+    }
+}
+
 #[proc_macro_attribute]
 pub fn transform_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(item as DeriveInput);
@@ -43,6 +71,7 @@ pub fn transform_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
 
     let struct_name = &ast.ident;
     let register_fn = format_ident!("{}_register", el_name);
@@ -53,10 +82,16 @@ pub fn transform_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let get_property_descriptions_fn = format_ident!("{}_get_property_descriptions", el_name);
     let recv_message_fn = format_ident!("{}_recv_message", el_name);
     let post_configuration_messages_fn = format_ident!("{}_post_configuration_messages", el_name);
+    let suggested_dt_fn = format_ident!("{}_suggested_dt", el_name);
+    let transform_targets_fn = format_ident!("{}_transform_targets", el_name);
+    let checkpoint_state_fn = format_ident!("{}_checkpoint_state", el_name);
+    let restore_state_fn = format_ident!("{}_restore_state", el_name);
 
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         pub unsafe extern "C" fn #init_fn(config: *const u8, len: usize) -> *mut ::std::ffi::c_void {
             if config.is_null() {
@@ -97,6 +132,71 @@ pub fn transform_element(attr: TokenStream, item: TokenStream) -> TokenStream {
             }
         }
 
+        // looked up separately from the api so that older plugins still load
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn #transform_targets_fn(obj: *const ::std::ffi::c_void, state: *const Entity, state_len: usize, targets: *const usize, targets_len: usize, acceleration: *mut Acceleration, acceleration_len: usize) {
+            let el: & #struct_name = unsafe { &*(obj as *const #struct_name) };
+            let s =  unsafe { ::std::slice::from_raw_parts(state, state_len) };
+            let t =  unsafe { ::std::slice::from_raw_parts(targets, targets_len) };
+            let n =  unsafe {  ::std::slice::from_raw_parts_mut(acceleration, acceleration_len) };
+            if let Err(_) = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| { el.transform_targets(s, t, n)})) {
+                eprintln!("Problem encountered in the {} element's transform_targets method. Aborting", #el_name);
+                ::std::process::abort();
+            }
+        }
+
+        // NaN means there is no suggestion
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn #suggested_dt_fn(obj: *const ::std::ffi::c_void) -> f64 {
+            let el: & #struct_name = unsafe { &*(obj as *const #struct_name) };
+            match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| el.suggested_dt())) {
+                Ok(dt) => dt.unwrap_or(f64::NAN),
+                Err(_) => {
+                    eprintln!("Problem encountered in the {} element's suggested_dt method. Aborting", #el_name);
+                    ::std::process::abort();
+                }
+            }
+        }
+
+        // null means there is no state to keep
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn #checkpoint_state_fn(obj: *const ::std::ffi::c_void, alloc: ::physim_core::plugin::RustStringAllocFn) -> *mut ::std::ffi::c_char {
+            let el: & #struct_name = unsafe { &*(obj as *const #struct_name) };
+            match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                el.checkpoint_state().map(|state| state.to_string())
+            })) {
+                Ok(Some(s)) => {
+                    let c_s = ::std::ffi::CString::new(s.replace("\0", "")).expect("Failed to make CString");
+                    alloc(c_s.as_ptr())
+                }
+                Ok(None) => ::std::ptr::null_mut(),
+                Err(_) => {
+                    eprintln!("Problem encountered in the {} element's checkpoint_state method. Aborting", #el_name);
+                    ::std::process::abort();
+                }
+            }
+        }
+
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn #restore_state_fn(obj: *const ::std::ffi::c_void, state: *const u8, len: usize) -> bool {
+            let el: & #struct_name = unsafe { &*(obj as *const #struct_name) };
+            let state = unsafe { ::std::str::from_raw_parts(state, len) };
+            let Ok(state) = ::physim_core::plugin::deps::serde_json::from_str(state) else {
+                return false;
+            };
+            match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| el.restore_state(state))) {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    eprintln!("{}: {e}", #el_name);
+                    false
+                }
+                Err(_) => {
+                    eprintln!("Problem encountered in the {} element's restore_state method. Aborting", #el_name);
+                    ::std::process::abort();
+                }
+            }
+        }
+
         #[unsafe(no_mangle)]
         pub unsafe extern "C" fn #destroy_fn(obj: *mut ::std::ffi::c_void) {
             if obj.is_null() {
@@ -208,6 +308,7 @@ pub fn render_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
     let name = &ast.ident;
     let create_element = format_ident!("{}_create_element", el_name);
     let register_fn = format_ident!("{}_register", el_name);
@@ -215,6 +316,8 @@ pub fn render_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         fn #create_element(properties: HashMap<String, Value>) -> Box<dyn ::physim_core::plugin::render::RenderElement> {
             #name::create_element(properties)
@@ -253,6 +356,7 @@ pub fn initialise_state_element(attr: TokenStream, item: TokenStream) -> TokenSt
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
     let name = &ast.ident;
     let create_element = format_ident!("{}_create_element", el_name);
     let register_fn = format_ident!("{}_register", el_name);
@@ -260,6 +364,8 @@ pub fn initialise_state_element(attr: TokenStream, item: TokenStream) -> TokenSt
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         fn #create_element(properties: ::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>) -> Box<dyn ::physim_core::plugin::generator::GeneratorElement> {
             #name::create_element(properties)
@@ -298,6 +404,7 @@ pub fn synth_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
     let name = &ast.ident;
     let create_element = format_ident!("{}_create_element", el_name);
     let register_fn = format_ident!("{}_register", el_name);
@@ -305,6 +412,8 @@ pub fn synth_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         fn #create_element(properties: ::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>) -> Box<dyn ::physim_core::plugin::generator::GeneratorElement> {
             #name::create_element(properties)
@@ -343,6 +452,7 @@ pub fn transmute_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
     let name = &ast.ident;
     let create_element = format_ident!("{}_create_element", el_name);
     let register_fn = format_ident!("{}_register", el_name);
@@ -350,6 +460,8 @@ pub fn transmute_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         fn #create_element(properties: ::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>) -> Box<dyn ::physim_core::plugin::transmute::TransmuteElement> {
             #name::create_element(properties)
@@ -388,6 +500,7 @@ pub fn integrator_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let args = parse_macro_input!(attr as ElementArgs);
     let el_name = args.name.value();
     let blurb = args.blurb.value();
+    let property_schema_fn = args.property_schema_fn();
     let name = &ast.ident;
     let create_element = format_ident!("{}_create_element", el_name);
     let register_fn = format_ident!("{}_register", el_name);
@@ -395,6 +508,8 @@ pub fn integrator_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     let g = quote! {
         #ast
 
+        #property_schema_fn
+
         #[unsafe(no_mangle)]
         fn #create_element(properties: ::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>) -> Box<dyn ::physim_core::plugin::integrator::IntegratorElement> {
             #name::create_element(properties)
@@ -425,3 +540,228 @@ pub fn integrator_element(attr: TokenStream, item: TokenStream) -> TokenStream {
     };
     g.into()
 }
+
+/// Declares the properties of an element. Each field is a property of the
+/// same name, and its doc comment is the description shown by `physcan`.
+///
+/// ```ignore
+/// #[derive(Properties)]
+/// struct CubeProperties {
+///     /// Number of stars
+///     #[property(default = 100_000, range = 1..)]
+///     n: u64,
+///     /// Centre (specify in CLI with \[x,y,z\])
+///     #[property(default = [0.0, 0.0, 0.0])]
+///     centre: [f64; 3],
+///     #[property(default = "wide", choices = ["wide", "long"])]
+///     mode: String,
+///     /// Fields without a default must be an `Option`
+///     seed: Option<u64>,
+///     /// or be marked as required
+///     #[property(required)]
+///     file: String,
+/// }
+/// ```
+/// `default` is written as it would be in a TOML file, `range` takes an
+/// inclusive or open ended range, and `choices` is an array or slice which
+/// restricts the values of a string or list of strings.
+///
+/// Properties which depend on each other are checked by a function given
+/// with `#[properties(validate = path)]` on the struct, which takes `&Self`
+/// and returns `Result<(), String>`.
+///
+/// A field marked `#[property(flatten)]` is itself a `Properties` struct,
+/// whose properties are shared by several elements. They are listed and set
+/// as properties of the outer struct.
+#[proc_macro_derive(Properties, attributes(property, properties))]
+pub fn derive_properties(item: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(item as DeriveInput);
+    match properties_impl(&ast) {
+        Ok(g) => g.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Whether a field is marked `#[property(flatten)]`
+fn is_flatten(attr: &syn::Attribute) -> bool {
+    attr.path().is_ident("property")
+        && attr
+            .parse_args::<syn::Ident>()
+            .is_ok_and(|ident| ident == "flatten")
+}
+
+fn properties_impl(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let syn::Data::Struct(syn::DataStruct {
+        fields: syn::Fields::Named(fields),
+        ..
+    }) = &ast.data
+    else {
+        return Err(syn::Error::new_spanned(
+            ast,
+            "Properties can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut specs = vec![];
+    let mut values = vec![];
+    for field in fields.named.iter() {
+        let ident = field.ident.as_ref().expect("fields are named");
+        let ty = &field.ty;
+        let name = ident.to_string();
+
+        if field.attrs.iter().any(is_flatten) {
+            specs.push(quote! {
+                schema.extend(<#ty as ::physim_core::plugin::properties::Properties>::schema());
+            });
+            values.push(quote! {
+                #ident: ::physim_core::plugin::properties::flattened(properties)?
+            });
+            continue;
+        }
+
+        let description = field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .filter_map(|attr| match &attr.meta {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    value:
+                        syn::Expr::Lit(syn::ExprLit {
+                            lit: syn::Lit::Str(doc),
+                            ..
+                        }),
+                    ..
+                }) => Some(doc.value().trim().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let mut default = None;
+        let mut min = None;
+        let mut max = None;
+        let mut choices = None;
+        let mut required = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("property")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("default") {
+                    default = Some(meta.value()?.parse::<syn::Expr>()?);
+                } else if meta.path.is_ident("range") {
+                    let range = match meta.value()?.parse::<syn::Expr>()? {
+                        syn::Expr::Range(range) => range,
+                        other => {
+                            return Err(syn::Error::new_spanned(other, "expected a range"));
+                        }
+                    };
+                    if matches!(range.limits, syn::RangeLimits::HalfOpen(_)) && range.end.is_some()
+                    {
+                        return Err(syn::Error::new_spanned(
+                            range,
+                            "use ..= for the upper bound of a range",
+                        ));
+                    }
+                    min = range.start;
+                    max = range.end;
+                } else if meta.path.is_ident("choices") {
+                    choices = Some(meta.value()?.parse::<syn::Expr>()?);
+                } else if meta.path.is_ident("required") {
+                    required = true;
+                } else {
+                    return Err(meta.error("expected default, range, choices or required"));
+                }
+                Ok(())
+            })?;
+        }
+
+        let is_option = matches!(ty, syn::Type::Path(p)
+            if p.path.segments.last().is_some_and(|s| s.ident == "Option"));
+        if required && (default.is_some() || is_option) {
+            return Err(syn::Error::new_spanned(
+                field,
+                "required properties can't have a default or an Option type",
+            ));
+        }
+        if default.is_none() && !is_option && !required {
+            return Err(syn::Error::new_spanned(
+                field,
+                "properties need a default, an Option type or to be required",
+            ));
+        }
+
+        let default = match default {
+            Some(default) => {
+                quote! { Some(::physim_core::plugin::deps::serde_json::json!(#default)) }
+            }
+            None => quote! { None },
+        };
+        let min = match min {
+            Some(min) => quote! { Some((#min) as f64) },
+            None => quote! { None },
+        };
+        let max = match max {
+            Some(max) => quote! { Some((#max) as f64) },
+            None => quote! { None },
+        };
+        let choices = match choices {
+            Some(choices) => quote! { (#choices).iter().map(|c| c.to_string()).collect() },
+            None => quote! { vec![] },
+        };
+
+        specs.push(quote! {
+            schema.push(::physim_core::plugin::properties::PropertySpec {
+                name: String::from(#name),
+                kind: <#ty as ::physim_core::plugin::properties::PropertyValue>::kind(),
+                description: String::from(#description),
+                default: #default,
+                min: #min,
+                max: #max,
+                choices: #choices,
+            });
+        });
+        values.push(quote! {
+            #ident: ::physim_core::plugin::properties::get(
+                schema.iter().find(|spec| spec.name == #name).expect("the schema has every field"),
+                properties,
+            )?
+        });
+    }
+
+    let mut validate = None;
+    for attr in ast.attrs.iter().filter(|a| a.path().is_ident("properties")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("validate") {
+                validate = Some(meta.value()?.parse::<syn::Path>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected validate"))
+            }
+        })?;
+    }
+    let validate = match validate {
+        Some(validate) => quote! { #validate(&parsed)?; },
+        None => quote! {},
+    };
+
+    let struct_name = &ast.ident;
+    Ok(quote! {
+        impl ::physim_core::plugin::properties::Properties for #struct_name {
+            fn schema() -> Vec<::physim_core::plugin::properties::PropertySpec> {
+                let mut schema = vec![];
+                #(#specs)*
+                schema
+            }
+
+            fn from_properties(
+                properties: &::std::collections::HashMap<String, ::physim_core::plugin::deps::serde_json::Value>,
+            ) -> Result<Self, String> {
+                let schema = Self::schema();
+                ::physim_core::plugin::properties::validate(&schema, properties)?;
+                let parsed = Self {
+                    #(#values,)*
+                };
+                #validate
+                Ok(parsed)
+            }
+        }
+    })
+}
@@ -0,0 +1,320 @@
+//! This module fans the state produced by the simulation out to every
+//! render element in a pipeline. Each render element gets its own queue
+//! and a thread which forwards states from the queue to the element's
+//! receiver. The simulation only ever touches the queues, and the
+//! backpressure policy of a queue decides what happens when its render
+//! element is slow to receive. With `drop_oldest` or `latest`, that only
+//! affects its own queue. With `block`, the simulation waits for the render
+//! element, which throttles the whole run and so every other render element.
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{mpsc, Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use log::debug;
+
+use crate::plugin::render::Frame;
+
+/// What the simulation does when a render element has not kept up with
+/// the states it has been sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait for the render element to catch up. Every state is delivered,
+    /// but the simulation, and so every other render element, waits too.
+    Block,
+    /// Discard the oldest queued state to make room for the new one.
+    DropOldest,
+    /// Discard everything queued and keep only the newest state.
+    #[default]
+    Latest,
+}
+
+impl Backpressure {
+    fn capacity(&self) -> usize {
+        match self {
+            Backpressure::Block | Backpressure::DropOldest => 2,
+            Backpressure::Latest => 1,
+        }
+    }
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Backpressure::Block),
+            "drop_oldest" => Ok(Backpressure::DropOldest),
+            "latest" => Ok(Backpressure::Latest),
+            _ => Err(format!(
+                "{s} is not a valid backpressure policy. Choose from block, drop_oldest or latest"
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueInner {
+    states: VecDeque<Arc<Frame>>,
+    // no more states will be pushed
+    closed: bool,
+    // the render element has dropped its receiver
+    disconnected: bool,
+}
+
+struct StateQueue {
+    inner: Mutex<QueueInner>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    backpressure: Backpressure,
+}
+
+impl StateQueue {
+    fn new(backpressure: Backpressure) -> Self {
+        Self {
+            inner: Mutex::new(QueueInner::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            backpressure,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns false if the render element is no longer receiving states.
+    fn push(&self, state: Arc<Frame>) -> bool {
+        let mut inner = self.lock();
+        let capacity = self.backpressure.capacity();
+        match self.backpressure {
+            Backpressure::Block => {
+                while inner.states.len() >= capacity && !inner.disconnected {
+                    inner = self.not_full.wait(inner).unwrap_or_else(|e| e.into_inner());
+                }
+            }
+            Backpressure::DropOldest => {
+                while inner.states.len() >= capacity {
+                    inner.states.pop_front();
+                }
+            }
+            Backpressure::Latest => inner.states.clear(),
+        }
+        if inner.disconnected {
+            return false;
+        }
+        inner.states.push_back(state);
+        self.not_empty.notify_one();
+        true
+    }
+
+    /// Blocks until a state is available. Returns `None` once the queue is
+    /// closed and drained.
+    fn pop(&self) -> Option<Arc<Frame>> {
+        let mut inner = self.lock();
+        while inner.states.is_empty() && !inner.closed {
+            inner = self
+                .not_empty
+                .wait(inner)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        let state = inner.states.pop_front();
+        self.not_full.notify_one();
+        state
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    fn disconnect(&self) {
+        let mut inner = self.lock();
+        inner.disconnected = true;
+        inner.states.clear();
+        self.not_full.notify_all();
+    }
+}
+
+/// Sends a copy of each state to every render element in a pipeline.
+/// Dropping the broadcaster closes the queues. Queued states are still
+/// delivered, after which the render elements' receivers disconnect.
+pub struct Broadcaster {
+    queues: Vec<Arc<StateQueue>>,
+}
+
+impl Broadcaster {
+    /// Create a broadcaster with one receiver for each backpressure policy
+    /// given. The receivers are returned in the same order as the policies.
+    pub fn new(policies: &[Backpressure]) -> (Self, Vec<mpsc::Receiver<Frame>>) {
+        let mut queues = Vec::with_capacity(policies.len());
+        let mut receivers = Vec::with_capacity(policies.len());
+        for policy in policies {
+            let queue = Arc::new(StateQueue::new(*policy));
+            // The forwarder holds on to a state until the render element asks
+            // for it, so the queue is where the backpressure policy applies.
+            let (sender, receiver) = mpsc::sync_channel(0);
+            let forward_queue = queue.clone();
+            thread::spawn(move || {
+                while let Some(state) = forward_queue.pop() {
+                    if sender.send(Arc::unwrap_or_clone(state)).is_err() {
+                        debug!("Render element stopped receiving states");
+                        forward_queue.disconnect();
+                        return;
+                    }
+                }
+            });
+            queues.push(queue);
+            receivers.push(receiver);
+        }
+        (Self { queues }, receivers)
+    }
+
+    /// Send a state to every render element. Returns false if none of the
+    /// render elements are receiving states any more.
+    pub fn send(&self, state: Frame) -> bool {
+        let state = Arc::new(state);
+        let mut receiving = false;
+        // every queue must be offered the state, so don't short circuit
+        for queue in self.queues.iter() {
+            receiving |= queue.push(state.clone());
+        }
+        receiving
+    }
+}
+
+impl Drop for Broadcaster {
+    fn drop(&mut self) {
+        self.queues.iter().for_each(|queue| queue.close());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Entity;
+
+    fn state(n: usize) -> Arc<Frame> {
+        Arc::new(Frame {
+            entities: vec![Entity::default(); n],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_backpressure_from_str() {
+        assert_eq!(Backpressure::from_str("block"), Ok(Backpressure::Block));
+        assert_eq!(
+            Backpressure::from_str("drop_oldest"),
+            Ok(Backpressure::DropOldest)
+        );
+        assert_eq!(Backpressure::from_str("latest"), Ok(Backpressure::Latest));
+        assert!(Backpressure::from_str("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = StateQueue::new(Backpressure::DropOldest);
+        for n in 1..=4 {
+            assert!(queue.push(state(n)));
+        }
+        queue.close();
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(3));
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(4));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_latest() {
+        let queue = StateQueue::new(Backpressure::Latest);
+        for n in 1..=4 {
+            assert!(queue.push(state(n)));
+        }
+        queue.close();
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(4));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_block_waits_for_receiver() {
+        let queue = Arc::new(StateQueue::new(Backpressure::Block));
+        assert!(queue.push(state(1)));
+        assert!(queue.push(state(2)));
+
+        let producer_queue = queue.clone();
+        let producer = thread::spawn(move || producer_queue.push(state(3)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished(), "push should block on a full queue");
+
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(1));
+        assert!(producer.join().unwrap());
+        queue.close();
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(2));
+        assert_eq!(queue.pop().map(|s| s.entities.len()), Some(3));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_lagging_receiver_does_not_stall_others() {
+        let (broadcaster, receivers) =
+            Broadcaster::new(&[Backpressure::Block, Backpressure::Latest]);
+        let mut receivers = receivers.into_iter();
+        let fast = receivers.next().unwrap();
+        // never read from the lagging receiver
+        let _slow = receivers.next().unwrap();
+
+        let consumer = thread::spawn(move || fast.iter().count());
+        for n in 0..100 {
+            assert!(broadcaster.send(Arc::unwrap_or_clone(state(n))));
+        }
+        drop(broadcaster);
+        assert_eq!(consumer.join().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_stalled_receiver_does_not_stall_block() {
+        let (broadcaster, receivers) =
+            Broadcaster::new(&[Backpressure::default(), Backpressure::Block]);
+        let mut receivers = receivers.into_iter();
+        // never read from the first receiver
+        let _stalled = receivers.next().unwrap();
+        let block = receivers.next().unwrap();
+
+        let consumer = thread::spawn(move || {
+            block
+                .iter()
+                .map(|frame| frame.iteration)
+                .collect::<Vec<_>>()
+        });
+        for iteration in 0..100 {
+            let frame = Frame {
+                iteration,
+                ..Default::default()
+            };
+            assert!(broadcaster.send(frame));
+        }
+        drop(broadcaster);
+        assert_eq!(consumer.join().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_disconnected_receivers() {
+        let (broadcaster, receivers) = Broadcaster::new(&[Backpressure::Block]);
+        drop(receivers);
+        // the forwarder needs a state to discover the receiver is gone
+        let mut receiving = true;
+        for _ in 0..10 {
+            receiving = broadcaster.send(Frame::default());
+            if !receiving {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!receiving);
+    }
+}
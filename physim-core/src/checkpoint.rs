@@ -0,0 +1,119 @@
+//! Checkpoints capture everything needed to resume a simulation: the
+//! entities, how far the simulation has progressed and the state of any
+//! element whose behaviour depends on more than its properties. They are
+//! stored as JSON so that they can be inspected and edited by hand, which
+//! is useful for branching a run.
+
+use std::{collections::HashMap, error::Error, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Entity;
+
+const CHECKPOINT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub version: u32,
+    /// Number of completed iterations
+    pub iteration: u64,
+    /// Simulated time
+    pub time: f64,
+    pub dt: f64,
+    pub entities: Vec<Entity>,
+    /// Opaque element state, keyed by the element's name and its position
+    /// amongst elements of the same name e.g. `verlet.0`.
+    pub elements: HashMap<String, Value>,
+}
+
+impl Checkpoint {
+    pub fn new(
+        iteration: u64,
+        time: f64,
+        dt: f64,
+        entities: Vec<Entity>,
+        elements: HashMap<String, Value>,
+    ) -> Self {
+        Self {
+            version: CHECKPOINT_VERSION,
+            iteration,
+            time,
+            dt,
+            entities,
+            elements,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read checkpoint {}: {e}", path.display()))?;
+        let checkpoint: Checkpoint = serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid checkpoint {}: {e}", path.display()))?;
+        if checkpoint.version != CHECKPOINT_VERSION {
+            return Err(format!(
+                "Checkpoint {} has version {} but only version {CHECKPOINT_VERSION} is supported",
+                path.display(),
+                checkpoint.version
+            )
+            .into());
+        }
+        Ok(checkpoint)
+    }
+
+    /// Write the checkpoint to a temporary file and move it into place so an
+    /// interrupted write never leaves a partial checkpoint behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let entities = vec![
+            Entity {
+                x: 0.1,
+                y: -1.0 / 3.0,
+                z: 1e-300,
+                vx: f64::MAX,
+                mass: 2.0,
+                radius: 0.01,
+                id: 7,
+                fixed: true,
+                ..Default::default()
+            },
+            Entity::new(1.0, 2.0, 3.0, 4.0),
+        ];
+        let elements = HashMap::from([("bpm.0".to_string(), serde_json::json!(12))]);
+        let checkpoint = Checkpoint::new(42, 0.042, 0.001, entities, elements);
+
+        let path = std::env::temp_dir().join("physim_test_round_trip.json");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // positions must be bitwise identical for a restart to be exact
+        assert_eq!(checkpoint, loaded);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut checkpoint = Checkpoint::new(0, 0.0, 0.1, vec![], HashMap::new());
+        checkpoint.version = CHECKPOINT_VERSION + 1;
+        let path = std::env::temp_dir().join("physim_test_unsupported_version.json");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+}
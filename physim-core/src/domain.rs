@@ -0,0 +1,84 @@
+//! The periodic domain of a simulation. It is set with `global domain_min=...
+//! domain_max=...`, and elements use it to measure distances between
+//! entities with the minimum-image convention. Each plugin has its own copy
+//! of it, which the pipeline sets before creating the plugin's elements.
+use std::sync::RwLock;
+
+/// A box from `min` to `max`, whose opposite faces are joined along the axes
+/// which are periodic
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Domain {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+    pub periodic: [bool; 3],
+}
+
+impl Domain {
+    pub fn new(min: [f64; 3], max: [f64; 3], periodic: [bool; 3]) -> Self {
+        Self { min, max, periodic }
+    }
+
+    pub fn size(&self) -> [f64; 3] {
+        [0, 1, 2].map(|k| self.max[k] - self.min[k])
+    }
+
+    /// The shortest separation `a - b` between `a` and any image of `b`
+    pub fn separation(&self, a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+        let size = self.size();
+        [0, 1, 2].map(|k| {
+            let d = a[k] - b[k];
+            match self.periodic[k] {
+                true => d - size[k] * (d / size[k]).round(),
+                false => d,
+            }
+        })
+    }
+
+    /// The image of `b` which is nearest to `a`
+    pub fn nearest_image(&self, a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+        let d = self.separation(a, b);
+        [0, 1, 2].map(|k| a[k] - d[k])
+    }
+
+    /// `x` moved into the box along the periodic axes
+    pub fn wrap(&self, x: [f64; 3]) -> [f64; 3] {
+        let size = self.size();
+        [0, 1, 2].map(|k| match self.periodic[k] {
+            true => self.min[k] + (x[k] - self.min[k]).rem_euclid(size[k]),
+            false => x[k],
+        })
+    }
+}
+
+static DOMAIN: RwLock<Option<Domain>> = RwLock::new(None);
+
+/// The periodic domain of the simulation, if it has one
+pub fn periodic_domain() -> Option<Domain> {
+    *DOMAIN.read().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn set_periodic_domain(domain: Option<Domain>) {
+    *DOMAIN.write().unwrap_or_else(|e| e.into_inner()) = domain;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimum_image() {
+        let domain = Domain::new([-1.0, 0.0, 0.0], [1.0, 4.0, 1.0], [true, true, false]);
+        let d = domain.separation([0.9, 3.5, 0.9], [-0.9, 0.5, 0.1]);
+        assert!((d[0] + 0.2).abs() < 1e-12);
+        assert!((d[1] + 1.0).abs() < 1e-12);
+        assert!((d[2] - 0.8).abs() < 1e-12);
+        let image = domain.nearest_image([0.9, 3.5, 0.9], [-0.9, 0.5, 0.1]);
+        assert!((image[0] - 1.1).abs() < 1e-12 && (image[1] - 4.5).abs() < 1e-12);
+
+        let x = domain.wrap([2.5, -1.0, 3.0]);
+        assert!((x[0] - 0.5).abs() < 1e-12);
+        assert!((x[1] - 3.0).abs() < 1e-12);
+        assert_eq!(x[2], 3.0);
+    }
+}
@@ -1,9 +1,12 @@
-#![feature(test)]
-#![feature(vec_into_raw_parts)]
-#![feature(box_as_ptr)]
+mod broadcast;
+pub mod checkpoint;
+pub mod domain;
 pub mod messages;
 pub mod pipeline;
 pub mod plugin;
+pub mod snapshot;
+pub mod trajectory;
+pub mod units;
 
 pub use log;
 pub use once_cell;
@@ -12,8 +15,9 @@ use std::ops::{Add, AddAssign, Neg, Sub};
 
 use rand::Rng;
 use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Default, Debug, PartialEq)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Entity {
     pub x: f64,
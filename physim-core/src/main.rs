@@ -1,7 +1,7 @@
 #![feature(iter_intersperse)]
 use std::env;
 
-use physim_core::pipeline::Pipeline;
+use physim_core::pipeline::{Pipeline, PropertyChecks};
 
 fn main() -> Result<(), String> {
     env_logger::init();
@@ -11,6 +11,14 @@ fn main() -> Result<(), String> {
 
     let help_text = include_str!("help.txt");
 
+    let mut checks = PropertyChecks::default();
+    while let Some(flag) = args.next_if(|v| v == "--strict" || v == "--lenient") {
+        checks = match flag.as_str() {
+            "--lenient" => PropertyChecks::Lenient,
+            _ => PropertyChecks::Strict,
+        };
+    }
+
     let pipeline = if let Some(v) = args.peek() {
         match v.as_str() {
             "-h" | "--help" => {
@@ -24,11 +32,11 @@ fn main() -> Result<(), String> {
             "-f" | "--file" => {
                 args.next();
                 let file = args.next().ok_or("No file provided")?;
-                Pipeline::new_from_file(&file).map_err(|e| format!("{}", e))?
+                Pipeline::new_from_file(&file, checks).map_err(|e| format!("{}", e))?
             }
             _ => {
                 let desc: String = args.intersperse(" ".to_string()).collect();
-                Pipeline::new_from_description(&desc).map_err(|e| format!("{}", e))?
+                Pipeline::new_from_description(&desc, checks).map_err(|e| format!("{}", e))?
             }
         }
     } else {
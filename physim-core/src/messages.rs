@@ -292,11 +292,18 @@ mod tests {
         }
     }
 
-    #[rustfmt::skip]
     #[test]
     fn test_message_bus() {
-        
-        assert!(true)
+        let mut bus = MessageBus::new();
+        bus.add_client(Arc::new(TestClient {}));
+        bus.post_message(Message {
+            priority: MessagePriority::Low,
+            topic: "topic".to_string(),
+            message: "message".to_string(),
+            sender_id: 0,
+        });
+        bus.pop_messages();
+        assert!(bus.queue.lock().unwrap().is_empty())
     }
 
     #[test]
@@ -324,6 +331,7 @@ mod tests {
         };
 
         let c_msg = msg.to_c_message();
+        #[allow(dropping_copy_types, dropping_references, clippy::drop_non_drop)]
         drop(c_msg)
     }
 }
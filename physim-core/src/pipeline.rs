@@ -4,28 +4,35 @@ use std::{
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc, Arc, Mutex,
+        Arc, Mutex,
     },
     thread,
     time::{Duration, Instant},
 };
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use serde::Deserialize;
 use serde_json::Value;
 
 use crate::{
+    broadcast::{Backpressure, Broadcaster},
+    checkpoint::Checkpoint,
+    domain::{self, Domain},
     messages::{Message, MessageBus, MessageClient, MessagePriority},
     plugin::{
         element_db,
         generator::GeneratorElementHandler,
-        integrator::{IntegratorElement, IntegratorElementHandler},
-        render::RenderElementHandler,
-        set_bus,
+        integrator::{
+            ErrorEstimate, IntegratorElement, IntegratorElementHandler, TargetedAccelerationFn,
+        },
+        properties::{self, PropertyKind, PropertySpec, PropertyValue},
+        render::{Frame, RenderElementHandler},
+        set_bus, set_domain, set_units,
         transform::TransformElementHandler,
         transmute::{TransmuteElement, TransmuteElementHandler},
-        ElementKind, Loadable, RegisteredElement,
+        CheckPropertiesFn, Element, ElementKind, Loadable, RegisteredElement,
     },
+    units::{self, UnitSystem},
     Acceleration, Entity,
 };
 
@@ -36,11 +43,50 @@ pub struct Pipeline {
     synths: Option<Vec<Arc<GeneratorElementHandler>>>,
     transforms: Vec<Arc<TransformElementHandler>>,
     transmutes: Vec<Arc<TransmuteElementHandler>>,
-    render: Arc<RenderElementHandler>,
+    renders: Vec<(Arc<RenderElementHandler>, Backpressure)>,
     integrator: Arc<IntegratorElementHandler>,
     timestep: f64,
+    adaptive: Option<StepBounds>,
     iterations: u64,
     bus: Arc<Mutex<MessageBus>>,
+    // every element, keyed for checkpointing
+    elements: Vec<(String, Arc<dyn Element>)>,
+    checkpoint_path: Option<String>,
+    checkpoint_n: u64,
+    restart: Option<Checkpoint>,
+    description: String,
+}
+
+/// Limits of an adaptive timestep
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StepBounds {
+    min: f64,
+    max: f64,
+}
+
+// how far an error estimate can change dt in one step
+const STEP_SAFETY: f64 = 0.9;
+const MIN_STEP_SCALE: f64 = 0.2;
+const MAX_STEP_SCALE: f64 = 5.0;
+
+/// The timestep which would bring the error of a step of `dt` to the
+/// integrator's tolerance.
+fn scaled_timestep(dt: f64, estimate: ErrorEstimate) -> f64 {
+    let scale = if estimate.error > 0.0 {
+        STEP_SAFETY * estimate.error.powf(-1.0 / (estimate.order as f64 + 1.0))
+    } else {
+        MAX_STEP_SCALE
+    };
+    dt * scale.clamp(MIN_STEP_SCALE, MAX_STEP_SCALE)
+}
+
+/// The index of the renderer which runs on the main thread: the one which
+/// needs it, or else the first.
+fn main_thread_render(renders: &[(Arc<RenderElementHandler>, Backpressure)]) -> usize {
+    renders
+        .iter()
+        .position(|(render, _)| render.needs_main_thread())
+        .unwrap_or(0)
 }
 
 struct PipelineMessageClient {
@@ -72,7 +118,7 @@ impl MessageClient for PipelineMessageClient {
 }
 
 impl Pipeline {
-    pub fn run(self) -> Result<(), String> {
+    pub fn run(mut self) -> Result<(), String> {
         // cannot be reference since it'd break renderer
         let pipeline_messages = Arc::new(PipelineMessageClient::new());
         match self.bus.lock() {
@@ -97,14 +143,23 @@ impl Pipeline {
             }
         }
 
-        let mut state = Vec::new();
-        for el in self.initialisers.iter() {
-            state.extend(el.create_entities());
-        }
-        let mut new_state = Vec::with_capacity(state.capacity());
-        for _ in 0..state.len() {
-            new_state.push(Entity::default());
-        }
+        let (state, count, time) = match self.restart.take() {
+            Some(checkpoint) => {
+                info!(
+                    "Restarting from iteration {} with {} entities",
+                    checkpoint.iteration,
+                    checkpoint.entities.len()
+                );
+                (checkpoint.entities, checkpoint.iteration, checkpoint.time)
+            }
+            None => {
+                let mut state = Vec::new();
+                for el in self.initialisers.iter() {
+                    state.extend(el.create_entities());
+                }
+                (state, 0, 0.0)
+            }
+        };
         debug!("Set up initial state");
 
         let msg_flag = Arc::new(AtomicBool::new(true));
@@ -126,80 +181,229 @@ impl Pipeline {
             }
         });
 
-        let (simulation_sender, renderer_receiver) = mpsc::sync_channel(2);
-        simulation_sender
-            .send(state.clone())
-            .expect("The renderer has definitely not been dropped");
-
-        thread::spawn(move || {
-            let dt = self.timestep;
-            let mut count = 0;
-            let transform_fn = |state: &[Entity], accelerations: &mut [Acceleration]| {
-                self.transforms
-                    .iter()
-                    .for_each(|element| element.transform(state, accelerations))
+        let bus = self.bus.clone();
+        if self.renders.is_empty() {
+            info!("No renderer in pipeline. Running headless");
+            self.simulate(state, count, time, &pipeline_messages, None);
+        } else {
+            let policies: Vec<Backpressure> = self.renders.iter().map(|(_, p)| *p).collect();
+            let (broadcaster, receivers) = Broadcaster::new(&policies);
+            broadcaster.send(Frame {
+                iteration: count,
+                time,
+                dt: self.timestep,
+                entities: state.clone(),
+            });
+
+            // The renderer which needs the main thread gets it, or the first
+            // if none do. The rest get their own threads.
+            let main = main_thread_render(&self.renders);
+            let mut renders: Vec<_> = self
+                .renders
+                .iter()
+                .map(|(render, _)| render.clone())
+                .zip(receivers)
+                .collect();
+            let (main_render, main_receiver) = renders.remove(main);
+            let render_threads: Vec<_> = renders
+                .into_iter()
+                .map(|(render, receiver)| thread::spawn(move || render.render(receiver)))
+                .collect();
+
+            let simulation_thread = thread::spawn(move || {
+                self.simulate(state, count, time, &pipeline_messages, Some(broadcaster))
+            });
+            main_render.render(main_receiver);
+            for render_thread in render_threads {
+                render_thread
+                    .join()
+                    .map_err(|e| format!("Failed join render thread {:?}", e))?;
+            }
+            simulation_thread
+                .join()
+                .map_err(|e| format!("Failed join simulation thread {:?}", e))?;
+        }
+
+        msg_flag.store(false, std::sync::atomic::Ordering::Relaxed);
+        message_thread
+            .join()
+            .map_err(|e| format!("Failed join message thread {:?}", e))?;
+
+        // deliver anything posted after the message thread stopped, e.g.
+        // the finished message in a headless pipeline.
+        match bus.lock() {
+            Ok(mut bus) => bus.pop_messages(),
+            Err(_) => {
+                eprintln!("Failed to flush message bus. Message bus poisoned");
+                std::process::exit(1)
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the simulation loop from iteration `count` and simulated time
+    /// `time` until `iterations` is reached, a quit message is received or every renderer has stopped.
+    /// When `broadcaster` is `None`, the pipeline is headless and the loop
+    /// runs without a renderer.
+    fn simulate(
+        &self,
+        mut state: Vec<Entity>,
+        mut count: u64,
+        mut time: f64,
+        pipeline_messages: &PipelineMessageClient,
+        broadcaster: Option<Broadcaster>,
+    ) {
+        let mut new_state = vec![Entity::default(); state.len()];
+        let mut dt = self.timestep;
+        let frame = |entities: &[Entity], count: u64, time: f64, dt: f64| Frame {
+            iteration: count,
+            time,
+            dt,
+            entities: entities.to_vec(),
+        };
+        let transform_fn =
+            |state: &[Entity], targets: Option<&[usize]>, accelerations: &mut [Acceleration]| {
+                self.transforms.iter().for_each(|element| match targets {
+                    Some(targets) => element.transform_targets(state, targets, accelerations),
+                    None => element.transform(state, accelerations),
+                })
             };
 
-            while count < self.iterations {
-                if pipeline_messages.quit.load(Ordering::Relaxed) {
-                    break;
-                }
-                if pipeline_messages.paused.load(Ordering::Relaxed) {
-                    thread::sleep(Duration::from_millis(1));
-                    if let Err(_) = simulation_sender.send(new_state.clone()) {
-                        return;
+        while count < self.iterations {
+            if pipeline_messages.quit.load(Ordering::Relaxed) {
+                break;
+            }
+            if pipeline_messages.paused.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+                if let Some(broadcaster) = &broadcaster {
+                    if !broadcaster.send(frame(&state, count, time, dt)) {
+                        break;
                     };
-                    continue;
-                } else {
-                    count += 1;
                 }
-                let start = Instant::now();
+                continue;
+            } else {
+                count += 1;
+            }
+            let start = Instant::now();
 
-                self.synths.iter().for_each(|els| {
-                    for el in els {
-                        let entities = el.create_entities();
-                        state.extend(entities.iter());
-                        new_state.extend(entities.iter());
-                    }
-                });
+            self.synths.iter().for_each(|els| {
+                for el in els {
+                    let entities = el.create_entities();
+                    state.extend(entities.iter());
+                    new_state.extend(entities.iter());
+                }
+            });
 
-                self.integrator
-                    .integrate(&state, &mut new_state, &transform_fn, dt);
+            let (step, next_dt) = self.step(&state, &mut new_state, &transform_fn, dt);
+            time += step;
+            dt = step;
 
-                for t in &self.transmutes {
-                    t.transmute(&mut new_state);
-                }
+            for t in &self.transmutes {
+                t.transmute(&mut new_state);
+            }
 
-                state = new_state.clone();
-                info!(
-                    "Updated state in {} ms. Sending state of len {}",
-                    start.elapsed().as_millis(),
-                    state.len()
-                );
-                if simulation_sender.send(new_state.clone()).is_err() {
-                    return;
+            state = new_state.clone();
+            info!(
+                "Updated state in {} ms. State has len {}",
+                start.elapsed().as_millis(),
+                state.len()
+            );
+            if self.checkpoint_n != 0 && count.is_multiple_of(self.checkpoint_n) {
+                self.save_checkpoint(&state, count, time, next_dt);
+            }
+            if let Some(broadcaster) = &broadcaster {
+                if !broadcaster.send(frame(&state, count, time, dt)) {
+                    break;
                 }
             }
-            info!("Finalising pipeline");
-            let msg = msg!(1, "pipeline", "finished", MessagePriority::RealTime);
-            match self.bus.lock() {
-                Ok(mut bus) => bus.post_message(msg),
-                Err(_) => {
-                    eprintln!("Failed to post exit message");
-                    std::process::exit(1)
+            dt = next_dt;
+        }
+        info!("Finalising pipeline");
+        self.save_checkpoint(&state, count, time, dt);
+        let msg = msg!(self, "pipeline", "finished", MessagePriority::RealTime);
+        match self.bus.lock() {
+            Ok(mut bus) => bus.post_message(msg),
+            Err(_) => {
+                eprintln!("Failed to post exit message");
+                std::process::exit(1)
+            }
+        }
+    }
+
+    /// Advance `state` by one step, starting with a timestep of `dt`. Returns
+    /// the timestep which was used and the one to try next. With an adaptive
+    /// timestep, steps whose error is above the integrator's tolerance are
+    /// retried with a smaller timestep and the next timestep is the largest
+    /// the error estimate and transforms allow.
+    fn step(
+        &self,
+        state: &[Entity],
+        new_state: &mut [Entity],
+        transform_fn: &TargetedAccelerationFn<'_>,
+        mut dt: f64,
+    ) -> (f64, f64) {
+        let Some(bounds) = self.adaptive else {
+            self.integrator
+                .integrate_targets(state, new_state, transform_fn, dt);
+            return (dt, dt);
+        };
+        let estimated = loop {
+            self.integrator
+                .integrate_targets(state, new_state, transform_fn, dt);
+            match self.integrator.error_estimate() {
+                Some(estimate) if estimate.error > 1.0 && !estimate.resolved && dt > bounds.min => {
+                    debug!("Rejected step of {dt} with error {}", estimate.error);
+                    dt = scaled_timestep(dt, estimate).max(bounds.min);
                 }
+                estimate => break estimate.map(|estimate| scaled_timestep(dt, estimate)),
             }
-        });
+        };
+        let suggested = self
+            .transforms
+            .iter()
+            .filter_map(|t| t.suggested_dt())
+            .reduce(f64::min);
+        let next_dt = match (estimated, suggested) {
+            (Some(estimated), Some(suggested)) => estimated.min(suggested),
+            (Some(next_dt), None) | (None, Some(next_dt)) => next_dt,
+            (None, None) => dt,
+        };
+        (dt, next_dt.clamp(bounds.min, bounds.max))
+    }
 
-        self.render.render(renderer_receiver);
-        msg_flag.store(false, std::sync::atomic::Ordering::Relaxed);
-        message_thread
-            .join()
-            .map_err(|e| format!("Failed join message thread {:?}", e))
+    fn save_checkpoint(&self, state: &[Entity], count: u64, time: f64, dt: f64) {
+        let Some(path) = &self.checkpoint_path else {
+            return;
+        };
+        let elements = self
+            .elements
+            .iter()
+            .filter_map(|(key, el)| el.checkpoint_state().map(|s| (key.clone(), s)))
+            .collect();
+        let checkpoint = Checkpoint::new(count, time, dt, state.to_vec(), elements);
+        match checkpoint.save(path) {
+            Ok(()) => info!("Wrote checkpoint for iteration {count} to {path}"),
+            // losing a checkpoint shouldn't end a long run
+            Err(e) => error!("Failed to write checkpoint to {path}: {e}"),
+        }
     }
 
     fn post_configuration_messages(&self) {
         debug!("Posting configuration messages");
+        // lets sinks record how their output was produced
+        let msg = msg!(
+            self,
+            "pipeline_description",
+            self.description.clone(),
+            MessagePriority::Normal
+        );
+        match self.bus.lock() {
+            Ok(mut bus) => bus.post_message(msg),
+            Err(_) => {
+                eprintln!("Failed to post pipeline description. Message bus poisoned");
+                std::process::exit(1)
+            }
+        }
         self.transforms
             .iter()
             .for_each(|el| el.post_configuration_messages());
@@ -214,24 +418,37 @@ impl Pipeline {
         self.transmutes
             .iter()
             .for_each(|el| el.post_configuration_messages());
-        self.render.post_configuration_messages();
+        self.renders
+            .iter()
+            .for_each(|(el, _)| el.post_configuration_messages());
         self.integrator.post_configuration_messages();
         debug!("Finished posting configuration messages");
     }
 
-    pub fn new_from_description(pipeline_description: &str) -> Result<Self, Box<dyn Error>> {
+    pub fn new_from_description(
+        pipeline_description: &str,
+        checks: PropertyChecks,
+    ) -> Result<Self, Box<dyn Error>> {
         info!("Parsing: {pipeline_description}");
         let element_descriptions: Vec<&str> = pipeline_description.split_terminator("!").collect();
 
         let mut builder = PipelineBuilder::new();
-        for desc in element_descriptions.into_iter() {
-            let (el_name, props) = Self::parse_element_description(desc)?;
+        builder.checks = checks;
+        builder.description = pipeline_description.to_string();
+        let mut descriptions = element_descriptions
+            .into_iter()
+            .map(Self::parse_element_description)
+            .collect::<Result<Vec<_>, _>>()?;
+        // elements are created with the global settings, such as the units,
+        // wherever global is in the description
+        descriptions.sort_by_key(|(el_name, _)| el_name != "global");
+        for (el_name, props) in descriptions {
             builder = builder.add(&el_name, props)?;
         }
         builder.build()
     }
 
-    pub fn new_from_file(path: &str) -> Result<Pipeline, Box<dyn Error>> {
+    pub fn new_from_file(path: &str, checks: PropertyChecks) -> Result<Pipeline, Box<dyn Error>> {
         let toml_str =
             std::fs::read_to_string(path).map_err(|_| format!("Could not read {path}"))?;
         let config: PipelineConfig = toml::from_str(&toml_str).map_err(|e| {
@@ -252,18 +469,15 @@ impl Pipeline {
             }
         })?;
         let mut builder = PipelineBuilder::new();
+        builder.checks = checks;
+        builder.description = toml_str.clone();
 
-        // let props = HashMap::from([("dt", serde_json::json!(config.global.dt),])
-        let props = HashMap::from([
-            ("dt".to_string(), serde_json::json!(config.global.dt)),
-            (
-                "iterations".to_string(),
-                serde_json::json!(config.global.iterations),
-            ),
-        ]);
-        builder = builder.add("global", props)?;
+        builder = builder.add("global", config.global)?;
 
         for (el_name, descriptions) in config.elements {
+            let descriptions: Vec<HashMap<String, Value>> = descriptions
+                .try_into()
+                .map_err(|e| format!("Invalid description of {el_name}: {e}"))?;
             for props in descriptions {
                 builder = builder.add(&el_name, props)?;
             }
@@ -308,17 +522,40 @@ impl Pipeline {
     }
 }
 
+/// How the pipeline treats properties which an element doesn't accept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PropertyChecks {
+    /// Refuse to build the pipeline
+    #[default]
+    Strict,
+    /// Log a warning and leave the property out, as older versions did
+    Lenient,
+}
+
 struct PipelineBuilder {
     initialisers: Vec<Arc<GeneratorElementHandler>>,
     synths: Option<Vec<Arc<GeneratorElementHandler>>>,
     transforms: Vec<Arc<TransformElementHandler>>,
     transmutes: Vec<Arc<TransmuteElementHandler>>,
-    render: Option<Arc<RenderElementHandler>>,
+    renders: Vec<(Arc<RenderElementHandler>, Backpressure)>,
     integrator: Option<Arc<IntegratorElementHandler>>,
     element_db: HashMap<String, RegisteredElement>,
-    timestep: f64,
+    timestep: Option<f64>,
+    adaptive: bool,
+    dt_min: Option<f64>,
+    dt_max: Option<f64>,
     iterations: u64,
     bus: Arc<Mutex<MessageBus>>,
+    elements: Vec<(String, Arc<dyn Element>)>,
+    checkpoint_path: Option<String>,
+    checkpoint_n: u64,
+    restart: Option<Checkpoint>,
+    description: String,
+    checks: PropertyChecks,
+    units: UnitSystem,
+    domain: Option<Domain>,
+    // number of elements added so far, for error messages
+    position: usize,
 }
 
 impl PipelineBuilder {
@@ -328,36 +565,121 @@ impl PipelineBuilder {
             synths: None,
             transforms: vec![],
             transmutes: vec![],
-            render: None,
+            renders: vec![],
             integrator: None,
             element_db: element_db(),
-            timestep: 0.000001,
+            timestep: None,
+            adaptive: false,
+            dt_min: None,
+            dt_max: None,
             iterations: 10000,
             bus: Arc::new(Mutex::new(MessageBus::new())),
+            elements: vec![],
+            checkpoint_path: None,
+            checkpoint_n: 0,
+            restart: None,
+            description: String::new(),
+            checks: PropertyChecks::default(),
+            units: UnitSystem::default(),
+            domain: None,
+            position: 0,
         }
     }
 
     pub fn add(
         mut self,
         el_name: &str,
-        properties: HashMap<String, Value>,
+        mut properties: HashMap<String, Value>,
     ) -> Result<Self, Box<dyn Error>> {
         if el_name == "global" {
+            let schema = global_schema();
+            let names: Vec<&str> = schema.iter().map(|spec| spec.name.as_str()).collect();
+            check_properties(
+                "global",
+                &names,
+                &schema,
+                None,
+                self.checks,
+                &mut properties,
+            )?;
             if let Some(x) = properties.get("dt").and_then(|x| x.as_f64()) {
-                self.timestep = x;
+                self.timestep = Some(x);
+            }
+            if let Some(x) = properties.get("adaptive").and_then(|x| x.as_bool()) {
+                self.adaptive = x;
+            }
+            if let Some(x) = properties.get("dt_min").and_then(|x| x.as_f64()) {
+                self.dt_min = Some(x);
+            }
+            if let Some(x) = properties.get("dt_max").and_then(|x| x.as_f64()) {
+                self.dt_max = Some(x);
             }
             if let Some(x) = properties.get("iterations").and_then(|x| x.as_u64()) {
                 self.iterations = x;
             }
+            if let Some(x) = properties.get("checkpoint").and_then(|x| x.as_str()) {
+                self.checkpoint_path = Some(x.to_string());
+            }
+            if let Some(x) = properties.get("checkpoint_n").and_then(|x| x.as_u64()) {
+                self.checkpoint_n = x;
+            }
+            if let Some(x) = properties.get("restart").and_then(|x| x.as_str()) {
+                self.restart = Some(Checkpoint::load(x)?);
+            }
+            if let Some(x) = properties.get("units").and_then(|x| x.as_str()) {
+                self.units = UnitSystem::from_str(x).map_err(|e| format!("global: {e}"))?;
+                units::set_unit_system(self.units);
+            }
+            self.domain = periodic_domain(&properties).map_err(|e| format!("global: {e}"))?;
+            domain::set_periodic_domain(self.domain);
             return Ok(self);
         }
 
-        let element_data = self
-            .element_db
-            .get(el_name)
-            .ok_or(format!("{el_name} is not a registered element"))?;
+        self.position += 1;
+        let Some(element_data) = self.element_db.get(el_name) else {
+            let names: Vec<&str> = self.element_db.keys().map(|name| name.as_str()).collect();
+            return Err(match properties::suggest(el_name, &names) {
+                Some(name) => {
+                    format!("{el_name} is not a registered element, did you mean {name}?")
+                }
+                None => format!("{el_name} is not a registered element"),
+            }
+            .into());
+        };
+
+        // backpressure is handled by the pipeline rather than the element
+        let backpressure = match element_data.get_element_kind() {
+            ElementKind::Render => match properties.remove("backpressure") {
+                Some(Value::String(policy)) => {
+                    Some(Backpressure::from_str(&policy).map_err(|e| format!("{el_name}: {e}"))?)
+                }
+                Some(v) => {
+                    return Err(format!("{el_name}: backpressure must be a string, got {v}").into())
+                }
+                None => None,
+            },
+            _ => None,
+        };
+        let mut names: Vec<&str> = element_data
+            .get_property_descriptions()
+            .keys()
+            .map(|name| name.as_str())
+            .collect();
+        if matches!(element_data.get_element_kind(), ElementKind::Render) {
+            names.push("backpressure");
+        }
+        check_properties(
+            &format!("{el_name} (element {})", self.position),
+            &names,
+            element_data.get_property_schema().unwrap_or_default(),
+            element_data.get_property_check(),
+            self.checks,
+            &mut properties,
+        )?;
 
         unsafe { set_bus(element_data, self.bus.clone())? };
+        unsafe { set_units(element_data, self.units)? };
+        unsafe { set_domain(element_data, self.domain)? };
 
         match element_data.get_element_kind() {
             ElementKind::Initialiser => {
@@ -365,6 +687,7 @@ impl PipelineBuilder {
                     GeneratorElementHandler::load(element_data.get_lib_path(), el_name, properties)
                         .map_err(|_| "Failed to load initialiser element")?;
                 self.add_element_to_bus(element.clone());
+                self.record_element(el_name, element.clone());
                 self.initialisers.push(element);
             }
             ElementKind::Transform => {
@@ -372,20 +695,33 @@ impl PipelineBuilder {
                     TransformElementHandler::load(element_data.get_lib_path(), el_name, properties)
                         .map_err(|_| "Failed to load transform element")?;
                 self.add_element_to_bus(element.clone());
+                self.record_element(el_name, element.clone());
                 self.transforms.push(element);
             }
             ElementKind::Render => {
                 let element =
                     RenderElementHandler::load(element_data.get_lib_path(), el_name, properties)
-                        .map_err(|_| "Failed to load transform element")?;
+                        .map_err(|_| "Failed to load render element")?;
                 self.add_element_to_bus(element.clone());
-                self.render = Some(element);
+                self.record_element(el_name, element.clone());
+                if element.needs_main_thread()
+                    && self.renders.iter().any(|(r, _)| r.needs_main_thread())
+                {
+                    return Err(format!(
+                        "{el_name} (element {}) needs the main thread, but another renderer in the pipeline already does",
+                        self.position
+                    )
+                    .into());
+                }
+                let backpressure = backpressure.unwrap_or_else(|| element.default_backpressure());
+                self.renders.push((element, backpressure));
             }
             ElementKind::Synth => {
                 let element =
                     GeneratorElementHandler::load(element_data.get_lib_path(), el_name, properties)
                         .map_err(|_| "Failed to load synth element")?;
                 self.add_element_to_bus(element.clone());
+                self.record_element(el_name, element.clone());
                 match self.synths.as_mut() {
                     Some(els) => {
                         els.push(element);
@@ -400,6 +736,7 @@ impl PipelineBuilder {
                     TransmuteElementHandler::load(element_data.get_lib_path(), el_name, properties)
                         .map_err(|_| "Failed to load transmute element")?;
                 self.add_element_to_bus(element.clone());
+                self.record_element(el_name, element.clone());
                 self.transmutes.push(element);
             }
             ElementKind::Integrator => {
@@ -410,6 +747,7 @@ impl PipelineBuilder {
                 )
                 .map_err(|_| "Failed to load transmute element")?;
                 self.add_element_to_bus(element.clone());
+                self.record_element(el_name, element.clone());
                 self.integrator = Some(element);
             }
         }
@@ -417,27 +755,86 @@ impl PipelineBuilder {
     }
 
     pub fn build(self) -> Result<Pipeline, Box<dyn Error>> {
-        if self.integrator.is_none() {
-            Err("No integrator defined in pipeline".into())
-        } else if self.render.is_none() {
-            Err("No renderer defined in pipeline".into())
-        } else if self.transforms.is_empty() && self.transmutes.is_empty() {
-            Err("No transforms defined in pipeline".into())
+        let Some(integrator) = self.integrator else {
+            return Err("No integrator defined in pipeline".into());
+        };
+        if self.transforms.is_empty() && self.transmutes.is_empty() {
+            return Err("No transforms defined in pipeline".into());
+        }
+        // an explicit dt takes precedence so that a restarted run can be branched
+        let timestep = match (self.timestep, &self.restart) {
+            (Some(dt), _) => dt,
+            (None, Some(checkpoint)) => checkpoint.dt,
+            (None, None) => 0.000001,
+        };
+        let adaptive = if self.adaptive {
+            // the initial timestep is the largest by default
+            let bounds = StepBounds {
+                min: self.dt_min.unwrap_or(timestep / 1000.0),
+                max: self.dt_max.unwrap_or(timestep),
+            };
+            if bounds.min <= 0.0 || bounds.min > bounds.max {
+                return Err(format!(
+                    "dt_min must be above 0 and no more than dt_max, got {} and {}",
+                    bounds.min, bounds.max
+                )
+                .into());
+            }
+            Some(bounds)
         } else {
-            let transforms = self.transforms;
-            let transmutes = self.transmutes;
-            Ok(Pipeline {
-                initialisers: self.initialisers,
-                synths: self.synths,
-                transforms,
-                transmutes,
-                render: self.render.expect("Checked just above"),
-                integrator: self.integrator.expect("Checked just above"),
-                timestep: self.timestep,
-                iterations: self.iterations,
-                bus: self.bus,
-            })
+            if self.dt_min.is_some() || self.dt_max.is_some() {
+                warn!("dt_min and dt_max are ignored without adaptive=true");
+            }
+            None
+        };
+        let timestep = match adaptive {
+            Some(bounds) => timestep.clamp(bounds.min, bounds.max),
+            None => timestep,
+        };
+        if let Some(checkpoint) = &self.restart {
+            for (key, el) in self.elements.iter() {
+                if let Some(state) = checkpoint.elements.get(key) {
+                    el.restore_state(state.clone())
+                        .map_err(|e| format!("Failed to restore state of {key}: {e}"))?;
+                }
+            }
+            for key in checkpoint.elements.keys() {
+                if !self.elements.iter().any(|(k, _)| k == key) {
+                    warn!("Checkpoint has state for {key} but it is not in the pipeline");
+                }
+            }
         }
+        Ok(Pipeline {
+            initialisers: self.initialisers,
+            synths: self.synths,
+            transforms: self.transforms,
+            transmutes: self.transmutes,
+            renders: self.renders,
+            integrator,
+            timestep,
+            adaptive,
+            iterations: self.iterations,
+            bus: self.bus,
+            elements: self.elements,
+            checkpoint_path: self.checkpoint_path,
+            checkpoint_n: self.checkpoint_n,
+            restart: self.restart,
+            description: self.description,
+        })
+    }
+
+    /// Elements are identified in checkpoints by their name and how many
+    /// elements of the same name came before them, e.g. `star.1`.
+    fn record_element(&mut self, el_name: &str, element: Arc<dyn Element>) {
+        let index = self
+            .elements
+            .iter()
+            .filter(|(key, _)| {
+                key.rsplit_once('.')
+                    .is_some_and(|(name, _)| name == el_name)
+            })
+            .count();
+        self.elements.push((format!("{el_name}.{index}"), element));
     }
 
     fn add_element_to_bus(&self, element: Arc<dyn MessageClient>) {
@@ -457,10 +854,139 @@ impl Default for PipelineBuilder {
     }
 }
 
+/// The properties of `global`
+fn global_schema() -> Vec<PropertySpec> {
+    vec![
+        PropertySpec::new("dt", PropertyKind::Float, "Timestep of the simulation"),
+        PropertySpec::new(
+            "adaptive",
+            PropertyKind::Bool,
+            "Adjust dt between iterations",
+        ),
+        PropertySpec::new("dt_min", PropertyKind::Float, "Smallest adaptive timestep"),
+        PropertySpec::new("dt_max", PropertyKind::Float, "Largest adaptive timestep"),
+        PropertySpec::new("iterations", PropertyKind::Integer, "Number of iterations"),
+        PropertySpec::new(
+            "checkpoint",
+            PropertyKind::String,
+            "Path to write checkpoints to",
+        ),
+        PropertySpec::new(
+            "checkpoint_n",
+            PropertyKind::Integer,
+            "Write a checkpoint every n iterations",
+        ),
+        PropertySpec::new(
+            "restart",
+            PropertyKind::String,
+            "Path of a checkpoint to start from",
+        ),
+        PropertySpec {
+            choices: vec!["nbody".to_string(), "si".to_string(), "au".to_string()],
+            ..PropertySpec::new(
+                "units",
+                PropertyKind::String,
+                "Units of the simulation. nbody has G = 1, si is metres, kilograms and seconds, and au is astronomical units, solar masses and years",
+            )
+        },
+        PropertySpec::new(
+            "domain_min",
+            PropertyKind::Floats(Some(3)),
+            "Lowest corner of the periodic domain",
+        ),
+        PropertySpec::new(
+            "domain_max",
+            PropertyKind::Floats(Some(3)),
+            "Highest corner of the periodic domain",
+        ),
+        PropertySpec {
+            choices: vec!["x".to_string(), "y".to_string(), "z".to_string()],
+            ..PropertySpec::new(
+                "periodic",
+                PropertyKind::Strings,
+                "Axes along which the domain is periodic, x,y,z by default",
+            )
+        },
+    ]
+}
+
+/// The periodic domain given by the properties of `global`, if there is one
+fn periodic_domain(properties: &HashMap<String, Value>) -> Result<Option<Domain>, String> {
+    let corner = |name: &str| properties.get(name).and_then(<[f64; 3]>::from_value);
+    let axes = properties
+        .get("periodic")
+        .and_then(Vec::<String>::from_value);
+    let (min, max) = match (corner("domain_min"), corner("domain_max")) {
+        (Some(min), Some(max)) => (min, max),
+        (None, None) if axes.is_none() => return Ok(None),
+        _ => return Err("domain_min and domain_max are both needed for a domain".to_string()),
+    };
+    if (0..3).any(|k| min[k] >= max[k]) {
+        return Err("domain_min must be below domain_max".to_string());
+    }
+    let periodic = match axes {
+        Some(axes) => ["x", "y", "z"].map(|axis| axes.iter().any(|a| a == axis)),
+        None => [true; 3],
+    };
+    Ok(Some(Domain::new(min, max, periodic)))
+}
+
+/// Check properties against the names an element advertises and, when it has
+/// one, its schema. Every problem is reported at once in strict mode. In
+/// lenient mode the offending properties are removed, so the element uses its
+/// defaults instead. The properties which are left are then given to the
+/// element's `check`, and properties which disagree with each other are an
+/// error in either mode, since there is no single property to remove.
+fn check_properties(
+    location: &str,
+    names: &[&str],
+    schema: &[PropertySpec],
+    check: Option<CheckPropertiesFn>,
+    checks: PropertyChecks,
+    properties: &mut HashMap<String, Value>,
+) -> Result<(), String> {
+    let mut keys: Vec<String> = properties.keys().cloned().collect();
+    keys.sort();
+    let mut problems = vec![];
+    for key in keys {
+        let problem = if names.contains(&key.as_str()) {
+            schema
+                .iter()
+                .find(|spec| spec.name == key)
+                .and_then(|spec| spec.check(&properties[&key]).err())
+        } else {
+            Some(properties::unknown_property(&key, names))
+        };
+        if let Some(problem) = problem {
+            problems.push(problem);
+            if checks == PropertyChecks::Lenient {
+                properties.remove(&key);
+            }
+        }
+    }
+    match checks {
+        PropertyChecks::Strict if !problems.is_empty() => {
+            return Err(format!("{location}: {}", problems.join("; ")));
+        }
+        PropertyChecks::Strict => {}
+        PropertyChecks::Lenient => {
+            for problem in problems {
+                warn!("{location}: {problem} (ignored)");
+            }
+        }
+    }
+    match check {
+        Some(check) => unsafe { check(properties) }.map_err(|e| format!("{location}: {e}")),
+        None => Ok(()),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct PipelineConfig {
-    global: GlobalOptions,
-    elements: HashMap<String, Vec<HashMap<String, Value>>>,
+    #[serde(default)]
+    global: HashMap<String, Value>,
+    // keeps the order elements appear in the file
+    elements: toml::Table,
 }
 
 // #[derive(Deserialize, Debug)]
@@ -469,15 +995,302 @@ struct PipelineConfig {
 //     branches:
 // }
 
-#[derive(Deserialize, Debug)]
-struct GlobalOptions {
-    dt: f64,
-    iterations: u64,
-}
-
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+
+    use super::{
+        check_properties, global_schema, periodic_domain, scaled_timestep, PropertyChecks,
+    };
+    use crate::plugin::integrator::ErrorEstimate;
 
     #[test]
     fn test_parse() {}
+
+    #[test]
+    fn test_scaled_timestep() {
+        // an error of 2^5 needs half the timestep for a 4th order method
+        let estimate = ErrorEstimate {
+            error: 32.0,
+            order: 4,
+            resolved: false,
+        };
+        assert!((scaled_timestep(1.0, estimate) - 0.45).abs() < 1e-12);
+        // the change in one step is limited
+        let estimate = ErrorEstimate {
+            error: 1e9,
+            order: 4,
+            resolved: false,
+        };
+        assert_eq!(scaled_timestep(1.0, estimate), 0.2);
+        let estimate = ErrorEstimate {
+            error: 0.0,
+            order: 1,
+            resolved: false,
+        };
+        assert_eq!(scaled_timestep(1.0, estimate), 5.0);
+    }
+
+    #[test]
+    fn test_periodic_domain() {
+        use crate::domain::Domain;
+
+        assert_eq!(periodic_domain(&HashMap::new()), Ok(None));
+        let properties = HashMap::from([
+            ("domain_min".to_string(), json!([-1, -1, 0])),
+            ("domain_max".to_string(), json!([1, 1, 2])),
+        ]);
+        assert_eq!(
+            periodic_domain(&properties),
+            Ok(Some(Domain::new(
+                [-1.0, -1.0, 0.0],
+                [1.0, 1.0, 2.0],
+                [true; 3]
+            )))
+        );
+        let mut properties = properties;
+        properties.insert("periodic".to_string(), json!("x,z"));
+        assert_eq!(
+            periodic_domain(&properties).unwrap().unwrap().periodic,
+            [true, false, true]
+        );
+        properties.remove("domain_max");
+        assert!(periodic_domain(&properties).is_err());
+        let backwards = HashMap::from([
+            ("domain_min".to_string(), json!([1, 1, 1])),
+            ("domain_max".to_string(), json!([-1, 1, 2])),
+        ]);
+        assert!(periodic_domain(&backwards).is_err());
+    }
+
+    // C plugins only define the symbols their elements need
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_library_without_optional_symbols() {
+        use crate::domain::Domain;
+        use crate::plugin::{
+            meta::ElementMeta, set_domain, set_units, ElementKind, RegisteredElement,
+        };
+        use crate::units::UnitSystem;
+
+        let meta = ElementMeta {
+            kind: ElementKind::Transform,
+            name: "cdrag".to_string(),
+            plugin: "c".to_string(),
+            version: String::new(),
+            license: String::new(),
+            author: String::new(),
+            blurb: String::new(),
+            repo: String::new(),
+        };
+        let element = RegisteredElement::new(meta, "libm.so.6", HashMap::new(), None);
+        assert!(unsafe { set_units(&element, UnitSystem::Astronomical) }.is_ok());
+        let domain = Domain::new([-1.0; 3], [1.0; 3], [true; 3]);
+        assert!(unsafe { set_domain(&element, Some(domain)) }.is_ok());
+    }
+
+    #[test]
+    fn test_main_thread_render() {
+        use std::error::Error;
+        use std::sync::{mpsc::Receiver, Arc};
+
+        use super::main_thread_render;
+        use crate::messages::MessageClient;
+        use crate::plugin::{
+            render::{Backpressure, Frame, RenderElement, RenderElementHandler},
+            Element, Loadable,
+        };
+
+        struct Render {
+            window: bool,
+        }
+        impl MessageClient for Render {}
+        impl Element for Render {
+            fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+                Ok(HashMap::new())
+            }
+        }
+        impl RenderElement for Render {
+            fn render(&self, _state_recv: Receiver<Frame>) {}
+
+            fn needs_main_thread(&self) -> bool {
+                self.window
+            }
+        }
+
+        let renders = |windows: &[bool]| -> Vec<_> {
+            windows
+                .iter()
+                .map(|&window| {
+                    let render = RenderElementHandler::new(Box::new(Render { window }));
+                    (Arc::new(render), Backpressure::Latest)
+                })
+                .collect()
+        };
+        assert_eq!(main_thread_render(&renders(&[false, false])), 0);
+        assert_eq!(main_thread_render(&renders(&[false, true, false])), 1);
+    }
+
+    // headless pipelines run their iterations without a renderer
+    #[test]
+    fn test_headless() {
+        use std::error::Error;
+        use std::sync::{Arc, Mutex};
+
+        use super::Pipeline;
+        use crate::messages::{MessageBus, MessageClient};
+        use crate::plugin::{
+            generator::{GeneratorElement, GeneratorElementHandler},
+            integrator::{IntegratorElement, IntegratorElementHandler},
+            transmute::{TransmuteElement, TransmuteElementHandler},
+            Element, Loadable,
+        };
+        use crate::{Acceleration, Entity};
+
+        struct Mover;
+        impl MessageClient for Mover {}
+        impl Element for Mover {
+            fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+                Ok(HashMap::new())
+            }
+        }
+        impl GeneratorElement for Mover {
+            fn create_entities(&self) -> Vec<Entity> {
+                vec![Entity {
+                    vx: 1.0,
+                    ..Default::default()
+                }]
+            }
+        }
+        impl IntegratorElement for Mover {
+            fn integrate(
+                &self,
+                entities: &[Entity],
+                new_state: &mut [Entity],
+                _acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
+                dt: f64,
+            ) {
+                for (old, new) in entities.iter().zip(new_state.iter_mut()) {
+                    *new = *old;
+                    new.x += old.vx * dt;
+                }
+            }
+        }
+
+        // records the state after each step
+        struct Recorder(Arc<Mutex<Vec<f64>>>);
+        impl MessageClient for Recorder {}
+        impl Element for Recorder {
+            fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+                Ok(HashMap::new())
+            }
+        }
+        impl TransmuteElement for Recorder {
+            fn transmute(&self, data: &mut Vec<Entity>) {
+                self.0.lock().unwrap().push(data[0].x);
+            }
+        }
+
+        let steps = Arc::new(Mutex::new(vec![]));
+        let pipeline = Pipeline {
+            initialisers: vec![Arc::new(GeneratorElementHandler::new(Box::new(Mover)))],
+            synths: None,
+            transforms: vec![],
+            transmutes: vec![Arc::new(TransmuteElementHandler::new(Box::new(Recorder(
+                steps.clone(),
+            ))))],
+            renders: vec![],
+            integrator: Arc::new(IntegratorElementHandler::new(Box::new(Mover))),
+            timestep: 0.5,
+            adaptive: None,
+            iterations: 4,
+            bus: Arc::new(Mutex::new(MessageBus::new())),
+            elements: vec![],
+            checkpoint_path: None,
+            checkpoint_n: 0,
+            restart: None,
+            description: String::new(),
+        };
+        assert_eq!(pipeline.run(), Ok(()));
+        assert_eq!(*steps.lock().unwrap(), vec![0.5, 1.0, 1.5, 2.0]);
+    }
+
+    #[test]
+    fn test_check_properties() {
+        let schema = global_schema();
+        let names = ["dt", "iterations", "checkpoint", "checkpoint_n", "restart"];
+        let mut properties = HashMap::from([
+            ("dt".to_string(), json!("small")),
+            ("iteration".to_string(), json!(10)),
+            ("restart".to_string(), json!("run.ckpt")),
+        ]);
+        assert_eq!(
+            check_properties(
+                "global",
+                &names,
+                &schema,
+                None,
+                PropertyChecks::Strict,
+                &mut properties
+            ),
+            Err("global: dt must be a float, got \"small\"; \
+                unknown property iteration, did you mean iterations?"
+                .to_string())
+        );
+        assert_eq!(properties.len(), 3);
+
+        check_properties(
+            "global",
+            &names,
+            &schema,
+            None,
+            PropertyChecks::Lenient,
+            &mut properties,
+        )
+        .unwrap();
+        assert_eq!(
+            properties,
+            HashMap::from([("restart".to_string(), json!("run.ckpt"))])
+        );
+
+        // elements which parse their properties by hand only advertise names
+        let mut properties = HashMap::from([("lim".to_string(), json!("anything"))]);
+        assert!(check_properties(
+            "bbox (element 2)",
+            &["lim"],
+            &[],
+            None,
+            PropertyChecks::Strict,
+            &mut properties
+        )
+        .is_ok());
+
+        // properties which are fine on their own can disagree with each other
+        fn ordered(properties: &HashMap<String, Value>) -> Result<(), String> {
+            let value = |name: &str| properties.get(name).and_then(Value::as_f64);
+            match (value("min"), value("max")) {
+                (Some(min), Some(max)) if min >= max => Err("min must be below max".to_string()),
+                _ => Ok(()),
+            }
+        }
+        for checks in [PropertyChecks::Strict, PropertyChecks::Lenient] {
+            let mut properties = HashMap::from([
+                ("min".to_string(), json!(2.0)),
+                ("max".to_string(), json!(1.0)),
+            ]);
+            assert_eq!(
+                check_properties(
+                    "box (element 1)",
+                    &["min", "max"],
+                    &[],
+                    Some(ordered),
+                    checks,
+                    &mut properties
+                ),
+                Err("box (element 1): min must be below max".to_string())
+            );
+        }
+    }
 }
@@ -7,14 +7,16 @@ use std::{
 };
 
 use libloading::{Library, Symbol};
+use serde_json::Value;
 use terminal_colorsaurus::{theme_mode, QueryOptions, ThemeMode};
 use yansi::Paint;
 
 use crate::{
     messages::MessageClient,
     plugin::{
-        host_alloc_string, setup_plugin_logger, transform::TransformElementHandler, Element,
-        ElementKind, ElementMeta, LibLoader, Loadable, PluginGetMetaFn, RegisterPluginFn,
+        host_alloc_string, properties::PropertySpec, setup_plugin_logger,
+        transform::TransformElementHandler, Element, ElementKind, ElementMeta, LibLoader, Loadable,
+        PluginGetMetaFn, RegisterPluginFn,
     },
 };
 
@@ -29,14 +31,27 @@ pub struct RegisteredElement {
     element_info: ElementMeta,
     lib_path: String,
     properties: HashMap<String, String>,
+    schema: Option<Vec<PropertySpec>>,
+    check: Option<CheckPropertiesFn>,
 }
 
+/// Checks the properties of an element as a whole, e.g. that they agree
+/// with each other
+pub type CheckPropertiesFn = unsafe extern "Rust" fn(&HashMap<String, Value>) -> Result<(), String>;
+
 impl RegisteredElement {
-    fn new(element_info: ElementMeta, lib_path: &str, properties: HashMap<String, String>) -> Self {
+    pub(crate) fn new(
+        element_info: ElementMeta,
+        lib_path: &str,
+        properties: HashMap<String, String>,
+        schema: Option<Vec<PropertySpec>>,
+    ) -> Self {
         RegisteredElement {
             element_info,
             lib_path: lib_path.to_string(),
             properties,
+            schema,
+            check: None,
         }
     }
 
@@ -52,6 +67,24 @@ impl RegisteredElement {
         self.element_info.kind
     }
 
+    /// The descriptions of the properties the element advertises, keyed by
+    /// property name.
+    pub fn get_property_descriptions(&self) -> &HashMap<String, String> {
+        &self.properties
+    }
+
+    /// The declared properties of the element. This is `None` for elements
+    /// which parse their properties by hand.
+    pub fn get_property_schema(&self) -> Option<&[PropertySpec]> {
+        self.schema.as_deref()
+    }
+
+    /// The check of the element's properties as a whole, for elements with a
+    /// schema
+    pub fn get_property_check(&self) -> Option<CheckPropertiesFn> {
+        self.check
+    }
+
     pub fn print_element_info_brief(&self) {
         match *THEME_MODE {
             ThemeMode::Dark => self.print_element_info_brief_dark(),
@@ -298,10 +331,29 @@ fn discover() -> Vec<RegisteredElement> {
                 };
 
                 for element_info in get_plugin_meta(&lib) {
-                    if let Some(properties) =
-                        get_registered_element_properties(&element_info, &lib_path)
-                    {
-                        elements.push(RegisteredElement::new(element_info, &lib_path, properties));
+                    let schema = get_property_schema(&lib, &element_info.name);
+                    // elements with a schema may have required properties, so
+                    // they can't be created to ask for their descriptions
+                    let properties = match &schema {
+                        Some(schema) => Some(
+                            schema
+                                .iter()
+                                .map(|spec| (spec.name.clone(), spec.describe()))
+                                .collect(),
+                        ),
+                        None => get_registered_element_properties(&element_info, &lib_path),
+                    };
+                    if let Some(properties) = properties {
+                        let check = lib
+                            .get::<CheckPropertiesFn>(
+                                format!("{}_check_properties", element_info.name).as_bytes(),
+                            )
+                            .ok()
+                            .map(|f| *f);
+                        elements.push(RegisteredElement {
+                            check,
+                            ..RegisteredElement::new(element_info, &lib_path, properties, schema)
+                        });
                     }
                 }
             };
@@ -345,9 +397,9 @@ unsafe fn validate_plugin_abi(lib_path: &str) -> bool {
     // about all the terrible things that can happen in physim.
     let warn = (rust_version == PHYSIM_PLUGIN_LOADER_RUSTC_VERSION) || (rust_version == "C");
     if !warn {
-        eprintln!("{} was built with a different version of the rust compiler or for a different platform. The plugin compiled with {} but physim compiled with {} ",&lib_path, rust_version,PHYSIM_PLUGIN_LOADER_RUSTC_VERSION);
+        eprintln!("{} was built with a different version of the rust compiler or for a different platform. The plugin compiled with {} but physim compiled with {} ",lib_path, rust_version,PHYSIM_PLUGIN_LOADER_RUSTC_VERSION);
     }
-    return true;
+    true
 }
 
 unsafe fn get_plugin_meta(lib: &Library) -> Vec<ElementMeta> {
@@ -393,6 +445,14 @@ fn get_registered_element_properties(
     }
 }
 
+unsafe fn get_property_schema(lib: &Library, name: &str) -> Option<Vec<PropertySpec>> {
+    type PropertySchemaFn = unsafe extern "Rust" fn() -> Vec<PropertySpec>;
+    let schema_fn = lib
+        .get::<PropertySchemaFn>(format!("{name}_property_schema").as_bytes())
+        .ok()?;
+    Some(schema_fn())
+}
+
 /// Struct for determining metadata
 struct MetaElement {
     instance: Box<dyn Element>,
@@ -27,6 +27,14 @@ impl Element for GeneratorElementHandler {
     fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
         self.instance.get_property_descriptions()
     }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.instance.checkpoint_state()
+    }
+
+    fn restore_state(&self, state: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        self.instance.restore_state(state)
+    }
 }
 
 impl MessageClient for GeneratorElementHandler {
@@ -2,6 +2,25 @@ use crate::{messages::MessageClient, Acceleration, Entity};
 
 use super::Element;
 
+/// The error of the last step taken by an integrator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorEstimate {
+    /// Error relative to the integrator's tolerance. Steps with an error
+    /// above 1 are rejected by pipelines with an adaptive timestep.
+    pub error: f64,
+    /// Order of the method the error is estimated for. The error of a step
+    /// scales with `dt` to the power of `order + 1`.
+    pub order: u32,
+    /// The integrator took substeps to keep the step within its tolerance,
+    /// so it is never rejected. `error` is then the error a single step
+    /// would have had, which still sets the next timestep.
+    pub resolved: bool,
+}
+
+/// Adds the accelerations of the target entities, or of every entity when
+/// there are no targets, to the accelerations.
+pub type TargetedAccelerationFn<'a> = dyn Fn(&[Entity], Option<&[usize]>, &mut [Acceleration]) + 'a;
+
 pub trait IntegratorElement: Element + Send + Sync {
     fn integrate(
         &self,
@@ -10,6 +29,33 @@ pub trait IntegratorElement: Element + Send + Sync {
         acc_fn: &dyn Fn(&[Entity], &mut [Acceleration]),
         dt: f64,
     );
+
+    /// Like `integrate`, but `acc_fn` can calculate the accelerations of a
+    /// subset of the entities. When it is given the indices of some target
+    /// entities, only their accelerations are added to the accelerations and
+    /// the rest are left as they are. `None` calculates every acceleration.
+    /// Integrators which evaluate a few entities at a time should implement
+    /// this, and the pipeline calls it instead of `integrate`.
+    fn integrate_targets(
+        &self,
+        entities: &[Entity],
+        new_state: &mut [Entity],
+        acc_fn: &TargetedAccelerationFn<'_>,
+        dt: f64,
+    ) {
+        self.integrate(
+            entities,
+            new_state,
+            &|state, accelerations| acc_fn(state, None, accelerations),
+            dt,
+        );
+    }
+
+    /// The error of the last call to `integrate`, if the integrator can
+    /// estimate it.
+    fn error_estimate(&self) -> Option<ErrorEstimate> {
+        None
+    }
 }
 
 pub struct IntegratorElementHandler {
@@ -26,6 +72,21 @@ impl IntegratorElement for IntegratorElementHandler {
     ) {
         self.instance.integrate(entities, new_state, acc_fn, dt);
     }
+
+    fn integrate_targets(
+        &self,
+        entities: &[Entity],
+        new_state: &mut [Entity],
+        acc_fn: &TargetedAccelerationFn<'_>,
+        dt: f64,
+    ) {
+        self.instance
+            .integrate_targets(entities, new_state, acc_fn, dt);
+    }
+
+    fn error_estimate(&self) -> Option<ErrorEstimate> {
+        self.instance.error_estimate()
+    }
 }
 
 impl Element for IntegratorElementHandler {
@@ -34,6 +95,14 @@ impl Element for IntegratorElementHandler {
     ) -> Result<std::collections::HashMap<String, String>, Box<dyn std::error::Error>> {
         self.instance.get_property_descriptions()
     }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.instance.checkpoint_state()
+    }
+
+    fn restore_state(&self, state: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.instance.restore_state(state)
+    }
 }
 
 impl super::Loadable for IntegratorElementHandler {
@@ -8,11 +8,16 @@ use libloading::{Library, Symbol};
 use once_cell::sync::OnceCell;
 use serde_json::Value;
 
-use crate::messages::{MessageBus, MessageClient};
+use crate::{
+    domain::Domain,
+    messages::{MessageBus, MessageClient},
+    units::UnitSystem,
+};
 
 pub mod generator;
 pub mod integrator;
 pub mod meta;
+pub mod properties;
 pub mod render;
 pub mod transform;
 pub mod transmute;
@@ -22,7 +27,7 @@ pub mod deps {
 
 mod discover;
 
-pub use discover::{element_db, RegisteredElement};
+pub use discover::{element_db, CheckPropertiesFn, RegisteredElement};
 pub use meta::*;
 
 static LIBRARY_LOADER: OnceCell<LibLoader> = OnceCell::new();
@@ -70,6 +75,19 @@ pub enum ElementKind {
 
 pub trait Element: MessageClient {
     fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>>;
+
+    /// State that can't be recreated from the element's properties, e.g. the
+    /// history kept by a multistep integrator. It is written to checkpoints
+    /// and handed back to `restore_state` when a pipeline restarts from one.
+    /// Elements without such state don't need to implement this.
+    fn checkpoint_state(&self) -> Option<Value> {
+        None
+    }
+
+    /// Restore state previously returned by `checkpoint_state`.
+    fn restore_state(&self, _state: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 }
 
 pub trait Loadable {
@@ -144,6 +162,19 @@ macro_rules! register_plugin {
             }
         }
 
+        #[unsafe(no_mangle)]
+        pub extern "C" fn set_unit_system(units: u8) {
+            $crate::units::set_unit_system($crate::units::UnitSystem::from_id(units));
+        }
+
+        /// # Safety
+        ///
+        /// `domain` must be null or point to a valid `Domain`.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn set_periodic_domain(domain: *const $crate::domain::Domain) {
+            $crate::domain::set_periodic_domain(unsafe { domain.as_ref() }.copied());
+        }
+
         static LOGGER: $crate::once_cell::sync::OnceCell<Result<(), $crate::log::SetLoggerError>> = $crate::once_cell::sync::OnceCell::new();
 
         #[no_mangle]
@@ -243,6 +274,51 @@ pub unsafe fn set_bus(
     Ok(())
 }
 
+/// Set the unit system of the plugin which provides an element, with the
+/// `set_unit_system` function that `register_plugin` defines.
+///
+/// # Safety
+///
+/// The dynamic library at the element's path must be trusted, and its
+/// `set_unit_system` function must have the expected signature. Libraries
+/// which don't define it, such as C plugins, are left alone.
+pub unsafe fn set_units(
+    element: &RegisteredElement,
+    units: UnitSystem,
+) -> Result<(), libloading::Error> {
+    let lib = LibLoader::get(element.get_lib_path())?;
+    let set_units: Option<Symbol<unsafe extern "C" fn(u8)>> = lib.get(b"set_unit_system").ok();
+    if let Some(set_units) = set_units {
+        set_units(units.id());
+    }
+    Ok(())
+}
+
+/// Set the periodic domain of the plugin which provides an element, with the
+/// `set_periodic_domain` function that `register_plugin` defines.
+///
+/// # Safety
+///
+/// The dynamic library at the element's path must be trusted, and its
+/// `set_periodic_domain` function must have the expected signature. Libraries
+/// which don't define it are left alone.
+pub unsafe fn set_domain(
+    element: &RegisteredElement,
+    domain: Option<Domain>,
+) -> Result<(), libloading::Error> {
+    let lib = LibLoader::get(element.get_lib_path())?;
+    let set_domain: Option<Symbol<unsafe extern "C" fn(*const Domain)>> =
+        lib.get(b"set_periodic_domain").ok();
+    if let Some(set_domain) = set_domain {
+        set_domain(
+            domain
+                .as_ref()
+                .map_or(std::ptr::null(), |d| d as *const Domain),
+        );
+    }
+    Ok(())
+}
+
 type SetupLogger = extern "Rust" fn(
     logger: &'static dyn log::Log,
     level: log::LevelFilter,
@@ -0,0 +1,652 @@
+//! Typed element properties.
+//!
+//! Elements declare their properties with a struct deriving
+//! `physim_attribute::Properties`, e.g.
+//! ```ignore
+//! #[derive(Properties)]
+//! struct AstroProperties {
+//!     /// Barnes-Hut parameter. Increase for speed, decrease for accuracy
+//!     #[property(default = 1.0, range = 0.0..)]
+//!     theta: f64,
+//! }
+//! ```
+//! The derive generates the element's [`PropertySpec`]s, the parsing and the
+//! descriptions shown by `physcan`. Passing the struct to the element macro
+//! with `properties = AstroProperties` lets the pipeline check the properties
+//! of the element before it is created.
+use std::{collections::HashMap, fmt::Display};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropertyKind {
+    Float,
+    /// A non-negative integer
+    Integer,
+    Bool,
+    String,
+    /// A list of floats, with a fixed length if given. Without one, it can
+    /// also be given as one float
+    Floats(Option<usize>),
+    /// A list of strings, which can also be given as a comma separated string
+    Strings,
+    /// A list of non-negative integers, which can also be given as one integer
+    Integers,
+}
+
+impl PropertyKind {
+    pub fn accepts(&self, value: &Value) -> bool {
+        match self {
+            PropertyKind::Float => value.is_number(),
+            PropertyKind::Integer => value.is_u64(),
+            PropertyKind::Bool => value.is_boolean(),
+            // the CLI parses values that look like numbers as numbers
+            PropertyKind::String => value.is_string() || value.is_number() || value.is_boolean(),
+            PropertyKind::Floats(None) if value.is_number() => true,
+            PropertyKind::Floats(len) => value.as_array().is_some_and(|values| {
+                values.iter().all(|v| v.is_number()) && len.is_none_or(|len| values.len() == len)
+            }),
+            PropertyKind::Strings => {
+                value.is_string()
+                    || value
+                        .as_array()
+                        .is_some_and(|values| values.iter().all(|v| v.is_string()))
+            }
+            PropertyKind::Integers => {
+                value.is_u64()
+                    || value
+                        .as_array()
+                        .is_some_and(|values| values.iter().all(|v| v.is_u64()))
+            }
+        }
+    }
+}
+
+impl Display for PropertyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyKind::Float => write!(f, "float"),
+            PropertyKind::Integer => write!(f, "integer"),
+            PropertyKind::Bool => write!(f, "bool"),
+            PropertyKind::String => write!(f, "string"),
+            PropertyKind::Floats(Some(len)) => write!(f, "list of {len} floats"),
+            PropertyKind::Floats(None) => write!(f, "list of floats"),
+            PropertyKind::Strings => write!(f, "list of strings"),
+            PropertyKind::Integers => write!(f, "list of integers"),
+        }
+    }
+}
+
+/// The declaration of a single property of an element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertySpec {
+    pub name: String,
+    pub kind: PropertyKind,
+    pub description: String,
+    /// Value used when the property isn't given. Properties without a
+    /// default are optional.
+    pub default: Option<Value>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Allowed values of a string, or of each string in a list
+    pub choices: Vec<String>,
+}
+
+impl PropertySpec {
+    /// An optional property without a range or choices.
+    pub fn new(name: &str, kind: PropertyKind, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            description: description.to_string(),
+            default: None,
+            min: None,
+            max: None,
+            choices: vec![],
+        }
+    }
+
+    /// Check a value against the type, range and choices of the property.
+    pub fn check(&self, value: &Value) -> Result<(), String> {
+        let name = &self.name;
+        if !self.kind.accepts(value) {
+            let article = match self.kind {
+                PropertyKind::Integer => "an",
+                _ => "a",
+            };
+            return Err(format!(
+                "{name} must be {article} {}, got {value}",
+                self.kind
+            ));
+        }
+        // the range applies to each float of a list
+        let numbers: Vec<f64> = match value {
+            Value::Array(values) => values.iter().filter_map(|v| v.as_f64()).collect(),
+            value => value.as_f64().into_iter().collect(),
+        };
+        for x in numbers {
+            match (self.min, self.max) {
+                (Some(min), Some(max)) if !(min..=max).contains(&x) => {
+                    return Err(format!("{name} must be between {min} and {max}, got {x}"));
+                }
+                (Some(min), _) if x < min => {
+                    return Err(format!("{name} must be at least {min}, got {x}"));
+                }
+                (_, Some(max)) if x > max => {
+                    return Err(format!("{name} must be at most {max}, got {x}"));
+                }
+                _ => {}
+            }
+        }
+        if !self.choices.is_empty() {
+            let values = match self.kind {
+                PropertyKind::Strings => strings(value),
+                _ => String::from_value(value).into_iter().collect(),
+            };
+            if let Some(choice) = values.into_iter().find(|s| !self.choices.contains(s)) {
+                return Err(format!(
+                    "{name} must be one of {}, got {choice}",
+                    self.choices.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The description followed by the type, default and allowed values.
+    pub fn describe(&self) -> String {
+        let mut details = vec![self.kind.to_string()];
+        match &self.default {
+            Some(Value::String(default)) => details.push(format!("default {default}")),
+            Some(default) => details.push(format!("default {default}")),
+            None => {}
+        }
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => details.push(format!("between {min} and {max}")),
+            (Some(min), None) => details.push(format!("at least {min}")),
+            (None, Some(max)) => details.push(format!("at most {max}")),
+            (None, None) => {}
+        }
+        if !self.choices.is_empty() {
+            details.push(format!("one of {}", self.choices.join(", ")));
+        }
+        if self.description.is_empty() {
+            format!("({})", details.join(", "))
+        } else {
+            format!("{} ({})", self.description, details.join(", "))
+        }
+    }
+}
+
+/// Check properties against the schema of an element. Every property must be
+/// declared and have a valid value.
+pub fn validate(
+    schema: &[PropertySpec],
+    properties: &HashMap<String, Value>,
+) -> Result<(), String> {
+    let mut keys: Vec<&String> = properties.keys().collect();
+    keys.sort();
+    for key in keys {
+        let Some(spec) = schema.iter().find(|spec| &spec.name == key) else {
+            let names: Vec<&str> = schema.iter().map(|spec| spec.name.as_str()).collect();
+            return Err(unknown_property(key, &names));
+        };
+        spec.check(&properties[key])?;
+    }
+    Ok(())
+}
+
+/// Describe a property which isn't one of `names`, suggesting the one that
+/// was probably meant.
+pub fn unknown_property(key: &str, names: &[&str]) -> String {
+    if let Some(name) = suggest(key, names) {
+        return format!("unknown property {key}, did you mean {name}?");
+    }
+    let mut names = names.to_vec();
+    names.sort();
+    if names.is_empty() {
+        format!("unknown property {key}. It has no properties")
+    } else {
+        format!(
+            "unknown property {key}. Valid properties are {}",
+            names.join(", ")
+        )
+    }
+}
+
+/// The name closest to a misspelt property, if any are close enough to be
+/// what was meant.
+pub fn suggest<'a>(key: &str, names: &[&'a str]) -> Option<&'a str> {
+    // abbreviations, e.g. m for mass
+    let mut longer = names.iter().filter(|name| name.starts_with(key));
+    if let (Some(name), None) = (longer.next(), longer.next()) {
+        if !key.is_empty() {
+            return Some(name);
+        }
+    }
+    let length = key.chars().count();
+    names
+        .iter()
+        .map(|name| (edit_distance(key, name), *name))
+        .min()
+        .filter(|(distance, _)| *distance <= (length / 3).max(1) && *distance < length)
+        .map(|(_, name)| name)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Get the value of a property, or its default.
+pub fn get<T: PropertyValue>(
+    spec: &PropertySpec,
+    properties: &HashMap<String, Value>,
+) -> Result<T, String> {
+    match properties.get(&spec.name).or(spec.default.as_ref()) {
+        Some(value) => {
+            spec.check(value)?;
+            T::from_value(value)
+                .ok_or_else(|| format!("{} must be a {}, got {value}", spec.name, spec.kind))
+        }
+        None => T::absent().ok_or_else(|| format!("{} is required", spec.name)),
+    }
+}
+
+/// Parse a struct of properties shared by several elements from the
+/// properties of one of them, which also has properties of its own.
+pub fn flattened<P: Properties>(properties: &HashMap<String, Value>) -> Result<P, String> {
+    let names: Vec<String> = P::schema().into_iter().map(|spec| spec.name).collect();
+    let properties = properties
+        .iter()
+        .filter(|(key, _)| names.contains(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    P::from_properties(&properties)
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map_or_else(|| v.to_string(), |s| s.trim().to_string())
+            })
+            .collect(),
+        Value::String(s) => s.split(',').map(|s| s.trim().to_string()).collect(),
+        other => vec![other.to_string()],
+    }
+}
+
+/// Types which can be used as element properties.
+pub trait PropertyValue: Sized {
+    fn kind() -> PropertyKind;
+
+    fn from_value(value: &Value) -> Option<Self>;
+
+    /// Value of a property which isn't given and has no default.
+    fn absent() -> Option<Self> {
+        None
+    }
+}
+
+impl PropertyValue for f64 {
+    fn kind() -> PropertyKind {
+        PropertyKind::Float
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_f64()
+    }
+}
+
+impl PropertyValue for u64 {
+    fn kind() -> PropertyKind {
+        PropertyKind::Integer
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_u64()
+    }
+}
+
+impl PropertyValue for usize {
+    fn kind() -> PropertyKind {
+        PropertyKind::Integer
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_u64().and_then(|x| usize::try_from(x).ok())
+    }
+}
+
+impl PropertyValue for bool {
+    fn kind() -> PropertyKind {
+        PropertyKind::Bool
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        value.as_bool()
+    }
+}
+
+impl PropertyValue for String {
+    fn kind() -> PropertyKind {
+        PropertyKind::String
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl<const N: usize> PropertyValue for [f64; N] {
+    fn kind() -> PropertyKind {
+        PropertyKind::Floats(Some(N))
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let values: Vec<f64> = Vec::from_value(value)?;
+        values.try_into().ok()
+    }
+}
+
+impl PropertyValue for Vec<f64> {
+    fn kind() -> PropertyKind {
+        PropertyKind::Floats(None)
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Array(values) => values.iter().map(|v| v.as_f64()).collect(),
+            other => other.as_f64().map(|x| vec![x]),
+        }
+    }
+}
+
+impl PropertyValue for Vec<String> {
+    fn kind() -> PropertyKind {
+        PropertyKind::Strings
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(_) | Value::Array(_) => Some(strings(value)),
+            _ => None,
+        }
+    }
+}
+
+impl PropertyValue for Vec<u64> {
+    fn kind() -> PropertyKind {
+        PropertyKind::Integers
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Array(values) => values.iter().map(|v| v.as_u64()).collect(),
+            other => other.as_u64().map(|x| vec![x]),
+        }
+    }
+}
+
+impl<T: PropertyValue> PropertyValue for Option<T> {
+    fn kind() -> PropertyKind {
+        T::kind()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        T::from_value(value).map(Some)
+    }
+
+    fn absent() -> Option<Self> {
+        Some(None)
+    }
+}
+
+/// Implemented by `#[derive(Properties)]`.
+pub trait Properties: Sized {
+    fn schema() -> Vec<PropertySpec>;
+
+    fn from_properties(properties: &HashMap<String, Value>) -> Result<Self, String>;
+
+    fn descriptions() -> HashMap<String, String> {
+        Self::schema()
+            .into_iter()
+            .map(|spec| {
+                let description = spec.describe();
+                (spec.name, description)
+            })
+            .collect()
+    }
+
+    /// Parse the properties of an element, exiting if they are invalid. The
+    /// pipeline checks properties before creating elements, so this only
+    /// fails for elements created some other way.
+    fn parse(element: &str, properties: &HashMap<String, Value>) -> Self {
+        match Self::from_properties(properties) {
+            Ok(properties) => properties,
+            Err(e) => {
+                eprintln!("{element}: {e}");
+                std::process::exit(1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn theta() -> PropertySpec {
+        PropertySpec {
+            name: "theta".to_string(),
+            kind: PropertyKind::Float,
+            description: "Barnes-Hut parameter".to_string(),
+            default: Some(json!(1.0)),
+            min: Some(0.0),
+            max: Some(2.0),
+            choices: vec![],
+        }
+    }
+
+    fn mode() -> PropertySpec {
+        PropertySpec {
+            name: "mode".to_string(),
+            kind: PropertyKind::String,
+            description: String::new(),
+            default: Some(json!("wide")),
+            min: None,
+            max: None,
+            choices: vec!["wide".to_string(), "long".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = [theta(), mode()];
+        let ok = HashMap::from([
+            ("theta".to_string(), json!(0.5)),
+            ("mode".to_string(), json!("long")),
+        ]);
+        assert!(validate(&schema, &ok).is_ok());
+        assert!(validate(&schema, &HashMap::new()).is_ok());
+
+        let typo = HashMap::from([("thetta".to_string(), json!(0.5))]);
+        assert_eq!(
+            validate(&schema, &typo).unwrap_err(),
+            "unknown property thetta, did you mean theta?"
+        );
+        let unknown = HashMap::from([("size".to_string(), json!(0.5))]);
+        assert_eq!(
+            validate(&schema, &unknown).unwrap_err(),
+            "unknown property size. Valid properties are mode, theta"
+        );
+        let wrong_type = HashMap::from([("theta".to_string(), json!("big"))]);
+        assert_eq!(
+            validate(&schema, &wrong_type).unwrap_err(),
+            "theta must be a float, got \"big\""
+        );
+        let out_of_range = HashMap::from([("theta".to_string(), json!(3))]);
+        assert_eq!(
+            validate(&schema, &out_of_range).unwrap_err(),
+            "theta must be between 0 and 2, got 3"
+        );
+        let bad_choice = HashMap::from([("mode".to_string(), json!("tall"))]);
+        assert_eq!(
+            validate(&schema, &bad_choice).unwrap_err(),
+            "mode must be one of wide, long, got tall"
+        );
+
+        let schema = [PropertySpec {
+            min: Some(0.0),
+            max: Some(1.0),
+            ..PropertySpec::new("restitution", PropertyKind::Floats(None), "")
+        }];
+        let ok = HashMap::from([("restitution".to_string(), json!([0.5, 1]))]);
+        assert!(validate(&schema, &ok).is_ok());
+        let out_of_range = HashMap::from([("restitution".to_string(), json!([0.5, 2]))]);
+        assert_eq!(
+            validate(&schema, &out_of_range).unwrap_err(),
+            "restitution must be between 0 and 1, got 2"
+        );
+    }
+
+    #[test]
+    fn test_get() {
+        let spec = theta();
+        let given = HashMap::from([("theta".to_string(), json!(2))]);
+        assert_eq!(get::<f64>(&spec, &given), Ok(2.0));
+        assert_eq!(get::<f64>(&spec, &HashMap::new()), Ok(1.0));
+
+        let spec = PropertySpec {
+            name: "centre".to_string(),
+            kind: PropertyKind::Floats(Some(3)),
+            default: None,
+            min: None,
+            max: None,
+            ..mode()
+        };
+        assert_eq!(get::<Option<[f64; 3]>>(&spec, &HashMap::new()), Ok(None));
+        let given = HashMap::from([("centre".to_string(), json!([1, 2.5, 3]))]);
+        assert_eq!(
+            get::<Option<[f64; 3]>>(&spec, &given),
+            Ok(Some([1.0, 2.5, 3.0]))
+        );
+        let given = HashMap::from([("centre".to_string(), json!([1, 2]))]);
+        assert!(get::<Option<[f64; 3]>>(&spec, &given).is_err());
+        let given = HashMap::from([("centre".to_string(), json!(1))]);
+        assert!(get::<Option<[f64; 3]>>(&spec, &given).is_err());
+
+        // a list of any length can be given as one float
+        let spec = PropertySpec {
+            default: Some(json!([1.0])),
+            ..PropertySpec::new("restitution", PropertyKind::Floats(None), "")
+        };
+        assert_eq!(get::<Vec<f64>>(&spec, &HashMap::new()), Ok(vec![1.0]));
+        let given = HashMap::from([("restitution".to_string(), json!(0.5))]);
+        assert_eq!(get::<Vec<f64>>(&spec, &given), Ok(vec![0.5]));
+
+        let fields = HashMap::from([("fields".to_string(), json!("x, y,z"))]);
+        let spec = PropertySpec {
+            name: "fields".to_string(),
+            kind: PropertyKind::Strings,
+            default: None,
+            choices: vec![],
+            ..mode()
+        };
+        assert_eq!(
+            get::<Vec<String>>(&spec, &fields),
+            Ok(vec!["x".to_string(), "y".to_string(), "z".to_string()])
+        );
+
+        let spec = PropertySpec {
+            name: "ids".to_string(),
+            kind: PropertyKind::Integers,
+            default: Some(json!([])),
+            choices: vec![],
+            ..mode()
+        };
+        assert_eq!(get::<Vec<u64>>(&spec, &HashMap::new()), Ok(vec![]));
+        let given = HashMap::from([("ids".to_string(), json!(4))]);
+        assert_eq!(get::<Vec<u64>>(&spec, &given), Ok(vec![4]));
+        let given = HashMap::from([("ids".to_string(), json!([0, 3]))]);
+        assert_eq!(get::<Vec<u64>>(&spec, &given), Ok(vec![0, 3]));
+        let given = HashMap::from([("ids".to_string(), json!([0, -3]))]);
+        assert!(get::<Vec<u64>>(&spec, &given).is_err());
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Shared {
+        theta: f64,
+    }
+
+    impl Properties for Shared {
+        fn schema() -> Vec<PropertySpec> {
+            vec![theta()]
+        }
+
+        fn from_properties(properties: &HashMap<String, Value>) -> Result<Self, String> {
+            let schema = Self::schema();
+            validate(&schema, properties)?;
+            Ok(Self {
+                theta: get(&schema[0], properties)?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_flattened() {
+        let given = HashMap::from([
+            ("theta".to_string(), json!(0.5)),
+            ("mode".to_string(), json!("long")),
+        ]);
+        assert_eq!(flattened::<Shared>(&given), Ok(Shared { theta: 0.5 }));
+        let given = HashMap::from([("mode".to_string(), json!("long"))]);
+        assert_eq!(flattened::<Shared>(&given), Ok(Shared { theta: 1.0 }));
+        let given = HashMap::from([("theta".to_string(), json!(3))]);
+        assert!(flattened::<Shared>(&given).is_err());
+    }
+
+    #[test]
+    fn test_suggest() {
+        let names = ["theta", "e", "mass", "radius", "iterations"];
+        assert_eq!(suggest("thetta", &names), Some("theta"));
+        assert_eq!(suggest("iteration", &names), Some("iterations"));
+        assert_eq!(suggest("m", &names), Some("mass"));
+        assert_eq!(suggest("r", &names), Some("radius"));
+        assert_eq!(suggest("size", &names), None);
+        assert_eq!(suggest("x", &names), None);
+        assert_eq!(suggest("thetta", &[]), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            theta().describe(),
+            "Barnes-Hut parameter (float, default 1.0, between 0 and 2)"
+        );
+        assert_eq!(
+            mode().describe(),
+            "(string, default wide, one of wide, long)"
+        );
+    }
+}
@@ -4,8 +4,65 @@ use crate::{messages::MessageClient, Entity};
 
 use super::Element;
 
+pub use crate::broadcast::Backpressure;
+
+/// A state of the simulation as it is sent to render elements.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    /// Number of completed iterations
+    pub iteration: u64,
+    /// Simulated time
+    pub time: f64,
+    /// The timestep used to reach this state
+    pub dt: f64,
+    pub entities: Vec<Entity>,
+}
+
+/// Picks the frames a sink writes: every `stride`th iteration, starting with
+/// the initial state, and each iteration only once.
+#[derive(Debug)]
+pub struct FrameStride {
+    stride: u64,
+    last_iteration: Option<u64>,
+}
+
+impl FrameStride {
+    pub fn new(stride: u64) -> Self {
+        Self {
+            stride: stride.max(1),
+            last_iteration: None,
+        }
+    }
+
+    /// Whether `frame` should be written
+    pub fn accept(&mut self, frame: &Frame) -> bool {
+        // frames are repeated while the simulation is paused
+        if self.last_iteration == Some(frame.iteration) {
+            return false;
+        }
+        // the first frame is the initial state, so it is always written
+        if self.last_iteration.is_some() && !frame.iteration.is_multiple_of(self.stride) {
+            return false;
+        }
+        self.last_iteration = Some(frame.iteration);
+        true
+    }
+}
+
 pub trait RenderElement: Element + Send + Sync + MessageClient {
-    fn render(&self, state_recv: Receiver<Vec<Entity>>);
+    fn render(&self, state_recv: Receiver<Frame>);
+
+    /// The backpressure policy used when the pipeline doesn't give one.
+    /// Sinks which record every state should use `Backpressure::Block`.
+    fn default_backpressure(&self) -> Backpressure {
+        Backpressure::default()
+    }
+
+    /// Whether `render` must be called on the main thread, as windowing
+    /// libraries require. A pipeline can only have one such element.
+    fn needs_main_thread(&self) -> bool {
+        false
+    }
 }
 pub struct RenderElementHandler {
     instance: Box<dyn RenderElement>,
@@ -20,15 +77,31 @@ impl super::Loadable for RenderElementHandler {
 }
 
 impl RenderElementHandler {
-    pub fn render(&self, state_recv: Receiver<Vec<Entity>>) {
+    pub fn render(&self, state_recv: Receiver<Frame>) {
         self.instance.render(state_recv);
     }
+
+    pub fn default_backpressure(&self) -> Backpressure {
+        self.instance.default_backpressure()
+    }
+
+    pub fn needs_main_thread(&self) -> bool {
+        self.instance.needs_main_thread()
+    }
 }
 
 impl Element for RenderElementHandler {
     fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
         self.instance.get_property_descriptions()
     }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.instance.checkpoint_state()
+    }
+
+    fn restore_state(&self, state: serde_json::Value) -> Result<(), Box<dyn Error>> {
+        self.instance.restore_state(state)
+    }
 }
 
 impl MessageClient for RenderElementHandler {
@@ -39,3 +112,24 @@ impl MessageClient for RenderElementHandler {
         self.instance.post_configuration_messages();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_stride() {
+        let mut stride = FrameStride::new(2);
+        let accepted: Vec<u64> = [3, 3, 4, 5, 5, 6, 6, 8]
+            .into_iter()
+            .filter(|&iteration| {
+                stride.accept(&Frame {
+                    iteration,
+                    ..Default::default()
+                })
+            })
+            .collect();
+        // a restarted simulation starts from wherever it got to
+        assert_eq!(accepted, [3, 4, 6, 8]);
+    }
+}
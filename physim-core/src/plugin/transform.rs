@@ -26,9 +26,60 @@ pub enum TransformElementLoadError {
 pub trait TransformElement: Send + Sync {
     fn new(properties: HashMap<String, Value>) -> Self;
     fn transform(&self, state: &[Entity], acceleration: &mut [Acceleration]);
+    /// Add the accelerations of only the entities in `targets`, leaving the
+    /// rest alone. By default every acceleration is calculated and those of
+    /// the other entities are thrown away.
+    fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        acceleration: &mut [Acceleration],
+    ) {
+        add_targets(state, targets, acceleration, |all| {
+            self.transform(state, all)
+        });
+    }
     fn get_property_descriptions(&self) -> HashMap<String, String>;
+    /// A timestep suited to the states seen since the last time this was
+    /// called. Pipelines with an adaptive timestep keep `dt` below the
+    /// smallest suggestion.
+    fn suggested_dt(&self) -> Option<f64> {
+        None
+    }
+    /// State to write to checkpoints, as for [`Element::checkpoint_state`]
+    fn checkpoint_state(&self) -> Option<Value> {
+        None
+    }
+    /// Restore state previously returned by `checkpoint_state`
+    fn restore_state(&self, _state: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 }
 
+/// Add the accelerations calculated by `transform` for `targets`
+fn add_targets(
+    state: &[Entity],
+    targets: &[usize],
+    acceleration: &mut [Acceleration],
+    transform: impl FnOnce(&mut [Acceleration]),
+) {
+    let mut all = vec![Acceleration::zero(); state.len()];
+    transform(&mut all);
+    for &i in targets {
+        acceleration[i] += all[i];
+    }
+}
+
+type TransformTargetsFn = unsafe extern "C" fn(
+    *const std::ffi::c_void,
+    *const Entity,
+    usize,
+    *const usize,
+    usize,
+    *mut Acceleration,
+    usize,
+);
+
 #[repr(C)]
 pub struct TransformElementAPI {
     pub init: unsafe extern "C" fn(*const u8, usize) -> *mut std::ffi::c_void,
@@ -49,9 +100,20 @@ pub struct TransformElementAPI {
     pub post_configuration_messages: unsafe extern "C" fn(obj: *mut std::ffi::c_void),
 }
 
+type CheckpointStateFn = unsafe extern "C" fn(
+    *const std::ffi::c_void,
+    crate::plugin::RustStringAllocFn,
+) -> *mut std::ffi::c_char;
+
+type RestoreStateFn = unsafe extern "C" fn(*const std::ffi::c_void, *const u8, usize) -> bool;
+
 pub struct TransformElementHandler {
     api: &'static TransformElementAPI,
     instance: AtomicPtr<std::ffi::c_void>,
+    suggested_dt: Option<unsafe extern "C" fn(*const std::ffi::c_void) -> f64>,
+    transform_targets: Option<TransformTargetsFn>,
+    checkpoint_state: Option<CheckpointStateFn>,
+    restore_state: Option<RestoreStateFn>,
 }
 
 impl TransformElementHandler {
@@ -69,6 +131,25 @@ impl TransformElementHandler {
                 lib.get(api_fn_name.as_bytes())
                     .map_err(TransformElementLoadError::DylibError)?;
             let api = get_api();
+            // plugins built before suggested timesteps existed don't have this
+            let suggested_dt = lib
+                .get::<unsafe extern "C" fn(*const std::ffi::c_void) -> f64>(
+                    format!("{name}_suggested_dt").as_bytes(),
+                )
+                .ok()
+                .map(|f| *f);
+            let transform_targets = lib
+                .get::<TransformTargetsFn>(format!("{name}_transform_targets").as_bytes())
+                .ok()
+                .map(|f| *f);
+            let checkpoint_state = lib
+                .get::<CheckpointStateFn>(format!("{name}_checkpoint_state").as_bytes())
+                .ok()
+                .map(|f| *f);
+            let restore_state = lib
+                .get::<RestoreStateFn>(format!("{name}_restore_state").as_bytes())
+                .ok()
+                .map(|f| *f);
             let (c, u, _l) = properties.into_raw_parts();
             let instance = ((*api).init)(c, u);
             if instance.is_null() {
@@ -77,6 +158,10 @@ impl TransformElementHandler {
             let element = Arc::new(Self {
                 api: &*api,
                 instance: AtomicPtr::new(instance),
+                suggested_dt,
+                transform_targets,
+                checkpoint_state,
+                restore_state,
             });
             Ok(element)
         }
@@ -103,6 +188,47 @@ impl TransformElementHandler {
         }
     }
 
+    /// Add the accelerations of the entities in `targets`
+    pub fn transform_targets(
+        &self,
+        state: &[Entity],
+        targets: &[usize],
+        acceleration: &mut [Acceleration],
+    ) {
+        let Some(transform_targets) = self.transform_targets else {
+            add_targets(state, targets, acceleration, |all| {
+                self.transform(state, all)
+            });
+            return;
+        };
+        let instance = self.instance.load(Ordering::SeqCst);
+        if instance.is_null() {
+            eprintln!("Transform is not loaded");
+        } else {
+            unsafe {
+                transform_targets(
+                    instance,
+                    state.as_ptr(),
+                    state.len(),
+                    targets.as_ptr(),
+                    targets.len(),
+                    acceleration.as_mut_ptr(),
+                    acceleration.len(),
+                );
+            }
+        }
+    }
+
+    pub fn suggested_dt(&self) -> Option<f64> {
+        let suggested_dt = self.suggested_dt?;
+        let instance = self.instance.load(Ordering::SeqCst);
+        if instance.is_null() {
+            return None;
+        }
+        let dt = unsafe { suggested_dt(instance) };
+        (dt.is_finite() && dt > 0.0).then_some(dt)
+    }
+
     pub fn destroy(&self) {
         unsafe {
             (self.api.destroy)(self.instance.load(Ordering::SeqCst));
@@ -125,6 +251,36 @@ impl Element for TransformElementHandler {
         let v = value.to_str().map_err(Box::new)?;
         Ok(serde_json::from_str(v).map_err(Box::new)?)
     }
+
+    fn checkpoint_state(&self) -> Option<Value> {
+        let checkpoint_state = self.checkpoint_state?;
+        let instance = self.instance.load(Ordering::SeqCst);
+        if instance.is_null() {
+            return None;
+        }
+        // null means there is no state to keep
+        let value = unsafe { checkpoint_state(instance, host_alloc_string) };
+        if value.is_null() {
+            return None;
+        }
+        let value = unsafe { std::ffi::CString::from_raw(value) };
+        serde_json::from_str(value.to_str().ok()?).ok()
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn Error>> {
+        let Some(restore_state) = self.restore_state else {
+            return Err("Transform has no restore_state".into());
+        };
+        let instance = self.instance.load(Ordering::SeqCst);
+        if instance.is_null() {
+            return Err("Transform is not loaded".into());
+        }
+        let state = serde_json::to_string(&state)?;
+        match unsafe { restore_state(instance, state.as_ptr(), state.len()) } {
+            true => Ok(()),
+            false => Err("Transform rejected the state".into()),
+        }
+    }
 }
 
 impl Drop for TransformElementHandler {
@@ -22,6 +22,14 @@ impl Element for TransmuteElementHandler {
     ) -> Result<std::collections::HashMap<String, String>, Box<dyn std::error::Error>> {
         self.instance.get_property_descriptions()
     }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.instance.checkpoint_state()
+    }
+
+    fn restore_state(&self, state: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        self.instance.restore_state(state)
+    }
 }
 
 impl super::Loadable for TransmuteElementHandler {
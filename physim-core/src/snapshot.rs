@@ -0,0 +1,276 @@
+//! Snapshots are files containing a single state of a simulation. They are
+//! used to seed simulations from catalogues or from the end of another run.
+//!
+//! # CSV layout
+//! The first line is a header naming the columns, which may be any of the
+//! `Entity` fields `x,y,z,vx,vy,vz,radius,mass,id,fixed` in any order.
+//! `x`, `y` and `z` are required and the other fields default to zero (or
+//! false). Other columns are ignored, except for `iteration` which selects
+//! the frame to read when the file holds more than one. Blank lines and
+//! lines starting with `#` are skipped.
+//!
+//! # Binary layout
+//! All values are little endian. The file starts with a 20 byte header:
+//! the magic bytes `PHYSNAP1` (8 bytes), a u32 version which is currently
+//! 1, and the number of entities as a u64. Each entity is then a 73 byte
+//! record: `x,y,z,vx,vy,vz,radius,mass` as f64, `id` as u64 and `fixed` as
+//! a u8 which is 0 or 1.
+
+use std::{
+    error::Error,
+    io::{BufRead, Read, Write},
+};
+
+use crate::Entity;
+
+pub const BINARY_MAGIC: &[u8; 8] = b"PHYSNAP1";
+pub const BINARY_VERSION: u32 = 1;
+const BINARY_RECORD_SIZE: usize = 8 * 8 + 8 + 1;
+
+pub const ENTITY_FIELDS: [&str; 10] = [
+    "x", "y", "z", "vx", "vy", "vz", "radius", "mass", "id", "fixed",
+];
+
+/// Which frame of a CSV file with an `iteration` column to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotFrame {
+    #[default]
+    Last,
+    Iteration(u64),
+}
+
+fn set_field(entity: &mut Entity, field: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let value = value.trim();
+    match field {
+        "x" => entity.x = value.parse()?,
+        "y" => entity.y = value.parse()?,
+        "z" => entity.z = value.parse()?,
+        "vx" => entity.vx = value.parse()?,
+        "vy" => entity.vy = value.parse()?,
+        "vz" => entity.vz = value.parse()?,
+        "radius" => entity.radius = value.parse()?,
+        "mass" => entity.mass = value.parse()?,
+        "id" => entity.id = value.parse()?,
+        "fixed" => {
+            entity.fixed = match value {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => return Err(format!("{value} is not a boolean").into()),
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn read_csv<R: BufRead>(
+    reader: R,
+    frame: SnapshotFrame,
+) -> Result<Vec<Entity>, Box<dyn Error>> {
+    let mut lines = reader.lines().enumerate().filter(|(_, line)| {
+        line.as_ref()
+            .map(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+            .unwrap_or(true)
+    });
+
+    let header = match lines.next() {
+        Some((_, line)) => line?,
+        None => return Ok(vec![]),
+    };
+    let columns: Vec<String> = header.split(',').map(|c| c.trim().to_string()).collect();
+    for required in ["x", "y", "z"] {
+        if !columns.iter().any(|c| c == required) {
+            return Err(format!("Snapshot header is missing the {required} column").into());
+        }
+    }
+    let iteration_column = columns.iter().position(|c| c == "iteration");
+
+    // only the entities of the selected frame are kept
+    let mut entities = vec![];
+    let mut current_iteration = None;
+    for (idx, line) in lines {
+        let line = line?;
+        let line_num = idx + 1;
+        let values: Vec<&str> = line.split(',').collect();
+        if values.len() != columns.len() {
+            return Err(format!(
+                "Line {line_num} has {} values but the header has {} columns",
+                values.len(),
+                columns.len()
+            )
+            .into());
+        }
+        if let Some(col) = iteration_column {
+            let iteration: u64 = values[col]
+                .trim()
+                .parse()
+                .map_err(|e| format!("Line {line_num}: invalid iteration: {e}"))?;
+            match frame {
+                SnapshotFrame::Iteration(i) if i != iteration => continue,
+                SnapshotFrame::Last if current_iteration != Some(iteration) => entities.clear(),
+                _ => {}
+            }
+            current_iteration = Some(iteration);
+        }
+        let mut entity = Entity::default();
+        for (column, value) in columns.iter().zip(values) {
+            set_field(&mut entity, column, value)
+                .map_err(|e| format!("Line {line_num}: invalid {column}: {e}"))?;
+        }
+        entities.push(entity);
+    }
+
+    if let (SnapshotFrame::Iteration(i), Some(_), None) =
+        (frame, iteration_column, current_iteration)
+    {
+        return Err(format!("Snapshot has no entities for iteration {i}").into());
+    }
+    Ok(entities)
+}
+
+pub fn read_binary<R: Read>(mut reader: R) -> Result<Vec<Entity>, Box<dyn Error>> {
+    let mut header = [0_u8; 20];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| "Snapshot is too short to be a binary snapshot")?;
+    if &header[0..8] != BINARY_MAGIC {
+        return Err("Snapshot is not a binary snapshot".into());
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into()?);
+    if version != BINARY_VERSION {
+        return Err(format!(
+            "Binary snapshot has version {version} but only version {BINARY_VERSION} is supported"
+        )
+        .into());
+    }
+    let n = u64::from_le_bytes(header[12..20].try_into()?) as usize;
+
+    // n isn't trusted to allocate, as a corrupt header can claim any number
+    let mut entities = Vec::new();
+    let mut record = [0_u8; BINARY_RECORD_SIZE];
+    for idx in 0..n {
+        reader
+            .read_exact(&mut record)
+            .map_err(|_| format!("Binary snapshot ended after {idx} of {n} entities"))?;
+        let f = |i: usize| f64::from_le_bytes(record[i * 8..(i + 1) * 8].try_into().unwrap());
+        entities.push(Entity {
+            x: f(0),
+            y: f(1),
+            z: f(2),
+            vx: f(3),
+            vy: f(4),
+            vz: f(5),
+            radius: f(6),
+            mass: f(7),
+            id: u64::from_le_bytes(record[64..72].try_into()?) as usize,
+            fixed: match record[72] {
+                0 => false,
+                1 => true,
+                v => return Err(format!("Entity {idx} has invalid fixed value {v}").into()),
+            },
+        });
+    }
+    Ok(entities)
+}
+
+pub fn write_binary<W: Write>(mut writer: W, entities: &[Entity]) -> std::io::Result<()> {
+    writer.write_all(BINARY_MAGIC)?;
+    writer.write_all(&BINARY_VERSION.to_le_bytes())?;
+    writer.write_all(&(entities.len() as u64).to_le_bytes())?;
+    for e in entities {
+        for v in [e.x, e.y, e.z, e.vx, e.vy, e.vz, e.radius, e.mass] {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        writer.write_all(&(e.id as u64).to_le_bytes())?;
+        writer.write_all(&[e.fixed as u8])?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities() -> Vec<Entity> {
+        vec![
+            Entity {
+                x: 0.1,
+                y: -2.5,
+                z: 3.0,
+                vx: 1.0 / 3.0,
+                vy: -1e-12,
+                vz: 7.0,
+                radius: 0.05,
+                mass: 12.0,
+                id: 42,
+                fixed: true,
+            },
+            Entity::new(1.0, 2.0, 3.0, 4.0),
+        ]
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut buffer = vec![];
+        write_binary(&mut buffer, &entities()).unwrap();
+        assert_eq!(buffer.len(), 20 + 2 * BINARY_RECORD_SIZE);
+        assert_eq!(read_binary(buffer.as_slice()).unwrap(), entities());
+    }
+
+    #[test]
+    fn test_binary_truncated() {
+        let mut buffer = vec![];
+        write_binary(&mut buffer, &entities()).unwrap();
+        buffer.pop();
+        assert!(read_binary(buffer.as_slice()).is_err());
+
+        let mut header = vec![];
+        write_binary(&mut header, &[]).unwrap();
+        header[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_binary(header.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_csv_all_fields() {
+        let csv = "# a catalogue\nid,mass,x,y,z,vx,vy,vz,radius,fixed\n\n42,12,0.1,-2.5,3,0.3333333333333333,-1e-12,7,0.05,true\n0,4,1,2,3,0,0,0,1.5815,0\n";
+        let read = read_csv(csv.as_bytes(), SnapshotFrame::Last).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0], entities()[0]);
+        assert_eq!(read[1].radius, 1.5815);
+        assert!(!read[1].fixed);
+    }
+
+    #[test]
+    fn test_csv_defaults_and_extra_columns() {
+        let csv = "x,y,z,name\n1,2,3,sol\n";
+        let read = read_csv(csv.as_bytes(), SnapshotFrame::Last).unwrap();
+        assert_eq!(
+            read,
+            vec![Entity {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_csv_frames() {
+        let csv = "iteration,x,y,z\n0,1,1,1\n0,2,2,2\n5,3,3,3\n";
+        let last = read_csv(csv.as_bytes(), SnapshotFrame::Last).unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].x, 3.0);
+        let first = read_csv(csv.as_bytes(), SnapshotFrame::Iteration(0)).unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(read_csv(csv.as_bytes(), SnapshotFrame::Iteration(3)).is_err());
+    }
+
+    #[test]
+    fn test_csv_errors() {
+        assert!(read_csv("x,y\n1,2\n".as_bytes(), SnapshotFrame::Last).is_err());
+        assert!(read_csv("x,y,z\n1,2\n".as_bytes(), SnapshotFrame::Last).is_err());
+        assert!(read_csv("x,y,z\n1,2,a\n".as_bytes(), SnapshotFrame::Last).is_err());
+        assert!(read_csv("x,y,z,fixed\n1,2,3,maybe\n".as_bytes(), SnapshotFrame::Last).is_err());
+    }
+}
@@ -0,0 +1,648 @@
+//! Trajectories are a series of frames stored in a chunked, columnar
+//! container. The container is a [Zarr v2](https://zarr.readthedocs.io)
+//! directory store, so it can be read by zarr, xarray, dask and friends
+//! as well as with the [`Trajectory`] reader in this module.
+//!
+//! # Layout
+//! The root of the store is a group whose attributes describe the
+//! trajectory, including the pipeline which produced it. Every entity field
+//! which was recorded is a 1D array holding the rows of every frame one
+//! after another. The frame arrays `iteration`, `time`, `dt`, `offset` and
+//! `count` have one element per frame, and the rows of frame `i` are
+//! `offset[i]..offset[i] + count[i]`. This allows the number of entities to
+//! change between frames.
+//!
+//! Chunks are uncompressed little endian values. Array metadata is updated
+//! every time a chunk is written, so a trajectory which is still being
+//! written, or whose writer was interrupted, can be read up to the last
+//! complete chunk.
+
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{plugin::render::Frame, snapshot::ENTITY_FIELDS, Entity};
+
+pub const TRAJECTORY_FORMAT: &str = "physim-trajectory";
+pub const TRAJECTORY_VERSION: u64 = 1;
+
+const FRAME_ARRAYS: [&str; 5] = ["iteration", "time", "dt", "offset", "count"];
+const FRAME_CHUNK_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DType {
+    Float,
+    UInt,
+    Bool,
+}
+
+impl DType {
+    fn of(name: &str) -> Option<Self> {
+        match name {
+            "x" | "y" | "z" | "vx" | "vy" | "vz" | "radius" | "mass" | "time" | "dt" => {
+                Some(DType::Float)
+            }
+            "id" | "iteration" | "offset" | "count" => Some(DType::UInt),
+            "fixed" => Some(DType::Bool),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            DType::Float | DType::UInt => 8,
+            DType::Bool => 1,
+        }
+    }
+
+    fn zarr_dtype(&self) -> &'static str {
+        match self {
+            DType::Float => "<f8",
+            DType::UInt => "<u8",
+            DType::Bool => "|b1",
+        }
+    }
+
+    fn fill_value(&self) -> Value {
+        match self {
+            DType::Float => json!(0.0),
+            DType::UInt => json!(0),
+            DType::Bool => json!(false),
+        }
+    }
+}
+
+fn entity_field_bytes(entity: &Entity, field: &str) -> Vec<u8> {
+    match field {
+        "x" => entity.x.to_le_bytes().to_vec(),
+        "y" => entity.y.to_le_bytes().to_vec(),
+        "z" => entity.z.to_le_bytes().to_vec(),
+        "vx" => entity.vx.to_le_bytes().to_vec(),
+        "vy" => entity.vy.to_le_bytes().to_vec(),
+        "vz" => entity.vz.to_le_bytes().to_vec(),
+        "radius" => entity.radius.to_le_bytes().to_vec(),
+        "mass" => entity.mass.to_le_bytes().to_vec(),
+        "id" => (entity.id as u64).to_le_bytes().to_vec(),
+        "fixed" => vec![entity.fixed as u8],
+        _ => unreachable!("fields are checked when the writer is created"),
+    }
+}
+
+fn set_entity_field(entity: &mut Entity, field: &str, bytes: &[u8]) {
+    let float = || f64::from_le_bytes(bytes.try_into().expect("f64 columns have 8 bytes"));
+    match field {
+        "x" => entity.x = float(),
+        "y" => entity.y = float(),
+        "z" => entity.z = float(),
+        "vx" => entity.vx = float(),
+        "vy" => entity.vy = float(),
+        "vz" => entity.vz = float(),
+        "radius" => entity.radius = float(),
+        "mass" => entity.mass = float(),
+        "id" => {
+            entity.id =
+                u64::from_le_bytes(bytes.try_into().expect("u64 columns have 8 bytes")) as usize
+        }
+        "fixed" => entity.fixed = bytes[0] != 0,
+        _ => {}
+    }
+}
+
+fn write_json(path: &Path, value: &Value) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(value)?)
+}
+
+fn read_json(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+struct ColumnWriter {
+    dir: PathBuf,
+    dtype: DType,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    chunks_written: usize,
+    len: usize,
+}
+
+impl ColumnWriter {
+    fn create(root: &Path, name: &str, chunk_size: usize) -> io::Result<Self> {
+        let dtype = DType::of(name).expect("column names are checked by the caller");
+        let dir = root.join(name);
+        fs::create_dir_all(&dir)?;
+        let column = Self {
+            dir,
+            dtype,
+            chunk_size,
+            buffer: Vec::with_capacity(chunk_size * dtype.size()),
+            chunks_written: 0,
+            len: 0,
+        };
+        column.write_metadata(0)?;
+        Ok(column)
+    }
+
+    fn write_metadata(&self, len: usize) -> io::Result<()> {
+        write_json(
+            &self.dir.join(".zarray"),
+            &json!({
+                "zarr_format": 2,
+                "shape": [len],
+                "chunks": [self.chunk_size],
+                "dtype": self.dtype.zarr_dtype(),
+                "compressor": null,
+                "fill_value": self.dtype.fill_value(),
+                "order": "C",
+                "filters": null,
+            }),
+        )
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        self.buffer.resize(self.chunk_size * self.dtype.size(), 0);
+        fs::write(self.dir.join(self.chunks_written.to_string()), &self.buffer)?;
+        self.buffer.clear();
+        self.chunks_written += 1;
+        Ok(())
+    }
+
+    /// Returns true if a chunk was written
+    fn push(&mut self, bytes: &[u8]) -> io::Result<bool> {
+        self.buffer.extend_from_slice(bytes);
+        self.len += 1;
+        if self.buffer.len() == self.chunk_size * self.dtype.size() {
+            self.write_chunk()?;
+            self.write_metadata(self.len)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Write the partially filled chunk so that every element pushed so far
+    /// can be read. The chunk is overwritten once it is filled.
+    fn sync(&self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let mut chunk = self.buffer.clone();
+            chunk.resize(self.chunk_size * self.dtype.size(), 0);
+            fs::write(self.dir.join(self.chunks_written.to_string()), chunk)?;
+        }
+        self.write_metadata(self.len)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.write_chunk()?;
+        }
+        self.write_metadata(self.len)
+    }
+}
+
+/// Writes frames to a trajectory. The trajectory is completed when the
+/// writer is finished or dropped.
+pub struct TrajectoryWriter {
+    fields: Vec<String>,
+    columns: Vec<ColumnWriter>,
+    frame_columns: Vec<ColumnWriter>,
+    rows: u64,
+    finished: bool,
+}
+
+impl TrajectoryWriter {
+    /// Create a trajectory at `path` recording `fields` of each entity with
+    /// `chunk_size` rows per chunk. `attributes` are stored on the root
+    /// group. An existing trajectory at `path` is replaced, but any other
+    /// existing file or directory is an error.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        fields: &[String],
+        chunk_size: usize,
+        attributes: Map<String, Value>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let root = path.as_ref();
+        if let Some(field) = fields.iter().find(|f| !ENTITY_FIELDS.contains(&f.as_str())) {
+            return Err(format!(
+                "{field} is not an entity field. Choose from {}",
+                ENTITY_FIELDS.join(",")
+            )
+            .into());
+        }
+        if chunk_size == 0 {
+            return Err("The chunk size of a trajectory must be at least 1".into());
+        }
+        if root.exists() {
+            let is_trajectory = read_json(&root.join(".zattrs"))
+                .ok()
+                .is_some_and(|attrs| attrs["format"] == TRAJECTORY_FORMAT);
+            if !is_trajectory {
+                return Err(
+                    format!("{} already exists and is not a trajectory", root.display()).into(),
+                );
+            }
+            fs::remove_dir_all(root)?;
+        }
+        fs::create_dir_all(root)?;
+
+        let mut root_attributes = attributes;
+        root_attributes.insert("format".to_string(), json!(TRAJECTORY_FORMAT));
+        root_attributes.insert("version".to_string(), json!(TRAJECTORY_VERSION));
+        root_attributes.insert("fields".to_string(), json!(fields));
+        write_json(&root.join(".zgroup"), &json!({"zarr_format": 2}))?;
+        write_json(&root.join(".zattrs"), &Value::Object(root_attributes))?;
+
+        let columns = fields
+            .iter()
+            .map(|f| ColumnWriter::create(root, f, chunk_size))
+            .collect::<io::Result<_>>()?;
+        let frame_columns = FRAME_ARRAYS
+            .iter()
+            .map(|f| ColumnWriter::create(root, f, FRAME_CHUNK_SIZE))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            fields: fields.to_vec(),
+            columns,
+            frame_columns,
+            rows: 0,
+            finished: false,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut wrote_chunk = false;
+        for entity in frame.entities.iter() {
+            for (field, column) in self.fields.iter().zip(self.columns.iter_mut()) {
+                wrote_chunk |= column.push(&entity_field_bytes(entity, field))?;
+            }
+        }
+        let count = frame.entities.len() as u64;
+        let values = [
+            frame.iteration.to_le_bytes(),
+            frame.time.to_le_bytes(),
+            frame.dt.to_le_bytes(),
+            self.rows.to_le_bytes(),
+            count.to_le_bytes(),
+        ];
+        for (column, value) in self.frame_columns.iter_mut().zip(values) {
+            column.push(&value)?;
+        }
+        self.rows += count;
+        // keep the frames up to date with the rows which can be read
+        if wrote_chunk {
+            for column in self.frame_columns.iter() {
+                column.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any partially filled chunks and the final array metadata.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finish_columns()
+    }
+
+    fn finish_columns(&mut self) -> io::Result<()> {
+        self.finished = true;
+        for column in self.columns.iter_mut().chain(self.frame_columns.iter_mut()) {
+            column.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TrajectoryWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.finish_columns() {
+                log::error!("Failed to finish trajectory: {e}");
+            }
+        }
+    }
+}
+
+struct ColumnReader {
+    dir: PathBuf,
+    dtype: DType,
+    chunk_size: usize,
+    len: usize,
+}
+
+impl ColumnReader {
+    fn open(root: &Path, name: &str) -> Result<Self, Box<dyn Error>> {
+        let dir = root.join(name);
+        let meta = read_json(&dir.join(".zarray"))?;
+        let dtype = DType::of(name).ok_or(format!("{name} is not a trajectory array"))?;
+        if meta["dtype"] != dtype.zarr_dtype() || !meta["compressor"].is_null() {
+            return Err(format!(
+                "{name} is not stored as uncompressed {}",
+                dtype.zarr_dtype()
+            )
+            .into());
+        }
+        let len = meta["shape"][0]
+            .as_u64()
+            .ok_or(format!("{name} has an invalid shape"))? as usize;
+        let chunk_size = meta["chunks"][0]
+            .as_u64()
+            .filter(|c| *c > 0)
+            .ok_or(format!("{name} has an invalid chunk size"))? as usize;
+        Ok(Self {
+            dir,
+            dtype,
+            chunk_size,
+            len,
+        })
+    }
+
+    /// Read the raw bytes of elements `start..end`.
+    fn read_range(&self, start: usize, end: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let size = self.dtype.size();
+        let mut bytes = Vec::with_capacity((end - start) * size);
+        let mut idx = start;
+        while idx < end {
+            let chunk = idx / self.chunk_size;
+            let chunk_start = chunk * self.chunk_size;
+            let chunk_end = end.min(chunk_start + self.chunk_size);
+            let data = match fs::read(self.dir.join(chunk.to_string())) {
+                Ok(data) => data,
+                // zarr leaves chunks which only contain the fill value unwritten
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    vec![0; self.chunk_size * size]
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let range = (idx - chunk_start) * size..(chunk_end - chunk_start) * size;
+            bytes.extend_from_slice(data.get(range).ok_or(format!(
+                "Chunk {chunk} of {} is truncated",
+                self.dir.display()
+            ))?);
+            idx = chunk_end;
+        }
+        Ok(bytes)
+    }
+
+    fn read_all_u64(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self
+            .read_range(0, self.len)?
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().expect("chunks of 8")))
+            .collect())
+    }
+
+    fn read_all_f64(&self) -> Result<Vec<f64>, Box<dyn Error>> {
+        Ok(self
+            .read_range(0, self.len)?
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().expect("chunks of 8")))
+            .collect())
+    }
+}
+
+/// Reads trajectories written by [`TrajectoryWriter`].
+pub struct Trajectory {
+    attributes: Map<String, Value>,
+    fields: Vec<String>,
+    columns: Vec<ColumnReader>,
+    iterations: Vec<u64>,
+    times: Vec<f64>,
+    dts: Vec<f64>,
+    offsets: Vec<u64>,
+    counts: Vec<u64>,
+}
+
+impl Trajectory {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let root = path.as_ref();
+        let attributes = match read_json(&root.join(".zattrs"))? {
+            Value::Object(attributes) => attributes,
+            _ => return Err(format!("{} has invalid attributes", root.display()).into()),
+        };
+        if attributes.get("format") != Some(&json!(TRAJECTORY_FORMAT)) {
+            return Err(format!("{} is not a trajectory", root.display()).into());
+        }
+        if attributes.get("version") != Some(&json!(TRAJECTORY_VERSION)) {
+            return Err(format!(
+                "{} has an unsupported version. Only version {TRAJECTORY_VERSION} is supported",
+                root.display()
+            )
+            .into());
+        }
+        let fields: Vec<String> = serde_json::from_value(
+            attributes
+                .get("fields")
+                .cloned()
+                .ok_or(format!("{} does not list its fields", root.display()))?,
+        )?;
+        let columns = fields
+            .iter()
+            .map(|f| ColumnReader::open(root, f))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut iterations = ColumnReader::open(root, "iteration")?.read_all_u64()?;
+        let mut times = ColumnReader::open(root, "time")?.read_all_f64()?;
+        let mut dts = ColumnReader::open(root, "dt")?.read_all_f64()?;
+        let mut offsets = ColumnReader::open(root, "offset")?.read_all_u64()?;
+        let mut counts = ColumnReader::open(root, "count")?.read_all_u64()?;
+
+        // Arrays are flushed independently, so only frames whose data has
+        // been completely written are readable.
+        let rows = columns.iter().map(|c| c.len).min().unwrap_or(usize::MAX) as u64;
+        let frames = [
+            iterations.len(),
+            times.len(),
+            dts.len(),
+            offsets.len(),
+            counts.len(),
+        ]
+        .into_iter()
+        .min()
+        .unwrap_or_default();
+        let complete = (0..frames)
+            .take_while(|&i| offsets[i] + counts[i] <= rows)
+            .count();
+
+        iterations.truncate(complete);
+        times.truncate(complete);
+        dts.truncate(complete);
+        offsets.truncate(complete);
+        counts.truncate(complete);
+        Ok(Self {
+            attributes,
+            fields,
+            columns,
+            iterations,
+            times,
+            dts,
+            offsets,
+            counts,
+        })
+    }
+
+    /// The number of frames in the trajectory
+    pub fn len(&self) -> usize {
+        self.iterations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iterations.is_empty()
+    }
+
+    /// The entity fields which were recorded. The other fields of entities
+    /// read from the trajectory are left at their default values.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn attributes(&self) -> &Map<String, Value> {
+        &self.attributes
+    }
+
+    /// The description of the pipeline which produced the trajectory
+    pub fn pipeline(&self) -> Option<&str> {
+        self.attributes.get("pipeline").and_then(|p| p.as_str())
+    }
+
+    pub fn iterations(&self) -> &[u64] {
+        &self.iterations
+    }
+
+    pub fn times(&self) -> &[f64] {
+        &self.times
+    }
+
+    pub fn read_frame(&self, idx: usize) -> Result<Frame, Box<dyn Error>> {
+        if idx >= self.len() {
+            return Err(format!("Frame {idx} is out of range for {} frames", self.len()).into());
+        }
+        let start = self.offsets[idx] as usize;
+        let end = start + self.counts[idx] as usize;
+        let mut entities = vec![Entity::default(); end - start];
+        for (field, column) in self.fields.iter().zip(self.columns.iter()) {
+            let bytes = column.read_range(start, end)?;
+            for (entity, value) in entities
+                .iter_mut()
+                .zip(bytes.chunks_exact(column.dtype.size()))
+            {
+                set_entity_field(entity, field, value);
+            }
+        }
+        Ok(Frame {
+            iteration: self.iterations[idx],
+            time: self.times[idx],
+            dt: self.dts[idx],
+            entities,
+        })
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = Result<Frame, Box<dyn Error>>> + '_ {
+        (0..self.len()).map(|idx| self.read_frame(idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_fields() -> Vec<String> {
+        ENTITY_FIELDS.iter().map(|f| f.to_string()).collect()
+    }
+
+    fn frame(iteration: u64, n: usize) -> Frame {
+        let entities = (0..n)
+            .map(|i| Entity {
+                x: iteration as f64 + i as f64 / 10.0,
+                y: -(i as f64),
+                vz: 1.0 / 3.0,
+                mass: 2.0,
+                id: i + 1,
+                fixed: i % 2 == 0,
+                ..Default::default()
+            })
+            .collect();
+        Frame {
+            iteration,
+            time: iteration as f64 * 0.5,
+            dt: 0.5,
+            entities,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("physim_test_{name}.zarr"));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_round_trip_with_changing_entity_count() {
+        let dir = test_dir("round_trip");
+        let frames: Vec<Frame> = [(0, 3), (1, 5), (2, 0), (4, 4)]
+            .into_iter()
+            .map(|(i, n)| frame(i, n))
+            .collect();
+        let attributes = Map::from_iter([("pipeline".to_string(), json!("cube ! astro"))]);
+        // a chunk size which doesn't line up with the frames
+        let mut writer = TrajectoryWriter::create(&dir, &all_fields(), 3, attributes).unwrap();
+        for f in frames.iter() {
+            writer.write_frame(f).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let trajectory = Trajectory::open(&dir).unwrap();
+        assert_eq!(trajectory.pipeline(), Some("cube ! astro"));
+        assert_eq!(trajectory.iterations(), &[0, 1, 2, 4]);
+        let read: Vec<Frame> = trajectory.frames().map(|f| f.unwrap()).collect();
+        assert_eq!(read, frames);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_field_selection() {
+        let dir = test_dir("field_selection");
+        let fields = vec!["x".to_string(), "id".to_string()];
+        let mut writer = TrajectoryWriter::create(&dir, &fields, 16, Map::new()).unwrap();
+        writer.write_frame(&frame(0, 2)).unwrap();
+        drop(writer);
+
+        let trajectory = Trajectory::open(&dir).unwrap();
+        assert_eq!(trajectory.fields(), fields.as_slice());
+        let read = trajectory.read_frame(0).unwrap();
+        assert_eq!(read.entities[1].x, 0.1);
+        assert_eq!(read.entities[1].id, 2);
+        assert_eq!(read.entities[1].mass, 0.0);
+        assert!(!dir.join("mass").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_while_writing() {
+        let dir = test_dir("read_while_writing");
+        let mut writer = TrajectoryWriter::create(&dir, &all_fields(), 4, Map::new()).unwrap();
+        for i in 0..3 {
+            writer.write_frame(&frame(i, 3)).unwrap();
+        }
+        // 9 rows have been written, but only 2 chunks of 4 rows are complete
+        let trajectory = Trajectory::open(&dir).unwrap();
+        assert_eq!(trajectory.len(), 2);
+        assert_eq!(trajectory.read_frame(1).unwrap(), frame(1, 3));
+        drop(writer);
+        assert_eq!(Trajectory::open(&dir).unwrap().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_does_not_replace_other_files() {
+        let dir = test_dir("replace");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("important"), "data").unwrap();
+        assert!(TrajectoryWriter::create(&dir, &all_fields(), 4, Map::new()).is_err());
+        assert!(
+            TrajectoryWriter::create(dir.join("t"), &["w".to_string()], 4, Map::new()).is_err()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! The units that the quantities of a simulation are measured in. The unit
+//! system is chosen with `global units=...`. Each plugin has its own copy of
+//! it, which the pipeline sets before creating the plugin's elements.
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Metres in an astronomical unit
+pub const AU: f64 = 1.495_978_707e11;
+/// Kilograms in a solar mass
+pub const SOLAR_MASS: f64 = 1.988_47e30;
+/// Seconds in a Julian year
+pub const YEAR: f64 = 3.155_76e7;
+/// The gravitational constant in SI units
+pub const G_SI: f64 = 6.674_30e-11;
+/// The speed of light in SI units
+pub const C_SI: f64 = 299_792_458.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnitSystem {
+    /// Dimensionless units where G = 1
+    #[default]
+    NBody,
+    /// Metres, kilograms and seconds
+    Si,
+    /// Astronomical units, solar masses and years
+    Astronomical,
+}
+
+impl UnitSystem {
+    const ALL: [UnitSystem; 3] = [UnitSystem::NBody, UnitSystem::Si, UnitSystem::Astronomical];
+
+    /// The units of length, mass and time in metres, kilograms and seconds.
+    /// N-body units are dimensionless, so they have none.
+    pub fn scales(self) -> Option<[f64; 3]> {
+        match self {
+            UnitSystem::NBody => None,
+            UnitSystem::Si => Some([1.0, 1.0, 1.0]),
+            UnitSystem::Astronomical => Some([AU, SOLAR_MASS, YEAR]),
+        }
+    }
+
+    /// Names of the units of length, mass and time
+    pub fn names(self) -> Option<[&'static str; 3]> {
+        match self {
+            UnitSystem::NBody => None,
+            UnitSystem::Si => Some(["m", "kg", "s"]),
+            UnitSystem::Astronomical => Some(["AU", "Msun", "yr"]),
+        }
+    }
+
+    /// The gravitational constant in these units
+    pub fn g(self) -> f64 {
+        match self.scales() {
+            Some([length, mass, time]) => G_SI * mass * time * time / length.powi(3),
+            None => 1.0,
+        }
+    }
+
+    /// The speed of light in these units. N-body units don't have one.
+    pub fn speed_of_light(self) -> Option<f64> {
+        let [length, _, time] = self.scales()?;
+        Some(C_SI * time / length)
+    }
+
+    /// The unit of a field of an entity, e.g. `AU/yr` for `vx`. Fields
+    /// without a unit, and every field in N-body units, have none.
+    pub fn unit_of(self, field: &str) -> Option<String> {
+        let [length, mass, time] = self.names()?;
+        match field {
+            "x" | "y" | "z" | "radius" => Some(length.to_string()),
+            "vx" | "vy" | "vz" => Some(format!("{length}/{time}")),
+            "mass" => Some(mass.to_string()),
+            "time" | "dt" => Some(time.to_string()),
+            _ => None,
+        }
+    }
+
+    /// A number for the unit system, to pass it to a plugin
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|u| u.id() == id)
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for UnitSystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|u| u.to_string() == s)
+            .ok_or_else(|| format!("unknown unit system {s}, expected one of nbody, si or au"))
+    }
+}
+
+impl Display for UnitSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            UnitSystem::NBody => "nbody",
+            UnitSystem::Si => "si",
+            UnitSystem::Astronomical => "au",
+        };
+        write!(f, "{name}")
+    }
+}
+
+static UNIT_SYSTEM: AtomicU8 = AtomicU8::new(0);
+
+/// The unit system of the simulation
+pub fn unit_system() -> UnitSystem {
+    UnitSystem::from_id(UNIT_SYSTEM.load(Ordering::Relaxed))
+}
+
+pub fn set_unit_system(units: UnitSystem) {
+    UNIT_SYSTEM.store(units.id(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gravitational_constant() {
+        assert_eq!(UnitSystem::NBody.g(), 1.0);
+        assert_eq!(UnitSystem::Si.g(), G_SI);
+        // Kepler's third law makes G about 4 pi^2 in astronomical units
+        let g = UnitSystem::Astronomical.g();
+        let four_pi_squared = 4.0 * std::f64::consts::PI.powi(2);
+        assert!((g - four_pi_squared).abs() < 1e-3 * four_pi_squared, "{g}");
+
+        assert_eq!(UnitSystem::NBody.speed_of_light(), None);
+        // about 63240 AU a year
+        let c = UnitSystem::Astronomical.speed_of_light().unwrap();
+        assert!((c - 63_241.1).abs() < 1.0, "{c}");
+    }
+
+    #[test]
+    fn test_names() {
+        for units in UnitSystem::ALL {
+            assert_eq!(units.to_string().parse(), Ok(units));
+            assert_eq!(UnitSystem::from_id(units.id()), units);
+        }
+        assert!("cgs".parse::<UnitSystem>().is_err());
+        assert_eq!(
+            UnitSystem::Astronomical.unit_of("vx").as_deref(),
+            Some("AU/yr")
+        );
+        assert_eq!(UnitSystem::Si.unit_of("id"), None);
+        assert_eq!(UnitSystem::NBody.unit_of("x"), None);
+    }
+}
@@ -1,33 +1,229 @@
 use std::collections::HashMap;
 
-use physim_attribute::transmute_element;
+use physim_attribute::{Properties, transmute_element};
 use physim_core::{
     Entity,
-    messages::MessageClient,
-    plugin::{Element, ElementCreator, transmute::TransmuteElement},
+    messages::{MessageClient, MessagePriority},
+    msg,
+    plugin::{Element, ElementCreator, properties::Properties, transmute::TransmuteElement},
+    post_bus_msg,
 };
-use serde_json::Value;
+use serde_json::{Map, Value, json};
 
-#[transmute_element(name = "bbox", blurb = "Reflect entities at bounding box")]
-struct BBox {
+/// The faces of the box, in the order they are given in
+const FACES: [&str; 6] = ["xmin", "xmax", "ymin", "ymax", "zmin", "zmax"];
+
+// an entity moving fast enough to cross the box this many times in one step
+// is put on the wall instead
+const MAX_REFLECTIONS: usize = 64;
+
+#[derive(Properties)]
+#[properties(validate = BBoxProperties::validate)]
+struct BBoxProperties {
+    /// Maximum distance from origin in x, unless min and max are given
+    #[property(default = 1.0, range = 0.0..)]
     xlim: f64,
+    /// Maximum distance from origin in y, unless min and max are given
+    #[property(default = 1.0, range = 0.0..)]
     ylim: f64,
+    /// Maximum distance from origin in z, unless min and max are given
+    #[property(default = 1.0, range = 0.0..)]
     zlim: f64,
+    /// Lowest corner of the box, e.g. \[0,0,-1\]
+    min: Option<[f64; 3]>,
+    /// Highest corner of the box, e.g. \[2,1,1\]
+    max: Option<[f64; 3]>,
+    /// What each wall does to entities which cross it. One for every wall, or six for xmin, xmax, ymin, ymax, zmin and zmax
+    #[property(
+        default = ["reflective"],
+        choices = ["reflective", "periodic", "open", "absorbing"]
+    )]
+    walls: Vec<String>,
+    /// Coefficient of restitution of reflective walls. One for every wall, or six in the same order as walls
+    #[property(default = [1.0], range = 0.0..=1.0)]
+    restitution: Vec<f64>,
+    /// Post the impulse each reflective wall gives to the entities on the message bus every step
+    #[property(default = false)]
+    report_impulses: bool,
 }
 
-impl TransmuteElement for BBox {
-    fn transmute(&self, data: &mut Vec<Entity>) {
-        for e in data.iter_mut() {
-            if e.x.abs() > self.xlim {
-                e.vx *= -1.0
+impl BBoxProperties {
+    fn walls(&self) -> Result<Walls, String> {
+        let limits = [self.xlim, self.ylim, self.zlim];
+        let min = self.min.unwrap_or(limits.map(|l| -l));
+        let max = self.max.unwrap_or(limits);
+        Walls::new(min, max, &self.walls, &self.restitution)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.walls().map(|_| ())
+    }
+}
+
+/// What happens to an entity which crosses a wall
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Wall {
+    /// It bounces back inside, keeping `restitution` of its speed
+    Reflective { restitution: f64 },
+    /// It comes back in through the opposite wall
+    Periodic,
+    /// It carries on outside the box
+    Open,
+    /// It is removed
+    Absorbing,
+}
+
+/// A box, which can be offset from the origin, and what each of its walls
+/// does. The walls are in the order of `FACES`.
+#[derive(Debug, Clone, PartialEq)]
+struct Walls {
+    min: [f64; 3],
+    max: [f64; 3],
+    faces: [Wall; 6],
+}
+
+/// One for each wall, from either one or six values
+fn per_wall<T: Clone>(name: &str, values: &[T]) -> Result<Vec<T>, String> {
+    match values.len() {
+        1 => Ok(vec![values[0].clone(); 6]),
+        6 => Ok(values.to_vec()),
+        n => Err(format!("{name} needs one value or six, got {n}")),
+    }
+}
+
+impl Walls {
+    fn new(
+        min: [f64; 3],
+        max: [f64; 3],
+        walls: &[String],
+        restitution: &[f64],
+    ) -> Result<Self, String> {
+        for axis in 0..3 {
+            if min[axis] >= max[axis] {
+                return Err(format!(
+                    "the box must have {} below {}",
+                    FACES[2 * axis],
+                    FACES[2 * axis + 1]
+                ));
             }
-            if e.y.abs() > self.ylim {
-                e.vy *= -1.0
+        }
+        let walls = per_wall("walls", walls)?;
+        let restitution = per_wall("restitution", restitution)?;
+        let mut faces = [Wall::Open; 6];
+        for (face, (wall, restitution)) in walls.iter().zip(restitution).enumerate() {
+            faces[face] = match wall.as_str() {
+                "reflective" => Wall::Reflective { restitution },
+                "periodic" => Wall::Periodic,
+                "absorbing" => Wall::Absorbing,
+                _ => Wall::Open,
+            };
+        }
+        for axis in 0..3 {
+            let periodic = [faces[2 * axis], faces[2 * axis + 1]].map(|w| w == Wall::Periodic);
+            if periodic[0] != periodic[1] {
+                return Err(format!(
+                    "{} and {} must both be periodic or neither",
+                    FACES[2 * axis],
+                    FACES[2 * axis + 1]
+                ));
             }
-            if e.z.abs() > self.zlim {
-                e.vz *= -1.0
+        }
+        Ok(Self { min, max, faces })
+    }
+
+    /// Apply the walls to entities which have crossed them. Returns the
+    /// impulse each wall gave to the entities which bounced off it.
+    fn contain(&self, data: &mut Vec<Entity>) -> [f64; 6] {
+        let mut impulses = [0.0; 6];
+        data.retain_mut(|e| {
+            e.fixed || (0..3).all(|axis| self.contain_axis(e, axis, &mut impulses))
+        });
+        impulses
+    }
+
+    /// Apply the walls across `axis` to an entity. Returns false if it is
+    /// absorbed.
+    fn contain_axis(&self, e: &mut Entity, axis: usize, impulses: &mut [f64; 6]) -> bool {
+        let (lo, hi) = (self.min[axis], self.max[axis]);
+        let mass = e.mass;
+        let (x, v) = match axis {
+            0 => (&mut e.x, &mut e.vx),
+            1 => (&mut e.y, &mut e.vy),
+            _ => (&mut e.z, &mut e.vz),
+        };
+        // a fast entity can bounce off both walls in one step
+        for _ in 0..MAX_REFLECTIONS {
+            let (face, wall, outwards) = if *x < lo {
+                (2 * axis, lo, -1.0)
+            } else if *x > hi {
+                (2 * axis + 1, hi, 1.0)
+            } else {
+                return true;
+            };
+            match self.faces[face] {
+                Wall::Open => return true,
+                Wall::Absorbing => return false,
+                Wall::Periodic => {
+                    *x = lo + (*x - lo).rem_euclid(hi - lo);
+                    return true;
+                }
+                Wall::Reflective { restitution } => {
+                    // the distance past the wall is mirrored back inside, and
+                    // slowed like the velocity is
+                    *x = wall - restitution * (*x - wall);
+                    if *v * outwards > 0.0 {
+                        impulses[face] += mass * (1.0 + restitution) * v.abs();
+                        *v *= -restitution;
+                    }
+                }
             }
         }
+        *x = x.clamp(lo, hi);
+        true
+    }
+
+    /// The area of each wall
+    fn areas(&self) -> [f64; 6] {
+        let size: [f64; 3] = [0, 1, 2].map(|axis| self.max[axis] - self.min[axis]);
+        [0, 1, 2, 3, 4, 5].map(|face| {
+            let axis = face / 2;
+            size[(axis + 1) % 3] * size[(axis + 2) % 3]
+        })
+    }
+
+    fn reflective(&self) -> bool {
+        self.faces
+            .iter()
+            .any(|w| matches!(w, Wall::Reflective { .. }))
+    }
+}
+
+#[transmute_element(
+    name = "bbox",
+    blurb = "Keep entities in a bounding box",
+    properties = BBoxProperties
+)]
+struct BBox {
+    walls: Walls,
+    report_impulses: bool,
+}
+
+impl TransmuteElement for BBox {
+    fn transmute(&self, data: &mut Vec<Entity>) {
+        let impulses = self.walls.contain(data);
+        if self.report_impulses && self.walls.reflective() {
+            let per_face = |values: [f64; 6]| -> Map<String, Value> {
+                FACES
+                    .iter()
+                    .zip(values)
+                    .map(|(face, value)| (face.to_string(), json!(value)))
+                    .collect()
+            };
+            let message =
+                json!({"impulse": per_face(impulses), "area": per_face(self.walls.areas())});
+            let msg = msg!(self, "bbox", message, MessagePriority::Low);
+            post_bus_msg!(msg);
+        }
     }
 }
 
@@ -35,19 +231,14 @@ impl MessageClient for BBox {}
 
 impl ElementCreator for BBox {
     fn create_element(props: HashMap<String, Value>) -> Box<Self> {
-        let xlim = props
-            .get("xlim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        let ylim = props
-            .get("ylim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        let zlim = props
-            .get("zlim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        Box::new(Self { xlim, ylim, zlim })
+        let properties = BBoxProperties::parse("bbox", &props);
+        let walls = properties
+            .walls()
+            .expect("parse checks that the walls are valid");
+        Box::new(Self {
+            walls,
+            report_impulses: properties.report_impulses,
+        })
     }
 }
 
@@ -55,19 +246,113 @@ impl Element for BBox {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            (
-                "xlim".to_string(),
-                "Maximum distance from origin in x".to_string(),
-            ),
-            (
-                "ylim".to_string(),
-                "Maximum distance from origin in y".to_string(),
-            ),
+        Ok(BBoxProperties::descriptions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walls(walls: &[&str], restitution: &[f64]) -> Walls {
+        let walls: Vec<String> = walls.iter().map(|w| w.to_string()).collect();
+        Walls::new([-1.0; 3], [1.0; 3], &walls, restitution).unwrap()
+    }
+
+    fn entity(x: f64, vx: f64) -> Entity {
+        Entity {
+            x,
+            vx,
+            mass: 2.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reflect() {
+        let mut data = vec![entity(1.3, 2.0), entity(-1.2, 1.0)];
+        let impulses = walls(&["reflective"], &[1.0]).contain(&mut data);
+        assert!((data[0].x - 0.7).abs() < 1e-12);
+        assert_eq!(data[0].vx, -2.0);
+        // it is already heading back inside
+        assert!((data[1].x + 0.8).abs() < 1e-12);
+        assert_eq!(data[1].vx, 1.0);
+        assert_eq!(impulses, [0.0, 8.0, 0.0, 0.0, 0.0, 0.0]);
+
+        let mut data = vec![entity(1.3, 2.0)];
+        let impulses = walls(&["reflective"], &[0.5]).contain(&mut data);
+        assert!((data[0].x - 0.85).abs() < 1e-12);
+        assert_eq!(data[0].vx, -1.0);
+        assert_eq!(impulses[1], 6.0);
+    }
+
+    #[test]
+    fn test_overshoot() {
+        // it used to get stuck outside, flipping its velocity every step
+        let mut data = vec![entity(5.5, 10.0)];
+        let impulses = walls(&["reflective"], &[1.0]).contain(&mut data);
+        assert!((data[0].x - 0.5).abs() < 1e-12);
+        assert_eq!(data[0].vx, -10.0);
+        assert_eq!(impulses, [40.0, 80.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_faces() {
+        let faces = [
+            "periodic",
+            "periodic",
+            "open",
+            "absorbing",
+            "reflective",
+            "reflective",
+        ];
+        let faces: Vec<String> = faces.iter().map(|w| w.to_string()).collect();
+        let walls = Walls::new([0.0, -1.0, 2.0], [3.0, 1.0, 5.0], &faces, &[1.0]).unwrap();
+        let at = |x: f64, y: f64, z: f64| Entity {
+            x,
+            y,
+            z,
+            ..Default::default()
+        };
+        let mut data = vec![
+            at(3.5, 0.0, 3.0),
+            at(-7.0, 0.0, 3.0),
+            at(1.0, -2.0, 3.0),
+            at(1.0, 1.5, 3.0),
+            at(1.0, 0.0, 1.5),
+        ];
+        walls.contain(&mut data);
+        assert_eq!(data.len(), 4);
+        assert!((data[0].x - 0.5).abs() < 1e-12);
+        assert!((data[1].x - 2.0).abs() < 1e-12);
+        assert_eq!(data[2].y, -2.0);
+        assert_eq!(data[3].z, 2.5);
+        assert_eq!(walls.areas(), [6.0, 6.0, 9.0, 9.0, 6.0, 6.0]);
+    }
+
+    #[test]
+    fn test_invalid_walls() {
+        let strings =
+            |walls: &[&str]| -> Vec<String> { walls.iter().map(|w| w.to_string()).collect() };
+        let one_periodic = strings(&["periodic", "open", "open", "open", "open", "open"]);
+        assert!(Walls::new([-1.0; 3], [1.0; 3], &one_periodic, &[1.0]).is_err());
+        assert!(Walls::new([-1.0; 3], [1.0; 3], &strings(&["open", "open"]), &[1.0]).is_err());
+        assert!(Walls::new([-1.0; 3], [1.0; 3], &strings(&["open"]), &[1.0, 0.5]).is_err());
+        assert!(Walls::new([1.0, -1.0, -1.0], [1.0; 3], &strings(&["open"]), &[1.0]).is_err());
+
+        // the pipeline finds them while checking properties
+        let properties = HashMap::from([
             (
-                "zlim".to_string(),
-                "Maximum distance from origin in z".to_string(),
+                "walls".to_string(),
+                json!(["periodic", "open", "open", "open", "open", "open"]),
             ),
-        ]))
+            ("max".to_string(), json!([2, 2, 2])),
+        ]);
+        assert_eq!(
+            bbox_check_properties(&properties),
+            Err("xmin and xmax must both be periodic or neither".to_string())
+        );
+        let properties = HashMap::from([("min".to_string(), json!([2, 0, 0]))]);
+        assert!(bbox_check_properties(&properties).is_err());
     }
 }
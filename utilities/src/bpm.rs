@@ -1,11 +1,11 @@
 use std::{collections::HashMap, sync::Mutex};
 
-use physim_attribute::transmute_element;
+use physim_attribute::{Properties, transmute_element};
 use physim_core::{
     Entity,
     log::info,
     messages::MessageClient,
-    plugin::{Element, ElementCreator, transmute::TransmuteElement},
+    plugin::{Element, ElementCreator, properties::Properties, transmute::TransmuteElement},
 };
 use serde_json::Value;
 
@@ -15,7 +15,23 @@ enum BpmMode {
     Exclude,
 }
 
-#[transmute_element(name = "bpm", blurb = "Generate Entities")]
+#[derive(Properties)]
+struct BpmProperties {
+    /// generate an entity on every nth frame
+    #[property(default = 1, range = 1..)]
+    n: u64,
+    /// Mass of the entity
+    #[property(default = 1.0, range = 0.0..)]
+    m: f64,
+    /// Radius of the element. Default to size found in simulation
+    #[property(range = 0.0..)]
+    r: Option<f64>,
+    /// always will always make an element, exclude will only do it if the entities are spread out enough
+    #[property(default = "always", choices = ["always", "exclude"])]
+    mode: String,
+}
+
+#[transmute_element(name = "bpm", blurb = "Generate Entities", properties = BpmProperties)]
 struct Bpm {
     inner: Mutex<BpmInner>,
 }
@@ -95,29 +111,16 @@ impl MessageClient for Bpm {}
 
 impl ElementCreator for Bpm {
     fn create_element(props: HashMap<String, Value>) -> Box<Self> {
-        let n = props.get("n").map(|x| x.as_u64().unwrap_or(1)).unwrap_or(1);
-
-        let m = props
-            .get("m")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-
-        let radius = props.get("r").map(|x| x.as_f64().unwrap_or(1.0));
-
-        let mode: BpmMode = props
-            .get("mode")
-            .and_then(|x| x.as_str())
-            .map(|mode_str| match mode_str {
-                "always" => BpmMode::Always,
-                "exclude" => BpmMode::Exclude,
-                _ => BpmMode::Always,
-            })
-            .unwrap_or(BpmMode::Always);
+        let properties = BpmProperties::parse("bpm", &props);
+        let mode = match properties.mode.as_str() {
+            "exclude" => BpmMode::Exclude,
+            _ => BpmMode::Always,
+        };
 
         let inner = BpmInner {
-            n,
-            m,
-            radius,
+            n: properties.n,
+            m: properties.m,
+            radius: properties.r,
             current_frame: 0,
             mode,
         };
@@ -131,17 +134,20 @@ impl Element for Bpm {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            (
-                "n".to_string(),
-                "generate an entity on every nth frame".to_string(),
-            ),
-            ("m".to_string(), "Mass of the entity".to_string()),
-            (
-                "r".to_string(),
-                "Radius of the element. Default to size found in simulation".to_string(),
-            ),
-            ("mode".to_string(), "valid modes are always, exclude. Always will always make an element, excude will only do it if the entities are spread out enough".to_string())
-        ]))
+        Ok(BpmProperties::descriptions())
+    }
+
+    fn checkpoint_state(&self) -> Option<Value> {
+        let inner = self.inner.lock().ok()?;
+        Some(Value::from(inner.current_frame))
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let current_frame = state
+            .as_u64()
+            .ok_or("bpm expects the current frame as its state")?;
+        let mut inner = self.inner.lock().map_err(|_| "BPM mutex poisoned")?;
+        inner.current_frame = current_frame;
+        Ok(())
     }
 }
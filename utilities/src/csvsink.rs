@@ -1,92 +1,251 @@
-use physim_attribute::render_element;
+use physim_attribute::{Properties, render_element};
 use physim_core::{
     Entity,
     messages::MessageClient,
-    plugin::{Element, ElementCreator, render::RenderElement},
+    plugin::{
+        Element, ElementCreator,
+        properties::Properties,
+        render::{Backpressure, Frame, FrameStride, RenderElement},
+    },
+    units::{UnitSystem, unit_system},
 };
 use serde_json::Value;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::{
     collections::HashMap,
     fs::File,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
+#[derive(Debug, PartialEq)]
+enum CsvMode {
+    /// One line per frame containing the positions of every entity
+    Wide,
+    /// One line per entity per frame with a header
+    Long,
+}
+
+#[derive(Properties)]
+struct CsvSinkProperties {
+    /// print every n iterations
+    #[property(default = 1, range = 1..)]
+    print_n: u64,
+    /// Path of the output file
+    #[property(default = "csvsink.csv")]
+    file: String,
+    /// wide writes the positions of every entity on one line per frame. long writes a header and one row per entity per frame
+    #[property(default = "wide", choices = ["wide", "long"])]
+    mode: String,
+    /// Columns written in long mode, e.g. \["iteration","x"\] or x,y,z
+    #[property(
+        default = ["iteration", "time", "x", "y", "z", "vx", "vy", "vz", "radius", "mass", "id", "fixed"],
+        choices = ["iteration", "time", "dt", "x", "y", "z", "vx", "vy", "vz", "radius", "mass", "id", "fixed"]
+    )]
+    fields: Vec<String>,
+}
+
 #[render_element(
     name = "csvsink",
-    blurb = "Output entity position to comma spaced values"
+    blurb = "Output entity position to comma spaced values",
+    properties = CsvSinkProperties
 )]
 struct CsvSink {
     iteration: AtomicUsize,
     print_n: usize,
     file: String,
+    mode: CsvMode,
+    fields: Vec<String>,
+    units: UnitSystem,
 }
 
 impl ElementCreator for CsvSink {
     fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
-        let print_n = properties
-            .get("print_n")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(1) as usize;
-        let file = properties
-            .get("file")
-            .and_then(|v| v.as_str())
-            .unwrap_or("csvsink.csv");
+        let properties = CsvSinkProperties::parse("csvsink", &properties);
+        let mode = match properties.mode.as_str() {
+            "long" => CsvMode::Long,
+            _ => CsvMode::Wide,
+        };
         Box::new(CsvSink {
             iteration: AtomicUsize::new(0),
-            print_n,
-            file: String::from(file),
+            print_n: properties.print_n as usize,
+            file: properties.file,
+            mode,
+            fields: properties.fields,
+            units: unit_system(),
         })
     }
 }
 
 impl RenderElement for CsvSink {
-    fn render(&self, state_recv: std::sync::mpsc::Receiver<Vec<Entity>>) {
+    fn render(&self, state_recv: std::sync::mpsc::Receiver<Frame>) {
         let res = File::options()
             .create(true)
             .write(true)
             .truncate(true)
             .open(&self.file);
 
-        let mut file = match res {
+        let file = match res {
             Ok(f) => f,
             Err(e) => {
                 eprintln!("Error opening {}: {}", self.file, e);
                 std::process::exit(1)
             }
         };
+        let mut file = BufWriter::new(file);
+
+        if self.mode == CsvMode::Long {
+            self.render_long(&mut file, state_recv);
+            return;
+        }
 
         if let Ok(state) = state_recv.recv() {
             self.iteration.fetch_add(1, Ordering::Relaxed);
-            print_state(&mut file, state);
+            print_state(&mut file, state.entities);
         }
 
         while let Ok(state) = state_recv.recv() {
             let iteration = self.iteration.fetch_add(1, Ordering::Relaxed);
             if iteration.rem_euclid(self.print_n) == 0 {
-                print_state(&mut file, state);
+                print_state(&mut file, state.entities);
+            }
+        }
+    }
+
+    fn default_backpressure(&self) -> Backpressure {
+        // every state is written
+        Backpressure::Block
+    }
+}
+
+impl CsvSink {
+    #[allow(unused_must_use)]
+    fn render_long(
+        &self,
+        file: &mut BufWriter<File>,
+        state_recv: std::sync::mpsc::Receiver<Frame>,
+    ) {
+        // columns are labelled with their units, unless they are N-body units
+        let header: Vec<String> = self
+            .fields
+            .iter()
+            .map(|field| match self.units.unit_of(field) {
+                Some(unit) => format!("{field} [{unit}]"),
+                None => field.clone(),
+            })
+            .collect();
+        writeln!(file, "{}", header.join(","));
+
+        let mut stride = FrameStride::new(self.print_n as u64);
+        while let Ok(frame) = state_recv.recv() {
+            if !stride.accept(&frame) {
+                continue;
+            }
+            for entity in &frame.entities {
+                print_row(file, &self.fields, &frame, entity);
             }
         }
+        file.flush();
     }
 }
 
 #[allow(unused_must_use)]
-fn print_state(mut file: &mut File, state: Vec<Entity>) {
+fn print_row(file: &mut BufWriter<File>, fields: &[String], frame: &Frame, entity: &Entity) {
+    for (idx, field) in fields.iter().enumerate() {
+        if idx != 0 {
+            write!(file, ",");
+        }
+        match field.as_str() {
+            "iteration" => write!(file, "{}", frame.iteration),
+            "time" => write!(file, "{}", frame.time),
+            "dt" => write!(file, "{}", frame.dt),
+            "x" => write!(file, "{}", entity.x),
+            "y" => write!(file, "{}", entity.y),
+            "z" => write!(file, "{}", entity.z),
+            "vx" => write!(file, "{}", entity.vx),
+            "vy" => write!(file, "{}", entity.vy),
+            "vz" => write!(file, "{}", entity.vz),
+            "radius" => write!(file, "{}", entity.radius),
+            "mass" => write!(file, "{}", entity.mass),
+            "id" => write!(file, "{}", entity.id),
+            "fixed" => write!(file, "{}", entity.fixed),
+            _ => Ok(()),
+        };
+    }
+    writeln!(file);
+}
+
+#[allow(unused_must_use)]
+fn print_state(file: &mut BufWriter<File>, state: Vec<Entity>) {
     for entity in &state {
-        write!(&mut file, "{},{},{},", entity.x, entity.y, entity.z);
+        write!(file, "{},{},{},", entity.x, entity.y, entity.z);
     }
-    writeln!(&mut file);
+    writeln!(file);
 }
 
 impl Element for CsvSink {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([(
-            String::from("print_n"),
-            String::from("print every n iterations"),
-        )]))
+        Ok(CsvSinkProperties::descriptions())
     }
 }
 
 impl MessageClient for CsvSink {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn frame(iteration: u64, x: f64) -> Frame {
+        Frame {
+            iteration,
+            time: iteration as f64 * 0.5,
+            dt: 0.5,
+            entities: vec![Entity {
+                x,
+                mass: 2.0,
+                ..Default::default()
+            }],
+        }
+    }
+
+    /// Render `frames` with `properties` and read back what was written
+    fn render(name: &str, properties: Value, units: UnitSystem, frames: Vec<Frame>) -> String {
+        let path = std::env::temp_dir().join(format!("physim_test_{name}.csv"));
+        let mut properties: HashMap<String, Value> = serde_json::from_value(properties).unwrap();
+        properties.insert("file".to_string(), json!(path.to_str().unwrap()));
+        let mut sink = CsvSink::create_element(properties);
+        sink.units = units;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for frame in frames {
+            sender.send(frame).unwrap();
+        }
+        drop(sender);
+        sink.render(receiver);
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        written
+    }
+
+    #[test]
+    fn test_long_mode() {
+        // the simulation was paused on iteration 1
+        let frames = vec![frame(0, 1.0), frame(1, 2.0), frame(1, 2.0), frame(2, 3.0)];
+        let properties = json!({"mode": "long", "fields": "iteration,time,dt,x,mass"});
+        assert_eq!(
+            render("long_mode", properties, UnitSystem::NBody, frames),
+            "iteration,time,dt,x,mass\n0,0,0.5,1,2\n1,0.5,0.5,2,2\n2,1,0.5,3,2\n"
+        );
+    }
+
+    #[test]
+    fn test_long_mode_stride_and_units() {
+        let frames = (0..6).map(|i| frame(i, i as f64)).collect();
+        let properties = json!({"mode": "long", "fields": "iteration,x,id", "print_n": 2});
+        assert_eq!(
+            render("long_stride", properties, UnitSystem::Si, frames),
+            "iteration,x [m],id\n0,0,0\n2,2,0\n4,4,0\n"
+        );
+    }
+}
@@ -37,6 +37,21 @@ impl Element for IdTransmute {
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
         Ok(HashMap::from([]))
     }
+
+    fn checkpoint_state(&self) -> Option<Value> {
+        Some(Value::from(
+            self.current_id.load(std::sync::atomic::Ordering::Relaxed),
+        ))
+    }
+
+    fn restore_state(&self, state: Value) -> Result<(), Box<dyn std::error::Error>> {
+        let current_id = state
+            .as_u64()
+            .ok_or("idset expects the next id as its state")?;
+        self.current_id
+            .store(current_id, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
 }
 
 impl MessageClient for IdTransmute {}
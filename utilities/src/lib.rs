@@ -2,8 +2,12 @@ mod bbox;
 mod bpm;
 mod csvsink;
 mod idset;
+mod snapshot;
+mod trajsink;
 mod wrapper;
 
 use physim_core::register_plugin;
 
-register_plugin!("csvsink", "bbox", "wrapper", "idset", "bpm");
+register_plugin!(
+    "csvsink", "bbox", "wrapper", "idset", "bpm", "snapshot", "trajsink"
+);
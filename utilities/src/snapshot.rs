@@ -0,0 +1,141 @@
+use std::{collections::HashMap, error::Error, fs::File, io::BufReader, path::Path};
+
+use physim_attribute::{Properties, initialise_state_element};
+use physim_core::{
+    Entity,
+    checkpoint::Checkpoint,
+    log::warn,
+    messages::MessageClient,
+    plugin::{Element, ElementCreator, generator::GeneratorElement, properties::Properties},
+    snapshot::{self, SnapshotFrame},
+};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SnapshotFormat {
+    Csv,
+    Binary,
+    Checkpoint,
+}
+
+impl SnapshotFormat {
+    fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => SnapshotFormat::Csv,
+            Some("ckpt") | Some("json") => SnapshotFormat::Checkpoint,
+            _ => SnapshotFormat::Binary,
+        }
+    }
+}
+
+#[derive(Properties)]
+#[properties(validate = SnapshotProperties::validate)]
+struct SnapshotProperties {
+    /// Path of the file to load
+    #[property(required)]
+    file: String,
+    /// Defaults to csv for .csv files, checkpoint for .ckpt and .json files and binary otherwise
+    #[property(choices = ["csv", "binary", "checkpoint"])]
+    format: Option<String>,
+    /// Iteration to load from a CSV file with an iteration column. Defaults to the last
+    iteration: Option<u64>,
+}
+
+impl SnapshotProperties {
+    fn validate(&self) -> Result<(), String> {
+        File::open(&self.file)
+            .map(|_| ())
+            .map_err(|e| format!("can't open {}: {e}", self.file))
+    }
+}
+
+#[initialise_state_element(
+    name = "snapshot",
+    blurb = "Load entities from a CSV, binary or checkpoint file",
+    properties = SnapshotProperties
+)]
+struct Snapshot {
+    entities: Vec<Entity>,
+}
+
+fn read(
+    file: &str,
+    format: SnapshotFormat,
+    frame: SnapshotFrame,
+) -> Result<Vec<Entity>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(file)?);
+    match format {
+        SnapshotFormat::Csv => snapshot::read_csv(reader, frame),
+        SnapshotFormat::Binary => snapshot::read_binary(reader),
+        SnapshotFormat::Checkpoint => Ok(Checkpoint::load(file)?.entities),
+    }
+}
+
+impl ElementCreator for Snapshot {
+    fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
+        let properties = SnapshotProperties::parse("snapshot", &properties);
+        let file = properties.file;
+        let format = match properties.format.as_deref() {
+            Some("csv") => SnapshotFormat::Csv,
+            Some("binary") => SnapshotFormat::Binary,
+            Some("checkpoint") => SnapshotFormat::Checkpoint,
+            _ => SnapshotFormat::from_path(&file),
+        };
+        let frame = properties
+            .iteration
+            .map(SnapshotFrame::Iteration)
+            .unwrap_or_default();
+        // the pipeline checks that the file can be opened, but its contents
+        // are only checked here, while the pipeline is being built
+        match read(&file, format, frame) {
+            Ok(entities) => Box::new(Self { entities }),
+            Err(e) => {
+                eprintln!("Error reading snapshot {file}: {e}");
+                std::process::exit(1)
+            }
+        }
+    }
+}
+
+impl GeneratorElement for Snapshot {
+    fn create_entities(&self) -> Vec<Entity> {
+        if self.entities.is_empty() {
+            warn!("snapshot has no entities to create");
+        }
+        self.entities.clone()
+    }
+}
+
+impl Element for Snapshot {
+    fn get_property_descriptions(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        Ok(SnapshotProperties::descriptions())
+    }
+}
+
+impl MessageClient for Snapshot {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_check_file() {
+        assert_eq!(
+            snapshot_check_properties(&HashMap::new()),
+            Err("file is required".to_string())
+        );
+
+        let path = std::env::temp_dir().join("physim_test_snapshot.csv");
+        std::fs::write(&path, "x,y,z\n1,2,3\n").unwrap();
+        let file = |path: &str| HashMap::from([("file".to_string(), json!(path))]);
+        assert!(snapshot_check_properties(&file(path.to_str().unwrap())).is_ok());
+        let snapshot = Snapshot::create_element(file(path.to_str().unwrap()));
+        assert_eq!(snapshot.create_entities().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = std::env::temp_dir().join("physim_test_no_snapshot.csv");
+        assert!(snapshot_check_properties(&file(missing.to_str().unwrap())).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use physim_attribute::{Properties, render_element};
+use physim_core::{
+    messages::{Message, MessageClient},
+    plugin::{
+        Element, ElementCreator,
+        properties::Properties,
+        render::{Backpressure, Frame, FrameStride, RenderElement},
+    },
+    snapshot::ENTITY_FIELDS,
+    trajectory::TrajectoryWriter,
+    units::{UnitSystem, unit_system},
+};
+use serde_json::{Map, Value, json};
+
+#[derive(Properties)]
+struct TrajSinkProperties {
+    /// Path of the Zarr store
+    #[property(default = "trajectory.zarr")]
+    file: String,
+    /// record every n iterations
+    #[property(default = 1, range = 1..)]
+    print_n: u64,
+    /// Entity fields to record, e.g. \["x","y"\] or x,y
+    #[property(default = ENTITY_FIELDS, choices = ENTITY_FIELDS)]
+    fields: Vec<String>,
+    /// Number of rows in each chunk
+    #[property(default = 65536, range = 1..)]
+    chunk: usize,
+}
+
+#[render_element(
+    name = "trajsink",
+    blurb = "Record trajectories to a chunked binary Zarr store",
+    properties = TrajSinkProperties
+)]
+struct TrajSink {
+    file: String,
+    print_n: u64,
+    fields: Vec<String>,
+    chunk: usize,
+    pipeline: Mutex<Option<String>>,
+    units: UnitSystem,
+}
+
+impl ElementCreator for TrajSink {
+    fn create_element(properties: HashMap<String, Value>) -> Box<Self> {
+        let properties = TrajSinkProperties::parse("trajsink", &properties);
+        Box::new(Self {
+            file: properties.file,
+            print_n: properties.print_n,
+            fields: properties.fields,
+            chunk: properties.chunk,
+            pipeline: Mutex::new(None),
+            units: unit_system(),
+        })
+    }
+}
+
+impl RenderElement for TrajSink {
+    fn render(&self, state_recv: std::sync::mpsc::Receiver<Frame>) {
+        let mut attributes = Map::new();
+        if let Some(pipeline) = self
+            .pipeline
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+        {
+            attributes.insert("pipeline".to_string(), json!(pipeline));
+        }
+        attributes.insert(
+            "physim_version".to_string(),
+            json!(env!("CARGO_PKG_VERSION")),
+        );
+        attributes.insert("stride".to_string(), json!(self.print_n));
+        let mut units = json!({"system": self.units.to_string(), "G": self.units.g()});
+        if let Some([length, mass, time]) = self.units.names() {
+            units["length"] = json!(length);
+            units["mass"] = json!(mass);
+            units["time"] = json!(time);
+        }
+        attributes.insert("units".to_string(), units);
+
+        let mut writer =
+            match TrajectoryWriter::create(&self.file, &self.fields, self.chunk, attributes) {
+                Ok(writer) => writer,
+                Err(e) => {
+                    eprintln!("Error creating {}: {}", self.file, e);
+                    std::process::exit(1)
+                }
+            };
+
+        let mut stride = FrameStride::new(self.print_n);
+        while let Ok(frame) = state_recv.recv() {
+            if !stride.accept(&frame) {
+                continue;
+            }
+            if let Err(e) = writer.write_frame(&frame) {
+                eprintln!("Error writing to {}: {}", self.file, e);
+                std::process::exit(1)
+            }
+        }
+        if let Err(e) = writer.finish() {
+            eprintln!("Error writing to {}: {}", self.file, e);
+            std::process::exit(1)
+        }
+    }
+
+    fn default_backpressure(&self) -> Backpressure {
+        // every state is written
+        Backpressure::Block
+    }
+}
+
+impl Element for TrajSink {
+    fn get_property_descriptions(
+        &self,
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        Ok(TrajSinkProperties::descriptions())
+    }
+}
+
+impl MessageClient for TrajSink {
+    fn recv_message(&self, message: &Message) {
+        if message.topic == "pipeline_description" {
+            *self.pipeline.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(message.message.clone());
+        }
+    }
+}
@@ -1,29 +1,40 @@
 use std::collections::HashMap;
 
-use physim_attribute::transmute_element;
+use physim_attribute::{Properties, transmute_element};
 use physim_core::{
     Entity,
+    domain::{Domain, periodic_domain},
     messages::MessageClient,
-    plugin::{Element, ElementCreator, transmute::TransmuteElement},
+    plugin::{Element, ElementCreator, properties::Properties, transmute::TransmuteElement},
 };
 use serde_json::Value;
 
+#[derive(Properties)]
+struct WrapperProperties {
+    /// Maximum distance from origin in x. Without any limits, entities are wrapped into the periodic domain of the simulation, or limits of 1
+    #[property(range = 0.0..)]
+    xlim: Option<f64>,
+    /// Maximum distance from origin in y
+    #[property(range = 0.0..)]
+    ylim: Option<f64>,
+    /// Maximum distance from origin in z
+    #[property(range = 0.0..)]
+    zlim: Option<f64>,
+}
+
 #[transmute_element(
     name = "wrapper",
-    blurb = "Define a cyclical boundary for the universe"
+    blurb = "Define a cyclical boundary for the universe",
+    properties = WrapperProperties
 )]
 struct Wrapper {
-    xlim: f64,
-    ylim: f64,
-    zlim: f64,
+    domain: Domain,
 }
 
 impl TransmuteElement for Wrapper {
     fn transmute(&self, data: &mut Vec<Entity>) {
         for e in data.iter_mut() {
-            e.x = (e.x + self.xlim).rem_euclid(2.0 * self.xlim) - self.xlim;
-            e.y = (e.y + self.ylim).rem_euclid(2.0 * self.ylim) - self.ylim;
-            e.z = (e.z + self.zlim).rem_euclid(2.0 * self.zlim) - self.zlim;
+            [e.x, e.y, e.z] = self.domain.wrap([e.x, e.y, e.z]);
         }
     }
 }
@@ -32,19 +43,16 @@ impl MessageClient for Wrapper {}
 
 impl ElementCreator for Wrapper {
     fn create_element(props: HashMap<String, Value>) -> Box<Self> {
-        let xlim = props
-            .get("xlim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        let ylim = props
-            .get("ylim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        let zlim = props
-            .get("zlim")
-            .map(|x| x.as_f64().unwrap_or(1.0))
-            .unwrap_or(1.0);
-        Box::new(Self { xlim, ylim, zlim })
+        let properties = WrapperProperties::parse("wrapper", &props);
+        let limits = [properties.xlim, properties.ylim, properties.zlim];
+        let domain = match periodic_domain() {
+            Some(domain) if limits.iter().all(Option::is_none) => domain,
+            _ => {
+                let limits = limits.map(|l| l.unwrap_or(1.0));
+                Domain::new(limits.map(|l| -l), limits, [true; 3])
+            }
+        };
+        Box::new(Self { domain })
     }
 }
 
@@ -52,19 +60,6 @@ impl Element for Wrapper {
     fn get_property_descriptions(
         &self,
     ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        Ok(HashMap::from([
-            (
-                "xlim".to_string(),
-                "Maximum distance from origin in x".to_string(),
-            ),
-            (
-                "ylim".to_string(),
-                "Maximum distance from origin in y".to_string(),
-            ),
-            (
-                "zlim".to_string(),
-                "Maximum distance from origin in z".to_string(),
-            ),
-        ]))
+        Ok(WrapperProperties::descriptions())
     }
 }